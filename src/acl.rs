@@ -0,0 +1,103 @@
+//! Rights of the access control lists of RFC 4314.
+
+use std::collections::BTreeMap;
+
+/// Every right, in the order rights are listed.
+pub const ALL_RIGHTS: &str = "lrswipkxtea";
+
+/// Identifier whose rights every user has.
+pub const ANYONE: &str = "anyone";
+
+/// Rights `acl`, the list of a mailbox, grants `user`, those of `anyone`
+/// included.
+pub fn granted(acl: Option<&BTreeMap<String, String>>, user: &str) -> String {
+    let acl = match acl {
+        Some(acl) => acl,
+        None => return String::new(),
+    };
+    normalize(
+        [user, ANYONE]
+            .iter()
+            .filter_map(|identifier| acl.get(*identifier))
+            .flat_map(|rights| rights.chars()),
+    )
+}
+
+/// Rights in the order of `ALL_RIGHTS`, each once.
+pub fn normalize(rights: impl Iterator<Item = char>) -> String {
+    let rights: Vec<char> = rights.collect();
+    ALL_RIGHTS.chars().filter(|r| rights.contains(r)).collect()
+}
+
+/// Parse rights of SETACL, the obsolete `c` and `d` of RFC 2086 standing
+/// for `k` and `xte`. `None` if one is unknown.
+pub fn parse(rights: &str) -> Option<String> {
+    let mut parsed = String::new();
+    for right in rights.chars() {
+        match right {
+            'c' => parsed.push('k'),
+            'd' => parsed.push_str("xte"),
+            r if ALL_RIGHTS.contains(r) => parsed.push(r),
+            _ => return None,
+        }
+    }
+    Some(normalize(parsed.chars()))
+}
+
+/// Right needed to add or remove `flag` on a message: `s` for `\Seen`, `t`
+/// for `\Deleted` and `w` for the others.
+pub fn flag_right(flag: &str) -> char {
+    if flag.eq_ignore_ascii_case("\\Seen") {
+        's'
+    } else if flag.eq_ignore_ascii_case("\\Deleted") {
+        't'
+    } else {
+        'w'
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_rights() {
+        assert_eq!(parse("rl").as_deref(), Some("lr"));
+        assert_eq!(parse("lrsll").as_deref(), Some("lrs"));
+        assert_eq!(parse("").as_deref(), Some(""));
+        // RFC 2086 rights
+        assert_eq!(parse("lc").as_deref(), Some("lk"));
+        assert_eq!(parse("d").as_deref(), Some("xte"));
+        assert_eq!(parse("cdlrswipa").as_deref(), Some(ALL_RIGHTS));
+        for invalid in ["L", "lrz", "l r", "0"] {
+            assert_eq!(parse(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn normalizes_in_rights_order() {
+        assert_eq!(normalize("aeltl".chars()), "ltea");
+        assert_eq!(normalize("zy".chars()), "");
+    }
+
+    #[test]
+    fn grants_anyone_rights_to_every_user() {
+        let acl: BTreeMap<String, String> = [("bob", "lrw"), (ANYONE, "rsl")]
+            .iter()
+            .map(|(id, rights)| (id.to_string(), rights.to_string()))
+            .collect();
+        assert_eq!(granted(Some(&acl), "bob"), "lrsw");
+        assert_eq!(granted(Some(&acl), "alice"), "lrs");
+        assert_eq!(granted(None, "bob"), "");
+        assert_eq!(granted(Some(&BTreeMap::new()), "bob"), "");
+    }
+
+    #[test]
+    fn flags_need_their_rights() {
+        assert_eq!(flag_right("\\Seen"), 's');
+        assert_eq!(flag_right("\\SEEN"), 's');
+        assert_eq!(flag_right("\\deleted"), 't');
+        assert_eq!(flag_right("\\Flagged"), 'w');
+        assert_eq!(flag_right("$Label1"), 'w');
+    }
+}
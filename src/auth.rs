@@ -0,0 +1,135 @@
+//! Checking the credentials of LOGIN and AUTHENTICATE.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, SaltString};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+
+use crate::config::Config;
+use crate::result::Result;
+
+/// Checks user credentials, a backend of its own being plugged in by
+/// implementing it.
+pub trait Authenticator: Send + Sync {
+    /// Whether `password` is the password of `user`. Unknown users are
+    /// refused like a wrong password.
+    fn verify(&self, user: &str, password: &[u8]) -> Result<bool>;
+}
+
+/// Backend of the configuration: the password file if there's one,
+/// otherwise every login is refused.
+pub fn from_config(config: &Config) -> Arc<dyn Authenticator> {
+    match config.passwd_file.as_ref() {
+        Some(path) => Arc::new(PasswordFile::new(path)),
+        None => Arc::new(NoUsers),
+    }
+}
+
+/// Refuses every login, the backend until one is configured.
+pub struct NoUsers;
+
+impl Authenticator for NoUsers {
+    fn verify(&self, _user: &str, _password: &[u8]) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Users and their argon2 password hashes in PHC string form, one
+/// `user:hash` per line, `#` starting a comment:
+///
+/// ```text
+/// bob:$argon2id$v=19$m=19456,t=2,p=1$...
+/// ```
+///
+/// The file is read at each login, so users are added without a restart.
+pub struct PasswordFile {
+    path: PathBuf,
+}
+
+impl PasswordFile {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Hash of the password of `user`, `None` if there's no such user.
+    fn hash_of(&self, user: &str) -> Result<Option<String>> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(find_hash(&content, user).map(String::from))
+    }
+}
+
+impl Authenticator for PasswordFile {
+    fn verify(&self, user: &str, password: &[u8]) -> Result<bool> {
+        // the names of the store's own files aren't users'
+        if user.starts_with('.') {
+            return Ok(false);
+        }
+        let hash = match self.hash_of(user)? {
+            Some(hash) => hash,
+            None => return Ok(false),
+        };
+        let hash = PasswordHash::new(&hash).map_err(|e| {
+            anyhow!("Invalid password hash of `{}`: {}", user, e)
+        })?;
+        Ok(Argon2::default().verify_password(password, &hash).is_ok())
+    }
+}
+
+/// Hash of `user` in password file `content`.
+fn find_hash<'a>(content: &'a str, user: &str) -> Option<&'a str> {
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| *name == user)
+        .map(|(_, hash)| hash)
+}
+
+/// Hash of `password` for the password file, with a random salt.
+pub fn hash_password(password: &[u8]) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password, &salt)
+        .map_err(|e| anyhow!("Cannot hash password: {}", e))?;
+    Ok(hash.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_users_hash() {
+        let content = "# users\nbob:$argon2id$b\n\n alice:$argon2id$a \n";
+        assert_eq!(find_hash(content, "alice"), Some("$argon2id$a"));
+        assert_eq!(find_hash(content, "bob"), Some("$argon2id$b"));
+        assert_eq!(find_hash(content, "# users"), None);
+        assert_eq!(find_hash(content, "carol"), None);
+    }
+
+    #[test]
+    fn verifies_passwords() {
+        let path = std::env::temp_dir()
+            .join(format!("imaple-passwd-{}", std::process::id()));
+        let hash = hash_password(b"secret").unwrap();
+        fs::write(&path, format!("bob:{}\n.shared:{}\n", hash, hash)).unwrap();
+
+        let file = PasswordFile::new(&path);
+        assert!(file.verify("bob", b"secret").unwrap());
+        assert!(!file.verify("bob", b"wrong").unwrap());
+        assert!(!file.verify("alice", b"secret").unwrap());
+        assert!(!file.verify(".shared", b"secret").unwrap());
+        assert!(!NoUsers.verify("bob", b"secret").unwrap());
+        fs::remove_file(path).unwrap();
+    }
+}
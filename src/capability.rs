@@ -0,0 +1,150 @@
+//! Capabilities the server advertises, which depend on the configuration
+//! and on the state of the session.
+
+use crate::session::Session;
+
+/// Capabilities of the server as `session` currently sees it, starting with
+/// the protocol revisions it speaks.
+///
+/// A plaintext connection offers STARTTLS and no way to log in, a TLS one
+/// offers the authentication mechanisms and, once logged in, the
+/// extensions of the authenticated state.
+pub fn capabilities(session: &Session) -> String {
+    let mut capabilities = vec![String::from("IMAP4rev1")];
+    let mut add = |capability: &str| capabilities.push(capability.into());
+
+    add("IMAP4rev2");
+
+    if !session.tls {
+        add("STARTTLS");
+        add("LOGINDISABLED");
+    } else if session.user.is_none() {
+        add("AUTH=PLAIN");
+        add("SASL-IR");
+    }
+    add("ID");
+    add(match session.config.literal_plus {
+        true => "LITERAL+",
+        false => "LITERAL-",
+    });
+
+    if session.user.is_some() {
+        let config = &session.config;
+        add("ACL");
+        add(&format!("APPENDLIMIT={}", config.max_message_size));
+        add("BINARY");
+        add("CHILDREN");
+        if !session.compressed {
+            add("COMPRESS=DEFLATE");
+        }
+        add("CONDSTORE");
+        add("CREATE-SPECIAL-USE");
+        add("ENABLE");
+        add("ESEARCH");
+        add("IDLE");
+        add("LIST-EXTENDED");
+        add("LIST-STATUS");
+        add("METADATA");
+        add("MOVE");
+        add("NAMESPACE");
+        add("PARTIAL");
+        add("QRESYNC");
+        add("QUOTA");
+        add("QUOTA=RES-MESSAGE");
+        add("QUOTA=RES-STORAGE");
+        if session.is_admin() {
+            add("QUOTASET");
+        }
+        add("RIGHTS=texk");
+        add("SEARCHRES");
+        add("SORT");
+        add("SORT=DISPLAY");
+        add("SPECIAL-USE");
+        add("STATUS=SIZE");
+        add("THREAD=ORDEREDSUBJECT");
+        add("THREAD=REFERENCES");
+        if session.uidplus() {
+            add("UIDPLUS");
+        }
+        add("UNSELECT");
+    }
+
+    capabilities.join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{configured_session, mail_dir, run, session};
+
+    fn has(capabilities: &str, capability: &str) -> bool {
+        capabilities.split(' ').any(|c| c == capability)
+    }
+
+    #[tokio::test]
+    async fn depend_on_tls_and_login() {
+        let root = mail_dir("capabilities");
+        let mut s = session(&root);
+        s.user = None;
+        s.tls = false;
+
+        let plaintext = capabilities(&s);
+        assert!(plaintext.starts_with("IMAP4rev1 IMAP4rev2 "));
+        for capability in ["STARTTLS", "LOGINDISABLED", "ID"] {
+            assert!(has(&plaintext, capability), "{}", capability);
+        }
+        for capability in ["AUTH=PLAIN", "IDLE", "UIDPLUS"] {
+            assert!(!has(&plaintext, capability), "{}", capability);
+        }
+
+        s.tls = true;
+        let tls = capabilities(&s);
+        for capability in ["AUTH=PLAIN", "SASL-IR", "ID"] {
+            assert!(has(&tls, capability), "{}", capability);
+        }
+        for capability in ["STARTTLS", "LOGINDISABLED", "IDLE"] {
+            assert!(!has(&tls, capability), "{}", capability);
+        }
+
+        s.user = Some("bob".to_string());
+        let authenticated = capabilities(&s);
+        for capability in
+            ["IDLE", "MOVE", "UIDPLUS", "APPENDLIMIT=52428800", "BINARY"]
+        {
+            assert!(has(&authenticated, capability), "{}", capability);
+        }
+        for capability in ["STARTTLS", "AUTH=PLAIN", "SASL-IR"] {
+            assert!(!has(&authenticated, capability), "{}", capability);
+        }
+
+        let out = run(&mut s, "a CAPABILITY").await;
+        assert_eq!(
+            out,
+            format!(
+                "* CAPABILITY {}\r\na OK CAPABILITY completed\r\n",
+                authenticated
+            )
+        );
+
+        // the client may switch to the second revision
+        let out = run(&mut s, "a ENABLE IMAP4rev2").await;
+        assert_eq!(out, "* ENABLED IMAP4rev2\r\na OK ENABLE completed\r\n");
+        assert!(s.imap4rev2());
+    }
+
+    #[test]
+    fn follow_the_configuration() {
+        let root = mail_dir("capabilities-config");
+        let mut s = configured_session(
+            &root,
+            "uidplus = false\nmax_message_size = 1024\nimplicit_tls = false",
+        );
+        let authenticated = capabilities(&s);
+        assert!(has(&authenticated, "APPENDLIMIT=1024"));
+        assert!(!has(&authenticated, "UIDPLUS"));
+
+        // without implicit TLS, connections start in plaintext
+        s.user = None;
+        assert!(has(&capabilities(&s), "STARTTLS"));
+    }
+}
@@ -18,7 +18,7 @@ pub(crate) fn load_certificates_from_pem(
 pub(crate) fn load_private_key_from_file_pkcs(
     path: &str,
 ) -> Result<PrivateKey, Box<dyn std::error::Error>> {
-    let file = File::open(&path)?;
+    let file = File::open(path)?;
     let mut reader = BufReader::new(file);
     let mut keys = rustls_pemfile::pkcs8_private_keys(&mut reader)?;
 
@@ -39,7 +39,7 @@ pub(crate) fn load_private_key_from_file(
 ) -> Result<PrivateKey, Box<dyn std::error::Error>> {
     debug!("Loading private key from `{}`", path);
 
-    let file = File::open(&path)?;
+    let file = File::open(path)?;
     let mut reader = BufReader::new(file);
     let mut keys = rustls_pemfile::rsa_private_keys(&mut reader)?;
 
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+
+/// Server configuration, read from the `.conf` file given on the command
+/// line.
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    #[serde(default = "default_imap_port")]
+    pub imap_port: u16,
+
+    /// Start connections with TLS, otherwise they start in plaintext and
+    /// clients switch to TLS with STARTTLS before logging in.
+    #[serde(default = "default_true")]
+    pub implicit_tls: bool,
+
+    /// Text of the greeting, after the capabilities.
+    #[serde(default = "default_greeting")]
+    pub greeting: String,
+
+    #[serde(default = "default_smtp_port")]
+    pub smtp_port: u16,
+
+    #[serde(default = "default_mail_dir")]
+    pub mail_dir: String,
+
+    /// File of the users and their password hashes, every login being
+    /// refused if not set.
+    pub passwd_file: Option<String>,
+
+    /// Hierarchy delimiter of mailbox names shown to clients.
+    #[serde(default = "default_delimiter")]
+    pub delimiter: char,
+
+    /// Directory of the full-text search index, disabled if not set.
+    pub fts_dir: Option<String>,
+
+    /// Accept non-synchronizing literals of any size (LITERAL+), otherwise
+    /// up to 4096 bytes (LITERAL-, RFC 7888).
+    #[serde(default = "default_true")]
+    pub literal_plus: bool,
+
+    /// Largest message APPEND accepts, in bytes.
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: u32,
+
+    /// Report the UIDs of appended and copied messages (RFC 4315).
+    #[serde(default = "default_true")]
+    pub uidplus: bool,
+
+    /// Address other nodes sharing `mail_dir` send their mailbox events to.
+    pub event_listen: Option<String>,
+
+    /// Addresses of the other nodes sharing `mail_dir`, which are sent the
+    /// mailbox events of this one.
+    #[serde(default)]
+    pub event_peers: Vec<String>,
+
+    /// Secret shared by the nodes exchanging mailbox events, which they
+    /// prove they know before their events are taken.
+    pub event_secret: Option<String>,
+
+    /// Fields the ID command reports about the server (RFC 2971).
+    #[serde(default = "default_server_id")]
+    pub server_id: BTreeMap<String, String>,
+
+    /// Mailboxes created when a user first logs in, with their special-use
+    /// attribute (RFC 6154).
+    #[serde(default = "default_special_use")]
+    pub special_use: BTreeMap<String, String>,
+
+    /// Users allowed to set other users' quotas, who have every right on
+    /// the mailboxes of the shared namespace.
+    #[serde(default)]
+    pub admins: Vec<String>,
+
+    #[serde(default)]
+    pub namespaces: Namespaces,
+
+    #[serde(default)]
+    pub quota: Quota,
+
+    #[serde(default)]
+    pub metadata: MetadataLimits,
+}
+
+/// Prefixes of the namespaces NAMESPACE reports (RFC 2342), a namespace
+/// without one isn't reported.
+#[derive(Deserialize, Debug)]
+pub struct Namespaces {
+    #[serde(default = "default_personal_namespace")]
+    pub personal: Option<String>,
+
+    /// Prefix of the mailboxes other users share with ACLs, followed by
+    /// the owner's name.
+    #[serde(default = "default_other_users_namespace")]
+    pub other_users: Option<String>,
+
+    /// Prefix of the mailboxes no user owns.
+    #[serde(default = "default_shared_namespace")]
+    pub shared: Option<String>,
+}
+
+impl Default for Namespaces {
+    fn default() -> Self {
+        Self {
+            personal: default_personal_namespace(),
+            other_users: default_other_users_namespace(),
+            shared: default_shared_namespace(),
+        }
+    }
+}
+
+/// Quota of every user (RFC 9208) unless an admin set another with
+/// SETQUOTA, a resource without a limit being unlimited.
+#[derive(Deserialize, Debug)]
+pub struct Quota {
+    /// Size of the user's messages, in units of 1024 bytes.
+    pub storage: Option<u64>,
+
+    /// Number of the user's messages.
+    pub messages: Option<u64>,
+
+    /// Percentage of a limit from which reaching it is logged as a warning.
+    #[serde(default = "default_quota_warning")]
+    pub warning: u64,
+}
+
+impl Default for Quota {
+    fn default() -> Self {
+        Self {
+            storage: None,
+            messages: None,
+            warning: default_quota_warning(),
+        }
+    }
+}
+
+/// Limits of the metadata entries of SETMETADATA (RFC 5464).
+#[derive(Deserialize, Debug)]
+pub struct MetadataLimits {
+    /// Longest value of an entry, in bytes.
+    #[serde(default = "default_metadata_max_size")]
+    pub max_size: usize,
+
+    /// Most entries of a mailbox, or of the server, each user may keep.
+    #[serde(default = "default_metadata_max_entries")]
+    pub max_entries: usize,
+}
+
+impl Default for MetadataLimits {
+    fn default() -> Self {
+        Self {
+            max_size: default_metadata_max_size(),
+            max_entries: default_metadata_max_entries(),
+        }
+    }
+}
+
+fn default_imap_port() -> u16 {
+    143
+}
+
+fn default_greeting() -> String {
+    "IMAPLE ready".to_string()
+}
+
+fn default_smtp_port() -> u16 {
+    25
+}
+
+fn default_mail_dir() -> String {
+    "mail".to_string()
+}
+
+fn default_delimiter() -> char {
+    '/'
+}
+
+fn default_max_message_size() -> u32 {
+    50 * 1024 * 1024
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_server_id() -> BTreeMap<String, String> {
+    let mut id = BTreeMap::new();
+    id.insert("name".to_string(), "imaple".to_string());
+    id.insert("version".to_string(), env!("CARGO_PKG_VERSION").to_string());
+    id
+}
+
+fn default_special_use() -> BTreeMap<String, String> {
+    [
+        ("Archive", "\\Archive"),
+        ("Drafts", "\\Drafts"),
+        ("Junk", "\\Junk"),
+        ("Sent", "\\Sent"),
+        ("Trash", "\\Trash"),
+    ]
+    .iter()
+    .map(|(name, attribute)| (name.to_string(), attribute.to_string()))
+    .collect()
+}
+
+fn default_quota_warning() -> u64 {
+    90
+}
+
+fn default_metadata_max_size() -> usize {
+    4096
+}
+
+fn default_metadata_max_entries() -> usize {
+    100
+}
+
+fn default_personal_namespace() -> Option<String> {
+    Some(String::new())
+}
+
+fn default_other_users_namespace() -> Option<String> {
+    Some("Other Users/".to_string())
+}
+
+fn default_shared_namespace() -> Option<String> {
+    Some("Shared/".to_string())
+}
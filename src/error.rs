@@ -11,6 +11,13 @@ impl std::fmt::Display for WError {
     }
 }
 
+impl WError {
+    /// The reason of a refusal, `None` for a failure.
+    pub fn refusal(&self) -> Option<&'static str> {
+        self.0.downcast_ref::<Refused>().map(|refused| refused.0)
+    }
+}
+
 impl From<AnyhowError> for WError {
     fn from(error: AnyhowError) -> Self {
         Self(error)
@@ -23,6 +30,38 @@ impl From<io::Error> for WError {
     }
 }
 
+/// Reply to commands on a mailbox which doesn't exist (RFC 5530).
+pub const NONEXISTENT: &str = "[NONEXISTENT] Mailbox doesn't exist";
+
+/// Reply to commands the user lacks the rights for (RFC 5530).
+pub const NOPERM: &str = "[NOPERM] Permission denied";
+
+/// Reply to commands which would exceed a quota (RFC 9208).
+pub const OVERQUOTA: &str = "[OVERQUOTA] Quota exceeded";
+
+/// Reply to fetching the decoded content of a part whose transfer encoding
+/// isn't known (RFC 3516).
+pub const UNKNOWN_CTE: &str = "[UNKNOWN-CTE] Unknown Content-Transfer-Encoding";
+
+/// A command refused rather than failed, answered `NO` with the reason
+/// alone so its response code comes first.
+#[derive(Debug)]
+pub struct Refused(pub &'static str);
+
+impl std::fmt::Display for Refused {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+impl From<Refused> for WError {
+    fn from(refused: Refused) -> Self {
+        Self(AnyhowError::new(refused))
+    }
+}
+
 type ImapCodec = imap_codec::codec::DecodeError;
 
 impl From<ImapCodec> for WError {
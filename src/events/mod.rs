@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use tokio::sync::broadcast;
+
+mod tcp_transport;
+
+pub use tcp_transport::TcpTransport;
+
+/// Events a session may miss before reading them, beyond which it falls
+/// back to reloading the mailbox index.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// A change made to a mailbox.
+#[derive(Clone, Debug)]
+pub enum MailboxChange {
+    /// Messages were appended, copied or moved in.
+    Exists(Vec<u32>),
+
+    /// Flags of these messages changed.
+    Flags(Vec<u32>),
+
+    /// These messages were expunged.
+    Expunge(Vec<u32>),
+}
+
+/// Origin of the events received from other nodes, no session has it.
+pub const REMOTE_ORIGIN: u64 = 0;
+
+#[derive(Clone, Debug)]
+pub struct MailboxEvent {
+    /// Id of the session which made the change, [`REMOTE_ORIGIN`] if it was
+    /// made on another node.
+    pub origin: u64,
+    pub change: MailboxChange,
+}
+
+/// Carries the events published on this node to the other imaple nodes
+/// sharing its storage, where they are handed to [`EventBus::deliver`].
+pub trait EventTransport: Send + Sync {
+    fn send(&self, user: &str, mailbox: &str, event: &MailboxEvent);
+}
+
+/// Delivers the changes made to a mailbox to every session which has it
+/// selected.
+#[derive(Default)]
+pub struct EventBus {
+    channels: Mutex<HashMap<(String, String), broadcast::Sender<MailboxEvent>>>,
+    transport: Option<Box<dyn EventTransport>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A bus whose events also reach other nodes through `transport`.
+    pub fn with_transport(transport: Box<dyn EventTransport>) -> Self {
+        Self {
+            channels: Mutex::default(),
+            transport: Some(transport),
+        }
+    }
+
+    /// Receive the changes made to `mailbox` of `user` from now on.
+    pub fn subscribe(
+        &self,
+        user: &str,
+        mailbox: &str,
+    ) -> broadcast::Receiver<MailboxEvent> {
+        let mut channels = self.channels.lock().unwrap();
+        // channels of mailboxes nobody has selected anymore
+        channels.retain(|_, sender| sender.receiver_count() > 0);
+        channels
+            .entry((user.to_string(), mailbox.to_string()))
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Tell the sessions of this node and of the other nodes about `event`.
+    pub fn publish(&self, user: &str, mailbox: &str, event: MailboxEvent) {
+        if let Some(transport) = self.transport.as_ref() {
+            transport.send(user, mailbox, &event);
+        }
+        self.deliver(user, mailbox, event);
+    }
+
+    /// Tell the sessions of this node about `event`.
+    pub fn deliver(&self, user: &str, mailbox: &str, event: MailboxEvent) {
+        let channels = self.channels.lock().unwrap();
+        let key = (user.to_string(), mailbox.to_string());
+        if let Some(sender) = channels.get(&key) {
+            // fails only when there is no one to tell
+            let _ = sender.send(event);
+        }
+    }
+}
@@ -0,0 +1,384 @@
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use blake2::digest::Mac;
+use blake2::{Blake2b512, Blake2bMac512, Digest};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use super::{
+    EventBus, EventTransport, MailboxChange, MailboxEvent, REMOTE_ORIGIN,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a peer has to answer the challenge, or to take an event.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long an unreachable peer is left alone before connecting again.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// Events waiting to be sent to a peer, beyond which new ones are dropped.
+const QUEUE_CAPACITY: usize = 1024;
+
+/// Longest line read from a peer.
+const MAX_LINE: usize = 1024 * 1024;
+
+/// Start of the challenge a node sends to the peers connecting to it.
+const GREETING: &str = "imaple-events";
+
+/// Exchanges events with other nodes over TCP, one line per event. A peer
+/// proves it knows the shared secret before its events are taken, by
+/// answering a random challenge with its keyed hash; the events themselves
+/// aren't encrypted, so peers should share a private network. A peer misses
+/// the events sent while it can't be reached, or while too many wait for
+/// it, its sessions catch up when they select the mailbox again.
+pub struct TcpTransport {
+    queues: Vec<(String, mpsc::Sender<Arc<str>>)>,
+}
+
+impl TcpTransport {
+    /// Send the events of this node to the `peers` addresses, each from a
+    /// task of its own so a slow one doesn't hold back the others.
+    pub fn new(peers: Vec<String>, secret: &str) -> Self {
+        let key = Key::new(secret);
+        let queues = peers
+            .into_iter()
+            .map(|addr| {
+                let (queue, lines) = mpsc::channel(QUEUE_CAPACITY);
+                tokio::spawn(send_lines(addr.clone(), key.clone(), lines));
+                (addr, queue)
+            })
+            .collect();
+        Self { queues }
+    }
+
+    /// Deliver the events other nodes knowing `secret` send to `addr` to
+    /// the sessions of `bus`.
+    pub async fn listen(
+        addr: &str,
+        secret: &str,
+        bus: Arc<EventBus>,
+    ) -> io::Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        let key = Key::new(secret);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((socket, _)) => {
+                        let (key, bus) = (key.clone(), bus.clone());
+                        tokio::spawn(receive_lines(socket, key, bus));
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to accept event peer; err = {:?}", e)
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+impl EventTransport for TcpTransport {
+    fn send(&self, user: &str, mailbox: &str, event: &MailboxEvent) {
+        let line: Arc<str> = encode(user, mailbox, &event.change).into();
+        for (addr, queue) in self.queues.iter() {
+            // fails once the queue is full or the server is going down
+            if queue.try_send(line.clone()).is_err() {
+                eprintln!("Dropped event for peer {}; queue full", addr);
+            }
+        }
+    }
+}
+
+/// Key of the hashes answering challenges, derived from the shared secret.
+#[derive(Clone)]
+struct Key(Arc<[u8]>);
+
+impl Key {
+    fn new(secret: &str) -> Self {
+        Self(Blake2b512::digest(secret.as_bytes()).to_vec().into())
+    }
+
+    fn mac(&self, challenge: &[u8]) -> Blake2bMac512 {
+        let mut mac = <Blake2bMac512 as Mac>::new_from_slice(&self.0)
+            .expect("keys are 64 bytes long");
+        mac.update(challenge);
+        mac
+    }
+
+    /// Answer to `challenge`, in hex.
+    fn answer(&self, challenge: &[u8]) -> String {
+        hex(&self.mac(challenge).finalize().into_bytes())
+    }
+
+    /// Whether `answer` is the answer to `challenge`, compared in
+    /// constant time.
+    fn verify(&self, challenge: &[u8], answer: &str) -> bool {
+        match unhex(answer) {
+            Some(answer) => self.mac(challenge).verify_slice(&answer).is_ok(),
+            None => false,
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    text.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [hi, lo] => Some(digit(*hi) << 4 | digit(*lo)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn digit(hex: u8) -> u8 {
+    (hex as char).to_digit(16).unwrap_or(0) as u8
+}
+
+/// `KIND<TAB>uids<TAB>user<TAB>mailbox`, the names escaped so no tab or
+/// line break of theirs breaks the line.
+fn encode(user: &str, mailbox: &str, change: &MailboxChange) -> String {
+    let (kind, uids) = match change {
+        MailboxChange::Exists(uids) => ("EXISTS", uids),
+        MailboxChange::Flags(uids) => ("FLAGS", uids),
+        MailboxChange::Expunge(uids) => ("EXPUNGE", uids),
+    };
+    let uids: Vec<String> = uids.iter().map(u32::to_string).collect();
+    format!(
+        "{}\t{}\t{}\t{}\n",
+        kind,
+        uids.join(","),
+        escape(user),
+        escape(mailbox)
+    )
+}
+
+fn decode(line: &str) -> Option<(String, String, MailboxChange)> {
+    let mut fields = line.split('\t');
+    let kind = fields.next()?;
+    let uids = fields
+        .next()?
+        .split(',')
+        .filter(|uid| !uid.is_empty())
+        .map(|uid| uid.parse().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    let user = unescape(fields.next()?)?;
+    let mailbox = unescape(fields.next()?)?;
+    if fields.next().is_some() {
+        return None;
+    }
+    let change = match kind {
+        "EXISTS" => MailboxChange::Exists(uids),
+        "FLAGS" => MailboxChange::Flags(uids),
+        "EXPUNGE" => MailboxChange::Expunge(uids),
+        _ => return None,
+    };
+    Some((user, mailbox, change))
+}
+
+/// Percent-encode `%`, tabs and line breaks.
+fn escape(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '%' | '\t' | '\r' | '\n' => {
+                escaped.push_str(&format!("%{:02X}", c as u8))
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn unescape(field: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = field.as_bytes();
+    while let Some((&c, tail)) = rest.split_first() {
+        if c == b'%' {
+            bytes.extend(unhex(std::str::from_utf8(tail.get(..2)?).ok()?)?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(c);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Read a line of at most `MAX_LINE` bytes, without its line break, `None`
+/// at the end of the stream.
+async fn read_line<R>(reader: &mut R) -> io::Result<Option<String>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = vec![];
+    loop {
+        let buf = reader.fill_buf().await?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let (chunk, done) = match buf.iter().position(|c| *c == b'\n') {
+            Some(end) => (&buf[..end + 1], true),
+            None => (buf, false),
+        };
+        line.extend_from_slice(chunk);
+        let n = chunk.len();
+        reader.consume(n);
+        if line.len() > MAX_LINE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "too long"));
+        }
+        if done {
+            line.pop();
+            return String::from_utf8(line)
+                .map(Some)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+        }
+    }
+}
+
+/// Connect to the peer at `addr` and answer its challenge.
+async fn connect(addr: &str, key: &Key) -> io::Result<BufReader<TcpStream>> {
+    let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "timed out");
+    let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| timed_out())??;
+    let mut stream = BufReader::new(stream);
+
+    let handshake = async {
+        let greeting = read_line(&mut stream).await?.unwrap_or_default();
+        let challenge = greeting
+            .strip_prefix(GREETING)
+            .and_then(|rest| unhex(rest.trim_start()))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "not an imaple peer")
+            })?;
+        let answer = format!("{}\n", key.answer(&challenge));
+        stream.get_mut().write_all(answer.as_bytes()).await
+    };
+    timeout(IO_TIMEOUT, handshake)
+        .await
+        .map_err(|_| timed_out())??;
+    Ok(stream)
+}
+
+async fn send_lines(
+    addr: String,
+    key: Key,
+    mut lines: mpsc::Receiver<Arc<str>>,
+) {
+    let mut stream = None;
+    let mut retry_at = Instant::now();
+    while let Some(line) = lines.recv().await {
+        if stream.is_none() && Instant::now() >= retry_at {
+            match connect(&addr, &key).await {
+                Ok(connected) => stream = Some(connected),
+                Err(e) => {
+                    eprintln!(
+                        "Failed to connect to event peer {}; err = {:?}",
+                        addr, e
+                    );
+                    retry_at = Instant::now() + RETRY_DELAY;
+                }
+            }
+        }
+        if let Some(connected) = stream.as_mut() {
+            let write = connected.get_mut().write_all(line.as_bytes());
+            let written = match timeout(IO_TIMEOUT, write).await {
+                Ok(written) => written,
+                Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "")),
+            };
+            if let Err(e) = written {
+                eprintln!("Failed to send event to {}; err = {:?}", addr, e);
+                stream = None;
+                retry_at = Instant::now() + RETRY_DELAY;
+            }
+        }
+    }
+}
+
+/// Challenge a connecting peer, then deliver its events.
+async fn receive_lines(socket: TcpStream, key: Key, bus: Arc<EventBus>) {
+    let peer = socket.peer_addr().ok();
+    let mut reader = BufReader::new(socket);
+
+    let mut challenge = [0; 32];
+    OsRng.fill_bytes(&mut challenge);
+    let handshake = async {
+        let greeting = format!("{} {}\n", GREETING, hex(&challenge));
+        reader.get_mut().write_all(greeting.as_bytes()).await?;
+        read_line(&mut reader).await
+    };
+    match timeout(IO_TIMEOUT, handshake).await {
+        Ok(Ok(Some(answer))) if key.verify(&challenge, &answer) => {}
+        _ => {
+            eprintln!("Refused event peer {:?}; failed to authenticate", peer);
+            return;
+        }
+    }
+
+    loop {
+        let line = match read_line(&mut reader).await {
+            Ok(Some(line)) => line,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("Failed to read from event peer; err = {:?}", e);
+                return;
+            }
+        };
+        match decode(&line) {
+            Some((user, mailbox, change)) => {
+                let event = MailboxEvent {
+                    origin: REMOTE_ORIGIN,
+                    change,
+                };
+                bus.deliver(&user, &mailbox, event);
+            }
+            None => eprintln!("Invalid event from peer: {:?}", line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_cant_break_lines() {
+        let change = MailboxChange::Expunge(vec![1, 5]);
+        let line = encode("bob", "a\tb\r\nc%41", &change);
+        assert_eq!(line, "EXPUNGE\t1,5\tbob\ta%09b%0D%0Ac%2541\n");
+        let (user, mailbox, decoded) = decode(line.trim_end()).unwrap();
+        assert_eq!((user.as_str(), mailbox.as_str()), ("bob", "a\tb\r\nc%41"));
+        assert!(
+            matches!(decoded, MailboxChange::Expunge(uids) if uids == [1, 5])
+        );
+
+        assert!(decode("EXISTS\t1\tbob\tINBOX\textra").is_none());
+        assert!(decode("EXISTS\t1\tbob\tIN%4").is_none());
+        assert!(decode("EXISTS\t1\tbob\tIN%+4").is_none());
+        assert!(decode("MOVED\t1\tbob\tINBOX").is_none());
+        assert!(decode("FLAGS\tx\tbob\tINBOX").is_none());
+    }
+
+    #[test]
+    fn answers_need_the_secret() {
+        let key = Key::new("secret");
+        let answer = key.answer(b"challenge");
+        assert!(key.verify(b"challenge", &answer));
+        assert!(!key.verify(b"other", &answer));
+        assert!(!Key::new("guess").verify(b"challenge", &answer));
+        assert!(!key.verify(b"challenge", "zz"));
+    }
+}
@@ -0,0 +1,231 @@
+//! Commands of IMAP extensions imap-codec can't decode, parsed by hand.
+
+use std::num::NonZeroU32;
+
+use imap_codec::sequence::SequenceSet;
+
+/// Argument of an extension command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Atom(String),
+    String(Vec<u8>),
+    List(Vec<Token>),
+}
+
+impl Token {
+    /// Value of an atom or string, as for an `astring`.
+    pub fn as_astring(&self) -> Option<String> {
+        match self {
+            Token::Atom(atom) => Some(atom.clone()),
+            Token::String(bytes) => Some(String::from_utf8_lossy(bytes).into()),
+            Token::List(_) => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[Token]> {
+        match self {
+            Token::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_sequence_set(&self) -> Option<SequenceSet> {
+        match self {
+            Token::Atom(atom) => atom.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_number(&self) -> Option<u64> {
+        match self {
+            Token::Atom(atom) => atom.parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_nz_number(&self) -> Option<NonZeroU32> {
+        match self {
+            Token::Atom(atom) => atom.parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Whether this is the atom `name`, case-insensitively.
+    pub fn is_atom(&self, name: &str) -> bool {
+        matches!(self, Token::Atom(atom) if atom.eq_ignore_ascii_case(name))
+    }
+
+    /// Write the token as it appears in a command, strings quoted unless
+    /// they need a literal.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Token::Atom(atom) => out.extend_from_slice(atom.as_bytes()),
+            Token::String(bytes)
+                if bytes
+                    .iter()
+                    .all(|c| c.is_ascii() && !b"\r\n\0".contains(c)) =>
+            {
+                out.push(b'"');
+                for c in bytes {
+                    if *c == b'"' || *c == b'\\' {
+                        out.push(b'\\');
+                    }
+                    out.push(*c);
+                }
+                out.push(b'"');
+            }
+            Token::String(bytes) => {
+                out.extend_from_slice(
+                    format!("{{{}}}\r\n", bytes.len()).as_bytes(),
+                );
+                out.extend_from_slice(bytes);
+            }
+            Token::List(items) => {
+                out.push(b'(');
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        out.push(b' ');
+                    }
+                    item.encode(out);
+                }
+                out.push(b')');
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ExtCommand {
+    pub tag: String,
+
+    /// Uppercased command name, `UID ` prefixed for UID commands.
+    pub name: String,
+
+    pub args: Vec<Token>,
+}
+
+/// Parse a complete command line, with any literal already inlined as
+/// `{n}\r\n<n bytes>`.
+pub fn ext_command_decode(buf: &[u8]) -> Option<ExtCommand> {
+    let buf = buf.strip_suffix(b"\r\n").unwrap_or(buf);
+    let mut parser = Parser { buf, pos: 0 };
+
+    let tag = parser.atom()?;
+    parser.space()?;
+    let mut name = parser.atom()?.to_ascii_uppercase();
+    if name == "UID" {
+        parser.space()?;
+        name = format!("UID {}", parser.atom()?.to_ascii_uppercase());
+    }
+
+    let mut args = vec![];
+    while parser.pos < buf.len() {
+        parser.space()?;
+        args.push(parser.token()?);
+    }
+
+    Some(ExtCommand { tag, name, args })
+}
+
+struct Parser<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.buf.get(self.pos).copied()
+    }
+
+    fn space(&mut self) -> Option<()> {
+        if self.peek()? != b' ' {
+            return None;
+        }
+        self.pos += 1;
+        Some(())
+    }
+
+    fn atom(&mut self) -> Option<String> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c == b'[' {
+                // a section like `BODY[HEADER.FIELDS (From)]` is one atom
+                let rest = &self.buf[self.pos..];
+                if let Some(end) = rest.iter().position(|c| *c == b']') {
+                    if !rest[..end].contains(&b'\r') {
+                        self.pos += end + 1;
+                        continue;
+                    }
+                }
+            }
+            if c <= b' ' || c >= 0x7f || b"(){\"".contains(&c) {
+                break;
+            }
+            self.pos += 1;
+        }
+        if self.pos == start {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&self.buf[start..self.pos]).into())
+    }
+
+    fn token(&mut self) -> Option<Token> {
+        match self.peek()? {
+            b'(' => {
+                self.pos += 1;
+                let mut items = vec![];
+                if self.peek()? == b')' {
+                    self.pos += 1;
+                    return Some(Token::List(items));
+                }
+                loop {
+                    items.push(self.token()?);
+                    match self.peek()? {
+                        b')' => {
+                            self.pos += 1;
+                            return Some(Token::List(items));
+                        }
+                        b' ' => self.pos += 1,
+                        _ => return None,
+                    }
+                }
+            }
+            b'"' => {
+                self.pos += 1;
+                let mut value = vec![];
+                loop {
+                    match self.peek()? {
+                        b'"' => break,
+                        b'\\' => {
+                            self.pos += 1;
+                            value.push(self.peek()?);
+                        }
+                        c => value.push(c),
+                    }
+                    self.pos += 1;
+                }
+                self.pos += 1;
+                Some(Token::String(value))
+            }
+            b'{' => {
+                let end = self.pos
+                    + self.buf[self.pos..].iter().position(|c| *c == b'}')?;
+                let length: usize =
+                    std::str::from_utf8(&self.buf[self.pos + 1..end])
+                        .ok()?
+                        .trim_end_matches('+')
+                        .parse()
+                        .ok()?;
+                let start = end + 3; // `}\r\n`
+                if self.buf.get(end + 1..start)? != b"\r\n"
+                    || start + length > self.buf.len()
+                {
+                    return None;
+                }
+                self.pos = start + length;
+                Some(Token::String(self.buf[start..self.pos].to_vec()))
+            }
+            _ => self.atom().map(Token::Atom),
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Splitting of the client's byte stream into commands.
+
+use anyhow::anyhow;
+
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+
+use crate::result::Result;
+
+/// Longest command line accepted, literals excluded.
+const MAX_LINE: u64 = 64 * 1024;
+
+/// Largest literal accepted in a command, except for APPEND's message which
+/// is never held in memory.
+const MAX_LITERAL: u32 = 64 * 1024;
+
+/// Largest non-synchronizing literal of LITERAL- (RFC 7888).
+pub const MAX_LITERAL_MINUS: u32 = 4096;
+
+/// A literal announced at the end of a line.
+pub struct Literal {
+    pub length: u32,
+
+    /// `false` for `{n+}`, which the client sends without waiting for a
+    /// continuation request.
+    pub synchronizing: bool,
+
+    /// `~{n}`, a `literal8` which may hold NULs (RFC 3516).
+    pub binary: bool,
+}
+
+pub enum Frame {
+    /// A complete command, literals inlined as `{n}\r\n<n bytes>`.
+    Command(Vec<u8>),
+
+    /// An APPEND up to the `{n}\r\n` announcing its message, the `n` bytes
+    /// of which are left on the socket for the handler to stream.
+    Append { line: Vec<u8>, literal: Literal },
+}
+
+/// Read the next command from `socket`, sending the continuation requests
+/// its literals need. `None` once the client has gone.
+///
+/// Non-synchronizing literals are accepted up to `max_non_sync` bytes. A
+/// client going over it, or over the limits of lines and literals, gets a
+/// BAD and an error ends the connection, as what it sends next can't be
+/// told apart from commands.
+pub async fn read_frame<IO>(
+    socket: &mut IO,
+    max_non_sync: u32,
+) -> Result<Option<Frame>>
+where
+    IO: AsyncBufRead + AsyncWrite + Unpin,
+{
+    let mut buf = vec![];
+    loop {
+        let start = buf.len();
+        let n = (&mut *socket)
+            .take(MAX_LINE)
+            .read_until(b'\n', &mut buf)
+            .await?;
+        if n == 0 {
+            if buf.is_empty() {
+                return Ok(None);
+            }
+            return Err(anyhow!("Connection closed within a command").into());
+        }
+        if !buf.ends_with(b"\n") {
+            reject(socket, &buf, "Command line too long").await?;
+            return Err(anyhow!("Command line too long").into());
+        }
+        // be lenient with clients ending lines with a bare LF
+        if !buf.ends_with(b"\r\n") {
+            buf.insert(buf.len() - 1, b'\r');
+        }
+
+        let literal = match literal(&buf[start..]) {
+            Some(literal) => literal,
+            None => return Ok(Some(Frame::Command(buf))),
+        };
+        if !literal.synchronizing && literal.length > max_non_sync {
+            reject(socket, &buf, "[TOOBIG] Literal too big").await?;
+            return Err(anyhow!(
+                "Non-synchronizing literal of {} bytes is too big",
+                literal.length
+            )
+            .into());
+        }
+        if is_append_message(&buf, start) {
+            return Ok(Some(Frame::Append { line: buf, literal }));
+        }
+        // only the message of APPEND may be a literal8
+        if literal.binary {
+            reject(socket, &buf, "Unexpected binary literal").await?;
+            if literal.synchronizing {
+                buf.clear();
+                continue;
+            }
+            return Err(anyhow!("Binary literal outside of APPEND").into());
+        }
+        if literal.length > MAX_LITERAL {
+            reject(socket, &buf, "[TOOBIG] Literal too big").await?;
+            if literal.synchronizing {
+                // never sent, the client goes on with its next command
+                buf.clear();
+                continue;
+            }
+            return Err(anyhow!(
+                "Literal of {} bytes is too big",
+                literal.length
+            )
+            .into());
+        }
+
+        if literal.synchronizing {
+            socket.write_all(b"+ Ready for literal data\r\n").await?;
+            socket.flush().await?;
+        }
+
+        let start = buf.len();
+        buf.resize(start + literal.length as usize, 0);
+        socket.read_exact(&mut buf[start..]).await?;
+    }
+}
+
+/// The literal announced at the end of `line`, if any.
+pub fn literal(line: &[u8]) -> Option<Literal> {
+    let line = line.strip_suffix(b"}\r\n")?;
+    let open = line.iter().rposition(|c| *c == b'{')?;
+    let length = std::str::from_utf8(&line[open + 1..]).ok()?;
+    let (length, synchronizing) = match length.strip_suffix('+') {
+        Some(length) => (length, false),
+        None => (length, true),
+    };
+    Some(Literal {
+        length: length.parse().ok()?,
+        synchronizing,
+        binary: line[..open].ends_with(b"~"),
+    })
+}
+
+/// Send BAD to the command being read in `buf`.
+async fn reject<IO>(socket: &mut IO, buf: &[u8], msg: &str) -> Result<()>
+where
+    IO: AsyncWrite + Unpin,
+{
+    let tag = buf
+        .split(|c| *c == b' ')
+        .next()
+        .filter(|tag| !tag.is_empty() && tag.iter().all(u8::is_ascii_graphic))
+        .unwrap_or(b"*");
+    let response = format!("{} BAD {}\r\n", String::from_utf8_lossy(tag), msg);
+    socket.write_all(response.as_bytes()).await?;
+    socket.flush().await?;
+    Ok(())
+}
+
+/// `line` without the literal announced at its end.
+pub fn strip_literal(line: &[u8]) -> &[u8] {
+    match line.iter().rposition(|c| *c == b'{') {
+        Some(open) if literal(line).is_some() => {
+            let line = &line[..open];
+            let line = line.strip_suffix(b"~").unwrap_or(line);
+            line.strip_suffix(b" ").unwrap_or(line)
+        }
+        _ => line,
+    }
+}
+
+/// Whether the literal ending `buf` is the message of an APPEND, rather
+/// than its mailbox name. `start` is where the last line of `buf` begins.
+fn is_append_message(buf: &[u8], start: usize) -> bool {
+    let words: Vec<&[u8]> =
+        strip_literal(buf).splitn(3, |c| *c == b' ').collect();
+    let is_append = words
+        .get(1)
+        .is_some_and(|name| name.eq_ignore_ascii_case(b"APPEND"));
+
+    // `tag APPEND {n}` announces the mailbox name
+    is_append && !(start == 0 && words.len() == 2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announced(line: &str) -> Option<(u32, bool)> {
+        literal(line.as_bytes()).map(|l| (l.length, l.synchronizing))
+    }
+
+    #[test]
+    fn finds_binary_literals() {
+        let line = b"a APPEND INBOX ~{5+}\r\n";
+        let literal = literal(line).unwrap();
+        assert!(literal.binary && !literal.synchronizing);
+        assert_eq!(strip_literal(line), b"a APPEND INBOX");
+        assert!(!super::literal(b"a APPEND INBOX {5}\r\n").unwrap().binary);
+    }
+
+    #[test]
+    fn finds_literals_ending_lines() {
+        assert_eq!(announced("a LOGIN bob {5}\r\n"), Some((5, true)));
+        assert_eq!(announced("a LOGIN bob {5+}\r\n"), Some((5, false)));
+        assert_eq!(announced("a LOGIN {0}\r\n"), Some((0, true)));
+        for line in [
+            "a LOGIN bob pw\r\n",
+            "a LOGIN bob {5}",
+            "a LOGIN bob {5} \r\n",
+            "a LOGIN bob {}\r\n",
+            "a LOGIN bob {+}\r\n",
+            "a LOGIN bob {-5}\r\n",
+            "a LOGIN bob {5++}\r\n",
+            "a LOGIN bob {99999999999}\r\n",
+        ] {
+            assert_eq!(announced(line), None, "{:?}", line);
+        }
+
+        assert_eq!(strip_literal(b"a LOGIN bob {5+}\r\n"), b"a LOGIN bob");
+        assert_eq!(strip_literal(b"a NOOP {x}\r\n"), b"a NOOP {x}\r\n");
+    }
+
+    #[test]
+    fn tells_append_messages_from_mailbox_names() {
+        let append =
+            |buf: &str, start| is_append_message(buf.as_bytes(), start);
+        assert!(append("a APPEND INBOX {10}\r\n", 0));
+        assert!(append("a append INBOX (\\Seen) {10+}\r\n", 0));
+        // the mailbox name is a literal, followed by the message
+        assert!(!append("a APPEND {5}\r\n", 0));
+        assert!(append("a APPEND {5}\r\nINBOX {10}\r\n", 14));
+        assert!(!append("a LOGIN bob {5}\r\n", 0));
+        assert!(!append("a SEARCH TEXT {6}\r\n", 0));
+    }
+
+    #[tokio::test]
+    async fn inlines_literals_and_leaves_append_messages() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut server = tokio::io::BufReader::new(server);
+        client
+            .write_all(
+                b"a LOGIN {3}\r\nbob pw\r\n\
+                  b APPEND INBOX {4+}\r\nbody",
+            )
+            .await
+            .unwrap();
+
+        match read_frame(&mut server, MAX_LITERAL_MINUS).await.unwrap() {
+            Some(Frame::Command(line)) => {
+                assert_eq!(line, b"a LOGIN {3}\r\nbob pw\r\n")
+            }
+            _ => panic!("expected a command"),
+        }
+        let mut continuation = [0; 26];
+        client.read_exact(&mut continuation).await.unwrap();
+        assert_eq!(&continuation, b"+ Ready for literal data\r\n");
+
+        match read_frame(&mut server, MAX_LITERAL_MINUS).await.unwrap() {
+            Some(Frame::Append { line, literal }) => {
+                assert_eq!(line, b"b APPEND INBOX {4+}\r\n");
+                assert_eq!(literal.length, 4);
+                assert!(!literal.synchronizing);
+            }
+            _ => panic!("expected an append"),
+        }
+        let mut message = [0; 4];
+        server.read_exact(&mut message).await.unwrap();
+        assert_eq!(&message, b"body");
+
+        // a literal8 elsewhere is refused before it's sent
+        client
+            .write_all(b"c SEARCH TEXT ~{2}\r\nd NOOP\r\n")
+            .await
+            .unwrap();
+        match read_frame(&mut server, MAX_LITERAL_MINUS).await.unwrap() {
+            Some(Frame::Command(line)) => assert_eq!(line, b"d NOOP\r\n"),
+            _ => panic!("expected a command"),
+        }
+        let mut refusal = [0; 33];
+        client.read_exact(&mut refusal).await.unwrap();
+        assert_eq!(&refusal, b"c BAD Unexpected binary literal\r\n");
+    }
+}
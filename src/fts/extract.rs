@@ -0,0 +1,203 @@
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+use crate::message::Message;
+
+/// Longest term kept in the index, longer words are mostly encoded blobs.
+const MAX_TERM_LEN: usize = 64;
+
+/// Searchable text of a message, split by the fields SEARCH can target.
+/// Both the index and the scan of SEARCH look at it, so the index never
+/// misses a message the scan would find.
+pub struct Extracted {
+    pub subject: String,
+    pub headers: String,
+    pub body: String,
+}
+
+pub fn extract(msg: &Message<'_>) -> Extracted {
+    // what HTML parts display, entities decoded, is searched besides
+    // their source
+    let mut body = msg.body_text();
+    for (mimetype, text) in msg.text_parts() {
+        if mimetype == "text/html" {
+            body.push_str(&strip_html(&text));
+            body.push('\n');
+        }
+    }
+
+    Extracted {
+        subject: msg.header_values("Subject").join("\n"),
+        headers: msg.header_text(),
+        body,
+    }
+}
+
+/// Fold `text` for matching: compatibility decomposition, diacritics
+/// removed, lowercased.
+pub fn normalize(text: &str) -> String {
+    text.nfkd()
+        .filter(|c| !is_combining_mark(*c))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Normalized words of `text`.
+fn words(text: &str) -> Vec<String> {
+    normalize(text)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Split `text` into normalized terms, words too long to be indexed left
+/// out.
+pub fn tokenize(text: &str) -> Vec<String> {
+    let mut terms = words(text);
+    terms.retain(|t| t.chars().count() <= MAX_TERM_LEN);
+    terms
+}
+
+/// Terms of `text` to index, an empty one standing for every word too long
+/// to be kept, which could hold any shorter word.
+pub fn index_terms(text: &str) -> Vec<String> {
+    let mut terms = words(text);
+    for term in terms.iter_mut() {
+        if term.chars().count() > MAX_TERM_LEN {
+            term.clear();
+        }
+    }
+    terms
+}
+
+/// Reduce an HTML document to its visible text.
+pub fn strip_html(html: &str) -> String {
+    let mut text = String::with_capacity(html.len() / 2);
+    let mut rest = html;
+
+    while let Some(start) = rest.find('<') {
+        text.push_str(&decode_entities(&rest[..start]));
+        text.push(' ');
+        rest = &rest[start..];
+
+        let tag_end = match rest.find('>') {
+            Some(end) => end,
+            None => return text,
+        };
+        let tag = rest[1..tag_end].trim().to_ascii_lowercase();
+        rest = &rest[tag_end + 1..];
+
+        // content of these elements is never displayed
+        for skipped in ["script", "style", "head"] {
+            if tag == skipped || tag.starts_with(&format!("{} ", skipped)) {
+                let close = format!("</{}", skipped);
+                rest = match rest.to_ascii_lowercase().find(&close) {
+                    Some(pos) => &rest[pos..],
+                    None => "",
+                };
+            }
+        }
+    }
+    text.push_str(&decode_entities(rest));
+    text
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let decoded = rest
+            .find(';')
+            .filter(|end| *end <= 10)
+            .and_then(|end| Some((decode_entity(&rest[1..end])?, end)));
+
+        match decoded {
+            Some((decoded, end)) => {
+                out.push_str(&decoded);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn decode_entity(entity: &str) -> Option<String> {
+    let c = match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        "szlig" => '\u{df}',
+        _ if entity.starts_with("#x") || entity.starts_with("#X") => {
+            char::from_u32(u32::from_str_radix(&entity[2..], 16).ok()?)?
+        }
+        _ if entity.starts_with('#') => {
+            char::from_u32(entity[1..].parse().ok()?)?
+        }
+        _ => {
+            // accented latin letters, `&eacute;` is `e` + combining acute
+            let mut chars = entity.chars();
+            let letter = chars.next().filter(char::is_ascii_alphabetic)?;
+            let mark = match chars.as_str() {
+                "grave" => '\u{300}',
+                "acute" => '\u{301}',
+                "circ" => '\u{302}',
+                "tilde" => '\u{303}',
+                "uml" => '\u{308}',
+                "ring" => '\u{30a}',
+                "cedil" => '\u{327}',
+                _ => return None,
+            };
+            return Some([letter, mark].iter().copied().nfc().collect());
+        }
+    };
+    Some(c.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenizes_normalized_words() {
+        assert_eq!(
+            tokenize("Héllo, WORLD! x-ray ﬁne 42"),
+            ["hello", "world", "x", "ray", "fine", "42"]
+        );
+        assert_eq!(tokenize(" ,;- "), Vec::<String>::new());
+
+        let long = "a".repeat(MAX_TERM_LEN + 1);
+        assert_eq!(
+            tokenize(&format!("short {} word", long)),
+            ["short", "word"]
+        );
+        assert_eq!(index_terms(&format!("short {}", long)), ["short", ""]);
+    }
+
+    #[test]
+    fn strips_html_to_visible_text() {
+        let html = "<html><head><title>Hidden</title></head>\
+                    <body><p>Caf&eacute; &amp; <b>bar</b>&#33;&#x21;</p>\
+                    <SCRIPT type=\"x\">var hidden;</SCRIPT>\
+                    <style>.hidden {}</style>shown &bogus; &</body></html>";
+        let text = strip_html(html);
+        assert_eq!(words(&text), ["cafe", "bar", "shown", "bogus"]);
+        assert!(text.contains("Café & ") && text.contains(" !!"));
+        assert!(text.contains("&bogus; &"));
+
+        // an unclosed tag hides the rest
+        assert_eq!(strip_html("a <b c").trim(), "a");
+    }
+}
@@ -0,0 +1,765 @@
+//! Optional full-text search index used for BODY, TEXT and SUBJECT search
+//! keys.
+//!
+//! Each mailbox gets an inverted index mapping normalized terms to the UIDs
+//! containing them, kept under the configured `fts_dir` as a log of the
+//! messages added and removed. Changes are appended to it, and it's
+//! rewritten with the indexed messages only once it's mostly outdated.
+//! Messages are added when they are appended to a mailbox and removed on
+//! expunge, `imaple reindex` rebuilds it from the store.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use log::debug;
+
+use crate::message::Message;
+use crate::result::Result;
+use crate::storage::MailStore;
+
+mod extract;
+
+pub use extract::{extract, normalize, tokenize, Extracted};
+
+/// First line of a log, followed by the identifier of the log, which
+/// changes whenever it's rewritten.
+const HEADER_PREFIX: &str = "#imaple-fts 2 ";
+
+/// Outdated records a log may hold beyond those of the indexed messages
+/// before it's rewritten.
+const COMPACT_SLACK: usize = 256;
+
+/// Length of the grams terms are looked up by.
+const GRAM_LEN: usize = 3;
+
+/// Part of the message a search key looks at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Field {
+    Subject,
+    Body,
+    Text,
+}
+
+impl Field {
+    /// Term prefixes covered by this field.
+    fn prefixes(&self) -> &'static [&'static str] {
+        match self {
+            Field::Subject => &["s:"],
+            Field::Body => &["b:"],
+            Field::Text => &["s:", "h:", "b:"],
+        }
+    }
+}
+
+/// Change to the index of a mailbox, a line of its log.
+#[derive(Debug, PartialEq, Eq)]
+enum Record {
+    /// `+uid term...`: a message and its terms, replacing any indexed
+    /// under its UID.
+    Add(u32, Vec<String>),
+
+    /// `-uid...`: messages no longer in the mailbox.
+    Remove(Vec<u32>),
+}
+
+impl Record {
+    fn parse(line: &str) -> Result<Self> {
+        let corrupted = || anyhow!("Corrupted full-text index");
+        let mut fields = line.get(1..).ok_or_else(corrupted)?.split(' ');
+        match line.as_bytes()[0] {
+            b'+' => {
+                let uid = parse_uid(fields.next().unwrap_or_default())?;
+                Ok(Record::Add(uid, fields.map(String::from).collect()))
+            }
+            b'-' => Ok(Record::Remove(
+                fields.map(parse_uid).collect::<Result<_>>()?,
+            )),
+            _ => Err(corrupted().into()),
+        }
+    }
+
+    fn encode(&self, out: &mut String) {
+        match self {
+            Record::Add(uid, terms) => {
+                out.push_str(&format!("+{}", uid));
+                for term in terms {
+                    out.push(' ');
+                    out.push_str(term);
+                }
+            }
+            Record::Remove(uids) => {
+                out.push('-');
+                let uids: Vec<String> =
+                    uids.iter().map(|uid| uid.to_string()).collect();
+                out.push_str(&uids.join(" "));
+            }
+        }
+        out.push('\n');
+    }
+}
+
+fn parse_uid(text: &str) -> Result<u32> {
+    text.parse().map_err(|_| {
+        anyhow!("Invalid UID `{}` in full-text index", text).into()
+    })
+}
+
+/// Inverted index of a single mailbox.
+#[derive(Clone, Default)]
+pub struct MailboxFts {
+    /// Terms of each indexed message, so it's removed or copied without
+    /// going through every term.
+    messages: HashMap<u32, Vec<String>>,
+
+    /// Messages having each term.
+    postings: BTreeMap<String, BTreeSet<u32>>,
+
+    /// Terms having each trigram of their word, the trigram prefixed by
+    /// the field as terms are. A word shorter than a trigram is its own.
+    grams: BTreeMap<String, BTreeSet<Arc<str>>>,
+
+    /// Records of its log, outdated ones included.
+    records: usize,
+}
+
+impl MailboxFts {
+    pub fn is_indexed(&self, uid: u32) -> bool {
+        self.messages.contains_key(&uid)
+    }
+
+    /// UIDs of the messages which may have `needle` in `field`: those with
+    /// every word of it within a term of the field, or a word too long to
+    /// be indexed. The index only knowing words, the caller confirms each
+    /// by scanning it. `None` if `needle` has no indexable word, every
+    /// message being a candidate then.
+    pub fn matches(&self, field: Field, needle: &str) -> Option<HashSet<u32>> {
+        let words = tokenize(needle);
+        if words.is_empty() {
+            return None;
+        }
+
+        let mut result: Option<HashSet<u32>> = None;
+        for word in words {
+            let mut found = HashSet::new();
+            for prefix in field.prefixes() {
+                // the empty term of words too long to be indexed
+                let long = self.postings.get(*prefix).into_iter();
+                let terms = self.terms_containing(prefix, &word).into_iter();
+                for uids in long.chain(terms.map(|term| &self.postings[term])) {
+                    found.extend(uids.iter().copied());
+                }
+            }
+            result = Some(match result {
+                Some(prev) => prev.intersection(&found).copied().collect(),
+                None => found,
+            });
+        }
+        result
+    }
+
+    /// Terms of the field `prefix` whose word contains `word`.
+    fn terms_containing(&self, prefix: &str, word: &str) -> BTreeSet<&str> {
+        if word.chars().count() < GRAM_LEN {
+            // a short word is within one of the trigrams of a term having
+            // it, or is the whole word of the term
+            return self
+                .grams
+                .range(prefix.to_string()..)
+                .map_while(|(gram, terms)| {
+                    Some((gram.strip_prefix(prefix)?, terms))
+                })
+                .filter(|(gram, _)| gram.contains(word))
+                .flat_map(|(_, terms)| terms.iter().map(|term| &**term))
+                .collect();
+        }
+
+        // those having every trigram of `word`, the rarest one first
+        let mut sets = vec![];
+        for gram in grams(prefix, word) {
+            match self.grams.get(&gram) {
+                Some(terms) => sets.push(terms),
+                None => return BTreeSet::new(),
+            }
+        }
+        sets.sort_by_key(|terms| terms.len());
+        sets[0]
+            .iter()
+            .filter(|term| sets[1..].iter().all(|terms| terms.contains(*term)))
+            .map(|term| &**term)
+            .filter(|term| term[prefix.len()..].contains(word))
+            .collect()
+    }
+
+    /// Distinct terms of `msg`, prefixed by their field.
+    fn terms(msg: &Message<'_>) -> Vec<String> {
+        let text = extract::extract(msg);
+        let fields = [
+            ("s:", &text.subject),
+            ("h:", &text.headers),
+            ("b:", &text.body),
+        ];
+        let mut terms: Vec<String> = fields
+            .iter()
+            .flat_map(|(prefix, text)| {
+                extract::index_terms(text)
+                    .into_iter()
+                    .map(move |term| format!("{}{}", prefix, term))
+            })
+            .collect();
+        terms.sort_unstable();
+        terms.dedup();
+        terms
+    }
+
+    fn apply(&mut self, record: &Record) {
+        match record {
+            Record::Add(uid, terms) => {
+                self.remove(*uid);
+                for term in terms {
+                    let uids = self.postings.entry(term.clone()).or_default();
+                    let new = uids.is_empty();
+                    uids.insert(*uid);
+                    if new {
+                        self.add_grams(term);
+                    }
+                }
+                self.messages.insert(*uid, terms.clone());
+            }
+            Record::Remove(uids) => {
+                for uid in uids {
+                    self.remove(*uid);
+                }
+            }
+        }
+        self.records += 1;
+    }
+
+    fn remove(&mut self, uid: u32) {
+        for term in self.messages.remove(&uid).unwrap_or_default() {
+            if let Some(postings) = self.postings.get_mut(&term) {
+                postings.remove(&uid);
+                if postings.is_empty() {
+                    self.postings.remove(&term);
+                    self.remove_grams(&term);
+                }
+            }
+        }
+    }
+
+    fn add_grams(&mut self, term: &str) {
+        let (prefix, word) = split_term(term);
+        let term: Arc<str> = term.into();
+        for gram in grams(prefix, word) {
+            self.grams.entry(gram).or_default().insert(term.clone());
+        }
+    }
+
+    fn remove_grams(&mut self, term: &str) {
+        let (prefix, word) = split_term(term);
+        for gram in grams(prefix, word) {
+            if let Some(terms) = self.grams.get_mut(&gram) {
+                terms.remove(term);
+                if terms.is_empty() {
+                    self.grams.remove(&gram);
+                }
+            }
+        }
+    }
+
+    /// Apply the records of log lines `content`.
+    fn read_records(&mut self, content: &str) -> Result<()> {
+        for line in content.lines() {
+            self.apply(&Record::parse(line)?);
+        }
+        Ok(())
+    }
+
+    /// Whether its log is mostly made of outdated records.
+    fn needs_compaction(&self) -> bool {
+        self.records > 2 * self.messages.len() + COMPACT_SLACK
+    }
+
+    /// Log of the indexed messages only, identified by `id`.
+    fn compacted(&self, id: &str) -> String {
+        let mut out = format!("{}{}\n", HEADER_PREFIX, id);
+        let mut uids: Vec<&u32> = self.messages.keys().collect();
+        uids.sort_unstable();
+        for uid in uids {
+            Record::Add(*uid, self.messages[uid].clone()).encode(&mut out);
+        }
+        out
+    }
+}
+
+/// The field prefix of `term` and its word.
+fn split_term(term: &str) -> (&str, &str) {
+    term.split_at(term.find(':').map_or(0, |i| i + 1))
+}
+
+/// Keys of `MailboxFts::grams` for `word` of the field `prefix`, none for
+/// the empty word.
+fn grams(prefix: &str, word: &str) -> Vec<String> {
+    let chars: Vec<char> = word.chars().collect();
+    if chars.is_empty() {
+        return vec![];
+    }
+    if chars.len() < GRAM_LEN {
+        return vec![format!("{}{}", prefix, word)];
+    }
+    let mut grams: Vec<String> = chars
+        .windows(GRAM_LEN)
+        .map(|gram| prefix.chars().chain(gram.iter().copied()).collect())
+        .collect();
+    grams.sort_unstable();
+    grams.dedup();
+    grams
+}
+
+/// Identifier of a new log, unique across rewrites and processes.
+fn new_log_id() -> String {
+    static COUNT: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}-{:x}", nanos, std::process::id(), count)
+}
+
+/// One flat file per mailbox: escape the hierarchy delimiter, and a leading
+/// dot so no name can point outside of the index directory.
+fn escape_file_name(name: &str) -> String {
+    let escaped = name.replace('%', "%25").replace('/', "%2F");
+    match escaped.strip_prefix('.') {
+        Some(rest) => format!("%2E{}", rest),
+        None => escaped,
+    }
+}
+
+fn unescape_file_name(name: &str) -> String {
+    name.replace("%2E", ".")
+        .replace("%2F", "/")
+        .replace("%25", "%")
+}
+
+struct Cached {
+    /// Identifier of the log `fts` was read from.
+    id: String,
+
+    /// Bytes of the log read, more may have been appended since.
+    len: u64,
+
+    fts: Arc<MailboxFts>,
+}
+
+/// Full-text indexes of every mailbox, kept under `root`.
+pub struct FtsIndex {
+    root: PathBuf,
+    cache: Mutex<HashMap<PathBuf, Cached>>,
+
+    /// Serializes read-modify-write cycles of concurrent sessions.
+    write_lock: Mutex<()>,
+}
+
+impl FtsIndex {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            cache: Mutex::new(HashMap::new()),
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    fn path(&self, user: &str, mailbox: &str) -> PathBuf {
+        self.root
+            .join(escape_file_name(user))
+            .join(format!("{}.fts", escape_file_name(mailbox)))
+    }
+
+    /// Index of `mailbox`, `None` if it was never built or has an unknown
+    /// format. Only the records appended since it was last read are read.
+    pub fn load(
+        &self,
+        user: &str,
+        mailbox: &str,
+    ) -> Result<Option<Arc<MailboxFts>>> {
+        let path = self.path(user, mailbox);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        let id = match header.trim_end().strip_prefix(HEADER_PREFIX) {
+            Some(id) => id,
+            None => {
+                debug!("ignoring full-text index `{}`", path.display());
+                return Ok(None);
+            }
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        let (mut fts, mut len) = match cache.remove(&path) {
+            Some(cached) if cached.id == id && cached.len <= size => {
+                (cached.fts, cached.len)
+            }
+            _ => {
+                debug!("loading full-text index `{}`", path.display());
+                (Arc::new(MailboxFts::default()), header.len() as u64)
+            }
+        };
+
+        // a record still being appended is left for later
+        let mut tail = vec![];
+        reader.seek(SeekFrom::Start(len))?;
+        reader.read_to_end(&mut tail)?;
+        let complete =
+            tail.iter().rposition(|c| *c == b'\n').map_or(0, |i| i + 1);
+        if complete > 0 {
+            let content = std::str::from_utf8(&tail[..complete])
+                .map_err(|_| anyhow!("Corrupted full-text index"))?;
+            Arc::make_mut(&mut fts).read_records(content)?;
+            len += complete as u64;
+        }
+
+        cache.insert(
+            path,
+            Cached {
+                id: id.to_string(),
+                len,
+                fts: fts.clone(),
+            },
+        );
+        Ok(Some(fts))
+    }
+
+    /// Append `records` to the log of `mailbox`, starting one if there's
+    /// none, and rewrite it once it's mostly outdated.
+    fn append(
+        &self,
+        user: &str,
+        mailbox: &str,
+        records: &[Record],
+    ) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
+        }
+        let _guard = self.write_lock.lock().unwrap();
+        let path = self.path(user, mailbox);
+
+        let mut fts = match self.load(user, mailbox)? {
+            Some(fts) => fts,
+            None => {
+                // messages missing from the index are scanned by the
+                // searcher, so starting an index here is safe
+                let mut fts = MailboxFts::default();
+                records.iter().for_each(|record| fts.apply(record));
+                if fts.messages.is_empty() {
+                    return Ok(());
+                }
+                return self.save(user, mailbox, fts);
+            }
+        };
+        // out of the cache, it's changed in place unless a search holds it
+        let (id, len) = match self.cache.lock().unwrap().remove(&path) {
+            Some(cached) => (cached.id, cached.len),
+            None => (String::new(), 0),
+        };
+
+        let changed = Arc::make_mut(&mut fts);
+        let mut lines = String::new();
+        for record in records {
+            changed.apply(record);
+            record.encode(&mut lines);
+        }
+        if changed.needs_compaction() {
+            let fts = Arc::try_unwrap(fts).unwrap_or_else(|fts| (*fts).clone());
+            return self.save(user, mailbox, fts);
+        }
+
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(lines.as_bytes())?;
+        // unless another process appended meanwhile, the cache is current
+        let size = file.metadata()?.len();
+        if size == len + lines.len() as u64 {
+            self.cache
+                .lock()
+                .unwrap()
+                .insert(path, Cached { id, len: size, fts });
+        }
+        Ok(())
+    }
+
+    /// Replace the log of `mailbox` by one of the messages `fts` indexes.
+    fn save(
+        &self,
+        user: &str,
+        mailbox: &str,
+        mut fts: MailboxFts,
+    ) -> Result<()> {
+        let path = self.path(user, mailbox);
+        fs::create_dir_all(path.parent().unwrap())?;
+
+        let id = new_log_id();
+        let content = fts.compacted(&id);
+        let tmp = path.with_extension("fts.tmp");
+        fs::write(&tmp, &content)?;
+        fs::rename(&tmp, &path)?;
+
+        fts.records = fts.messages.len();
+        self.cache.lock().unwrap().insert(
+            path,
+            Cached {
+                id,
+                len: content.len() as u64,
+                fts: Arc::new(fts),
+            },
+        );
+        Ok(())
+    }
+
+    /// Add a message delivered or appended to `mailbox`.
+    pub fn add_message(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+        raw: &[u8],
+    ) -> Result<()> {
+        let terms = MailboxFts::terms(&Message::parse(raw)?);
+        self.append(user, mailbox, &[Record::Add(uid, terms)])
+    }
+
+    /// Index messages copied from `mailbox` to `dest` of `dest_user` without
+    /// parsing them again, `uids` pairing their source and destination UIDs.
+    pub fn copy_messages(
+        &self,
+        user: &str,
+        mailbox: &str,
+        dest_user: &str,
+        dest: &str,
+        uids: &[(u32, u32)],
+    ) -> Result<()> {
+        let source = match self.load(user, mailbox)? {
+            Some(source) if !uids.is_empty() => source,
+            _ => return Ok(()),
+        };
+        let records: Vec<Record> = uids
+            .iter()
+            .filter_map(|(from, to)| {
+                let terms = source.messages.get(from)?;
+                Some(Record::Add(*to, terms.clone()))
+            })
+            .collect();
+        drop(source);
+        self.append(dest_user, dest, &records)
+    }
+
+    /// Forget expunged messages.
+    pub fn remove_messages(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+    ) -> Result<()> {
+        if uids.is_empty() {
+            return Ok(());
+        }
+        self.append(user, mailbox, &[Record::Remove(uids.to_vec())])
+    }
+
+    /// Drop the index of a deleted mailbox.
+    pub fn remove_mailbox(&self, user: &str, mailbox: &str) -> Result<()> {
+        let path = self.path(user, mailbox);
+        self.cache.lock().unwrap().remove(&path);
+        match fs::remove_file(path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Follow the rename of `mailbox`, and its inferiors, to `new_name`.
+    pub fn rename_mailbox(
+        &self,
+        user: &str,
+        mailbox: &str,
+        new_name: &str,
+    ) -> Result<()> {
+        let _guard = self.write_lock.lock().unwrap();
+        let dir = self.root.join(escape_file_name(user));
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let inferiors = format!("{}/", mailbox);
+        for entry in entries {
+            let file_name = entry?.file_name().to_string_lossy().into_owned();
+            let name = match file_name.strip_suffix(".fts") {
+                Some(escaped) => unescape_file_name(escaped),
+                None => continue,
+            };
+            let renamed = if name == mailbox {
+                new_name.to_string()
+            } else if let Some(rest) = name.strip_prefix(&inferiors) {
+                format!("{}/{}", new_name, rest)
+            } else {
+                continue;
+            };
+
+            let from = self.path(user, &name);
+            self.cache.lock().unwrap().remove(&from);
+            fs::rename(from, self.path(user, &renamed))?;
+        }
+        Ok(())
+    }
+
+    /// Index every message of `mailbox` from scratch, returns the number of
+    /// messages indexed.
+    pub fn rebuild(
+        &self,
+        store: &dyn MailStore,
+        user: &str,
+        mailbox: &str,
+    ) -> Result<usize> {
+        let index = store.load_index(user, mailbox)?;
+        let mut fts = MailboxFts::default();
+        for meta in index.messages.iter() {
+            let raw = store.read_message(user, mailbox, meta.uid)?;
+            match Message::parse(&raw) {
+                Ok(msg) => {
+                    let terms = MailboxFts::terms(&msg);
+                    fts.apply(&Record::Add(meta.uid, terms));
+                }
+                Err(e) => debug!("skipping uid {}: {}", meta.uid, e),
+            }
+        }
+        let count = fts.messages.len();
+
+        let _guard = self.write_lock.lock().unwrap();
+        self.save(user, mailbox, fts)?;
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(messages: &[(u32, &str)]) -> MailboxFts {
+        let mut fts = MailboxFts::default();
+        for (uid, raw) in messages {
+            let terms =
+                MailboxFts::terms(&Message::parse(raw.as_bytes()).unwrap());
+            fts.apply(&Record::Add(*uid, terms));
+        }
+        fts
+    }
+
+    #[test]
+    fn matches_are_candidates() {
+        let long = "x".repeat(100);
+        let fts = index(&[
+            (1, "Subject: Hello world\r\n\r\nsome body text\r\n"),
+            (2, "Subject: other abcabd\r\n\r\nbody\r\n"),
+            (3, &format!("Subject: blob\r\n\r\n{}\r\n", long)),
+        ]);
+        let uids = |field, needle| {
+            let mut uids: Vec<u32> =
+                fts.matches(field, needle).unwrap().into_iter().collect();
+            uids.sort_unstable();
+            uids
+        };
+
+        // words within terms, across fields for TEXT
+        assert_eq!(uids(Field::Subject, "lo wor"), [1]);
+        assert_eq!(uids(Field::Subject, "other"), [2]);
+        // words too long to index keep their message a candidate
+        assert_eq!(uids(Field::Body, "hello"), [3]);
+        assert_eq!(uids(Field::Body, "xxx"), [3]);
+        assert_eq!(uids(Field::Text, "world text"), [1, 3]);
+        // short words within trigrams, or whole words
+        assert_eq!(uids(Field::Subject, "ll"), [1]);
+        assert_eq!(uids(Field::Subject, "o"), [1, 2, 3]);
+        // having every trigram of a word isn't enough, it has to be within
+        // the term
+        assert_eq!(uids(Field::Subject, "abcabd"), [2]);
+        assert_eq!(uids(Field::Subject, "cabc"), Vec::<u32>::new());
+        assert!(fts.matches(Field::Body, &long).is_none());
+        assert!(fts.matches(Field::Body, "--").is_none());
+    }
+
+    #[test]
+    fn records_round_trip() {
+        for record in [
+            Record::Add(7, vec!["s:hello".into(), "b:".into()]),
+            Record::Add(8, vec![]),
+            Record::Remove(vec![1, 2]),
+        ] {
+            let mut line = String::new();
+            record.encode(&mut line);
+            assert_eq!(Record::parse(line.trim_end()).unwrap(), record);
+        }
+        assert!(Record::parse("").is_err());
+        assert!(Record::parse("+x a").is_err());
+        assert!(Record::parse("*1").is_err());
+    }
+
+    #[test]
+    fn logs_are_appended_and_compacted() {
+        let root = std::env::temp_dir()
+            .join(format!("imaple-fts-{}", std::process::id()));
+        let index = FtsIndex::new(&root);
+        let raw = b"Subject: hello\r\n\r\nbody\r\n";
+        index.add_message("bob", "INBOX", 1, raw).unwrap();
+        index.add_message("bob", "INBOX", 2, raw).unwrap();
+        index.remove_messages("bob", "INBOX", &[1]).unwrap();
+        index
+            .copy_messages("bob", "INBOX", "bob", "Sent", &[(2, 5)])
+            .unwrap();
+
+        let path = index.path("bob", "INBOX");
+        let log = fs::read_to_string(&path).unwrap();
+        assert_eq!(log.lines().count(), 4);
+        assert!(log.ends_with("-1\n"));
+
+        // another process sees the appended records
+        let other = FtsIndex::new(&root);
+        let fts = other.load("bob", "INBOX").unwrap().unwrap();
+        assert!(!fts.is_indexed(1) && fts.is_indexed(2));
+        index.add_message("bob", "INBOX", 3, raw).unwrap();
+        let fts = other.load("bob", "INBOX").unwrap().unwrap();
+        assert!(fts.is_indexed(3));
+        let sent = other.load("bob", "Sent").unwrap().unwrap();
+        assert!(sent.is_indexed(5));
+
+        // replacing a message outdates its record, until the log is
+        // rewritten
+        let compacted = (0..2 * COMPACT_SLACK).any(|_| {
+            index.add_message("bob", "INBOX", 4, raw).unwrap();
+            fs::read_to_string(&path).unwrap().lines().count() < 8
+        });
+        assert!(compacted);
+        let fts = other.load("bob", "INBOX").unwrap().unwrap();
+        let mut uids: Vec<u32> = fts.messages.keys().copied().collect();
+        uids.sort_unstable();
+        assert_eq!(uids, [2, 3, 4]);
+
+        // an index of an older format is started over
+        fs::write(&path, "#imaple-fts 1\nuids 1\n").unwrap();
+        assert!(index.load("bob", "INBOX").unwrap().is_none());
+        index.add_message("bob", "INBOX", 9, raw).unwrap();
+        assert!(other.load("bob", "INBOX").unwrap().unwrap().is_indexed(9));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
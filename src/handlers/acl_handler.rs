@@ -0,0 +1,111 @@
+use super::quoted;
+use crate::acl::{self, ALL_RIGHTS};
+use crate::result::Result;
+use crate::session::Session;
+use crate::storage::SHARED_OWNER;
+
+/// How SETACL changes the rights of an identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Replace,
+    Add,
+    Remove,
+}
+
+/// Parse the rights of SETACL, a leading `+` or `-` adding or removing them
+/// rather than replacing. `None` if a right is unknown.
+pub fn parse_change(rights: &str) -> Option<(Change, String)> {
+    let (change, rights) = match rights.as_bytes().first() {
+        Some(b'+') => (Change::Add, &rights[1..]),
+        Some(b'-') => (Change::Remove, &rights[1..]),
+        _ => (Change::Replace, rights),
+    };
+    Some((change, acl::parse(rights)?))
+}
+
+/// Whether `identifier` has every right on the mailboxes of `owner`, which
+/// the ACL can't change.
+pub fn is_owner(session: &Session, owner: &str, identifier: &str) -> bool {
+    identifier == owner
+        || (owner == SHARED_OWNER
+            && session
+                .config
+                .admins
+                .iter()
+                .any(|admin| admin == identifier))
+}
+
+/// Change the rights `identifier` has on `mailbox` of `owner`, an entry
+/// left without rights being removed.
+pub fn set_rights(
+    session: &Session,
+    owner: &str,
+    mailbox: &str,
+    identifier: &str,
+    change: Change,
+    rights: &str,
+) -> Result<()> {
+    session.store.update_acl(owner, &mut |acl| {
+        let entries = acl.entry(mailbox.to_string()).or_default();
+        let current = entries.remove(identifier).unwrap_or_default();
+        let updated = match change {
+            Change::Replace => rights.to_string(),
+            Change::Add => {
+                acl::normalize(current.chars().chain(rights.chars()))
+            }
+            Change::Remove => {
+                let kept = current.chars().filter(|r| !rights.contains(*r));
+                acl::normalize(kept)
+            }
+        };
+        if !updated.is_empty() {
+            entries.insert(identifier.to_string(), updated);
+        }
+        if entries.is_empty() {
+            acl.remove(mailbox);
+        }
+    })
+}
+
+/// `ACL` response listing the rights on `mailbox` of `owner`, which the
+/// client calls `name`. The owner comes first with every right.
+pub fn acl_response(
+    session: &Session,
+    name: &str,
+    owner: &str,
+    mailbox: &str,
+) -> Result<String> {
+    let mut response = format!("ACL {}", quoted(&session.client_name(name)));
+    if owner != SHARED_OWNER {
+        response.push_str(&format!(" {} {}", quoted(owner), ALL_RIGHTS));
+    }
+    if let Some(entries) = session.store.acl(owner)?.get(mailbox) {
+        for (identifier, rights) in entries {
+            response.push_str(&format!(" {} {}", quoted(identifier), rights));
+        }
+    }
+    Ok(response)
+}
+
+/// `LISTRIGHTS` response for `identifier` on the mailboxes of `owner`: an
+/// owner has every right, others any of them, each granted on its own.
+pub fn listrights_response(
+    session: &Session,
+    name: &str,
+    owner: &str,
+    identifier: &str,
+) -> String {
+    let rights = if is_owner(session, owner, identifier) {
+        ALL_RIGHTS.to_string()
+    } else {
+        let optional: Vec<String> =
+            ALL_RIGHTS.chars().map(String::from).collect();
+        format!("\"\" {}", optional.join(" "))
+    };
+    format!(
+        "LISTRIGHTS {} {} {}",
+        quoted(&session.client_name(name)),
+        quoted(identifier),
+        rights
+    )
+}
@@ -0,0 +1,149 @@
+use std::io::Write;
+
+use anyhow::anyhow;
+use chrono::{DateTime, FixedOffset, Local, SubsecRound};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::quota_handler;
+use super::store_handler::parse_flags;
+use crate::acl;
+use crate::events::MailboxChange;
+use crate::ext_command::Token;
+use crate::imap_serv::IMAPServ;
+use crate::result::Result;
+
+/// Bytes of the message literal read from the socket at once.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub struct AppendArgs {
+    pub mailbox: String,
+    pub flags: Vec<String>,
+    pub internal_date: DateTime<FixedOffset>,
+}
+
+/// Parse `mailbox [(flags)] ["date-time"]`, the arguments preceding the
+/// message literal. The mailbox name is left as the client gave it.
+pub fn parse_args(args: &[Token]) -> Option<AppendArgs> {
+    let (mailbox, mut rest) = args.split_first()?;
+    let mailbox = mailbox.as_astring()?;
+
+    let mut flags = vec![];
+    if let Some(list) = rest.first().and_then(Token::as_list) {
+        flags = parse_flags(list)?;
+        rest = &rest[1..];
+    }
+
+    let internal_date = match rest {
+        [] => Local::now().trunc_subsecs(0).into(),
+        [Token::String(date)] => parse_date_time(date)?,
+        _ => return None,
+    };
+
+    Some(AppendArgs {
+        mailbox,
+        flags,
+        internal_date,
+    })
+}
+
+/// Parse an IMAP `date-time`, like `17-Jul-1996 02:44:25 -0700`.
+fn parse_date_time(date: &[u8]) -> Option<DateTime<FixedOffset>> {
+    let date = std::str::from_utf8(date).ok()?.trim_start();
+    DateTime::parse_from_str(date, "%d-%b-%Y %H:%M:%S %z").ok()
+}
+
+/// Ask for the message literal, `length` bytes long, unless the client
+/// sends it without waiting, and stream it into the mailbox. Returns the
+/// UID of the new message.
+pub async fn append<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    args: AppendArgs,
+    length: u32,
+    synchronizing: bool,
+) -> Result<u32>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let AppendArgs {
+        mailbox: name,
+        mut flags,
+        internal_date,
+    } = args;
+    let (owner, mailbox) = s.session.locate(&name)?;
+    // flags the user has no right to set are dropped
+    let rights = s.session.rights_of(&owner, &mailbox)?;
+    flags.retain(|flag| rights.contains(acl::flag_right(flag)));
+    let quota = quota_handler::limits(s.session, &owner)?;
+    let store = s.session.store.clone();
+    let mut sink = store.append_message(&owner, &mailbox, Some(&quota))?;
+
+    if synchronizing {
+        s.write_str("+ Ready for literal data\r\n").await?;
+        s.flush().await?;
+    }
+
+    // the whole literal has to be consumed even if storing it fails, or its
+    // remainder would be taken for commands
+    let mut chunk = vec![0; CHUNK_SIZE.min(length as usize)];
+    let mut remaining = length as usize;
+    let mut written = Ok(());
+    while remaining > 0 {
+        let n = remaining.min(chunk.len());
+        s.read_exact(&mut chunk[..n]).await?;
+        if written.is_ok() {
+            written = sink.write_all(&chunk[..n]);
+        }
+        remaining -= n;
+    }
+    read_line_end(s).await?;
+
+    written?;
+    let uid = sink.commit(flags, internal_date)?;
+    s.session.publish(&name, MailboxChange::Exists(vec![uid]))?;
+
+    if let Some(fts) = s.session.fts.as_ref() {
+        // the message is stored already, SEARCH scans what isn't indexed
+        let indexed = store
+            .read_message(&owner, &mailbox, uid)
+            .and_then(|raw| fts.add_message(&owner, &mailbox, uid, &raw));
+        if let Err(e) = indexed {
+            eprintln!("Failed to index appended message; err = {:?}", e);
+        }
+    }
+
+    Ok(uid)
+}
+
+/// Read and drop the message literal of a refused APPEND, which the client
+/// sent without waiting for a continuation request.
+pub async fn discard<IO>(s: &mut IMAPServ<'_, IO>, length: u32) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut chunk = vec![0; CHUNK_SIZE.min(length as usize)];
+    let mut remaining = length as usize;
+    while remaining > 0 {
+        let n = remaining.min(chunk.len());
+        s.read_exact(&mut chunk[..n]).await?;
+        remaining -= n;
+    }
+    read_line_end(s).await
+}
+
+/// Read the end of the command line right after the message literal, a
+/// bare LF is tolerated like the framer does.
+async fn read_line_end<IO>(s: &mut IMAPServ<'_, IO>) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut c = [0; 1];
+    s.read_exact(&mut c).await?;
+    if c[0] == b'\r' {
+        s.read_exact(&mut c).await?;
+    }
+    if c[0] != b'\n' {
+        return Err(anyhow!("Unexpected data after the message").into());
+    }
+    Ok(())
+}
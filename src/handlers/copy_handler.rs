@@ -0,0 +1,194 @@
+use std::collections::HashSet;
+
+use anyhow::anyhow;
+use imap_codec::sequence::SequenceSet;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{quota_handler, resolve_sequence_set, uid_set_string};
+use crate::acl;
+use crate::events::MailboxChange;
+use crate::imap_serv::IMAPServ;
+use crate::result::Result;
+use crate::session::Session;
+
+/// Messages copied to another mailbox.
+pub struct Copied {
+    /// UIDVALIDITY of the destination mailbox.
+    pub uid_validity: u32,
+
+    /// `(source UID, destination UID)` pairs.
+    pub uids: Vec<(u32, u32)>,
+}
+
+impl Copied {
+    /// `COPYUID` response code of RFC 4315, `None` if nothing was copied.
+    pub fn copyuid(&self) -> Option<String> {
+        if self.uids.is_empty() {
+            return None;
+        }
+        let (from, to): (Vec<u32>, Vec<u32>) =
+            self.uids.iter().copied().unzip();
+        Some(format!(
+            "COPYUID {} {} {}",
+            self.uid_validity,
+            uid_set_string(&from),
+            uid_set_string(&to)
+        ))
+    }
+}
+
+/// Size in bytes and number of the selected mailbox's messages in
+/// `sequence_set`.
+pub fn messages_size(
+    session: &Session,
+    sequence_set: &SequenceSet,
+    uid: bool,
+) -> Result<(u64, u64)> {
+    let uids: HashSet<u32> =
+        resolve_sequence_set(session.selected()?, sequence_set, uid)
+            .into_iter()
+            .map(|(_, uid)| uid)
+            .collect();
+    let messages = session.selected_messages()?;
+    let sizes = messages.iter().filter(|m| uids.contains(&m.uid));
+    Ok(
+        sizes
+            .fold((0, 0), |(size, count), m| (size + m.size as u64, count + 1)),
+    )
+}
+
+/// Copy the messages of `sequence_set` from the selected mailbox to `dest`,
+/// which must exist and the user be allowed to insert into, keeping their
+/// internal date and the flags the user has the right to set there. The
+/// copies must fit the quota of `dest`, unless `moving` them under the same
+/// quota root.
+pub async fn copy<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    sequence_set: &SequenceSet,
+    dest: &str,
+    uid: bool,
+    moving: bool,
+) -> Result<Copied>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let selected = s.session.selected()?;
+    let uids: Vec<u32> = resolve_sequence_set(selected, sequence_set, uid)
+        .into_iter()
+        .map(|(_, uid)| uid)
+        .collect();
+    let (owner, mailbox) = s.session.selected_location()?;
+    let (dest_owner, dest_mailbox) = s.session.access(dest, "i")?;
+
+    let store = s.session.store.clone();
+    if !store.is_writable(&dest_owner, &dest_mailbox)? {
+        return Err(anyhow!("Mailbox `{}` is read-only", dest).into());
+    }
+    let uid_validity =
+        store.load_index(&dest_owner, &dest_mailbox)?.uid_validity;
+    // flags the user has no right to set there are dropped
+    let rights = s.session.rights_of(&dest_owner, &dest_mailbox)?;
+    let quota = match moving && dest_owner == owner {
+        true => None,
+        false => Some(quota_handler::limits(s.session, &dest_owner)?),
+    };
+    let copied = store.copy_messages(
+        &owner,
+        &mailbox,
+        &uids,
+        &dest_owner,
+        &dest_mailbox,
+        &|flag| rights.contains(acl::flag_right(flag)),
+        quota.as_ref(),
+    )?;
+    let new_uids = copied.iter().map(|(_, to)| *to).collect();
+    s.session.publish(dest, MailboxChange::Exists(new_uids))?;
+
+    if let Some(fts) = s.session.fts.as_ref() {
+        // copies missing from the index are scanned by SEARCH
+        let indexed = fts.copy_messages(
+            &owner,
+            &mailbox,
+            &dest_owner,
+            &dest_mailbox,
+            &copied,
+        );
+        if let Err(e) = indexed {
+            eprintln!("Failed to index copied messages; err = {:?}", e);
+        }
+    }
+
+    Ok(Copied {
+        uid_validity,
+        uids: copied,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::storage::INBOX;
+    use crate::testing::{
+        add_message, configured_session, mail_dir, run, session,
+    };
+
+    #[tokio::test]
+    async fn copies_and_moves_with_copyuid() {
+        let root = mail_dir("copy");
+        let mut s = session(&root);
+        add_message(&s, INBOX, "Subject: a\r\n\r\n", &[]);
+        add_message(&s, INBOX, "Subject: b\r\n\r\n", &["\\Seen"]);
+        add_message(&s, INBOX, "Subject: c\r\n\r\n", &["$Work"]);
+        run(&mut s, "a CREATE Archive").await;
+        let validity =
+            s.store.load_index("bob", "Archive").unwrap().uid_validity;
+        run(&mut s, "a SELECT INBOX").await;
+
+        let out = run(&mut s, "a COPY 2:3 Archive").await;
+        let copyuid = format!("[COPYUID {} 2:3 1:2]", validity);
+        assert_eq!(out, format!("a OK {} COPY completed\r\n", copyuid));
+        let out = run(&mut s, "a COPY 1 Missing").await;
+        assert_eq!(out, "a NO [TRYCREATE] Mailbox doesn't exist\r\n");
+
+        let archive = s.store.load_index("bob", "Archive").unwrap();
+        let flags: Vec<_> = archive.messages.iter().map(|m| &m.flags).collect();
+        assert_eq!(flags, [&["\\Seen"], &["$Work"]]);
+        assert_eq!(
+            s.store.read_message("bob", "Archive", 2).unwrap(),
+            b"Subject: c\r\n\r\n"
+        );
+
+        let out = run(&mut s, "a UID MOVE 1,3 Archive").await;
+        let moved = format!(
+            "* OK [COPYUID {} 1,3 3:4] Moved\r\n\
+             * 1 EXPUNGE\r\n\
+             * 2 EXPUNGE\r\n\
+             a OK MOVE completed\r\n",
+            validity
+        );
+        assert_eq!(out, moved);
+        let inbox = s.store.load_index("bob", INBOX).unwrap();
+        assert_eq!(inbox.messages.len(), 1);
+        assert_eq!(s.selected().unwrap().uids, [2]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn leaves_copyuid_out_without_uidplus() {
+        let root = mail_dir("copy-no-uidplus");
+        let mut s = configured_session(&root, "uidplus = false");
+        add_message(&s, INBOX, "Subject: a\r\n\r\n", &[]);
+        run(&mut s, "a CREATE Archive").await;
+        run(&mut s, "a SELECT INBOX").await;
+
+        assert_eq!(
+            run(&mut s, "a COPY 1 Archive").await,
+            "a OK COPY completed\r\n"
+        );
+        let out = run(&mut s, "a MOVE 1 Archive").await;
+        assert_eq!(out, "* 1 EXPUNGE\r\na OK MOVE completed\r\n");
+        fs::remove_dir_all(root).unwrap();
+    }
+}
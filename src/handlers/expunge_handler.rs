@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+
+use imap_codec::sequence::SequenceSet;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{in_sequence_set, uid_set_string};
+use crate::error::{Refused, NOPERM};
+use crate::events::MailboxChange;
+use crate::imap_serv::IMAPServ;
+use crate::result::Result;
+use crate::storage::MailboxIndex;
+
+/// Permanently remove the `\Deleted` messages of the selected mailbox, only
+/// those in `uid_set` for UID EXPUNGE. `* n EXPUNGE` is sent for each removed
+/// message unless `silent`, as CLOSE requires.
+pub async fn expunge<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    uid_set: Option<&SequenceSet>,
+    silent: bool,
+) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    if !s.session.writable_selected()?.rights.contains('e') {
+        return Err(Refused(NOPERM).into());
+    }
+
+    // picked while the index is locked, so a flag changed meanwhile counts
+    let mut deleted = |index: &MailboxIndex| {
+        let largest = index.messages.iter().map(|m| m.uid).max().unwrap_or(0);
+        index
+            .messages
+            .iter()
+            .filter(|m| m.has_flag("\\Deleted"))
+            .filter(|m| {
+                uid_set.is_none_or(|set| in_sequence_set(set, m.uid, largest))
+            })
+            .map(|m| m.uid)
+            .collect()
+    };
+    remove_selected(s, &mut deleted, silent).await
+}
+
+/// Remove messages of the selected mailbox and renumber the session, sending
+/// `* n EXPUNGE` for each unless `silent`.
+pub async fn remove_messages<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    uids: &[u32],
+    silent: bool,
+) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    if uids.is_empty() {
+        return Ok(());
+    }
+    remove_selected(s, &mut |_| uids.to_vec(), silent).await
+}
+
+/// Remove the messages of the selected mailbox `select` picks from its
+/// locked index, like `remove_messages`.
+async fn remove_selected<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    select: &mut (dyn FnMut(&MailboxIndex) -> Vec<u32> + Send),
+    silent: bool,
+) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let name = s.session.selected()?.name.clone();
+    let (owner, mailbox) = s.session.locate(&name)?;
+
+    let uids = s.session.store.remove_messages(&owner, &mailbox, select)?;
+    if uids.is_empty() {
+        return Ok(());
+    }
+    if let Some(fts) = s.session.fts.as_ref() {
+        fts.remove_messages(&owner, &mailbox, &uids)?;
+    }
+    s.session
+        .publish(&name, MailboxChange::Expunge(uids.clone()))?;
+
+    forget_messages(s, &uids, silent).await
+}
+
+/// UIDs expunged from the mailbox after `modseq`, among `known` if given.
+/// Without tombstones that old, every UID of `known` which doesn't exist is
+/// reported instead.
+pub fn vanished_since(
+    index: &MailboxIndex,
+    modseq: u64,
+    known: Option<&SequenceSet>,
+) -> Vec<u32> {
+    let largest = index.uid_next.saturating_sub(1);
+    let is_known =
+        |uid: &u32| known.is_none_or(|set| in_sequence_set(set, *uid, largest));
+    match index.expunged_since(modseq) {
+        Some(uids) => uids.into_iter().filter(is_known).collect(),
+        None => {
+            let existing: HashSet<u32> =
+                index.messages.iter().map(|m| m.uid).collect();
+            (1..index.uid_next)
+                .filter(|uid| !existing.contains(uid) && is_known(uid))
+                .collect()
+        }
+    }
+}
+
+/// Drop removed messages from the session, sending `* n EXPUNGE` for each,
+/// or a single `* VANISHED` with QRESYNC, unless `silent`.
+pub async fn forget_messages<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    uids: &[u32],
+    silent: bool,
+) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    // every removal shifts the sequence numbers of the following messages,
+    // so each response uses the numbering left by the previous one
+    let doomed: HashSet<u32> = uids.iter().copied().collect();
+    let selected = match s.session.selected.as_mut() {
+        Some(selected) => selected,
+        None => return Ok(()),
+    };
+    let mut expunged = vec![];
+    let mut i = 0;
+    while i < selected.uids.len() {
+        if doomed.contains(&selected.uids[i]) {
+            let uid = selected.uids.remove(i);
+            selected.recent.remove(&uid);
+            expunged.push((i + 1, uid));
+        } else {
+            i += 1;
+        }
+    }
+
+    if silent || expunged.is_empty() {
+        return Ok(());
+    }
+    if s.session.qresync() {
+        let mut gone: Vec<u32> = expunged.iter().map(|(_, uid)| *uid).collect();
+        gone.sort_unstable();
+        s.status(&format!("VANISHED {}", uid_set_string(&gone)))
+            .await;
+    } else {
+        for (seq, _) in expunged {
+            s.status(&format!("{} EXPUNGE", seq)).await;
+        }
+    }
+
+    Ok(())
+}
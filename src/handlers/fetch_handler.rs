@@ -1,115 +1,996 @@
-use std::convert::TryFrom;
-use std::num::NonZeroU32;
+use std::collections::{HashMap, HashSet};
 
+use mailparse::{addrparse, MailAddr, SingleInfo};
+
+use super::expunge_handler::vanished_since;
+use super::{flags_list, resolve_sequence_set, uid_set_string};
+use crate::error::{Refused, UNKNOWN_CTE};
+use crate::events::MailboxChange;
+use crate::ext_command::Token;
 use crate::imap_serv::IMAPServ;
+use crate::mime::Part;
 use crate::result::Result;
+use crate::storage::MessageMeta;
 
-use imap_codec::codec::Encode;
-use imap_codec::envelope::{Address, Envelope};
-use imap_codec::fetch::{
-    Macro, MacroOrMessageDataItemNames, MessageDataItem, MessageDataItemName,
-};
-use imap_codec::response::Data;
+use imap_codec::sequence::SequenceSet;
 
-use imap_codec::core::*;
 use log::debug;
 use tokio::io::{AsyncRead, AsyncWrite};
 
-pub async fn handle_seq_value<IO>(
+/// What a `BODY[section]` item names within its part.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SectionText {
+    /// The whole message, or the content of a part.
+    Full,
+    Header,
+    HeaderFields(Vec<String>),
+    HeaderFieldsNot(Vec<String>),
+    Text,
+
+    /// The MIME header of a part.
+    Mime,
+}
+
+/// Section of a `BODY[section]` item (RFC 3501 section 6.4.5).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Section {
+    /// Part numbers, like `[1, 2]` for `1.2`, empty for the message.
+    pub part: Vec<u32>,
+    pub text: SectionText,
+}
+
+/// A message data item of FETCH.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FetchItem {
+    Uid,
+    Flags,
+    Modseq,
+    InternalDate,
+    Rfc822Size,
+    Envelope,
+
+    /// Body structure without extension data.
+    Body,
+    BodyStructure,
+    Rfc822,
+    Rfc822Header,
+    Rfc822Text,
+    BodySection {
+        section: Section,
+
+        /// Octets from an offset, `<offset.count>`.
+        partial: Option<(u32, u32)>,
+
+        /// `BODY.PEEK`, which doesn't set `\Seen`.
+        peek: bool,
+    },
+
+    /// `BINARY[part]<offset.count>`, the content of a part with its
+    /// transfer encoding undone (RFC 3516).
+    Binary {
+        part: Vec<u32>,
+        partial: Option<(u32, u32)>,
+
+        /// `BINARY.PEEK`, which doesn't set `\Seen`.
+        peek: bool,
+    },
+
+    /// `BINARY.SIZE[part]`, the size of a part's decoded content.
+    BinarySize(Vec<u32>),
+}
+
+impl FetchItem {
+    /// Whether the item needs the message itself, not only its index entry.
+    fn reads_message(&self) -> bool {
+        !matches!(
+            self,
+            FetchItem::Uid
+                | FetchItem::Flags
+                | FetchItem::Modseq
+                | FetchItem::InternalDate
+                | FetchItem::Rfc822Size
+        )
+    }
+
+    /// Whether fetching the item sets `\Seen`.
+    fn sets_seen(&self) -> bool {
+        matches!(
+            self,
+            FetchItem::Rfc822
+                | FetchItem::Rfc822Text
+                | FetchItem::BodySection { peek: false, .. }
+                | FetchItem::Binary { peek: false, .. }
+        )
+    }
+}
+
+/// Arguments of FETCH.
+pub struct Fetch {
+    pub sequence_set: SequenceSet,
+    pub items: Vec<FetchItem>,
+
+    /// Only messages changed after this mod-sequence (RFC 7162).
+    pub changed_since: Option<u64>,
+
+    /// Also report the expunges after `changed_since` as VANISHED.
+    pub vanished: bool,
+}
+
+/// Parse `set items [(CHANGEDSINCE n [VANISHED])]`, `None` if invalid.
+pub fn parse_fetch(args: &[Token]) -> Option<Fetch> {
+    let (sequence_set, rest) = args.split_first()?;
+    let sequence_set = sequence_set.as_sequence_set()?;
+    let (names, modifiers) = match rest {
+        [names] => (names, None),
+        [names, Token::List(modifiers)] => (names, Some(modifiers)),
+        _ => return None,
+    };
+    let names = match names {
+        Token::List(names) if !names.is_empty() => names
+            .iter()
+            .map(Token::as_astring)
+            .collect::<Option<Vec<_>>>()?,
+        Token::Atom(name) => vec![name.clone()],
+        _ => return None,
+    };
+
+    let mut items = vec![];
+    for name in names {
+        for item in parse_item(&name)? {
+            if !items.contains(&item) {
+                items.push(item);
+            }
+        }
+    }
+
+    let mut changed_since = None;
+    let mut vanished = false;
+    let mut modifiers = modifiers.map(|m| m.iter()).into_iter().flatten();
+    while let Some(modifier) = modifiers.next() {
+        if modifier.is_atom("CHANGEDSINCE") {
+            changed_since = Some(modifiers.next()?.as_number()?);
+        } else if modifier.is_atom("VANISHED") {
+            vanished = true;
+        } else {
+            return None;
+        }
+    }
+    // VANISHED needs a mod-sequence to start from
+    if vanished && changed_since.is_none() {
+        return None;
+    }
+
+    Some(Fetch {
+        sequence_set,
+        items,
+        changed_since,
+        vanished,
+    })
+}
+
+/// Parse a message data item name, or the items of a macro.
+fn parse_item(name: &str) -> Option<Vec<FetchItem>> {
+    let upper = name.to_ascii_uppercase();
+    let item = match upper.as_str() {
+        "ALL" => {
+            return Some(vec![
+                FetchItem::Flags,
+                FetchItem::InternalDate,
+                FetchItem::Rfc822Size,
+                FetchItem::Envelope,
+            ])
+        }
+        "FAST" => {
+            return Some(vec![
+                FetchItem::Flags,
+                FetchItem::InternalDate,
+                FetchItem::Rfc822Size,
+            ])
+        }
+        "FULL" => {
+            return Some(vec![
+                FetchItem::Flags,
+                FetchItem::InternalDate,
+                FetchItem::Rfc822Size,
+                FetchItem::Envelope,
+                FetchItem::Body,
+            ])
+        }
+        "UID" => FetchItem::Uid,
+        "FLAGS" => FetchItem::Flags,
+        "MODSEQ" => FetchItem::Modseq,
+        "INTERNALDATE" => FetchItem::InternalDate,
+        "RFC822.SIZE" => FetchItem::Rfc822Size,
+        "ENVELOPE" => FetchItem::Envelope,
+        "BODY" => FetchItem::Body,
+        "BODYSTRUCTURE" => FetchItem::BodyStructure,
+        "RFC822" => FetchItem::Rfc822,
+        "RFC822.HEADER" => FetchItem::Rfc822Header,
+        "RFC822.TEXT" => FetchItem::Rfc822Text,
+        _ => parse_body_section(&upper)?,
+    };
+    Some(vec![item])
+}
+
+/// Parse `BODY[section]<offset.count>`, `BINARY[part]<offset.count>`,
+/// their `.PEEK` forms, or `BINARY.SIZE[part]`, uppercased.
+fn parse_body_section(name: &str) -> Option<FetchItem> {
+    let (prefix, rest) = name.split_once('[')?;
+    let (section, partial) = rest.rsplit_once(']')?;
+    let partial = match partial {
+        "" => None,
+        partial => {
+            let partial = partial.strip_prefix('<')?.strip_suffix('>')?;
+            let (offset, count) = partial.split_once('.')?;
+            let count: u32 = count.parse().ok()?;
+            if count == 0 {
+                return None;
+            }
+            Some((offset.parse().ok()?, count))
+        }
+    };
+    let item = match prefix {
+        "BODY" | "BODY.PEEK" => FetchItem::BodySection {
+            section: parse_section(section)?,
+            partial,
+            peek: prefix == "BODY.PEEK",
+        },
+        "BINARY" | "BINARY.PEEK" => FetchItem::Binary {
+            part: parse_part(section)?,
+            partial,
+            peek: prefix == "BINARY.PEEK",
+        },
+        "BINARY.SIZE" if partial.is_none() => {
+            FetchItem::BinarySize(parse_part(section)?)
+        }
+        _ => return None,
+    };
+    Some(item)
+}
+
+/// Parse the section of a BINARY item, part numbers alone.
+fn parse_part(section: &str) -> Option<Vec<u32>> {
+    match parse_section(section)? {
+        Section {
+            part,
+            text: SectionText::Full,
+        } => Some(part),
+        _ => None,
+    }
+}
+
+/// Parse a section like `1.2.HEADER.FIELDS (FROM TO)`.
+fn parse_section(section: &str) -> Option<Section> {
+    let mut part = vec![];
+    let mut rest = section;
+    while rest.starts_with(|c: char| c.is_ascii_digit()) {
+        let (number, after) = rest.split_once('.').unwrap_or((rest, ""));
+        let number: u32 = number.parse().ok()?;
+        if number == 0 || (after.is_empty() && rest.contains('.')) {
+            return None;
+        }
+        part.push(number);
+        rest = after;
+    }
+
+    let text = match rest {
+        "" => SectionText::Full,
+        "HEADER" => SectionText::Header,
+        "TEXT" => SectionText::Text,
+        "MIME" if !part.is_empty() => SectionText::Mime,
+        rest => {
+            let (kind, names) = rest.split_once(' ')?;
+            let names = names.strip_prefix('(')?.strip_suffix(')')?;
+            let names: Vec<String> = names
+                .split_whitespace()
+                .map(|name| name.trim_matches('"').to_string())
+                .collect();
+            if names.is_empty() {
+                return None;
+            }
+            match kind {
+                "HEADER.FIELDS" => SectionText::HeaderFields(names),
+                "HEADER.FIELDS.NOT" => SectionText::HeaderFieldsNot(names),
+                _ => return None,
+            }
+        }
+    };
+    Some(Section { part, text })
+}
+
+/// Section as it's named in a response.
+fn section_label(section: &Section) -> String {
+    let mut label: Vec<String> =
+        section.part.iter().map(|n| n.to_string()).collect();
+    let text = match &section.text {
+        SectionText::Full => None,
+        SectionText::Header => Some("HEADER".to_string()),
+        SectionText::HeaderFields(names) => {
+            Some(format!("HEADER.FIELDS ({})", names.join(" ")))
+        }
+        SectionText::HeaderFieldsNot(names) => {
+            Some(format!("HEADER.FIELDS.NOT ({})", names.join(" ")))
+        }
+        SectionText::Text => Some("TEXT".to_string()),
+        SectionText::Mime => Some("MIME".to_string()),
+    };
+    label.extend(text);
+    label.join(".")
+}
+
+/// Content of `section` of `message`, `None` if there's no such part.
+fn section_data(
+    message: &Part,
+    raw: &[u8],
+    section: &Section,
+) -> Option<Vec<u8>> {
+    // the header and text of a part are those of the message it holds
+    let (message, part) = match section.part.as_slice() {
+        [] => (Some(message), None),
+        path => {
+            let part = message.find(path)?;
+            (part.message.as_deref(), Some(part))
+        }
+    };
+    match (&section.text, part) {
+        (SectionText::Full, None) => Some(raw.to_vec()),
+        (SectionText::Full, Some(part)) => Some(part.body.to_vec()),
+        (SectionText::Mime, Some(part)) => Some(part.header.to_vec()),
+        (SectionText::Mime, None) => None,
+        (SectionText::Header, _) => Some(message?.header.to_vec()),
+        (SectionText::Text, _) => Some(message?.body.to_vec()),
+        (SectionText::HeaderFields(names), _) => {
+            Some(message?.header_fields(names, false))
+        }
+        (SectionText::HeaderFieldsNot(names), _) => {
+            Some(message?.header_fields(names, true))
+        }
+    }
+}
+
+/// Content of `part` of `message` with its transfer encoding undone, the
+/// whole message for no part, `None` if there's no such part. Refused with
+/// `UNKNOWN-CTE` when the encoding can't be undone.
+fn binary_data(
+    message: &Part,
+    raw: &[u8],
+    part: &[u32],
+) -> Result<Option<Vec<u8>>> {
+    if part.is_empty() {
+        return Ok(Some(raw.to_vec()));
+    }
+    match message.find(part) {
+        Some(part) => match part.decoded_body() {
+            Some(data) => Ok(Some(data)),
+            None => Err(Refused(UNKNOWN_CTE).into()),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Whether fetching `items` sets `\Seen`: if one of them reads a body
+/// without `PEEK`, unless the mailbox is read-only, EXAMINEd included, or
+/// `rights` lack the right to set it.
+pub fn marks_seen(items: &[FetchItem], read_only: bool, rights: &str) -> bool {
+    !read_only && rights.contains('s') && items.iter().any(FetchItem::sets_seen)
+}
+
+/// Send the FETCH responses of `fetch` for the selected mailbox, with the
+/// UIDs if `uid` and the mod-sequences with CONDSTORE. Fetching a body
+/// sets `\Seen`, the new flags being sent along.
+pub async fn fetch<IO>(
     s: &mut IMAPServ<'_, IO>,
-    seq_value: u32,
-    macro_or_item_names: MacroOrMessageDataItemNames<'_>,
+    fetch: &Fetch,
+    uid: bool,
 ) -> Result<()>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    let mut items = vec![MessageDataItem::Rfc822Size(1337)];
-
-    match macro_or_item_names {
-        MacroOrMessageDataItemNames::Macro(macro_name) => {
-            debug!("macro_name: {:?}", macro_name);
-            match macro_name {
-                Macro::All => {
-                    items.push(build_envelope(seq_value));
-                }
-                Macro::Full => {
-                    items.push(build_envelope(seq_value));
-                }
-                Macro::Fast => {
-                    // @TODO(robin): code here
+    let (owner, mailbox) = s.session.selected_location()?;
+    let selected = s.session.selected()?;
+    let store = s.session.store.clone();
+    let index = store.load_index(&owner, &mailbox)?;
+    let modseqs: HashMap<u32, u64> =
+        index.messages.iter().map(|m| (m.uid, m.modseq)).collect();
+    let targets: Vec<(u32, u32)> =
+        resolve_sequence_set(selected, &fetch.sequence_set, uid)
+            .into_iter()
+            .filter(|(_, uid)| match modseqs.get(uid) {
+                Some(modseq) => fetch.changed_since.is_none_or(|n| *modseq > n),
+                None => false,
+            })
+            .collect();
+
+    let mut responses = vec![];
+    if let Some(modseq) = fetch.changed_since.filter(|_| fetch.vanished) {
+        let vanished =
+            vanished_since(&index, modseq, Some(&fetch.sequence_set));
+        if !vanished.is_empty() {
+            let uids = uid_set_string(&vanished);
+            responses.push(format!("VANISHED (EARLIER) {}", uids));
+        }
+    }
+    for response in responses {
+        s.status(&response).await;
+    }
+
+    let mut seen = vec![];
+    let selected = s.session.selected()?;
+    if marks_seen(&fetch.items, selected.read_only, &selected.rights) {
+        let name = selected.name.clone();
+        let fetched: HashSet<u32> =
+            targets.iter().map(|(_, uid)| *uid).collect();
+        store.update_index(&owner, &mailbox, &mut |index| {
+            seen.clear();
+            let mut modseq = None;
+            for meta in index.messages.iter_mut() {
+                if !fetched.contains(&meta.uid) || meta.has_flag("\\Seen") {
+                    continue;
                 }
+                let modseq = *modseq.get_or_insert(index.highest_modseq + 1);
+                meta.flags.push("\\Seen".to_string());
+                meta.modseq = modseq;
+                seen.push(meta.uid);
             }
+            if modseq.is_some() {
+                index.next_modseq();
+            }
+        })?;
+        if !seen.is_empty() {
+            s.session
+                .publish(&name, MailboxChange::Flags(seen.clone()))?;
+        }
+    }
+
+    let index = match seen.is_empty() {
+        true => index,
+        false => store.load_index(&owner, &mailbox)?,
+    };
+    let stored: HashMap<u32, &MessageMeta> =
+        index.messages.iter().map(|m| (m.uid, m)).collect();
+    let with = |item| fetch.items.contains(&item);
+    let reads_message = fetch.items.iter().any(FetchItem::reads_message);
+
+    for (seq, target_uid) in targets {
+        let meta = match stored.get(&target_uid) {
+            Some(meta) => meta,
+            None => continue,
+        };
+        let raw = match reads_message {
+            true => store.read_message(&owner, &mailbox, meta.uid)?,
+            false => vec![],
+        };
+        let message = Part::parse(&raw);
+        let recent = s.session.selected()?.recent.contains(&meta.uid);
+
+        let mut items: Vec<Vec<u8>> = vec![];
+        if uid && !with(FetchItem::Uid) {
+            items.push(format!("UID {}", meta.uid).into_bytes());
+        }
+        for item in fetch.items.iter() {
+            items.push(item_data(item, meta, recent, &message, &raw)?);
+        }
+        if seen.contains(&meta.uid) && !with(FetchItem::Flags) {
+            let flags = flags_list(meta, recent);
+            items.push(format!("FLAGS {}", flags).into_bytes());
         }
-        MacroOrMessageDataItemNames::MessageDataItemNames(
-            msg_data_item_names,
-        ) => {
-            debug!("msg_data_item_names: {:?}", msg_data_item_names);
-            for mi_name in msg_data_item_names.iter() {
-                match mi_name {
-                    MessageDataItemName::Envelope => {
-                        items.push(build_envelope(seq_value));
-                    }
-                    MessageDataItemName::Body => todo!(),
-                    MessageDataItemName::BodyExt {
-                        section: _,
-                        partial: _,
-                        peek: _,
-                    } => todo!(),
-                    MessageDataItemName::BodyStructure => todo!(),
-                    MessageDataItemName::Flags => todo!(),
-                    MessageDataItemName::InternalDate => todo!(),
-                    MessageDataItemName::Rfc822 => todo!(),
-                    MessageDataItemName::Rfc822Header => todo!(),
-                    MessageDataItemName::Rfc822Size => todo!(),
-                    MessageDataItemName::Rfc822Text => todo!(),
-                    MessageDataItemName::Uid => todo!(),
+        if s.session.condstore() && !with(FetchItem::Modseq) {
+            items.push(format!("MODSEQ ({})", meta.modseq).into_bytes());
+        }
+
+        let mut response = format!("* {} FETCH (", seq).into_bytes();
+        response.extend_from_slice(&items.join(&b' '));
+        response.extend_from_slice(b")\r\n");
+        debug!(":> * {} FETCH ({} bytes)", seq, response.len());
+        s.write(&response).await?;
+    }
+    Ok(())
+}
+
+/// Data of `item` in a FETCH response, name included.
+fn item_data(
+    item: &FetchItem,
+    meta: &MessageMeta,
+    recent: bool,
+    message: &Part,
+    raw: &[u8],
+) -> Result<Vec<u8>> {
+    let mut out = vec![];
+    match item {
+        FetchItem::Uid => out.extend(format!("UID {}", meta.uid).bytes()),
+        FetchItem::Flags => {
+            out.extend(format!("FLAGS {}", flags_list(meta, recent)).bytes())
+        }
+        FetchItem::Modseq => {
+            out.extend(format!("MODSEQ ({})", meta.modseq).bytes())
+        }
+        FetchItem::InternalDate => {
+            let date = meta.internal_date.format("%d-%b-%Y %H:%M:%S %z");
+            out.extend(format!("INTERNALDATE \"{}\"", date).bytes());
+        }
+        FetchItem::Rfc822Size => {
+            out.extend(format!("RFC822.SIZE {}", meta.size).bytes())
+        }
+        FetchItem::Envelope => {
+            out.extend_from_slice(b"ENVELOPE ");
+            envelope(&mut out, message);
+        }
+        FetchItem::Body => {
+            out.extend_from_slice(b"BODY ");
+            body_structure(&mut out, message, false);
+        }
+        FetchItem::BodyStructure => {
+            out.extend_from_slice(b"BODYSTRUCTURE ");
+            body_structure(&mut out, message, true);
+        }
+        FetchItem::Rfc822 => {
+            out.extend_from_slice(b"RFC822 ");
+            literal(&mut out, raw);
+        }
+        FetchItem::Rfc822Header => {
+            out.extend_from_slice(b"RFC822.HEADER ");
+            literal(&mut out, message.header);
+        }
+        FetchItem::Rfc822Text => {
+            out.extend_from_slice(b"RFC822.TEXT ");
+            literal(&mut out, message.body);
+        }
+        FetchItem::BodySection {
+            section, partial, ..
+        } => {
+            out.extend(format!("BODY[{}]", section_label(section)).bytes());
+            let data = section_data(message, raw, section);
+            let data = partial_data(&mut out, data, partial);
+            out.push(b' ');
+            match data {
+                Some(data) => literal(&mut out, &data),
+                None => out.extend_from_slice(b"NIL"),
+            }
+        }
+        FetchItem::Binary { part, partial, .. } => {
+            out.extend(format!("BINARY[{}]", part_label(part)).bytes());
+            let data = binary_data(message, raw, part)?;
+            let data = partial_data(&mut out, data, partial);
+            out.push(b' ');
+            match data {
+                Some(data) => binary_literal(&mut out, &data),
+                None => out.extend_from_slice(b"NIL"),
+            }
+        }
+        FetchItem::BinarySize(part) => {
+            let size = binary_data(message, raw, part)?.map_or(0, |d| d.len());
+            let label = part_label(part);
+            out.extend(format!("BINARY.SIZE[{}] {}", label, size).bytes());
+        }
+    }
+    Ok(out)
+}
+
+/// The octets `partial` selects of `data`, after writing the offset they
+/// start at.
+fn partial_data(
+    out: &mut Vec<u8>,
+    data: Option<Vec<u8>>,
+    partial: &Option<(u32, u32)>,
+) -> Option<Vec<u8>> {
+    match (data, partial) {
+        (Some(data), Some((offset, count))) => {
+            out.extend(format!("<{}>", offset).bytes());
+            let start = (*offset as usize).min(data.len());
+            let end = start.saturating_add(*count as usize);
+            Some(data[start..end.min(data.len())].to_vec())
+        }
+        (data, _) => data,
+    }
+}
+
+/// Part numbers as in a section, like `1.2`.
+fn part_label(part: &[u32]) -> String {
+    let numbers: Vec<String> = part.iter().map(|n| n.to_string()).collect();
+    numbers.join(".")
+}
+
+/// Write `data` as a literal.
+fn literal(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend(format!("{{{}}}\r\n", data.len()).bytes());
+    out.extend_from_slice(data);
+}
+
+/// Write decoded `data` as a literal, a `literal8` if it has NULs which
+/// a literal can't hold (RFC 3516).
+fn binary_literal(out: &mut Vec<u8>, data: &[u8]) {
+    if data.contains(&0) {
+        out.push(b'~');
+    }
+    literal(out, data);
+}
+
+/// Write `value` as an nstring, quoted unless it needs a literal.
+fn nstring(out: &mut Vec<u8>, value: Option<&str>) {
+    match value {
+        Some(value) => Token::String(value.as_bytes().to_vec()).encode(out),
+        None => out.extend_from_slice(b"NIL"),
+    }
+}
+
+/// Write the envelope of `message` (RFC 3501 section 7.4.2), header values
+/// left encoded as stored.
+fn envelope(out: &mut Vec<u8>, message: &Part) {
+    let field = |name| message.field(name);
+    let from = field("From");
+
+    out.push(b'(');
+    nstring(out, field("Date").as_deref());
+    out.push(b' ');
+    nstring(out, field("Subject").as_deref());
+    for name in ["From", "Sender", "Reply-To", "To", "Cc", "Bcc"] {
+        out.push(b' ');
+        // sender and reply-to default to the author
+        let value = match name {
+            "Sender" | "Reply-To" => field(name).or_else(|| from.clone()),
+            _ => field(name),
+        };
+        addresses(out, value.as_deref());
+    }
+    out.push(b' ');
+    nstring(out, field("In-Reply-To").as_deref());
+    out.push(b' ');
+    nstring(out, field("Message-ID").as_deref());
+    out.push(b')');
+}
+
+/// Write the address list of header value `value`, groups as their start
+/// and end markers around their members.
+fn addresses(out: &mut Vec<u8>, value: Option<&str>) {
+    let list = match value.map(addrparse) {
+        Some(Ok(list)) if !list.is_empty() => list,
+        _ => return out.extend_from_slice(b"NIL"),
+    };
+    out.push(b'(');
+    for addr in list.iter() {
+        match addr {
+            MailAddr::Single(info) => address(out, info),
+            MailAddr::Group(group) => {
+                out.extend_from_slice(b"(NIL NIL ");
+                nstring(out, Some(&group.group_name));
+                out.extend_from_slice(b" NIL)");
+                for info in group.addrs.iter() {
+                    address(out, info);
                 }
+                out.extend_from_slice(b"(NIL NIL NIL NIL)");
             }
         }
     }
+    out.push(b')');
+}
 
-    let data = Data::Fetch {
-        seq: NonZeroU32::new(seq_value).unwrap(),
-        items: NonEmptyVec::try_from(items).unwrap(),
+fn address(out: &mut Vec<u8>, info: &SingleInfo) {
+    let (mailbox, host) = match info.addr.rsplit_once('@') {
+        Some((mailbox, host)) => (mailbox, Some(host)),
+        None => (info.addr.as_str(), None),
     };
+    out.push(b'(');
+    nstring(out, info.display_name.as_deref());
+    out.extend_from_slice(b" NIL ");
+    nstring(out, Some(mailbox));
+    out.push(b' ');
+    nstring(out, host);
+    out.push(b')');
+}
 
-    let resp = String::from_utf8(data.encode().dump()).unwrap();
+/// Write the body structure of `part`, with the extension data of
+/// BODYSTRUCTURE if `extensible`.
+fn body_structure(out: &mut Vec<u8>, part: &Part, extensible: bool) {
+    let (media_type, subtype) = part
+        .ctype
+        .mimetype
+        .split_once('/')
+        .unwrap_or(("text", "plain"));
 
-    // let resp = format!("{data}\r\n", data=data);
+    out.push(b'(');
+    if part.is_multipart() && !part.parts.is_empty() {
+        for child in part.parts.iter() {
+            body_structure(out, child, extensible);
+        }
+        out.push(b' ');
+        nstring(out, Some(&subtype.to_ascii_uppercase()));
+        if extensible {
+            out.push(b' ');
+            parameters(out, part);
+            out.push(b' ');
+            disposition(out, part);
+            out.push(b' ');
+            languages(out, part);
+            out.push(b' ');
+            nstring(out, part.field("Content-Location").as_deref());
+        }
+        out.push(b')');
+        return;
+    }
 
-    debug!(":> {}", resp);
+    nstring(out, Some(&media_type.to_ascii_uppercase()));
+    out.push(b' ');
+    nstring(out, Some(&subtype.to_ascii_uppercase()));
+    out.push(b' ');
+    parameters(out, part);
+    out.push(b' ');
+    nstring(out, part.field("Content-ID").as_deref());
+    out.push(b' ');
+    nstring(out, part.field("Content-Description").as_deref());
+    out.push(b' ');
+    let encoding = part
+        .field("Content-Transfer-Encoding")
+        .unwrap_or_else(|| "7BIT".to_string());
+    nstring(out, Some(&encoding.to_ascii_uppercase()));
+    out.extend(format!(" {}", part.body.len()).bytes());
+    let lines = part.body.iter().filter(|c| **c == b'\n').count();
+    match part.message.as_deref() {
+        Some(message) => {
+            out.push(b' ');
+            envelope(out, message);
+            out.push(b' ');
+            body_structure(out, message, extensible);
+            out.extend(format!(" {}", lines).bytes());
+        }
+        None if media_type == "text" => {
+            out.extend(format!(" {}", lines).bytes())
+        }
+        None => {}
+    }
+    if extensible {
+        out.push(b' ');
+        nstring(out, part.field("Content-MD5").as_deref());
+        out.push(b' ');
+        disposition(out, part);
+        out.push(b' ');
+        languages(out, part);
+        out.push(b' ');
+        nstring(out, part.field("Content-Location").as_deref());
+    }
+    out.push(b')');
+}
 
-    let _ = s.write_data(data).await;
+/// Write the `Content-Type` parameters of `part`, a text part's charset
+/// included even if it's the default.
+fn parameters(out: &mut Vec<u8>, part: &Part) {
+    let mut params = part.ctype.params.clone();
+    if part.ctype.mimetype.starts_with("text/") {
+        params
+            .entry("charset".to_string())
+            .or_insert_with(|| part.ctype.charset.clone());
+    }
+    attribute_list(out, &params);
+}
 
-    Ok(())
+fn attribute_list(
+    out: &mut Vec<u8>,
+    params: &std::collections::BTreeMap<String, String>,
+) {
+    if params.is_empty() {
+        return out.extend_from_slice(b"NIL");
+    }
+    out.push(b'(');
+    for (i, (name, value)) in params.iter().enumerate() {
+        if i > 0 {
+            out.push(b' ');
+        }
+        nstring(out, Some(&name.to_ascii_uppercase()));
+        out.push(b' ');
+        nstring(out, Some(value));
+    }
+    out.push(b')');
 }
 
-fn build_envelope<'a>(seq_value: u32) -> MessageDataItem<'a> {
-    MessageDataItem::Envelope(Envelope {
-        date: NString::try_from("Mon, 7 Feb 1994 21:52:25 -0800").unwrap(),
-        subject: NString::try_from("Imaple is cool!").unwrap(),
-        from: vec![Address {
-            name: NString::try_from("Joe Q. Public").unwrap(),
-            adl: NString(None),
-            mailbox: NString::try_from("john.q.public").unwrap(),
-            host: NString::try_from("example.com").unwrap(),
-        }],
-        sender: vec![Address {
-            name: NString::try_from("Joe Q. Public").unwrap(),
-            adl: NString(None),
-            mailbox: NString::try_from("john.q.public").unwrap(),
-            host: NString::try_from("example.com").unwrap(),
-        }],
-        reply_to: vec![],
-        to: vec![Address {
-            name: NString::try_from("Robin Syihab").unwrap(),
-            adl: NString(None),
-            mailbox: NString::try_from("robin").unwrap(),
-            host: NString::try_from("nu.id").unwrap(),
-        }],
-        cc: vec![],
-        bcc: vec![],
-        in_reply_to: NString(None),
-        message_id: NString::try_from(format!("{}", seq_value)).unwrap(),
-    })
+fn disposition(out: &mut Vec<u8>, part: &Part) {
+    let value = match part.field("Content-Disposition") {
+        Some(value) => value,
+        None => return out.extend_from_slice(b"NIL"),
+    };
+    let kind = value.split(';').next().unwrap_or_default().trim();
+    let params = mailparse::parse_content_disposition(&value).params;
+    out.push(b'(');
+    nstring(out, Some(&kind.to_ascii_uppercase()));
+    out.push(b' ');
+    attribute_list(out, &params);
+    out.push(b')');
+}
+
+fn languages(out: &mut Vec<u8>, part: &Part) {
+    let value = part.field("Content-Language").unwrap_or_default();
+    let languages: Vec<&str> = value
+        .split(',')
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .collect();
+    if languages.is_empty() {
+        return out.extend_from_slice(b"NIL");
+    }
+    out.push(b'(');
+    for (i, language) in languages.iter().enumerate() {
+        if i > 0 {
+            out.push(b' ');
+        }
+        nstring(out, Some(language));
+    }
+    out.push(b')');
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::ext_command::ext_command_decode;
+    use crate::storage::INBOX;
+    use crate::testing::{add_message, mail_dir, run, session};
+
+    fn items(line: &str) -> Option<Vec<FetchItem>> {
+        let ext = ext_command_decode(line.as_bytes())?;
+        parse_fetch(&ext.args).map(|fetch| fetch.items)
+    }
+
+    fn section(part: &[u32], text: SectionText) -> Section {
+        Section {
+            part: part.to_vec(),
+            text,
+        }
+    }
+
+    #[test]
+    fn parses_items_and_macros() {
+        assert_eq!(
+            items("a FETCH 1:* fast").unwrap(),
+            [
+                FetchItem::Flags,
+                FetchItem::InternalDate,
+                FetchItem::Rfc822Size
+            ]
+        );
+        assert_eq!(
+            items("a FETCH 1 (UID FLAGS UID)").unwrap(),
+            [FetchItem::Uid, FetchItem::Flags]
+        );
+        assert!(items("a FETCH 1 ()").is_none());
+        assert!(items("a FETCH 1 BOGUS").is_none());
+    }
+
+    #[test]
+    fn parses_body_sections() {
+        assert_eq!(
+            items(
+                "a FETCH 1 (BODY.PEEK[1.2.HEADER.FIELDS (From \"To\")]<0.10>)"
+            )
+            .unwrap(),
+            [FetchItem::BodySection {
+                section: section(
+                    &[1, 2],
+                    SectionText::HeaderFields(vec![
+                        "FROM".to_string(),
+                        "TO".to_string()
+                    ])
+                ),
+                partial: Some((0, 10)),
+                peek: true,
+            }]
+        );
+        assert_eq!(
+            items("a FETCH 1 BODY[]").unwrap(),
+            [FetchItem::BodySection {
+                section: section(&[], SectionText::Full),
+                partial: None,
+                peek: false,
+            }]
+        );
+        assert_eq!(
+            parse_section("3.MIME"),
+            Some(section(&[3], SectionText::Mime))
+        );
+        for invalid in ["MIME", "0", "1.", "1..2", "HEADER.FIELDS ()", "X"] {
+            assert_eq!(parse_section(invalid), None, "{}", invalid);
+        }
+        assert!(items("a FETCH 1 BODY[]<0.0>").is_none());
+    }
+
+    #[test]
+    fn parses_binary_items() {
+        assert_eq!(
+            items("a FETCH 1 (BINARY.PEEK[1.2]<0.10> BINARY.SIZE[1])").unwrap(),
+            [
+                FetchItem::Binary {
+                    part: vec![1, 2],
+                    partial: Some((0, 10)),
+                    peek: true,
+                },
+                FetchItem::BinarySize(vec![1]),
+            ]
+        );
+        assert!(marks_seen(
+            &items("a FETCH 1 BINARY[]").unwrap(),
+            false,
+            "s"
+        ));
+        for invalid in ["BINARY[1.TEXT]", "BINARY.SIZE[1]<0.1>", "BINARY"] {
+            let line = format!("a FETCH 1 {}", invalid);
+            assert!(items(&line).is_none(), "{}", invalid);
+        }
+    }
+
+    const PARTS: &str = "Subject: parts\r\n\
+        Content-Type: multipart/mixed; boundary=\"b\"\r\n\r\n\
+        --b\r\n\
+        Content-Type: application/octet-stream\r\n\
+        Content-Transfer-Encoding: base64\r\n\r\n\
+        AGJpbgA=\r\n\
+        --b\r\n\
+        Content-Transfer-Encoding: quoted-printable\r\n\r\n\
+        caf=C3=A9\r\n\
+        --b\r\n\
+        Content-Transfer-Encoding: x-uuencode\r\n\r\n\
+        begin 644 x\r\n\
+        --b--\r\n";
+
+    #[tokio::test]
+    async fn fetches_decoded_parts() {
+        let root = mail_dir("fetch-binary");
+        let mut s = session(&root);
+        add_message(&s, INBOX, PARTS, &[]);
+        run(&mut s, "a SELECT INBOX").await;
+
+        // NULs are sent in a literal8
+        let out =
+            run(&mut s, "a FETCH 1 (BINARY.PEEK[1] BINARY.SIZE[2])").await;
+        assert!(out.starts_with(
+            "* 1 FETCH (BINARY[1] ~{5}\r\n\0bin\0 BINARY.SIZE[2] 5)\r\n"
+        ));
+        let out = run(&mut s, "a FETCH 1 BINARY[2]<0.3>").await;
+        assert!(out.contains("(BINARY[2]<0> {3}\r\ncaf FLAGS (\\Seen"));
+        let out = run(&mut s, "a FETCH 1 BINARY.PEEK[4]").await;
+        assert!(out.starts_with("* 1 FETCH (BINARY[4] NIL)\r\n"));
+
+        let out = run(&mut s, "a FETCH 1 BINARY.PEEK[3]").await;
+        assert_eq!(
+            out,
+            "a NO [UNKNOWN-CTE] Unknown Content-Transfer-Encoding\r\n"
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn labels_sections() {
+        let names = vec!["FROM".to_string()];
+        assert_eq!(
+            section_label(&section(&[1, 2], SectionText::HeaderFields(names))),
+            "1.2.HEADER.FIELDS (FROM)"
+        );
+        assert_eq!(section_label(&section(&[], SectionText::Full)), "");
+    }
+
+    #[test]
+    fn examined_mailboxes_stay_unseen() {
+        let body = items("a FETCH 1 BODY[]").unwrap();
+        assert!(marks_seen(&body, false, "lrs"));
+        // EXAMINE selects the mailbox read-only
+        assert!(!marks_seen(&body, true, "lrs"));
+        assert!(!marks_seen(&body, false, "lr"));
+
+        let peek = items("a FETCH 1 (BODY.PEEK[] RFC822.HEADER)").unwrap();
+        assert!(!marks_seen(&peek, false, "lrs"));
+        let rfc822 = items("a FETCH 1 RFC822").unwrap();
+        assert!(marks_seen(&rfc822, false, "lrs"));
+        assert!(!marks_seen(&rfc822, true, "lrs"));
+    }
+
+    #[test]
+    fn parses_modifiers() {
+        let ext = ext_command_decode(
+            b"a UID FETCH 1 FLAGS (CHANGEDSINCE 5 VANISHED)",
+        )
+        .unwrap();
+        let fetch = parse_fetch(&ext.args).unwrap();
+        assert_eq!(fetch.changed_since, Some(5));
+        assert!(fetch.vanished);
+
+        let ext = ext_command_decode(b"a FETCH 1 FLAGS (VANISHED)").unwrap();
+        assert!(parse_fetch(&ext.args).is_none());
+    }
 }
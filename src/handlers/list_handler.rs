@@ -0,0 +1,435 @@
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::quoted;
+use super::status_handler::{self, STATUS_ITEMS};
+use crate::ext_command::Token;
+use crate::imap_serv::IMAPServ;
+use crate::result::Result;
+use crate::session::Session;
+use crate::storage::{
+    MailboxEntry, SpecialUse, DELIMITER, INBOX, SHARED_OWNER,
+};
+
+/// Options of an RFC 5258 extended LIST.
+#[derive(Default, Debug)]
+pub struct ListOptions {
+    /// Selection option SUBSCRIBED, list subscriptions instead of
+    /// mailboxes.
+    pub subscribed: bool,
+
+    /// Selection option RECURSIVEMATCH, also list the levels above
+    /// subscriptions that match the pattern.
+    pub recursive_match: bool,
+
+    /// Return option SUBSCRIBED, mark subscribed mailboxes.
+    pub return_subscribed: bool,
+
+    /// Selection option SPECIAL-USE (RFC 6154), list only mailboxes with a
+    /// special use. Their attributes are always returned.
+    pub special_use: bool,
+
+    /// Return option STATUS (RFC 5819), the status items sent along each
+    /// selectable mailbox.
+    pub status: Option<Vec<String>>,
+}
+
+/// Parse the arguments of an extended LIST into the options, reference and
+/// patterns. `None` if they are invalid.
+pub fn parse_extended(
+    args: &[Token],
+) -> Option<(ListOptions, String, Vec<String>)> {
+    let mut options = ListOptions::default();
+    let mut args = args;
+
+    if let Some(selection) = args.first().and_then(Token::as_list) {
+        for option in selection {
+            if option.is_atom("SUBSCRIBED") {
+                options.subscribed = true;
+                options.return_subscribed = true;
+            } else if option.is_atom("RECURSIVEMATCH") {
+                options.recursive_match = true;
+            } else if option.is_atom("SPECIAL-USE") {
+                options.special_use = true;
+            } else if !option.is_atom("REMOTE") {
+                // no remote mailboxes exist here, so REMOTE changes nothing
+                return None;
+            }
+        }
+        // RECURSIVEMATCH alone would be meaningless
+        if options.recursive_match && !options.subscribed {
+            return None;
+        }
+        args = &args[1..];
+    }
+
+    let reference = args.first()?.as_astring()?;
+    let patterns = match args.get(1)? {
+        Token::List(patterns) => patterns
+            .iter()
+            .map(Token::as_astring)
+            .collect::<Option<Vec<_>>>()?,
+        pattern => vec![pattern.as_astring()?],
+    };
+
+    match &args[2..] {
+        [] => {}
+        [keyword, Token::List(returned)] if keyword.is_atom("RETURN") => {
+            let mut returned = returned.iter();
+            while let Some(option) = returned.next() {
+                if option.is_atom("SUBSCRIBED") {
+                    options.return_subscribed = true;
+                } else if option.is_atom("STATUS") {
+                    let items = returned
+                        .next()?
+                        .as_list()?
+                        .iter()
+                        .map(|item| Some(item.as_astring()?.to_uppercase()))
+                        .collect::<Option<Vec<_>>>()?;
+                    if !items.iter().all(|i| STATUS_ITEMS.contains(&i.as_str()))
+                    {
+                        return None;
+                    }
+                    options.status = Some(items);
+                } else if !option.is_atom("CHILDREN")
+                    && !option.is_atom("SPECIAL-USE")
+                {
+                    // children and special-use attributes are always sent
+                    return None;
+                }
+            }
+        }
+        _ => return None,
+    }
+
+    Some((options, reference, patterns))
+}
+
+/// The pattern LIST and LSUB match mailbox names against, in storage form.
+/// INBOX is case-insensitive, so it's spelled the canonical way.
+pub fn list_pattern(reference: &str, wildcard: &str) -> String {
+    let pattern = format!("{}{}", reference, wildcard);
+    let first = pattern.split(DELIMITER).next().unwrap_or_default();
+    if first.eq_ignore_ascii_case(INBOX) {
+        return format!("{}{}", INBOX, &pattern[first.len()..]);
+    }
+    pattern
+}
+
+/// Whether mailbox `name` matches the LIST `pattern`, where `*` stands for
+/// anything and `%` for anything but the hierarchy delimiter.
+pub fn pattern_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    // matched[j]: whether the pattern so far matches name[..j]
+    let mut matched = vec![false; name.len() + 1];
+    matched[0] = true;
+    for p in pattern {
+        let mut next = vec![false; name.len() + 1];
+        for j in 0..=name.len() {
+            next[j] = match p {
+                '*' => matched[j] || (j > 0 && next[j - 1]),
+                '%' => {
+                    matched[j]
+                        || (j > 0 && name[j - 1] != DELIMITER && next[j - 1])
+                }
+                c => j > 0 && matched[j - 1] && name[j - 1] == c,
+            };
+        }
+        matched = next;
+    }
+    matched[name.len()]
+}
+
+/// A mailbox to be sent in a LIST response.
+#[derive(Default)]
+struct Listed {
+    exists: bool,
+    selectable: bool,
+    subscribed: bool,
+
+    /// Listed only for a subscribed inferior, RECURSIVEMATCH's CHILDINFO.
+    subscribed_child: bool,
+}
+
+/// Send `* LIST` for the mailboxes matching any of `patterns`, which are in
+/// storage form already.
+pub async fn list<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    patterns: &[String],
+    options: &ListOptions,
+) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let delimiter = quoted(&s.session.config.delimiter.to_string());
+
+    // an empty pattern asks for the hierarchy delimiter
+    if patterns.iter().all(String::is_empty) {
+        s.status(&format!("LIST (\\Noselect) {} \"\"", delimiter))
+            .await;
+        return Ok(());
+    }
+
+    let user = s.session.user()?.to_owned();
+    let (entries, special_use) = visible_mailboxes(s.session)?;
+    let subscriptions: HashSet<String> =
+        s.session.store.subscriptions(&user)?.into_iter().collect();
+    let matches = |name: &str| patterns.iter().any(|p| pattern_match(p, name));
+
+    let mut listed: BTreeMap<String, Listed> = BTreeMap::new();
+    if options.subscribed {
+        for name in subscriptions.iter() {
+            if matches(name) {
+                listed.entry(name.clone()).or_default().subscribed = true;
+            }
+            if !options.recursive_match {
+                continue;
+            }
+            let mut parent = name.as_str();
+            while let Some((above, _)) = parent.rsplit_once(DELIMITER) {
+                parent = above;
+                if matches(parent) {
+                    let entry = listed.entry(parent.to_string()).or_default();
+                    entry.subscribed_child = true;
+                }
+            }
+        }
+        for entry in entries.iter() {
+            if let Some(listed) = listed.get_mut(&entry.name) {
+                listed.exists = true;
+                listed.selectable = entry.selectable;
+                listed.subscribed = subscriptions.contains(&entry.name);
+            }
+        }
+    } else {
+        for entry in entries.iter().filter(|e| matches(&e.name)) {
+            listed.insert(
+                entry.name.clone(),
+                Listed {
+                    exists: true,
+                    selectable: entry.selectable,
+                    subscribed: subscriptions.contains(&entry.name),
+                    subscribed_child: false,
+                },
+            );
+        }
+    }
+
+    for (name, mailbox) in listed {
+        let uses = special_use.get(&name).filter(|_| mailbox.exists);
+        if options.special_use && uses.is_none_or(Vec::is_empty) {
+            continue;
+        }
+
+        let mut attributes = vec![];
+        if !mailbox.exists {
+            attributes.push("\\NonExistent");
+        } else {
+            if !mailbox.selectable {
+                attributes.push("\\Noselect");
+            }
+            let inferiors = format!("{}{}", name, DELIMITER);
+            if entries.iter().any(|e| e.name.starts_with(&inferiors)) {
+                attributes.push("\\HasChildren");
+            } else {
+                attributes.push("\\HasNoChildren");
+            }
+        }
+        if mailbox.subscribed && options.return_subscribed {
+            attributes.push("\\Subscribed");
+        }
+        for special_use in uses.into_iter().flatten() {
+            attributes.push(special_use);
+        }
+
+        let mut response = format!(
+            "LIST ({}) {} {}",
+            attributes.join(" "),
+            delimiter,
+            quoted(&s.session.client_name(&name))
+        );
+        if mailbox.subscribed_child {
+            response.push_str(" (\"CHILDINFO\" (\"SUBSCRIBED\"))");
+        }
+        s.status(&response).await;
+
+        if let Some(items) = options.status.as_ref() {
+            let readable = s.session.access(&name, "r").is_ok();
+            if mailbox.exists && mailbox.selectable && readable {
+                status_handler::send_status(s, &name, items).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Mailboxes the user may see, named as the client does, and their
+/// special uses: their own, then those of other users and the shared ones
+/// they have the lookup right on, below the levels of the namespaces.
+fn visible_mailboxes(
+    session: &Session,
+) -> Result<(Vec<MailboxEntry>, SpecialUse)> {
+    let user = session.user()?;
+    let namespaces = &session.config.namespaces;
+    let mut owners = vec![user.to_string()];
+    if namespaces.other_users.is_some() {
+        let others = session.store.list_users()?;
+        owners.extend(others.into_iter().filter(|other| other != user));
+    }
+    if namespaces.shared.is_some() {
+        owners.push(SHARED_OWNER.to_string());
+    }
+
+    let mut entries = vec![];
+    let mut special_use = SpecialUse::new();
+    for owner in owners {
+        let mut owned = session.store.list_mailboxes(&owner)?;
+        if owner == user && !owned.iter().any(|e| e.name == INBOX) {
+            // INBOX always exists, even before being stored
+            owned.push(MailboxEntry {
+                name: INBOX.to_string(),
+                selectable: true,
+            });
+        }
+        let mut uses = session.store.special_use(&owner)?;
+        for entry in owned {
+            if owner != user
+                && !session.rights_of(&owner, &entry.name)?.contains('l')
+            {
+                continue;
+            }
+            let name = session.qualified_name(&owner, &entry.name);
+            if let Some(uses) = uses.remove(&entry.name) {
+                special_use.insert(name.clone(), uses);
+            }
+            entries.push(MailboxEntry {
+                name,
+                selectable: entry.selectable,
+            });
+        }
+    }
+
+    // the levels leading to others' mailboxes, namespaces included, can't
+    // be selected
+    let names: HashSet<String> =
+        entries.iter().map(|e| e.name.clone()).collect();
+    let mut levels = BTreeSet::new();
+    for entry in entries.iter() {
+        if session.locate(&entry.name)?.0 == user {
+            continue;
+        }
+        let mut parent = entry.name.as_str();
+        while let Some((above, _)) = parent.rsplit_once(DELIMITER) {
+            parent = above;
+            if !names.contains(parent) {
+                levels.insert(parent.to_string());
+            }
+        }
+    }
+    entries.extend(levels.into_iter().map(|name| MailboxEntry {
+        name,
+        selectable: false,
+    }));
+    Ok((entries, special_use))
+}
+
+/// Send `* LSUB` for the subscribed mailboxes matching `pattern`. A level
+/// above a subscribed mailbox matched by `%` is sent as `\Noselect`.
+pub async fn lsub<IO>(s: &mut IMAPServ<'_, IO>, pattern: &str) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let subscriptions = s.session.store.subscriptions(s.session.user()?)?;
+
+    // name -> whether it's only a level above a subscription
+    let mut found: BTreeMap<String, bool> = BTreeMap::new();
+    for name in subscriptions.iter() {
+        if pattern_match(pattern, name) {
+            found.insert(name.clone(), false);
+            continue;
+        }
+        let mut parent = name.as_str();
+        while let Some((above, _)) = parent.rsplit_once(DELIMITER) {
+            parent = above;
+            if pattern_match(pattern, parent) {
+                found.entry(parent.to_string()).or_insert(true);
+                break;
+            }
+        }
+    }
+
+    let delimiter = quoted(&s.session.config.delimiter.to_string());
+    for (name, noselect) in found {
+        let attributes = if noselect { "\\Noselect" } else { "" };
+        let name = quoted(&s.session.client_name(&name));
+        s.status(&format!("LSUB ({}) {} {}", attributes, delimiter, name))
+            .await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ext_command::ext_command_decode;
+
+    #[test]
+    fn matches_wildcards() {
+        assert!(pattern_match("*", "Work/2024/Jan"));
+        assert!(pattern_match("Work/*", "Work/2024/Jan"));
+        assert!(pattern_match("Work*", "Work"));
+        assert!(pattern_match("%", "Work"));
+        assert!(!pattern_match("%", "Work/2024"));
+        assert!(pattern_match("Work/%", "Work/2024"));
+        assert!(!pattern_match("Work/%", "Work/2024/Jan"));
+        assert!(pattern_match("%/%/Jan", "Work/2024/Jan"));
+        assert!(pattern_match("W%k/*n", "Work/2024/Jan"));
+        assert!(pattern_match("", ""));
+        assert!(!pattern_match("", "Work"));
+        assert!(!pattern_match("work", "Work"));
+        assert!(pattern_match("Ünï*", "Ünïcode"));
+    }
+
+    #[test]
+    fn joins_references_and_spells_inbox() {
+        assert_eq!(list_pattern("Work/", "%"), "Work/%");
+        assert_eq!(list_pattern("", "inbox"), INBOX);
+        assert_eq!(list_pattern("Inbox/", "*"), "INBOX/*");
+        assert_eq!(list_pattern("", "inboxes"), "inboxes");
+    }
+
+    #[test]
+    fn parses_extended_options() {
+        let parse = |line: &str| {
+            parse_extended(&ext_command_decode(line.as_bytes()).unwrap().args)
+        };
+
+        let (options, reference, patterns) = parse(
+            "a LIST (SUBSCRIBED RECURSIVEMATCH) \"\" (INBOX \"Work/*\") \
+             RETURN (CHILDREN STATUS (messages UIDNEXT))",
+        )
+        .unwrap();
+        assert!(options.subscribed && options.recursive_match);
+        assert!(options.return_subscribed);
+        assert_eq!(
+            options.status.unwrap(),
+            ["MESSAGES".to_string(), "UIDNEXT".to_string()]
+        );
+        assert_eq!(reference, "");
+        assert_eq!(patterns, ["INBOX", "Work/*"]);
+
+        assert!(parse("a LIST \"\" %").is_some());
+        for invalid in [
+            "a LIST (RECURSIVEMATCH) \"\" %",
+            "a LIST (BOGUS) \"\" %",
+            "a LIST \"\" % RETURN (STATUS (BOGUS))",
+            "a LIST \"\" % RETURN (BOGUS)",
+            "a LIST \"\"",
+        ] {
+            assert!(parse(invalid).is_none(), "{}", invalid);
+        }
+    }
+}
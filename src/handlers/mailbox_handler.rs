@@ -0,0 +1,383 @@
+use std::collections::BTreeMap;
+
+use anyhow::anyhow;
+use log::info;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::expunge_handler;
+use crate::error::{Refused, NOPERM};
+use crate::events::MailboxChange;
+use crate::ext_command::Token;
+use crate::imap_serv::IMAPServ;
+use crate::result::Result;
+use crate::session::Session;
+use crate::storage::{INBOX, SPECIAL_USES};
+
+/// Parse the `(USE (attributes))` parameters of CREATE (RFC 6154). An
+/// attribute we don't know is `Err` with its name.
+pub fn parse_use(
+    params: &[Token],
+) -> Option<std::result::Result<Vec<String>, String>> {
+    let attributes = match params {
+        [keyword, Token::List(attributes)] if keyword.is_atom("USE") => {
+            attributes
+        }
+        _ => return None,
+    };
+    let mut uses = vec![];
+    for attribute in attributes {
+        let attribute = match attribute {
+            Token::Atom(attribute) => attribute,
+            _ => return None,
+        };
+        match SPECIAL_USES
+            .iter()
+            .find(|u| u.eq_ignore_ascii_case(attribute))
+        {
+            Some(known) => uses.push(known.to_string()),
+            None => return Some(Err(attribute.clone())),
+        }
+    }
+    Some(Ok(uses))
+}
+
+/// Create mailbox `name`, giving it the special-use attributes `uses`.
+/// Creating one in another user's namespace takes the right to create
+/// inferiors of its parent.
+pub async fn create<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    name: &str,
+    uses: &[String],
+) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    // a trailing delimiter only announces inferiors to come
+    let name = name.trim_end_matches('/');
+    let (owner, mailbox) = s.session.locate(name)?;
+    if !s.session.rights_of(&owner, parent(&mailbox))?.contains('k') {
+        return Err(Refused(NOPERM).into());
+    }
+    s.session.store.create_mailbox(&owner, &mailbox)?;
+    if !uses.is_empty() {
+        s.session
+            .store
+            .update_special_use(&owner, &mut |special_use| {
+                special_use.insert(mailbox.clone(), uses.to_vec());
+            })?;
+    }
+    Ok(())
+}
+
+/// Parent of `mailbox`, the empty name the top level.
+fn parent(mailbox: &str) -> &str {
+    mailbox.rsplit_once('/').map_or("", |(parent, _)| parent)
+}
+
+/// Create the configured special-use mailboxes, subscribed, for a user
+/// logging in for the first time, who has no mailbox yet.
+pub fn create_default_mailboxes(session: &Session) -> Result<()> {
+    let user = session.user()?;
+    let store = &session.store;
+    if !store.list_mailboxes(user)?.is_empty() {
+        return Ok(());
+    }
+
+    let mut created = vec![];
+    for (name, attribute) in session.config.special_use.iter() {
+        let name = session.storage_name(name);
+        if !store.has_mailbox(user, &name)? {
+            store.create_mailbox(user, &name)?;
+        }
+        created.push((name, attribute.clone()));
+    }
+    store.update_special_use(user, &mut |special_use| {
+        for (name, attribute) in created.iter() {
+            special_use.insert(name.clone(), vec![attribute.clone()]);
+        }
+    })?;
+    store.update_subscriptions(user, &mut |subscriptions| {
+        for (name, _) in created.iter() {
+            if !subscriptions.contains(name) {
+                subscriptions.push(name.clone());
+            }
+        }
+    })?;
+    info!("Created {} default mailboxes of {}", created.len(), user);
+    Ok(())
+}
+
+pub async fn delete<IO>(s: &mut IMAPServ<'_, IO>, name: &str) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let (owner, mailbox) = s.session.access(name, "x")?;
+    if mailbox == INBOX {
+        return Err(anyhow!("INBOX can't be deleted").into());
+    }
+    let store = s.session.store.clone();
+    store.delete_mailbox(&owner, &mailbox)?;
+    if let Some(fts) = s.session.fts.as_ref() {
+        fts.remove_mailbox(&owner, &mailbox)?;
+    }
+    if store.special_use(&owner)?.contains_key(&mailbox) {
+        store.update_special_use(&owner, &mut |special_use| {
+            special_use.remove(&mailbox);
+        })?;
+    }
+    if store.acl(&owner)?.contains_key(&mailbox) {
+        store.update_acl(&owner, &mut |acl| {
+            acl.remove(&mailbox);
+        })?;
+    }
+    if store.metadata(&owner)?.contains_key(&mailbox) {
+        store.update_metadata(&owner, &mut |metadata| {
+            metadata.remove(&mailbox);
+        })?;
+    }
+    Ok(())
+}
+
+/// Rename `name` to `new_name`, which must stay with the same owner and
+/// takes the right to create inferiors of its parent.
+pub async fn rename<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    name: &str,
+    new_name: &str,
+) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let (owner, mailbox) = s.session.access(name, "x")?;
+    let (new_owner, new_mailbox) = s.session.locate(new_name)?;
+    if new_owner != owner {
+        return Err(anyhow!("Mailboxes can't change owner").into());
+    }
+    if !s
+        .session
+        .rights_of(&owner, parent(&new_mailbox))?
+        .contains('k')
+    {
+        return Err(Refused(NOPERM).into());
+    }
+    let store = s.session.store.clone();
+    if store.has_mailbox(&owner, &new_mailbox)? {
+        return Err(anyhow!("Mailbox `{}` already exists", new_name).into());
+    }
+    if mailbox == INBOX {
+        return rename_inbox(s, name, &owner, &new_mailbox).await;
+    }
+
+    store.rename_mailbox(&owner, &mailbox, &new_mailbox)?;
+    if let Some(fts) = s.session.fts.as_ref() {
+        fts.rename_mailbox(&owner, &mailbox, &new_mailbox)?;
+    }
+    // attributes, rights and metadata go along with the mailbox and its
+    // inferiors
+    let moved = |m: &String| renamed(m, &mailbox, &new_mailbox).is_some();
+    if store.special_use(&owner)?.keys().any(moved) {
+        store.update_special_use(&owner, &mut |special_use| {
+            rename_keys(special_use, &mailbox, &new_mailbox)
+        })?;
+    }
+    if store.acl(&owner)?.keys().any(moved) {
+        store.update_acl(&owner, &mut |acl| {
+            rename_keys(acl, &mailbox, &new_mailbox)
+        })?;
+    }
+    if store.metadata(&owner)?.keys().any(moved) {
+        store.update_metadata(&owner, &mut |metadata| {
+            rename_keys(metadata, &mailbox, &new_mailbox)
+        })?;
+    }
+
+    // the selected mailbox may be the renamed one or one of its inferiors
+    let selected = s.session.selected.as_ref();
+    if let Some(selected_name) =
+        selected.and_then(|selected| renamed(&selected.name, name, new_name))
+    {
+        let (owner, mailbox) = s.session.locate(&selected_name)?;
+        let events = s.session.events.subscribe(&owner, &mailbox);
+        let selected = s.session.selected.as_mut().unwrap();
+        selected.events = events;
+        selected.name = selected_name;
+    }
+    Ok(())
+}
+
+/// The name `mailbox` has once `name` is renamed to `new_name`, `None` if
+/// it's neither `name` nor one of its inferiors.
+fn renamed(mailbox: &str, name: &str, new_name: &str) -> Option<String> {
+    if mailbox == name {
+        Some(new_name.to_string())
+    } else {
+        mailbox
+            .strip_prefix(&format!("{}/", name))
+            .map(|rest| format!("{}/{}", new_name, rest))
+    }
+}
+
+/// Move the entries of `name` and its inferiors in `map` to their new name.
+fn rename_keys<V>(map: &mut BTreeMap<String, V>, name: &str, new_name: &str) {
+    *map = std::mem::take(map)
+        .into_iter()
+        .map(|(mailbox, value)| match renamed(&mailbox, name, new_name) {
+            Some(new_name) => (new_name, value),
+            None => (mailbox, value),
+        })
+        .collect();
+}
+
+/// Renaming INBOX moves its messages to a new mailbox and leaves INBOX
+/// empty, its inferiors stay where they are (RFC 3501 6.3.5). `name` is
+/// how the client named the INBOX of `owner`.
+async fn rename_inbox<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    name: &str,
+    owner: &str,
+    new_mailbox: &str,
+) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let store = s.session.store.clone();
+    store.create_mailbox(owner, new_mailbox)?;
+
+    let uids: Vec<u32> = store
+        .load_index(owner, INBOX)?
+        .messages
+        .iter()
+        .map(|m| m.uid)
+        .collect();
+    let copied = store.copy_messages(
+        owner,
+        INBOX,
+        &uids,
+        owner,
+        new_mailbox,
+        &|_| true,
+        None,
+    )?;
+    if let Some(fts) = s.session.fts.as_ref() {
+        fts.copy_messages(owner, INBOX, owner, new_mailbox, &copied)?;
+    }
+
+    let moved: Vec<u32> = copied.iter().map(|(from, _)| *from).collect();
+    if s.session.selected.as_ref().is_some_and(|m| m.name == name) {
+        expunge_handler::remove_messages(s, &moved, false).await
+    } else {
+        store.remove_messages(owner, INBOX, &mut |_| moved.clone())?;
+        if let Some(fts) = s.session.fts.as_ref() {
+            fts.remove_messages(owner, INBOX, &moved)?;
+        }
+        s.session.publish(name, MailboxChange::Expunge(moved))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::session::Session;
+    use crate::storage::INBOX;
+    use crate::testing::{add_message, mail_dir, run, session};
+
+    /// Names of bob's mailboxes, `\Noselect` levels marked with a `*`.
+    fn mailboxes(s: &Session) -> Vec<String> {
+        let entries = s.store.list_mailboxes("bob").unwrap();
+        entries
+            .into_iter()
+            .map(|m| match m.selectable {
+                true => m.name,
+                false => format!("{}*", m.name),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn creates_and_deletes_mailboxes() {
+        let root = mail_dir("create-delete");
+        let mut s = session(&root);
+
+        let out = run(&mut s, "a CREATE Work/2024/").await;
+        assert_eq!(out, "a OK CREATE completed\r\n");
+        assert_eq!(mailboxes(&s), ["Work*", "Work/2024"]);
+        assert!(run(&mut s, "a CREATE Work/2024").await.starts_with("a NO "));
+        assert!(run(&mut s, "a CREATE Work").await.starts_with("a OK "));
+
+        // a mailbox with inferiors stays as a level of the hierarchy
+        add_message(&s, "Work", "Subject: a\r\n\r\n", &[]);
+        assert!(run(&mut s, "a DELETE Work").await.starts_with("a OK "));
+        assert_eq!(mailboxes(&s), ["Work*", "Work/2024"]);
+        assert!(s.store.load_index("bob", "Work").is_err());
+        assert!(run(&mut s, "a DELETE Work/2024").await.starts_with("a OK "));
+        assert!(run(&mut s, "a DELETE Work/2024").await.starts_with("a NO "));
+
+        assert!(run(&mut s, "a DELETE INBOX").await.starts_with("a NO "));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn renames_mailboxes_with_their_inferiors() {
+        let root = mail_dir("rename");
+        let mut s = session(&root);
+        run(&mut s, "a CREATE Work/Old").await;
+        run(&mut s, "a CREATE Projects").await;
+        add_message(&s, "Work/Old", "Subject: a\r\n\r\n", &["\\Seen"]);
+        run(&mut s, "a SELECT Work/Old").await;
+
+        let out = run(&mut s, "a RENAME Work Projects").await;
+        assert!(out.starts_with("a NO "));
+        let out = run(&mut s, "a RENAME Work Archive").await;
+        assert_eq!(out, "a OK RENAME completed\r\n");
+        assert_eq!(mailboxes(&s), ["Archive*", "Archive/Old", "Projects"]);
+        let index = s.store.load_index("bob", "Archive/Old").unwrap();
+        assert_eq!(index.messages[0].flags, ["\\Seen"]);
+        assert_eq!(s.selected().unwrap().name, "Archive/Old");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn renaming_inbox_moves_its_messages() {
+        let root = mail_dir("rename-inbox");
+        let mut s = session(&root);
+        add_message(&s, INBOX, "Subject: a\r\n\r\n", &[]);
+        add_message(&s, INBOX, "Subject: b\r\n\r\n", &[]);
+        run(&mut s, "a CREATE INBOX/Sub").await;
+        run(&mut s, "a SELECT INBOX").await;
+
+        let out = run(&mut s, "a RENAME INBOX Old").await;
+        assert_eq!(
+            out,
+            "* 1 EXPUNGE\r\n* 1 EXPUNGE\r\na OK RENAME completed\r\n"
+        );
+        assert_eq!(mailboxes(&s), ["INBOX", "INBOX/Sub", "Old"]);
+        assert!(s
+            .store
+            .load_index("bob", INBOX)
+            .unwrap()
+            .messages
+            .is_empty());
+        let old = s.store.load_index("bob", "Old").unwrap();
+        assert_eq!(old.messages.len(), 2);
+        assert_eq!(s.selected().unwrap().name, INBOX);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_subscriptions() {
+        let root = mail_dir("subscribe");
+        let mut s = session(&root);
+        run(&mut s, "a SUBSCRIBE Work").await;
+        run(&mut s, "a SUBSCRIBE News").await;
+        run(&mut s, "a SUBSCRIBE Work").await;
+        assert_eq!(s.store.subscriptions("bob").unwrap(), ["Work", "News"]);
+
+        let out = run(&mut s, "a UNSUBSCRIBE Work").await;
+        assert_eq!(out, "a OK UNSUBSCRIBE completed\r\n");
+        assert!(run(&mut s, "a UNSUBSCRIBE Work").await.starts_with("a NO "));
+        assert_eq!(s.store.subscriptions("bob").unwrap(), ["News"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
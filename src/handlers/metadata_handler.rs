@@ -0,0 +1,427 @@
+use std::collections::BTreeMap;
+
+use super::quoted;
+use crate::error::{Refused, NOPERM};
+use crate::ext_command::Token;
+use crate::result::Result;
+use crate::session::Session;
+use crate::storage::{Metadata, SHARED_OWNER};
+
+/// How far below the named entries GETMETADATA looks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Depth {
+    Zero,
+    One,
+    Infinity,
+}
+
+/// Options of GETMETADATA.
+#[derive(Debug)]
+pub struct GetOptions {
+    /// Values longer than this many bytes are left out.
+    pub max_size: Option<usize>,
+
+    pub depth: Depth,
+}
+
+/// Parse an entry name, lowercased as entry names are compared without
+/// case. `None` unless it's a well formed name below `/private` or
+/// `/shared`, or one of those if `root` is allowed.
+pub fn parse_entry(name: &str, root: bool) -> Option<String> {
+    let name = name.to_ascii_lowercase();
+    if root && (name == "/private" || name == "/shared") {
+        return Some(name);
+    }
+    let rest = name
+        .strip_prefix("/private/")
+        .or_else(|| name.strip_prefix("/shared/"))?;
+    let valid = !rest.is_empty()
+        && !rest.ends_with('/')
+        && !rest.contains("//")
+        && !rest.contains(['*', '%'])
+        && rest.bytes().all(|c| c > 0x19 && c != 0x7f);
+    valid.then_some(name)
+}
+
+fn is_shared(entry: &str) -> bool {
+    entry == "/shared" || entry.starts_with("/shared/")
+}
+
+/// Parse `[(options)] mailbox entries` of GETMETADATA, `None` if invalid.
+pub fn parse_get(args: &[Token]) -> Option<(GetOptions, String, Vec<String>)> {
+    let (options, mailbox, entries) = match args {
+        [Token::List(options), mailbox, entries] => {
+            (&options[..], mailbox, entries)
+        }
+        [mailbox, entries] => (&[][..], mailbox, entries),
+        _ => return None,
+    };
+
+    let mut parsed = GetOptions {
+        max_size: None,
+        depth: Depth::Zero,
+    };
+    for option in options.chunks(2) {
+        let (name, value) = match option {
+            [name, value] => (name.as_astring()?, value.as_astring()?),
+            _ => return None,
+        };
+        match name.to_ascii_uppercase().as_str() {
+            "MAXSIZE" => parsed.max_size = Some(value.parse().ok()?),
+            "DEPTH" => {
+                parsed.depth = match value.to_ascii_lowercase().as_str() {
+                    "0" => Depth::Zero,
+                    "1" => Depth::One,
+                    "infinity" => Depth::Infinity,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        }
+    }
+
+    let entries = match entries {
+        Token::List(entries) if !entries.is_empty() => entries.to_vec(),
+        Token::List(_) => return None,
+        entry => vec![entry.clone()],
+    };
+    let entries = entries
+        .iter()
+        .map(|entry| parse_entry(&entry.as_astring()?, true))
+        .collect::<Option<Vec<_>>>()?;
+    Some((parsed, mailbox.as_astring()?, entries))
+}
+
+/// Entries SETMETADATA sets and their values, `None` removing the entry.
+pub type Changes = Vec<(String, Option<String>)>;
+
+/// Parse `mailbox (entry value ...)` of SETMETADATA, `None` if invalid or a
+/// value isn't UTF-8.
+pub fn parse_set(args: &[Token]) -> Option<(String, Changes)> {
+    let (mailbox, list) = match args {
+        [mailbox, Token::List(list)] if !list.is_empty() => (mailbox, list),
+        _ => return None,
+    };
+    let mut entries = vec![];
+    for pair in list.chunks(2) {
+        let (entry, value) = match pair {
+            [entry, value] => {
+                (parse_entry(&entry.as_astring()?, false)?, value)
+            }
+            _ => return None,
+        };
+        let value = match value {
+            nil if nil.is_atom("NIL") => None,
+            Token::String(bytes) => {
+                Some(String::from_utf8(bytes.clone()).ok()?)
+            }
+            Token::Atom(atom) => Some(atom.clone()),
+            Token::List(_) => return None,
+        };
+        entries.push((entry, value));
+    }
+    Some((mailbox.as_astring()?, entries))
+}
+
+/// Where `entry` of mailbox `name`, the server if `None`, is kept: the user
+/// keeping it and the mailbox it's filed under. Shared entries are kept by
+/// the owner of the mailbox, or of the shared namespace for the server,
+/// private ones by the user under the name they give the mailbox.
+fn location(
+    session: &Session,
+    name: Option<&str>,
+    entry: &str,
+) -> Result<(String, String)> {
+    match (name, is_shared(entry)) {
+        (None, true) => Ok((SHARED_OWNER.to_string(), String::new())),
+        (Some(name), true) => session.locate(name),
+        (name, false) => {
+            let user = session.user()?.to_string();
+            Ok((user, name.unwrap_or_default().to_string()))
+        }
+    }
+}
+
+/// Make sure the user may change `entry` of mailbox `name`, the server if
+/// `None`: any user their private entries, admins the shared ones of the
+/// server, and the shared ones of a mailbox those with the write right.
+pub fn check_write(
+    session: &Session,
+    name: Option<&str>,
+    entry: &str,
+) -> Result<()> {
+    match name {
+        None if is_shared(entry) && !session.is_admin() => {
+            Err(Refused(NOPERM).into())
+        }
+        None => Ok(()),
+        Some(name) => {
+            let needed = if is_shared(entry) { "lrw" } else { "lr" };
+            session.access(name, needed).map(|_| ())
+        }
+    }
+}
+
+/// Values of entries by name, `None` for an entry without one.
+pub type Values = BTreeMap<String, Option<String>>;
+
+/// Values of `entries` of mailbox `name`, the server if `None`, and those
+/// below as deep as `options` asks, `None` for a named entry without one
+/// unless looking below it.
+/// Also returns the size of the longest value left out for `MAXSIZE`.
+pub fn lookup(
+    session: &Session,
+    name: Option<&str>,
+    entries: &[String],
+    options: &GetOptions,
+) -> Result<(Values, Option<usize>)> {
+    let mut found = BTreeMap::new();
+    let mut longest = None;
+    for entry in entries {
+        let (keeper, mailbox) = location(session, name, entry)?;
+        let kept = session.store.metadata(&keeper)?.remove(&mailbox);
+        let kept = kept.unwrap_or_default();
+
+        let prefix = format!("{}/", entry);
+        let below = kept.iter().filter(|(key, _)| match options.depth {
+            Depth::Zero => false,
+            Depth::One => key
+                .strip_prefix(&prefix)
+                .is_some_and(|rest| !rest.contains('/')),
+            Depth::Infinity => key.starts_with(&prefix),
+        });
+        if options.depth == Depth::Zero {
+            found.entry(entry.clone()).or_insert(None);
+        }
+        for (key, value) in kept.get_key_value(entry).into_iter().chain(below) {
+            if options.max_size.is_some_and(|max| value.len() > max) {
+                longest = longest.max(Some(value.len()));
+                if key == entry {
+                    found.remove(entry);
+                }
+                continue;
+            }
+            found.insert(key.clone(), Some(value.clone()));
+        }
+    }
+    Ok((found, longest))
+}
+
+/// Set `entries` of mailbox `name`, the server if `None`. Nothing is set
+/// and `false` returned if a user would keep more entries there than
+/// allowed.
+pub fn store(
+    session: &Session,
+    name: Option<&str>,
+    entries: &[(String, Option<String>)],
+) -> Result<bool> {
+    // entries kept by the same user are set at once
+    let mut by_location: BTreeMap<(String, String), Changes> = BTreeMap::new();
+    for (entry, value) in entries {
+        let location = location(session, name, entry)?;
+        by_location
+            .entry(location)
+            .or_default()
+            .push((entry.clone(), value.clone()));
+    }
+
+    // counted under the lock of each user, what was set for the users
+    // before is undone if a later one has too many
+    let max_entries = session.config.metadata.max_entries;
+    let mut done: Vec<(String, String, Changes)> = vec![];
+    for ((keeper, mailbox), changes) in by_location {
+        let mut undo = vec![];
+        let mut fits = true;
+        session.store.update_metadata(&keeper, &mut |metadata| {
+            let mut kept = metadata.get(&mailbox).cloned().unwrap_or_default();
+            undo = apply(&mut kept, &changes);
+            fits = kept.len() <= max_entries;
+            if fits {
+                replace(metadata, &mailbox, kept);
+            }
+        })?;
+        if !fits {
+            for (keeper, mailbox, undo) in done {
+                session.store.update_metadata(&keeper, &mut |metadata| {
+                    let mut kept =
+                        metadata.get(&mailbox).cloned().unwrap_or_default();
+                    apply(&mut kept, &undo);
+                    replace(metadata, &mailbox, kept);
+                })?;
+            }
+            return Ok(false);
+        }
+        done.push((keeper, mailbox, undo));
+    }
+    Ok(true)
+}
+
+/// Apply `changes` to the entries `kept`, returning the changes undoing
+/// them.
+fn apply(kept: &mut BTreeMap<String, String>, changes: &Changes) -> Changes {
+    let mut undo = vec![];
+    for (entry, value) in changes {
+        let previous = match value {
+            Some(value) => kept.insert(entry.clone(), value.clone()),
+            None => kept.remove(entry),
+        };
+        undo.push((entry.clone(), previous));
+    }
+    undo.reverse();
+    undo
+}
+
+/// Keep `kept` as the entries of `mailbox`, leaving out a mailbox without
+/// any.
+fn replace(
+    metadata: &mut Metadata,
+    mailbox: &str,
+    kept: BTreeMap<String, String>,
+) {
+    match kept.is_empty() {
+        true => metadata.remove(mailbox),
+        false => metadata.insert(mailbox.to_string(), kept),
+    };
+}
+
+/// `METADATA` response of mailbox `name`, the server if `None`, with the
+/// `values` found.
+pub fn metadata_response(
+    session: &Session,
+    name: Option<&str>,
+    values: &Values,
+) -> String {
+    let mut list = vec![];
+    for (entry, value) in values {
+        let value = match value {
+            Some(value) => {
+                let mut encoded = vec![];
+                Token::String(value.clone().into_bytes()).encode(&mut encoded);
+                String::from_utf8_lossy(&encoded).into_owned()
+            }
+            None => String::from("NIL"),
+        };
+        list.push(format!("{} {}", entry, value));
+    }
+    let name = name
+        .map(|name| session.client_name(name))
+        .unwrap_or_default();
+    format!("METADATA {} ({})", quoted(&name), list.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::testing::{
+        configured_session, mail_dir, other_session, run, session,
+    };
+
+    #[tokio::test]
+    async fn gets_entries_as_deep_as_asked() {
+        let root = mail_dir("metadata");
+        let mut s = session(&root);
+        let out = run(
+            &mut s,
+            "a SETMETADATA INBOX (/private/comment \"hi\" \
+             /private/comment/a \"one\" /private/comment/a/b \"two\")",
+        )
+        .await;
+        assert_eq!(out, "a OK SETMETADATA completed\r\n");
+
+        let get = |args: &str| format!("a GETMETADATA {}", args);
+        let out = run(&mut s, &get("INBOX /private/comment")).await;
+        assert_eq!(
+            out,
+            "* METADATA \"INBOX\" (/private/comment \"hi\")\r\n\
+             a OK GETMETADATA completed\r\n"
+        );
+        let out = run(&mut s, &get("(DEPTH 1) INBOX /private/comment")).await;
+        assert!(out.starts_with(
+            "* METADATA \"INBOX\" \
+             (/private/comment \"hi\" /private/comment/a \"one\")\r\n"
+        ));
+        let out = run(&mut s, &get("(DEPTH infinity) INBOX /private")).await;
+        assert!(out.starts_with(
+            "* METADATA \"INBOX\" (/private/comment \"hi\" \
+             /private/comment/a \"one\" /private/comment/a/b \"two\")\r\n"
+        ));
+        let out = run(&mut s, &get("INBOX (/private/Comment /shared/x)")).await;
+        assert!(out.starts_with(
+            "* METADATA \"INBOX\" (/private/comment \"hi\" /shared/x NIL)\r\n"
+        ));
+
+        // values longer than MAXSIZE are left out and reported
+        let line = get("(MAXSIZE 2 DEPTH infinity) INBOX /private/comment");
+        let out = run(&mut s, &line).await;
+        assert_eq!(
+            out,
+            "* METADATA \"INBOX\" (/private/comment \"hi\")\r\n\
+             a OK [METADATA LONGENTRIES 3] GETMETADATA completed\r\n"
+        );
+
+        run(&mut s, "a SETMETADATA INBOX (/private/comment NIL)").await;
+        let out = run(&mut s, &get("(DEPTH 1) INBOX /private/comment")).await;
+        assert!(out.starts_with(
+            "* METADATA \"INBOX\" (/private/comment/a \"one\")\r\n"
+        ));
+        let out = run(&mut s, &get("Missing /private/comment")).await;
+        assert!(out.starts_with("a NO "));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn limits_entries_and_their_size() {
+        let root = mail_dir("metadata-limits");
+        let conf = "[metadata]\nmax_size = 4\nmax_entries = 2";
+        let mut s = configured_session(&root, conf);
+
+        let out =
+            run(&mut s, "a SETMETADATA INBOX (/private/a \"12345\")").await;
+        assert_eq!(out, "a NO [METADATA MAXSIZE 4] Value too long\r\n");
+        let out = run(
+            &mut s,
+            "a SETMETADATA INBOX (/private/a \"1\" /private/b \"2\" \
+             /private/c \"3\")",
+        )
+        .await;
+        assert_eq!(out, "a NO [METADATA TOOMANY] Too many entries\r\n");
+        assert!(s.store.metadata("bob").unwrap().is_empty());
+
+        // the limit is per mailbox, removals making room
+        let set = "a SETMETADATA INBOX (/private/a \"1\" /private/b \"2\")";
+        assert!(run(&mut s, set).await.starts_with("a OK "));
+        let set = "a SETMETADATA \"\" (/private/a \"1\" /private/b \"2\")";
+        assert!(run(&mut s, set).await.starts_with("a OK "));
+        let set = "a SETMETADATA INBOX (/private/a NIL /private/c \"3\")";
+        assert!(run(&mut s, set).await.starts_with("a OK "));
+
+        // shared server entries are for admins
+        let set = "a SETMETADATA \"\" (/shared/motd \"hi\")";
+        assert!(run(&mut s, set).await.starts_with("a NO "));
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn sets_nothing_when_one_user_has_too_many() {
+        let root = mail_dir("metadata-undo");
+        let conf = "[metadata]\nmax_entries = 1";
+        let mut bob = configured_session(&root, conf);
+        run(&mut bob, "a SETMETADATA INBOX (/shared/x \"1\")").await;
+        run(&mut bob, "a SETACL INBOX alice lrw").await;
+        let mut alice = other_session(&bob);
+        alice.user = Some("alice".to_string());
+
+        // alice keeps her private entry, bob the shared one
+        let out = run(
+            &mut alice,
+            "a SETMETADATA \"Other Users/bob/INBOX\" \
+             (/private/a \"1\" /shared/b \"2\")",
+        )
+        .await;
+        assert_eq!(out, "a NO [METADATA TOOMANY] Too many entries\r\n");
+        assert!(alice.store.metadata("alice").unwrap().is_empty());
+        assert_eq!(bob.store.metadata("bob").unwrap()["INBOX"].len(), 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,22 +1,56 @@
+use crate::acl;
+use crate::capability::capabilities;
+use crate::error::{Refused, NONEXISTENT, NOPERM, OVERQUOTA};
+use crate::ext_command::{ExtCommand, Token};
 use crate::imap_serv::*;
 use crate::result::Result;
+use crate::session::{SelectedMailbox, Session};
+use crate::storage::{MessageMeta, SYSTEM_FLAGS};
 
-use imap_codec::fetch::MacroOrMessageDataItemNames;
+use std::borrow::Cow;
+
+use anyhow::anyhow;
+
+use imap_codec::auth::{AuthMechanism, AuthenticateData};
+use imap_codec::codec::Encode;
+use imap_codec::flag::{Flag, StoreResponse, StoreType};
 
 use imap_codec::search::SearchKey;
 use imap_codec::sequence::{SeqOrUid, Sequence, SequenceSet};
+use imap_codec::status::StatusDataItemName;
 use imap_codec::{
-    command::Command,
+    codec::Decode,
+    command::{Command, CommandBody},
     core::*,
     mailbox::{ListMailbox, Mailbox},
     secret::Secret,
 };
 
-use log::debug;
+use log::{debug, info};
 
 use tokio::io::{AsyncRead, AsyncWrite};
 
+mod acl_handler;
+mod append_handler;
+mod copy_handler;
+mod expunge_handler;
 mod fetch_handler;
+mod list_handler;
+mod mailbox_handler;
+mod metadata_handler;
+mod quota_handler;
+mod search_handler;
+mod select_handler;
+mod sort_handler;
+mod status_handler;
+mod store_handler;
+mod thread_handler;
+mod update_handler;
+
+use list_handler::ListOptions;
+pub(crate) use search_handler::{expand_saved, number_set};
+use search_handler::{search_encoding, SUPPORTED_CHARSETS};
+use thread_handler::ThreadAlgorithm;
 
 macro_rules! command_handler {
     ($name:ident, $cmd:ident, ($imap_sock:ident, $cmd2:ident ) => $cmd_body:expr ) => {
@@ -51,21 +85,456 @@ macro_rules! command_handler {
     };
 }
 
+/// Handler of a command parsed by [`crate::ext_command`].
+macro_rules! ext_command_handler {
+    ($name:ident, ($imap_sock:ident, $ext:ident) => $cmd_body:expr ) => {
+        pub(crate) struct $name;
+        impl $name {
+            pub async fn handle<'b, 'c, IO>(
+                $imap_sock: &mut IMAPServ<'b, IO>,
+                $ext: &ExtCommand,
+            ) -> Result<CommandPipe<'c>>
+            where
+                IO: AsyncRead + AsyncWrite + Unpin,
+            {
+                $cmd_body
+            }
+        }
+    };
+
+    ($name:ident, ($imap_sock:ident, $ext:ident, [$( $arg:ident : $tpe:ty ),*]) => $cmd_body:expr ) => {
+        pub(crate) struct $name;
+        impl $name {
+            pub async fn handle<'b, 'c, IO>(
+                $imap_sock: &mut IMAPServ<'b, IO>,
+                $ext: &ExtCommand,
+                $( $arg : $tpe, )*
+            ) -> Result<CommandPipe<'c>>
+            where
+                IO: AsyncRead + AsyncWrite + Unpin,
+            {
+                $cmd_body
+            }
+        }
+    };
+}
+
+/// Pattern of a LIST or LSUB command, as the client wrote it.
+pub(crate) fn list_mailbox_str(mailbox: &ListMailbox<'_>) -> String {
+    match mailbox {
+        ListMailbox::Token(token) => String::from_utf8_lossy(token.as_ref()),
+        ListMailbox::String(string) => String::from_utf8_lossy(string.as_ref()),
+    }
+    .into_owned()
+}
+
+/// `value` as an IMAP quoted string.
+pub(crate) fn quoted(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Whether `value` is part of `set`, with `*` standing for `largest`.
+pub(crate) fn in_sequence_set(
+    set: &SequenceSet,
+    value: u32,
+    largest: u32,
+) -> bool {
+    let expand = |s: &SeqOrUid| match s {
+        SeqOrUid::Value(v) => v.get(),
+        SeqOrUid::Asterisk => largest,
+    };
+    set.0.as_ref().iter().any(|seq| match seq {
+        Sequence::Single(s) => expand(s) == value,
+        Sequence::Range(a, b) => {
+            let (a, b) = (expand(a), expand(b));
+            a.min(b) <= value && value <= a.max(b)
+        }
+    })
+}
+
+/// `(seq, uid)` of the selected mailbox's messages in `set`, which holds
+/// UIDs if `uid` is set.
+pub(crate) fn resolve_sequence_set(
+    selected: &SelectedMailbox,
+    set: &SequenceSet,
+    uid: bool,
+) -> Vec<(u32, u32)> {
+    if uid {
+        let largest = selected.uids.iter().copied().max().unwrap_or(0);
+        selected
+            .uids
+            .iter()
+            .enumerate()
+            .filter(|(_, u)| in_sequence_set(set, **u, largest))
+            .map(|(i, u)| (i as u32 + 1, *u))
+            .collect()
+    } else {
+        let largest = selected.uids.len() as u32;
+        selected
+            .uids
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| in_sequence_set(set, *i as u32 + 1, largest))
+            .map(|(i, u)| (i as u32 + 1, *u))
+            .collect()
+    }
+}
+
+/// UIDs in the compact form of a sequence set, like `4:6,9`, keeping their
+/// order.
+pub(crate) fn uid_set_string(uids: &[u32]) -> String {
+    let mut ranges: Vec<(u32, u32)> = vec![];
+    for uid in uids {
+        match ranges.last_mut() {
+            Some((_, last)) if *last + 1 == *uid => *last = *uid,
+            _ => ranges.push((*uid, *uid)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(first, last)| match first == last {
+            true => first.to_string(),
+            false => format!("{}:{}", first, last),
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+pub(crate) fn flag_name(flag: &Flag<'_>) -> String {
+    String::from_utf8_lossy(&flag.encode().dump()).into()
+}
+
+/// Parenthesized flag list of a message as sent in FETCH responses.
+pub(crate) fn flags_list(meta: &MessageMeta, recent: bool) -> String {
+    let mut flags = meta.flags.clone();
+    if recent {
+        flags.push("\\Recent".to_string());
+    }
+    format!("({})", flags.join(" "))
+}
+
+/// Longest line kept while waiting for the DONE of IDLE.
+const MAX_DONE_LINE: usize = 64;
+
+/// Longest response to an AUTHENTICATE challenge.
+const MAX_AUTH_LINE: usize = 8192;
+
+/// Flags clients may store permanently with `rights`: the system flags
+/// those cover and, with `w`, any keyword.
+fn permanent_flags(rights: &str) -> String {
+    let mut flags: Vec<&str> = SYSTEM_FLAGS
+        .iter()
+        .copied()
+        .filter(|flag| rights.contains(acl::flag_right(flag)))
+        .collect();
+    if rights.contains('w') {
+        flags.push("\\*");
+    }
+    format!("({})", flags.join(" "))
+}
+
 command_handler!(NoopHandler, Noop, (s, cmd) => {
-    s.status("23 EXISTS").await;
+    update_handler::send_pending(s).await?;
     s.ok_completed(&cmd.tag, "NOOP").await;
     Ok(CommandPipe::Next(cmd.clone(), None))
 });
 
+// messages and flags are on disk already, a checkpoint only reports changes
+command_handler!(CheckHandler, Check, (s, cmd) => {
+    update_handler::send_pending(s).await?;
+    s.ok_completed(&cmd.tag, "CHECK").await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+command_handler!(IdleHandler, Idle, (s, cmd) => {
+    s.write_str("+ idling\r\n").await?;
+    update_handler::send_pending(s).await?;
+    s.flush().await?;
+
+    // anything but DONE ends the IDLE as a bad command
+    let mut line = vec![];
+    loop {
+        match s.wait().await? {
+            Wakeup::Input(None) => return Ok(CommandPipe::Quit),
+            Wakeup::Input(Some(b'\n')) => break,
+            Wakeup::Input(Some(byte)) if line.len() < MAX_DONE_LINE => line.push(byte),
+            Wakeup::Input(Some(_)) => {}
+            Wakeup::Event(event) => {
+                update_handler::apply(s, event).await?;
+                s.flush().await?;
+            }
+        }
+    }
+
+    if String::from_utf8_lossy(&line).trim_end().eq_ignore_ascii_case("DONE") {
+        s.ok_completed(&cmd.tag, "IDLE").await;
+    } else {
+        s.bad_completed2(cmd.tag.as_ref(), "Expected DONE").await;
+    }
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+/// Extensions ENABLE can turn on.
+const ENABLE_EXTENSIONS: [&str; 3] = ["CONDSTORE", "IMAP4rev2", "QRESYNC"];
+
+ext_command_handler!(EnableHandler, (s, ext) => {
+    s.session.user()?;
+    let names = ext.args.iter().map(Token::as_astring).collect::<Option<Vec<_>>>();
+    let names = match names.filter(|names| !names.is_empty()) {
+        Some(names) => names,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid ENABLE arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    // only the extensions this command turned on are listed, unknown ones
+    // are ignored
+    let mut enabled = String::from("ENABLED");
+    for name in names {
+        let known = ENABLE_EXTENSIONS.iter().find(|e| e.eq_ignore_ascii_case(&name));
+        if let Some(extension) = known {
+            if s.session.enabled.insert(extension) {
+                enabled.push_str(&format!(" {}", extension));
+            }
+        }
+    }
+    // QRESYNC implies CONDSTORE
+    if s.session.qresync() {
+        s.session.enable_condstore();
+    }
+    // IMAP4rev2 has no \Recent
+    if s.session.imap4rev2() {
+        if let Some(selected) = s.session.selected.as_mut() {
+            selected.recent.clear();
+        }
+    }
+
+    s.status(&enabled).await;
+    s.ok_completed2(&ext.tag, "ENABLE").await;
+    Ok(CommandPipe::Noop)
+});
+
+/// Parse the `(field value ...)` or `NIL` parameters of ID, `None` if
+/// invalid. Values may be NIL.
+fn id_params(args: &[Token]) -> Option<Vec<(String, Option<String>)>> {
+    let items = match args {
+        [nil] if nil.is_atom("NIL") => return Some(vec![]),
+        [Token::List(items)] if items.len() % 2 == 0 => items,
+        _ => return None,
+    };
+    items
+        .chunks(2)
+        .map(|pair| {
+            let value = match &pair[1] {
+                nil if nil.is_atom("NIL") => None,
+                value => Some(value.as_astring()?),
+            };
+            Some((pair[0].as_astring()?, value))
+        })
+        .collect()
+}
+
+ext_command_handler!(IdHandler, (s, ext) => {
+    let params = match id_params(&ext.args) {
+        Some(params) => params,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid ID arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+    if params.is_empty() {
+        info!("session {} client ID: NIL", s.session.id);
+    } else {
+        let client: Vec<String> = params
+            .iter()
+            .map(|(field, value)| format!("{}={}", field, value.as_deref().unwrap_or("NIL")))
+            .collect();
+        info!("session {} client ID: {}", s.session.id, client.join(", "));
+    }
+
+    let server_id = &s.session.config.server_id;
+    let resp = if server_id.is_empty() {
+        String::from("ID NIL")
+    } else {
+        let fields: Vec<String> = server_id
+            .iter()
+            .map(|(field, value)| format!("{} {}", quoted(field), quoted(value)))
+            .collect();
+        format!("ID ({})", fields.join(" "))
+    };
+    s.status(&resp).await;
+    s.ok_completed2(&ext.tag, "ID").await;
+    Ok(CommandPipe::Noop)
+});
+
+ext_command_handler!(NamespaceHandler, (s, ext) => {
+    s.session.user()?;
+    let config = &s.session.config;
+    let delimiter = quoted(&config.delimiter.to_string());
+    let namespace = |prefix: &Option<String>| match prefix {
+        Some(prefix) => format!("(({} {}))", quoted(prefix), delimiter),
+        None => String::from("NIL"),
+    };
+    let resp = format!(
+        "NAMESPACE {} {} {}",
+        namespace(&config.namespaces.personal),
+        namespace(&config.namespaces.other_users),
+        namespace(&config.namespaces.shared)
+    );
+    s.status(&resp).await;
+    s.ok_completed2(&ext.tag, "NAMESPACE").await;
+    Ok(CommandPipe::Noop)
+});
+
 command_handler!(CapabilityHandler, Capability, (s, cmd) => {
-    s.status("CAPABILITY IMAP4rev1 STARTTLS").await;
+    s.status(&format!("CAPABILITY {}", capabilities(s.session))).await;
     s.ok_completed(&cmd.tag, cmd.name()).await;
     Ok(CommandPipe::Next(cmd.clone(), None))
 });
 
+command_handler!(StartTlsHandler, StartTLS, (s, cmd) => {
+    if s.session.tls {
+        s.bad_completed2(cmd.tag.as_ref(), "TLS is already active").await;
+        return Ok(CommandPipe::Next(cmd.clone(), None));
+    }
+    s.ok(cmd.tag.as_ref(), "Begin TLS negotiation now").await?;
+    s.flush().await?;
+    Ok(CommandPipe::StartTls)
+});
+
+ext_command_handler!(CompressHandler, (s, ext) => {
+    s.session.user()?;
+    if !matches!(ext.args.as_slice(), [algorithm] if algorithm.is_atom("DEFLATE")) {
+        s.bad_completed2(&ext.tag, "Unsupported compression algorithm").await;
+        return Ok(CommandPipe::Noop);
+    }
+    if s.session.compressed {
+        s.no_completed2(&ext.tag, "[COMPRESSIONACTIVE] DEFLATE active via COMPRESS").await;
+        return Ok(CommandPipe::Noop);
+    }
+    s.ok(&ext.tag, "DEFLATE active").await?;
+    s.session.compressed = true;
+    Ok(CommandPipe::Compress)
+});
+
 command_handler!(LoginHandler, Login, (s, cmd, [ username: AString<'_>, password: Secret<AString<'_>> ]) => {
     debug!("username: {:?}, password: {:?}", username, password);
-    s.ok_completed(&cmd.tag, "LOGIN").await;
+    if !s.session.tls {
+        s.no_completed(&cmd.tag, "[PRIVACYREQUIRED] LOGIN needs STARTTLS first").await;
+        return Ok(CommandPipe::Next(cmd.clone(), None));
+    }
+    let user = String::from_utf8_lossy(username.as_ref()).into_owned();
+    if !verify_password(s.session, &user, password.declassify().as_ref()).await? {
+        s.no_completed(&cmd.tag, "[AUTHENTICATIONFAILED] Invalid credentials").await;
+        return Ok(CommandPipe::Next(cmd.clone(), None));
+    }
+    s.session.user = Some(user);
+    if let Err(e) = mailbox_handler::create_default_mailboxes(s.session) {
+        eprintln!("Failed to create default mailboxes; err = {:?}", e);
+    }
+    let msg = format!("[CAPABILITY {}] LOGIN completed", capabilities(s.session));
+    s.ok(cmd.tag.as_ref(), &msg).await?;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+/// Whether `password` is the one of `user`, checked off the session's task
+/// as hashing passwords is slow on purpose.
+async fn verify_password(
+    session: &Session,
+    user: &str,
+    password: &[u8],
+) -> Result<bool> {
+    let auth = session.auth.clone();
+    let (user, password) = (user.to_string(), password.to_vec());
+    tokio::task::spawn_blocking(move || auth.verify(&user, &password))
+        .await
+        .map_err(|e| anyhow!("Password check failed: {}", e))?
+}
+
+/// Fields of a SASL PLAIN response (RFC 4616): the user to act as, empty
+/// for the one authenticating, the user authenticating, and its password.
+/// `None` if invalid.
+fn plain_credentials(response: &[u8]) -> Option<(String, String, Vec<u8>)> {
+    let mut fields = response.split(|c| *c == 0);
+    let (authzid, authcid, passwd) =
+        (fields.next()?, fields.next()?, fields.next()?);
+    if fields.next().is_some() || authcid.is_empty() {
+        return None;
+    }
+    let authzid = std::str::from_utf8(authzid).ok()?.to_string();
+    let authcid = std::str::from_utf8(authcid).ok()?.to_string();
+    Some((authzid, authcid, passwd.to_vec()))
+}
+
+/// User a SASL PLAIN response authenticates, `None` if its credentials are
+/// wrong or it asks to act as another user without being an admin.
+async fn plain_user(
+    session: &Session,
+    response: &[u8],
+) -> Result<Option<String>> {
+    let (authzid, authcid, passwd) = match plain_credentials(response) {
+        Some(credentials) => credentials,
+        None => return Ok(None),
+    };
+    if !verify_password(session, &authcid, &passwd).await? {
+        return Ok(None);
+    }
+    if authzid.is_empty() || authzid == authcid {
+        return Ok(Some(authcid));
+    }
+    let admin = session.config.admins.contains(&authcid);
+    Ok((admin && !authzid.starts_with('.')).then_some(authzid))
+}
+
+command_handler!(AuthenticateHandler, Authenticate, (s, cmd,
+    [mechanism: AuthMechanism<'_>, initial_response: Option<Secret<Cow<'_, [u8]>>>]) =>
+{
+    if !s.session.tls {
+        s.no_completed(&cmd.tag, "[PRIVACYREQUIRED] AUTHENTICATE needs STARTTLS first").await;
+        return Ok(CommandPipe::Next(cmd.clone(), None));
+    }
+    if mechanism != AuthMechanism::PLAIN {
+        s.no_completed(&cmd.tag, "Unsupported authentication mechanism").await;
+        return Ok(CommandPipe::Next(cmd.clone(), None));
+    }
+
+    let response = match initial_response {
+        Some(response) => response.declassify().to_vec(),
+        None => {
+            s.write_str("+ \r\n").await?;
+            s.flush().await?;
+            let line = match s.read_line(MAX_AUTH_LINE).await? {
+                Some(line) => line,
+                None => return Ok(CommandPipe::Quit),
+            };
+            match AuthenticateData::decode(&line) {
+                Ok((_, AuthenticateData(data))) => data.declassify().to_vec(),
+                Err(_) if line.starts_with(b"*") => {
+                    s.bad_completed2(cmd.tag.as_ref(), "AUTHENTICATE cancelled").await;
+                    return Ok(CommandPipe::Next(cmd.clone(), None));
+                }
+                Err(_) => {
+                    s.bad_completed2(cmd.tag.as_ref(), "Invalid AUTHENTICATE response").await;
+                    return Ok(CommandPipe::Next(cmd.clone(), None));
+                }
+            }
+        }
+    };
+
+    match plain_user(s.session, &response).await? {
+        Some(user) => {
+            s.session.user = Some(user);
+            if let Err(e) = mailbox_handler::create_default_mailboxes(s.session) {
+                eprintln!("Failed to create default mailboxes; err = {:?}", e);
+            }
+            let msg = format!("[CAPABILITY {}] AUTHENTICATE completed", capabilities(s.session));
+            s.ok(cmd.tag.as_ref(), &msg).await?;
+        }
+        None => {
+            s.no_completed(&cmd.tag, "[AUTHENTICATIONFAILED] Invalid credentials").await;
+        }
+    }
     Ok(CommandPipe::Next(cmd.clone(), None))
 });
 
@@ -77,69 +546,928 @@ command_handler!(ListHandler, List, (s, cmd,
         reference, mailbox_wildcard
     );
 
-    s.status(r###"LIST (\Noselect) "/" ~/Mail/foo"###)
-        .await;
-    s.status(r###"LIST () "/" ~/Mail/meetings"###)
-        .await;
+    let pattern = list_handler::list_pattern(
+        &s.session.mailbox_name(&reference),
+        &s.session.storage_name(&list_mailbox_str(&mailbox_wildcard)),
+    );
+    list_handler::list(s, &[pattern], &ListOptions::default()).await?;
     s.ok_completed(&cmd.tag, "LIST").await;
 
     Ok(CommandPipe::Next(cmd.clone(), None))
 });
 
+ext_command_handler!(ExtendedListHandler, (s, ext) => {
+    let (options, reference, patterns) = match list_handler::parse_extended(&ext.args) {
+        Some(parsed) => parsed,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid LIST arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+    debug!("options: {:?}, reference: {:?}, patterns: {:?}", options, reference, patterns);
+
+    let reference = s.session.storage_name(&reference);
+    let patterns: Vec<String> = patterns
+        .iter()
+        .map(|p| list_handler::list_pattern(&reference, &s.session.storage_name(p)))
+        .collect();
+    list_handler::list(s, &patterns, &options).await?;
+    s.ok_completed2(&ext.tag, "LIST").await;
+    Ok(CommandPipe::Noop)
+});
+
 command_handler!(SelectHandler, Select, (s, cmd, [ mailbox: Mailbox<'_> ]) => {
     debug!("mailbox: {:?}", mailbox);
-    s.status("23 EXISTS").await;
-    s.status("23 RECENT").await;
-    s.status("OK [UNSEEN 12] Message 12 is first unseen")
-        .await;
-    s.status("OK [UIDVALIDITY 3857529045] UIDs valid")
-        .await;
-    s.status("OK [UIDNEXT 4392] Predicted next UID")
-        .await;
-    s.status("OK [PERMANENTFLAGS (\\Answered \\Flagged \\Deleted \\Seen \\Draft)] Limited").await;
-    s.ok_completed2(cmd.tag.as_ref(), "[READ-WRITE] SELECT")
-        .await;
+    let name = s.session.mailbox_name(&mailbox);
+    let access = match select_handler::select(s, name, false, None).await? {
+        Some(true) => "[READ-ONLY] SELECT",
+        Some(false) => "[READ-WRITE] SELECT",
+        None => {
+            s.no_completed(&cmd.tag, NONEXISTENT).await;
+            return Ok(CommandPipe::Next(cmd.clone(), None));
+        }
+    };
+    s.ok_completed2(cmd.tag.as_ref(), access).await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+command_handler!(ExamineHandler, Examine, (s, cmd, [ mailbox: Mailbox<'_> ]) => {
+    debug!("mailbox: {:?}", mailbox);
+    let name = s.session.mailbox_name(&mailbox);
+    if select_handler::select(s, name, true, None).await?.is_none() {
+        s.no_completed(&cmd.tag, NONEXISTENT).await;
+        return Ok(CommandPipe::Next(cmd.clone(), None));
+    }
+    s.ok_completed2(cmd.tag.as_ref(), "[READ-ONLY] EXAMINE").await;
     Ok(CommandPipe::Next(cmd.clone(), None))
 });
 
+// SELECT and EXAMINE with the CONDSTORE or QRESYNC parameters of RFC 7162.
+ext_command_handler!(ExtendedSelectHandler, (s, ext) => {
+    let args = match select_handler::parse_args(&ext.args) {
+        Some(args) => args,
+        None => {
+            let msg = format!("Invalid {} arguments", ext.name);
+            s.bad_completed2(&ext.tag, &msg).await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+    if args.qresync.is_some() && !s.session.qresync() {
+        s.bad_completed2(&ext.tag, "QRESYNC is not enabled").await;
+        return Ok(CommandPipe::Noop);
+    }
+    if args.condstore {
+        s.session.enable_condstore();
+    }
+
+    let name = s.session.storage_name(&args.mailbox);
+    let examine = ext.name == "EXAMINE";
+    let read_only = match select_handler::select(s, name, examine, args.qresync.as_ref()).await? {
+        Some(read_only) => read_only,
+        None => {
+            s.no_completed2(&ext.tag, NONEXISTENT).await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+    let access = if read_only { "[READ-ONLY]" } else { "[READ-WRITE]" };
+    s.ok_completed2(&ext.tag, &format!("{} {}", access, ext.name)).await;
+    Ok(CommandPipe::Noop)
+});
+
 command_handler!(SearchHandler, Search, (s, cmd,
     [ charset: Option<Charset<'_>>,  criteria: SearchKey<'_>, uid:bool ]) => {
     debug!(
         "charset: {:?}, criteria: {:?}, uid: {:?}",
         charset, criteria, uid
     );
-    s.status("SEARCH 3 5").await;
+
+    let encoding = match search_encoding(charset.as_ref()) {
+        Some(encoding) => encoding,
+        None => {
+            let msg = format!("[BADCHARSET ({})] Unsupported charset", SUPPORTED_CHARSETS);
+            s.no_completed(&cmd.tag, &msg).await;
+            return Ok(CommandPipe::Next(cmd.clone(), None));
+        }
+    };
+
+    let resp = search_handler::search_response(s.session, cmd.tag.as_ref(), encoding, &criteria, uid, false, None)?;
+    if let Some(resp) = resp {
+        s.status(&resp).await;
+    }
     s.ok_completed(&cmd.tag, "SEARCH").await;
     Ok(CommandPipe::Next(cmd.clone(), None))
 });
 
+// SEARCH with the return options of RFC 4731, RFC 5182 and RFC 9394, or the
+// MODSEQ key of RFC 7162, evaluated as the equivalent UID set.
+ext_command_handler!(ExtendedSearchHandler, (s, ext) => {
+    let uid = ext.name == "UID SEARCH";
+    let (options, args) = match search_handler::parse_return(&ext.args) {
+        Some(parsed) => parsed,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid SEARCH return options").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+    // a failed search saves an empty result
+    if options.as_ref().is_some_and(|o| o.save) {
+        search_handler::save_result(s.session, &[], uid)?;
+    }
+
+    let messages = s.session.selected_messages()?;
+    let (args, modseq) = match search_handler::rewrite_modseq(args, &messages) {
+        Some((args, modseq)) if modseq || options.is_some() => (args, modseq),
+        _ => {
+            s.bad_completed2(&ext.tag, "Invalid SEARCH arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+    if modseq {
+        s.session.enable_condstore();
+    }
+
+    let line = search_handler::search_line(&ext.tag, &args);
+    let (charset, criteria) = match Command::decode(&line) {
+        Ok((_, Command { body: CommandBody::Search { charset, criteria, .. }, .. })) => {
+            (charset, criteria)
+        }
+        _ => {
+            s.bad_completed2(&ext.tag, "Invalid SEARCH arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    let encoding = match search_encoding(charset.as_ref()) {
+        Some(encoding) => encoding,
+        None => {
+            let msg = format!("[BADCHARSET ({})] Unsupported charset", SUPPORTED_CHARSETS);
+            s.no_completed2(&ext.tag, &msg).await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    let resp = search_handler::search_response(s.session, &ext.tag, encoding, &criteria, uid, modseq, options.as_ref())?;
+    if let Some(resp) = resp {
+        s.status(&resp).await;
+    }
+    s.ok_completed2(&ext.tag, &ext.name).await;
+    Ok(CommandPipe::Noop)
+});
+
+// SORT of RFC 5256 with the DISPLAYFROM and DISPLAYTO keys of RFC 5957,
+// over the messages SEARCH would find.
+ext_command_handler!(SortHandler, (s, ext) => {
+    let uid = ext.name == "UID SORT";
+    let criteria = match ext.args.first().and_then(sort_handler::parse_criteria) {
+        Some(criteria) => criteria,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid sort criteria").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+    let found = match sort_handler::search(s, ext, &ext.args[1..], uid).await? {
+        Some(found) => found,
+        None => return Ok(CommandPipe::Noop),
+    };
+
+    let messages = sort_handler::load(s.session, &found, uid)?;
+    let mut resp = String::from("SORT");
+    for n in sort_handler::sort(messages, &criteria) {
+        resp.push_str(&format!(" {}", n));
+    }
+    s.status(&resp).await;
+    s.ok_completed2(&ext.tag, &ext.name).await;
+    Ok(CommandPipe::Noop)
+});
+
+// THREAD of RFC 5256 by the ORDEREDSUBJECT and REFERENCES algorithms.
+ext_command_handler!(ThreadHandler, (s, ext) => {
+    let uid = ext.name == "UID THREAD";
+    let algorithm = match ext.args.first() {
+        Some(Token::Atom(name)) => ThreadAlgorithm::parse(name),
+        _ => None,
+    };
+    let algorithm = match algorithm {
+        Some(algorithm) => algorithm,
+        None => {
+            s.bad_completed2(&ext.tag, "Unsupported threading algorithm").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+    let found = match sort_handler::search(s, ext, &ext.args[1..], uid).await? {
+        Some(found) => found,
+        None => return Ok(CommandPipe::Noop),
+    };
+
+    let messages = sort_handler::load(s.session, &found, uid)?;
+    let threads = thread_handler::threads(&messages, algorithm);
+    match threads.is_empty() {
+        true => s.status("THREAD").await,
+        false => s.status(&format!("THREAD {}", threads)).await,
+    }
+    s.ok_completed2(&ext.tag, &ext.name).await;
+    Ok(CommandPipe::Noop)
+});
+
 command_handler!(LogoutHandler, Logout, (s, cmd) => {
     s.status("BYE IMAP4rev1 Server logging out").await;
     s.ok_completed(&cmd.tag, "LOGOUT").await;
     Ok(CommandPipe::Next(cmd.clone(), None))
 });
 
-command_handler!(FetchHandler, Fetch, (s, cmd,
-    [sequence_set: SequenceSet, macro_or_item_names: MacroOrMessageDataItemNames<'_>, uid:bool] ) =>
-{
-    debug!("macro_or_item_names: {:?}", macro_or_item_names);
-    match sequence_set.0.as_ref()[0] {
-        Sequence::Single(seq_or_uid) => {
-            debug!("seq_or_uid: {:?}", seq_or_uid);
-            match seq_or_uid {
-                SeqOrUid::Value(seq) => {
-                    fetch_handler::handle_seq_value(s, seq.get(), macro_or_item_names).await?;
-                }
-                SeqOrUid::Asterisk => {
-                    debug!("uid: {:?}", uid);
-                }
-            }
+// FETCH, with the CHANGEDSINCE modifier of RFC 7162 or without.
+ext_command_handler!(FetchHandler, (s, ext) => {
+    let fetch = match fetch_handler::parse_fetch(&ext.args) {
+        Some(fetch) => fetch,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid FETCH arguments").await;
+            return Ok(CommandPipe::Noop);
         }
-        Sequence::Range(a, b) => {
-            debug!("seq ({:?}-{:?}):", a, b);
+    };
+    let uid = ext.name == "UID FETCH";
+    if fetch.vanished && !(uid && s.session.qresync()) {
+        s.bad_completed2(&ext.tag, "VANISHED needs UID FETCH and QRESYNC").await;
+        return Ok(CommandPipe::Noop);
+    }
+    if fetch.changed_since.is_some() || fetch.items.contains(&fetch_handler::FetchItem::Modseq) {
+        s.session.enable_condstore();
+    }
+
+    fetch_handler::fetch(s, &fetch, uid).await?;
+    s.ok_completed2(&ext.tag, &ext.name).await;
+    Ok(CommandPipe::Noop)
+});
+
+command_handler!(StoreHandler, Store, (s, cmd,
+    [sequence_set: SequenceSet, kind: StoreType, response: StoreResponse, flags: Vec<Flag<'_>>, uid: bool] ) =>
+{
+    let mut names = vec![];
+    for flag in flags.iter() {
+        if let Flag::Extension(_) = flag {
+            // \Recent and unknown system flags aren't in PERMANENTFLAGS
+            let msg = format!("{} can't be stored", flag_name(flag));
+            s.no_completed(&cmd.tag, &msg).await;
+            return Ok(CommandPipe::Next(cmd.clone(), None));
         }
+        names.push(flag_name(flag));
     }
 
+    store_handler::handle_store(s, &sequence_set, kind, response, &names, uid, None).await?;
+
     s.ok_completed(&cmd.tag, cmd.name()).await;
     Ok(CommandPipe::Next(cmd.clone(), None))
 });
+
+// STORE with the UNCHANGEDSINCE modifier of RFC 7162.
+ext_command_handler!(ExtendedStoreHandler, (s, ext) => {
+    let args = match store_handler::parse_args(&ext.args) {
+        Some(args) => args,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid STORE arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+    if args.unchanged_since.is_some() {
+        s.session.enable_condstore();
+    }
+
+    let uid = ext.name == "UID STORE";
+    let modified = store_handler::handle_store(
+        s,
+        &args.sequence_set,
+        args.kind,
+        args.response,
+        &args.flags,
+        uid,
+        args.unchanged_since,
+    )
+    .await?;
+
+    if modified.is_empty() {
+        s.ok_completed2(&ext.tag, &ext.name).await;
+    } else {
+        let msg = format!("[MODIFIED {}] Conditional {} failed", uid_set_string(&modified), ext.name);
+        s.ok(&ext.tag, &msg).await?;
+    }
+    Ok(CommandPipe::Noop)
+});
+
+command_handler!(ExpungeHandler, Expunge, (s, cmd) => {
+    expunge_handler::expunge(s, None, false).await?;
+    s.ok_completed(&cmd.tag, "EXPUNGE").await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+command_handler!(CloseHandler, Close, (s, cmd) => {
+    // a read-only mailbox, or one the user may not expunge, is closed
+    // without expunging
+    let selected = s.session.selected()?;
+    if !selected.read_only && selected.rights.contains('e') {
+        expunge_handler::expunge(s, None, true).await?;
+    }
+    s.session.selected = None;
+    s.ok_completed(&cmd.tag, "CLOSE").await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+command_handler!(UnselectHandler, Unselect, (s, cmd) => {
+    s.session.selected()?;
+    s.session.selected = None;
+    s.ok_completed(&cmd.tag, "UNSELECT").await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+command_handler!(CopyHandler, Copy, (s, cmd,
+    [sequence_set: SequenceSet, mailbox: Mailbox<'_>, uid: bool] ) =>
+{
+    let dest = s.session.mailbox_name(&mailbox);
+    let (owner, dest_mailbox) = s.session.access(&dest, "i")?;
+    if !s.session.store.has_mailbox(&owner, &dest_mailbox)? {
+        s.no_completed(&cmd.tag, "[TRYCREATE] Mailbox doesn't exist").await;
+        return Ok(CommandPipe::Next(cmd.clone(), None));
+    }
+    let (size, count) = copy_handler::messages_size(s.session, &sequence_set, uid)?;
+    if !quota_handler::fits(s.session, &owner, size, count)? {
+        s.no_completed(&cmd.tag, OVERQUOTA).await;
+        return Ok(CommandPipe::Next(cmd.clone(), None));
+    }
+
+    let copied = copy_handler::copy(s, &sequence_set, &dest, uid, false).await?;
+    quota_handler::warn_near_limits(s.session, &owner, size, count);
+
+    let report = s.session.reports_uids(&dest)?;
+    match copied.copyuid().filter(|_| report) {
+        Some(copyuid) => {
+            let msg = format!("[{}] COPY completed", copyuid);
+            s.ok(cmd.tag.as_ref(), &msg).await?;
+        }
+        None => s.ok_completed(&cmd.tag, "COPY").await,
+    }
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+command_handler!(MoveHandler, Move, (s, cmd,
+    [sequence_set: SequenceSet, mailbox: Mailbox<'_>, uid: bool] ) =>
+{
+    // the moved messages are flagged and expunged from the source
+    if !s.session.writable_selected()?.rights.contains('t')
+        || !s.session.selected()?.rights.contains('e')
+    {
+        return Err(Refused(NOPERM).into());
+    }
+    let dest = s.session.mailbox_name(&mailbox);
+    let (owner, dest_mailbox) = s.session.access(&dest, "i")?;
+    if !s.session.store.has_mailbox(&owner, &dest_mailbox)? {
+        s.no_completed(&cmd.tag, "[TRYCREATE] Mailbox doesn't exist").await;
+        return Ok(CommandPipe::Next(cmd.clone(), None));
+    }
+
+    // messages staying under the same quota root can't exceed it
+    let (source_owner, _) = s.session.selected_location()?;
+    let (size, count) = copy_handler::messages_size(s.session, &sequence_set, uid)?;
+    if owner != source_owner && !quota_handler::fits(s.session, &owner, size, count)? {
+        s.no_completed(&cmd.tag, OVERQUOTA).await;
+        return Ok(CommandPipe::Next(cmd.clone(), None));
+    }
+    let copied = copy_handler::copy(s, &sequence_set, &dest, uid, true).await?;
+
+    // RFC 6851: COPYUID comes untagged, before the expunges
+    let report = s.session.reports_uids(&dest)?;
+    if let Some(copyuid) = copied.copyuid().filter(|_| report) {
+        s.status(&format!("OK [{}] Moved", copyuid)).await;
+    }
+    let moved: Vec<u32> = copied.uids.iter().map(|(from, _)| *from).collect();
+    expunge_handler::remove_messages(s, &moved, false).await?;
+
+    s.ok_completed(&cmd.tag, "MOVE").await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+command_handler!(CreateHandler, Create, (s, cmd, [mailbox: Mailbox<'_>]) => {
+    let name = s.session.mailbox_name(&mailbox);
+    mailbox_handler::create(s, &name, &[]).await?;
+    s.ok_completed(&cmd.tag, "CREATE").await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+// CREATE with the USE parameter of RFC 6154, giving the new mailbox
+// special-use attributes.
+ext_command_handler!(ExtendedCreateHandler, (s, ext) => {
+    let parsed = match ext.args.as_slice() {
+        [mailbox, Token::List(params)] => mailbox
+            .as_astring()
+            .zip(mailbox_handler::parse_use(params)),
+        _ => None,
+    };
+    let (name, uses) = match parsed {
+        Some((name, Ok(uses))) => (s.session.storage_name(&name), uses),
+        Some((_, Err(attribute))) => {
+            let msg = format!("[USEATTR] Unsupported special-use attribute {}", attribute);
+            s.no_completed2(&ext.tag, &msg).await;
+            return Ok(CommandPipe::Noop);
+        }
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid CREATE arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+    mailbox_handler::create(s, &name, &uses).await?;
+    s.ok_completed2(&ext.tag, "CREATE").await;
+    Ok(CommandPipe::Noop)
+});
+
+command_handler!(DeleteHandler, Delete, (s, cmd, [mailbox: Mailbox<'_>]) => {
+    let name = s.session.mailbox_name(&mailbox);
+    mailbox_handler::delete(s, &name).await?;
+    s.ok_completed(&cmd.tag, "DELETE").await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+command_handler!(RenameHandler, Rename, (s, cmd,
+    [from: Mailbox<'_>, to: Mailbox<'_>] ) =>
+{
+    let (from, to) = (s.session.mailbox_name(&from), s.session.mailbox_name(&to));
+    mailbox_handler::rename(s, &from, &to).await?;
+    s.ok_completed(&cmd.tag, "RENAME").await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+command_handler!(SubscribeHandler, Subscribe, (s, cmd, [mailbox: Mailbox<'_>]) => {
+    let name = s.session.mailbox_name(&mailbox);
+    s.session.store.update_subscriptions(s.session.user()?, &mut |subscriptions| {
+        if !subscriptions.contains(&name) {
+            subscriptions.push(name.clone());
+        }
+    })?;
+    s.ok_completed(&cmd.tag, "SUBSCRIBE").await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+command_handler!(UnsubscribeHandler, Unsubscribe, (s, cmd, [mailbox: Mailbox<'_>]) => {
+    let name = s.session.mailbox_name(&mailbox);
+    let user = s.session.user()?.to_owned();
+    if !s.session.store.subscriptions(&user)?.contains(&name) {
+        s.no_completed(&cmd.tag, "Not subscribed to that mailbox").await;
+        return Ok(CommandPipe::Next(cmd.clone(), None));
+    }
+    s.session.store.update_subscriptions(&user, &mut |subscriptions| {
+        subscriptions.retain(|n| *n != name)
+    })?;
+    s.ok_completed(&cmd.tag, "UNSUBSCRIBE").await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+command_handler!(LsubHandler, Lsub, (s, cmd,
+    [reference: Mailbox<'_>, mailbox_wildcard: ListMailbox<'_>] ) =>
+{
+    let pattern = list_handler::list_pattern(
+        &s.session.mailbox_name(&reference),
+        &s.session.storage_name(&list_mailbox_str(&mailbox_wildcard)),
+    );
+    list_handler::lsub(s, &pattern).await?;
+    s.ok_completed(&cmd.tag, "LSUB").await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+command_handler!(StatusHandler, Status, (s, cmd,
+    [mailbox: Mailbox<'_>, item_names: Vec<StatusDataItemName>] ) =>
+{
+    let name = s.session.mailbox_name(&mailbox);
+    let items: Vec<String> = item_names
+        .iter()
+        .map(|item| String::from_utf8_lossy(&item.encode().dump()).into_owned())
+        .collect();
+    let (owner, mailbox) = s.session.access(&name, "r")?;
+    if !s.session.store.has_mailbox(&owner, &mailbox)? {
+        s.no_completed(&cmd.tag, NONEXISTENT).await;
+        return Ok(CommandPipe::Next(cmd.clone(), None));
+    }
+    status_handler::send_status(s, &name, &items).await?;
+    s.ok_completed(&cmd.tag, "STATUS").await;
+    Ok(CommandPipe::Next(cmd.clone(), None))
+});
+
+// STATUS asking for items imap-codec doesn't know, like SIZE.
+ext_command_handler!(ExtendedStatusHandler, (s, ext) => {
+    let (name, items) = match status_handler::parse_args(&ext.args) {
+        Some(parsed) => parsed,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid STATUS arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    let name = s.session.storage_name(&name);
+    let (owner, mailbox) = s.session.access(&name, "r")?;
+    if !s.session.store.has_mailbox(&owner, &mailbox)? {
+        s.no_completed2(&ext.tag, NONEXISTENT).await;
+        return Ok(CommandPipe::Noop);
+    }
+    status_handler::send_status(s, &name, &items).await?;
+    s.ok_completed2(&ext.tag, "STATUS").await;
+    Ok(CommandPipe::Noop)
+});
+
+ext_command_handler!(UidExpungeHandler, (s, ext) => {
+    let uid_set = match ext.args.as_slice() {
+        [set] => set.as_sequence_set(),
+        _ => None,
+    };
+    let uid_set = match uid_set {
+        Some(uid_set) => uid_set,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid UID set").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    expunge_handler::expunge(s, Some(&uid_set), false).await?;
+    s.ok_completed2(&ext.tag, "UID EXPUNGE").await;
+    Ok(CommandPipe::Noop)
+});
+
+ext_command_handler!(AppendHandler, (s, ext, [length: u32, synchronizing: bool]) => {
+    let mut args = match append_handler::parse_args(&ext.args) {
+        Some(args) => args,
+        None => {
+            if !synchronizing {
+                append_handler::discard(s, length).await?;
+            }
+            s.bad_completed2(&ext.tag, "Invalid APPEND arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    args.mailbox = s.session.storage_name(&args.mailbox);
+
+    // refused before the client is asked for the message, one it sends
+    // anyway is skipped, or the connection closed if it's too big for that
+    if length > s.session.config.max_message_size {
+        if !synchronizing {
+            s.bad_completed2(&ext.tag, "[TOOBIG] Message too big").await;
+            return Ok(CommandPipe::Quit);
+        }
+        s.no_completed2(&ext.tag, "[TOOBIG] Message too big").await;
+        return Ok(CommandPipe::Noop);
+    }
+    let location = match s.session.access(&args.mailbox, "i") {
+        Ok(location) => Ok(location),
+        Err(e) => Err(e.refusal().ok_or(e)?),
+    };
+    let store = s.session.store.clone();
+    let refusal = match &location {
+        Err(refusal) => Some(*refusal),
+        Ok((owner, mailbox)) if !store.has_mailbox(owner, mailbox)? => {
+            Some("[TRYCREATE] Mailbox doesn't exist")
+        }
+        Ok((owner, mailbox)) if !store.is_writable(owner, mailbox)? => {
+            Some("Mailbox is read-only")
+        }
+        Ok((owner, _)) if !quota_handler::fits(s.session, owner, length as u64, 1)? => {
+            Some(OVERQUOTA)
+        }
+        Ok(_) => None,
+    };
+    if let Some(refusal) = refusal {
+        if !synchronizing {
+            append_handler::discard(s, length).await?;
+        }
+        s.no_completed2(&ext.tag, refusal).await;
+        return Ok(CommandPipe::Noop);
+    }
+    let (owner, mailbox) = location.map_err(Refused)?;
+    let uid_validity = store.load_index(&owner, &mailbox)?.uid_validity;
+
+    let name = args.mailbox.clone();
+    let uid = append_handler::append(s, args, length, synchronizing).await?;
+    quota_handler::warn_near_limits(s.session, &owner, length as u64, 1);
+
+    if s.session.reports_uids(&name)? {
+        let msg = format!("[APPENDUID {} {}] APPEND completed", uid_validity, uid);
+        s.ok(&ext.tag, &msg).await?;
+    } else {
+        s.ok_completed2(&ext.tag, "APPEND").await;
+    }
+    Ok(CommandPipe::Noop)
+});
+
+// GETQUOTAROOT of RFC 9208, every mailbox being under the quota root named
+// after its owner. The root and its quota are only shown to the owner and
+// admins.
+ext_command_handler!(GetQuotaRootHandler, (s, ext) => {
+    let mailbox = match ext.args.as_slice() {
+        [mailbox] => mailbox.as_astring(),
+        _ => None,
+    };
+    let mailbox = match mailbox {
+        Some(mailbox) => s.session.storage_name(&mailbox),
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid GETQUOTAROOT arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    let (owner, located) = s.session.access(&mailbox, "l")?;
+    if !s.session.store.has_mailbox(&owner, &located)? {
+        s.no_completed2(&ext.tag, NONEXISTENT).await;
+        return Ok(CommandPipe::Noop);
+    }
+    let name = quoted(&s.session.client_name(&mailbox));
+    if owner == s.session.user()? || s.session.is_admin() {
+        let root = quoted(quota_handler::root_name(&owner));
+        s.status(&format!("QUOTAROOT {} {}", name, root)).await;
+        let quota = quota_handler::quota_response(s.session, &owner)?;
+        s.status(&quota).await;
+    } else {
+        s.status(&format!("QUOTAROOT {}", name)).await;
+    }
+    s.ok_completed2(&ext.tag, "GETQUOTAROOT").await;
+    Ok(CommandPipe::Noop)
+});
+
+// GETQUOTA, of the user's own quota root unless an admin asks.
+ext_command_handler!(GetQuotaHandler, (s, ext) => {
+    let root = match ext.args.as_slice() {
+        [root] => root.as_astring(),
+        _ => None,
+    };
+    let root = match root {
+        Some(root) => root,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid GETQUOTA arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    let owner = quota_handler::root_owner(&root);
+    if owner != s.session.user()? && !s.session.is_admin() {
+        s.no_completed2(&ext.tag, "No such quota root").await;
+        return Ok(CommandPipe::Noop);
+    }
+    let quota = quota_handler::quota_response(s.session, owner)?;
+    s.status(&quota).await;
+    s.ok_completed2(&ext.tag, "GETQUOTA").await;
+    Ok(CommandPipe::Noop)
+});
+
+// SETQUOTA, replacing the limits of a user's quota root, for admins only.
+ext_command_handler!(SetQuotaHandler, (s, ext) => {
+    let parsed = match ext.args.as_slice() {
+        [root, Token::List(list)] => {
+            root.as_astring().zip(quota_handler::parse_limits(list))
+        }
+        _ => None,
+    };
+    let (root, limits) = match parsed {
+        Some(parsed) => parsed,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid SETQUOTA arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    if !s.session.is_admin() {
+        s.no_completed2(&ext.tag, NOPERM).await;
+        return Ok(CommandPipe::Noop);
+    }
+    let limits = match limits {
+        Ok(limits) => limits,
+        Err(name) => {
+            let msg = format!("Unsupported resource {}", name);
+            s.no_completed2(&ext.tag, &msg).await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+    let owner = quota_handler::root_owner(&root);
+    s.session.store.set_quota_limits(owner, &limits)?;
+    let quota = quota_handler::quota_response(s.session, owner)?;
+    s.status(&quota).await;
+    s.ok_completed2(&ext.tag, "SETQUOTA").await;
+    Ok(CommandPipe::Noop)
+});
+
+/// Astring arguments of a command, `None` unless there are `count` of them.
+fn astring_args(args: &[Token], count: usize) -> Option<Vec<String>> {
+    if args.len() != count {
+        return None;
+    }
+    args.iter().map(Token::as_astring).collect()
+}
+
+// SETACL of RFC 4314, for users with the administer right.
+ext_command_handler!(SetAclHandler, (s, ext) => {
+    let parsed = astring_args(&ext.args, 3).and_then(|args| {
+        let (change, rights) = acl_handler::parse_change(&args[2])?;
+        Some((args[0].clone(), args[1].clone(), change, rights))
+    });
+    let (name, identifier, change, rights) = match parsed {
+        Some(parsed) => parsed,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid SETACL arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    let name = s.session.storage_name(&name);
+    let (owner, mailbox) = s.session.access(&name, "a")?;
+    if !s.session.store.has_mailbox(&owner, &mailbox)? {
+        s.no_completed2(&ext.tag, NONEXISTENT).await;
+        return Ok(CommandPipe::Noop);
+    }
+    if identifier.starts_with('-') {
+        s.no_completed2(&ext.tag, "Negative rights are not supported").await;
+        return Ok(CommandPipe::Noop);
+    }
+    if acl_handler::is_owner(s.session, &owner, &identifier) {
+        s.no_completed2(&ext.tag, "The owner's rights can't be changed").await;
+        return Ok(CommandPipe::Noop);
+    }
+    acl_handler::set_rights(s.session, &owner, &mailbox, &identifier, change, &rights)?;
+    s.ok_completed2(&ext.tag, "SETACL").await;
+    Ok(CommandPipe::Noop)
+});
+
+ext_command_handler!(DeleteAclHandler, (s, ext) => {
+    let (name, identifier) = match astring_args(&ext.args, 2) {
+        Some(args) => (s.session.storage_name(&args[0]), args[1].clone()),
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid DELETEACL arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    let (owner, mailbox) = s.session.access(&name, "a")?;
+    if !s.session.store.has_mailbox(&owner, &mailbox)? {
+        s.no_completed2(&ext.tag, NONEXISTENT).await;
+        return Ok(CommandPipe::Noop);
+    }
+    if acl_handler::is_owner(s.session, &owner, &identifier) {
+        s.no_completed2(&ext.tag, "The owner's rights can't be changed").await;
+        return Ok(CommandPipe::Noop);
+    }
+    let change = acl_handler::Change::Replace;
+    acl_handler::set_rights(s.session, &owner, &mailbox, &identifier, change, "")?;
+    s.ok_completed2(&ext.tag, "DELETEACL").await;
+    Ok(CommandPipe::Noop)
+});
+
+ext_command_handler!(GetAclHandler, (s, ext) => {
+    let name = match astring_args(&ext.args, 1) {
+        Some(args) => s.session.storage_name(&args[0]),
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid GETACL arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    let (owner, mailbox) = s.session.access(&name, "a")?;
+    if !s.session.store.has_mailbox(&owner, &mailbox)? {
+        s.no_completed2(&ext.tag, NONEXISTENT).await;
+        return Ok(CommandPipe::Noop);
+    }
+    let response = acl_handler::acl_response(s.session, &name, &owner, &mailbox)?;
+    s.status(&response).await;
+    s.ok_completed2(&ext.tag, "GETACL").await;
+    Ok(CommandPipe::Noop)
+});
+
+ext_command_handler!(ListRightsHandler, (s, ext) => {
+    let (name, identifier) = match astring_args(&ext.args, 2) {
+        Some(args) => (s.session.storage_name(&args[0]), args[1].clone()),
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid LISTRIGHTS arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    let (owner, mailbox) = s.session.access(&name, "a")?;
+    if !s.session.store.has_mailbox(&owner, &mailbox)? {
+        s.no_completed2(&ext.tag, NONEXISTENT).await;
+        return Ok(CommandPipe::Noop);
+    }
+    let response = acl_handler::listrights_response(s.session, &name, &owner, &identifier);
+    s.status(&response).await;
+    s.ok_completed2(&ext.tag, "LISTRIGHTS").await;
+    Ok(CommandPipe::Noop)
+});
+
+// MYRIGHTS, which any right on the mailbox allows.
+ext_command_handler!(MyRightsHandler, (s, ext) => {
+    let name = match astring_args(&ext.args, 1) {
+        Some(args) => s.session.storage_name(&args[0]),
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid MYRIGHTS arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    let (owner, mailbox) = s.session.locate(&name)?;
+    let rights = s.session.rights_of(&owner, &mailbox)?;
+    if rights.is_empty() || !s.session.store.has_mailbox(&owner, &mailbox)? {
+        s.no_completed2(&ext.tag, NONEXISTENT).await;
+        return Ok(CommandPipe::Noop);
+    }
+    let name = quoted(&s.session.client_name(&name));
+    s.status(&format!("MYRIGHTS {} {}", name, rights)).await;
+    s.ok_completed2(&ext.tag, "MYRIGHTS").await;
+    Ok(CommandPipe::Noop)
+});
+
+// GETMETADATA of RFC 5464, of a mailbox or, named "", of the server.
+ext_command_handler!(GetMetadataHandler, (s, ext) => {
+    s.session.user()?;
+    let (options, mailbox, entries) = match metadata_handler::parse_get(&ext.args) {
+        Some(parsed) => parsed,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid GETMETADATA arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    let name = (!mailbox.is_empty()).then(|| s.session.storage_name(&mailbox));
+    if let Some(name) = name.as_deref() {
+        let (owner, mailbox) = s.session.access(name, "lr")?;
+        if !s.session.store.has_mailbox(&owner, &mailbox)? {
+            s.no_completed2(&ext.tag, NONEXISTENT).await;
+            return Ok(CommandPipe::Noop);
+        }
+    }
+    let (values, longest) = metadata_handler::lookup(s.session, name.as_deref(), &entries, &options)?;
+    if !values.is_empty() {
+        let response = metadata_handler::metadata_response(s.session, name.as_deref(), &values);
+        s.status(&response).await;
+    }
+    match longest {
+        Some(longest) => {
+            let msg = format!("[METADATA LONGENTRIES {}] GETMETADATA completed", longest);
+            s.ok(&ext.tag, &msg).await?;
+        }
+        None => s.ok_completed2(&ext.tag, "GETMETADATA").await,
+    }
+    Ok(CommandPipe::Noop)
+});
+
+ext_command_handler!(SetMetadataHandler, (s, ext) => {
+    s.session.user()?;
+    let (mailbox, entries) = match metadata_handler::parse_set(&ext.args) {
+        Some(parsed) => parsed,
+        None => {
+            s.bad_completed2(&ext.tag, "Invalid SETMETADATA arguments").await;
+            return Ok(CommandPipe::Noop);
+        }
+    };
+
+    let name = (!mailbox.is_empty()).then(|| s.session.storage_name(&mailbox));
+    if let Some(name) = name.as_deref() {
+        let (owner, mailbox) = s.session.access(name, "l")?;
+        if !s.session.store.has_mailbox(&owner, &mailbox)? {
+            s.no_completed2(&ext.tag, NONEXISTENT).await;
+            return Ok(CommandPipe::Noop);
+        }
+    }
+    for (entry, _) in entries.iter() {
+        metadata_handler::check_write(s.session, name.as_deref(), entry)?;
+    }
+    let max_size = s.session.config.metadata.max_size;
+    if entries.iter().any(|(_, value)| value.as_ref().is_some_and(|v| v.len() > max_size)) {
+        let msg = format!("[METADATA MAXSIZE {}] Value too long", max_size);
+        s.no_completed2(&ext.tag, &msg).await;
+        return Ok(CommandPipe::Noop);
+    }
+    if !metadata_handler::store(s.session, name.as_deref(), &entries)? {
+        s.no_completed2(&ext.tag, "[METADATA TOOMANY] Too many entries").await;
+        return Ok(CommandPipe::Noop);
+    }
+    s.ok_completed2(&ext.tag, "SETMETADATA").await;
+    Ok(CommandPipe::Noop)
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_response_fields() {
+        assert_eq!(
+            plain_credentials(b"\0bob\0secret"),
+            Some((String::new(), "bob".to_string(), b"secret".to_vec()))
+        );
+        assert_eq!(
+            plain_credentials(b"alice\0bob\0"),
+            Some(("alice".to_string(), "bob".to_string(), vec![]))
+        );
+        assert_eq!(plain_credentials(b"\0\0secret"), None);
+        assert_eq!(plain_credentials(b"\0bob"), None);
+        assert_eq!(plain_credentials(b"\0bob\0secret\0more"), None);
+    }
+
+    #[test]
+    fn compacts_uid_sets() {
+        assert_eq!(uid_set_string(&[4, 5, 6, 9]), "4:6,9");
+        assert_eq!(uid_set_string(&[1, 3, 4]), "1,3:4");
+        assert_eq!(uid_set_string(&[7]), "7");
+        assert_eq!(uid_set_string(&[]), "");
+        // the order is kept, COPYUID pairing source and destination UIDs
+        assert_eq!(uid_set_string(&[6, 5, 4, 5, 6]), "6,5,4:6");
+    }
+}
@@ -0,0 +1,130 @@
+use log::warn;
+
+use super::quoted;
+use crate::ext_command::Token;
+use crate::result::Result;
+use crate::session::Session;
+use crate::storage::{QuotaLimits, QuotaUsage, SHARED_OWNER};
+
+/// Name of the quota root of `owner`'s mailboxes: the user's name, the
+/// empty one for the shared mailboxes, which no user has.
+pub fn root_name(owner: &str) -> &str {
+    match owner {
+        SHARED_OWNER => "",
+        user => user,
+    }
+}
+
+/// Owner of the mailboxes under quota root `root`.
+pub fn root_owner(root: &str) -> &str {
+    match root {
+        "" => SHARED_OWNER,
+        user => user,
+    }
+}
+
+/// Limits of `user`'s quota: those an admin set, else the configured ones.
+pub fn limits(session: &Session, user: &str) -> Result<QuotaLimits> {
+    match session.store.quota_limits(user)? {
+        Some(limits) => Ok(limits),
+        None => Ok(QuotaLimits {
+            storage: session.config.quota.storage,
+            messages: session.config.quota.messages,
+        }),
+    }
+}
+
+/// The limited resources as `(name, usage, limit)`, STORAGE in units of
+/// 1024 bytes.
+fn resources(
+    usage: &QuotaUsage,
+    limits: &QuotaLimits,
+) -> Vec<(&'static str, u64, u64)> {
+    vec![
+        ("STORAGE", usage.storage.div_ceil(1024), limits.storage),
+        ("MESSAGE", usage.messages, limits.messages),
+    ]
+    .into_iter()
+    .filter_map(|(name, used, limit)| Some((name, used, limit?)))
+    .collect()
+}
+
+/// `QUOTA` response of the quota root of `user`'s mailboxes.
+pub fn quota_response(session: &Session, user: &str) -> Result<String> {
+    let usage = session.store.quota_usage(user)?;
+    let resources: Vec<String> = resources(&usage, &limits(session, user)?)
+        .iter()
+        .map(|(name, used, limit)| format!("{} {} {}", name, used, limit))
+        .collect();
+    let root = quoted(root_name(user));
+    Ok(format!("QUOTA {} ({})", root, resources.join(" ")))
+}
+
+/// Whether `user` may add `messages` messages of `size` bytes in total.
+pub fn fits(
+    session: &Session,
+    user: &str,
+    size: u64,
+    messages: u64,
+) -> Result<bool> {
+    let usage = session.store.quota_usage(user)?;
+    Ok(limits(session, user)?.allow(&usage, size, messages))
+}
+
+/// Log a warning if adding `messages` messages of `size` bytes brought
+/// `user` to the configured percentage of a limit.
+pub fn warn_near_limits(
+    session: &Session,
+    user: &str,
+    size: u64,
+    messages: u64,
+) {
+    let (usage, limits) = match session
+        .store
+        .quota_usage(user)
+        .and_then(|usage| Ok((usage, limits(session, user)?)))
+    {
+        Ok(quota) => quota,
+        Err(e) => {
+            eprintln!("Failed to check quota usage; err = {:?}", e);
+            return;
+        }
+    };
+    let before = QuotaUsage {
+        storage: usage.storage.saturating_sub(size),
+        messages: usage.messages.saturating_sub(messages),
+    };
+
+    let percent = session.config.quota.warning;
+    let resources = resources(&before, &limits)
+        .into_iter()
+        .zip(resources(&usage, &limits));
+    for ((_, was, _), (name, used, limit)) in resources {
+        let threshold = limit.saturating_mul(percent) / 100;
+        if was < threshold && used >= threshold {
+            warn!("{} uses {} of its {} {} quota", user, used, limit, name);
+        }
+    }
+}
+
+/// Parse the resource limits of SETQUOTA, `Err` naming a resource other
+/// than STORAGE and MESSAGE. Resources left out are unlimited.
+pub fn parse_limits(
+    list: &[Token],
+) -> Option<std::result::Result<QuotaLimits, String>> {
+    let mut limits = QuotaLimits::default();
+    for pair in list.chunks(2) {
+        let (name, limit) = match pair {
+            [Token::Atom(name), limit] => {
+                (name.to_ascii_uppercase(), limit.as_number()?)
+            }
+            _ => return None,
+        };
+        match name.as_str() {
+            "STORAGE" => limits.storage = Some(limit),
+            "MESSAGE" => limits.messages = Some(limit),
+            _ => return Some(Err(name)),
+        }
+    }
+    Some(Ok(limits))
+}
@@ -0,0 +1,864 @@
+use std::cell::{OnceCell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use chrono::NaiveDate;
+use encoding_rs::Encoding;
+use imap_codec::core::{AString, Charset};
+use imap_codec::search::SearchKey;
+
+use log::debug;
+
+use super::{in_sequence_set, quoted, uid_set_string};
+use crate::ext_command::Token;
+use crate::fts::{extract, normalize, Extracted, Field, MailboxFts};
+use crate::message::Message;
+use crate::result::Result;
+use crate::session::Session;
+use crate::storage::MessageMeta;
+
+/// Charsets advertised in `[BADCHARSET]` when the client asks for one we
+/// can't decode.
+pub const SUPPORTED_CHARSETS: &str = "US-ASCII UTF-8 ISO-8859-1";
+
+/// Resolve the `CHARSET` argument of SEARCH, `None` if it is unknown.
+pub fn search_encoding(
+    charset: Option<&Charset<'_>>,
+) -> Option<&'static Encoding> {
+    match charset {
+        None => Some(encoding_rs::UTF_8),
+        Some(charset) => {
+            let label = charset.as_ref();
+            if label.eq_ignore_ascii_case("US-ASCII") {
+                // ASCII is a subset of UTF-8, encoding_rs maps it to
+                // windows-1252 instead
+                Some(encoding_rs::UTF_8)
+            } else {
+                Encoding::for_label(label.as_bytes())
+            }
+        }
+    }
+}
+
+/// Search keys taking arguments, which are never keys themselves.
+const KEY_ARGUMENTS: [(&str, usize); 19] = [
+    ("BCC", 1),
+    ("BEFORE", 1),
+    ("BODY", 1),
+    ("CC", 1),
+    ("CHARSET", 1),
+    ("FROM", 1),
+    ("HEADER", 2),
+    ("KEYWORD", 1),
+    ("LARGER", 1),
+    ("ON", 1),
+    ("SENTBEFORE", 1),
+    ("SENTON", 1),
+    ("SENTSINCE", 1),
+    ("SINCE", 1),
+    ("SMALLER", 1),
+    ("SUBJECT", 1),
+    ("TEXT", 1),
+    ("TO", 1),
+    ("UNKEYWORD", 1),
+];
+
+/// Replace each `MODSEQ [entry type] n` key of SEARCH arguments by the `UID`
+/// set of the `messages` changed since n, which imap-codec can decode.
+/// Mod-sequences are kept per message, so the entry is ignored. Returns
+/// whether any was found, `None` if one is invalid.
+pub fn rewrite_modseq(
+    args: &[Token],
+    messages: &[MessageMeta],
+) -> Option<(Vec<Token>, bool)> {
+    let mut rewritten = vec![];
+    let mut found = false;
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+        if arg.is_atom("MODSEQ") {
+            let modseq = match &args[i..] {
+                [Token::String(_), _, modseq, ..] => {
+                    i += 3;
+                    modseq.as_number()?
+                }
+                [modseq, ..] => {
+                    i += 1;
+                    modseq.as_number()?
+                }
+                [] => return None,
+            };
+            let uids: Vec<u32> = messages
+                .iter()
+                .filter(|m| m.modseq >= modseq)
+                .map(|m| m.uid)
+                .collect();
+            rewritten.push(Token::Atom("UID".to_string()));
+            rewritten.push(Token::Atom(number_set(&uids)));
+            found = true;
+        } else if let Token::List(keys) = arg {
+            let (keys, found_inside) = rewrite_modseq(keys, messages)?;
+            rewritten.push(Token::List(keys));
+            found |= found_inside;
+        } else {
+            rewritten.push(arg.clone());
+            let arguments = KEY_ARGUMENTS
+                .iter()
+                .find(|(key, _)| arg.is_atom(key))
+                .map_or(0, |(_, n)| *n);
+            let end = (i + arguments).min(args.len());
+            rewritten.extend_from_slice(&args[i..end]);
+            i = end;
+        }
+    }
+    Some((rewritten, found))
+}
+
+/// Return options of an extended SEARCH (RFC 4731, RFC 5182, RFC 9394).
+#[derive(Debug, Default, Clone)]
+pub struct ReturnOptions {
+    pub min: bool,
+    pub max: bool,
+    pub all: bool,
+    pub count: bool,
+    pub save: bool,
+    pub partial: Option<Partial>,
+}
+
+impl ReturnOptions {
+    /// Whether the client only wants the result saved, and no ESEARCH.
+    fn save_only(&self) -> bool {
+        self.save
+            && !(self.min || self.max || self.all || self.count)
+            && self.partial.is_none()
+    }
+}
+
+/// A range of positions in a search result, `first <= last`, counted from
+/// the last message if `from_end` (RFC 9394).
+#[derive(Debug, Clone, Copy)]
+pub struct Partial {
+    pub first: u32,
+    pub last: u32,
+    pub from_end: bool,
+}
+
+impl Partial {
+    fn parse(range: &Token) -> Option<Self> {
+        let range = match range {
+            Token::Atom(range) => range,
+            _ => return None,
+        };
+        let (a, b) = range.split_once(':')?;
+        let (a, b): (i64, i64) = (a.parse().ok()?, b.parse().ok()?);
+        let from_end = a < 0;
+        if a == 0 || b == 0 || (b < 0) != from_end {
+            return None;
+        }
+        let (a, b) =
+            (u32::try_from(a.abs()).ok()?, u32::try_from(b.abs()).ok()?);
+        Some(Self {
+            first: a.min(b),
+            last: a.max(b),
+            from_end,
+        })
+    }
+
+    /// The part of the ascending `found` in the range.
+    fn select(&self, found: &[u32]) -> Vec<u32> {
+        let len = found.len();
+        let (first, last) = (self.first as usize, self.last as usize);
+        let range = match self.from_end {
+            true => len.saturating_sub(last)..len.saturating_sub(first - 1),
+            false => (first - 1).min(len)..last.min(len),
+        };
+        found[range].to_vec()
+    }
+}
+
+impl std::fmt::Display for Partial {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.from_end {
+            true => write!(f, "-{}:-{}", self.first, self.last),
+            false => write!(f, "{}:{}", self.first, self.last),
+        }
+    }
+}
+
+/// Split `RETURN (options)` off the arguments of SEARCH. The options are
+/// `None` without `RETURN`, and the whole result if they are invalid.
+pub fn parse_return(
+    args: &[Token],
+) -> Option<(Option<ReturnOptions>, &[Token])> {
+    let list = match args {
+        [keyword, Token::List(list), ..] if keyword.is_atom("RETURN") => list,
+        _ => return Some((None, args)),
+    };
+
+    let mut options = ReturnOptions::default();
+    let mut items = list.iter();
+    while let Some(item) = items.next() {
+        let name = match item {
+            Token::Atom(name) => name.to_ascii_uppercase(),
+            _ => return None,
+        };
+        match name.as_str() {
+            "MIN" => options.min = true,
+            "MAX" => options.max = true,
+            "ALL" => options.all = true,
+            "COUNT" => options.count = true,
+            "SAVE" => options.save = true,
+            "PARTIAL" => options.partial = Some(Partial::parse(items.next()?)?),
+            _ => return None,
+        }
+    }
+    if list.is_empty() {
+        options.all = true;
+    }
+    if options.all && options.partial.is_some() {
+        return None;
+    }
+    Some((Some(options), &args[2..]))
+}
+
+/// Replace each `$` key of SEARCH arguments by the `UID` key of the saved
+/// result `uids` (RFC 5182).
+pub fn expand_saved(args: &[Token], uids: &[u32]) -> Vec<Token> {
+    let mut expanded = vec![];
+    let mut i = 0;
+    while i < args.len() {
+        let arg = &args[i];
+        i += 1;
+        if arg.is_atom("$") {
+            expanded.push(Token::Atom("UID".to_string()));
+            expanded.push(Token::Atom(number_set(uids)));
+        } else if let Token::List(keys) = arg {
+            expanded.push(Token::List(expand_saved(keys, uids)));
+        } else {
+            expanded.push(arg.clone());
+            let arguments = KEY_ARGUMENTS
+                .iter()
+                .find(|(key, _)| arg.is_atom(key))
+                .map_or(0, |(_, n)| *n);
+            let end = (i + arguments).min(args.len());
+            expanded.extend_from_slice(&args[i..end]);
+            i = end;
+        }
+    }
+    expanded
+}
+
+/// Ascending numbers as a sequence set, one matching no message if there
+/// are none.
+pub fn number_set(numbers: &[u32]) -> String {
+    // no message has the largest UID or sequence number
+    match numbers.is_empty() {
+        true => u32::MAX.to_string(),
+        false => uid_set_string(numbers),
+    }
+}
+
+/// A `tag SEARCH args` command line, for imap-codec to parse the search
+/// keys of commands it doesn't know.
+pub fn search_line(tag: &str, args: &[Token]) -> Vec<u8> {
+    let mut line = format!("{} SEARCH", tag).into_bytes();
+    for arg in args.iter() {
+        line.push(b' ');
+        arg.encode(&mut line);
+    }
+    line.extend_from_slice(b"\r\n");
+    line
+}
+
+/// The `* SEARCH` response to `criteria`, or `* ESEARCH` with return
+/// `options` or in IMAP4rev2, with the highest mod-sequence of the
+/// reported messages if `modseq` (RFC 7162). The result is saved for `$`
+/// if asked, and `None` returned if that is all the client wants.
+pub fn search_response(
+    session: &mut Session,
+    tag: &str,
+    encoding: &'static Encoding,
+    criteria: &SearchKey<'_>,
+    uid: bool,
+    modseq: bool,
+    options: Option<&ReturnOptions>,
+) -> Result<Option<String>> {
+    let found = Searcher::new(session, encoding).search(criteria, uid)?;
+
+    let all = ReturnOptions {
+        all: true,
+        ..ReturnOptions::default()
+    };
+    let options = match options {
+        Some(options) => options,
+        None if session.imap4rev2() => &all,
+        None => {
+            let mut resp = String::from("SEARCH");
+            for n in found.iter() {
+                resp.push_str(&format!(" {}", n));
+            }
+            if modseq && !found.is_empty() {
+                let highest = highest_modseq(session, &found, uid)?;
+                resp.push_str(&format!(" (MODSEQ {})", highest));
+            }
+            return Ok(Some(resp));
+        }
+    };
+
+    // the messages the response is about: those in the partial range, the
+    // smallest and largest without ALL or COUNT, or every one found
+    let partial = options.partial.map(|partial| partial.select(&found));
+    let reported = match &partial {
+        Some(partial) => partial.clone(),
+        None if (options.min || options.max)
+            && !(options.all || options.count) =>
+        {
+            let mut ends = vec![];
+            if let (true, Some(min)) = (options.min, found.first()) {
+                ends.push(*min);
+            }
+            if let (true, Some(max)) = (options.max, found.last()) {
+                ends.push(*max);
+            }
+            ends.dedup();
+            ends
+        }
+        None => found.clone(),
+    };
+    if options.save {
+        save_result(session, &reported, uid)?;
+    }
+    if options.save_only() {
+        return Ok(None);
+    }
+
+    let mut resp = format!("ESEARCH (TAG {})", quoted(tag));
+    if uid {
+        resp.push_str(" UID");
+    }
+    if let (true, Some(min)) = (options.min, found.first()) {
+        resp.push_str(&format!(" MIN {}", min));
+    }
+    if let (true, Some(max)) = (options.max, found.last()) {
+        resp.push_str(&format!(" MAX {}", max));
+    }
+    if options.count {
+        resp.push_str(&format!(" COUNT {}", found.len()));
+    }
+    if options.all && !found.is_empty() {
+        resp.push_str(&format!(" ALL {}", uid_set_string(&found)));
+    }
+    if let (Some(range), Some(partial)) = (options.partial, &partial) {
+        let set = match partial.is_empty() {
+            true => "NIL".to_string(),
+            false => uid_set_string(partial),
+        };
+        resp.push_str(&format!(" PARTIAL ({} {})", range, set));
+    }
+    if modseq && !reported.is_empty() {
+        let highest = highest_modseq(session, &reported, uid)?;
+        resp.push_str(&format!(" MODSEQ {}", highest));
+    }
+    Ok(Some(resp))
+}
+
+/// Highest mod-sequence of the ascending sequence numbers, or UIDs, `found`.
+fn highest_modseq(session: &Session, found: &[u32], uid: bool) -> Result<u64> {
+    Ok(session
+        .selected_messages()?
+        .iter()
+        .enumerate()
+        .filter(|(i, m)| {
+            let n = if uid { m.uid } else { *i as u32 + 1 };
+            found.binary_search(&n).is_ok()
+        })
+        .map(|(_, m)| m.modseq)
+        .max()
+        .unwrap_or(0))
+}
+
+/// Keep the sequence numbers, or UIDs, `found` for `$`.
+pub fn save_result(
+    session: &mut Session,
+    found: &[u32],
+    uid: bool,
+) -> Result<()> {
+    let selected = session
+        .selected
+        .as_mut()
+        .ok_or_else(|| anyhow!("No mailbox selected"))?;
+    selected.saved = match uid {
+        true => found.to_vec(),
+        false => found
+            .iter()
+            .filter_map(|seq| selected.uids.get(*seq as usize - 1))
+            .copied()
+            .collect(),
+    };
+    Ok(())
+}
+
+/// Candidates for a text key in the full-text index.
+type FtsMatches = Option<HashSet<u32>>;
+
+/// A message being matched, read, parsed and reduced to its text at most
+/// once however many keys look at it.
+struct Candidate<'m> {
+    seq: u32,
+    meta: &'m MessageMeta,
+    raw: &'m OnceCell<Option<Vec<u8>>>,
+    message: OnceCell<Option<Message<'m>>>,
+    text: OnceCell<Option<Extracted>>,
+}
+
+/// Evaluates a SEARCH key tree over the selected mailbox.
+pub struct Searcher<'s> {
+    session: &'s Session,
+    encoding: &'static Encoding,
+    max_seq: u32,
+    max_uid: u32,
+    fts: OnceCell<Option<Arc<MailboxFts>>>,
+    fts_matches: RefCell<HashMap<(Field, String), FtsMatches>>,
+}
+
+impl<'s> Searcher<'s> {
+    pub fn new(session: &'s Session, encoding: &'static Encoding) -> Self {
+        Self {
+            session,
+            encoding,
+            max_seq: 0,
+            max_uid: 0,
+            fts: OnceCell::new(),
+            fts_matches: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Matching sequence numbers, or UIDs if `uid` is set, in ascending
+    /// order.
+    pub fn search(
+        &mut self,
+        criteria: &SearchKey<'_>,
+        uid: bool,
+    ) -> Result<Vec<u32>> {
+        let messages = self.session.selected_messages()?;
+        self.max_seq = messages.len() as u32;
+        self.max_uid = messages.iter().map(|m| m.uid).max().unwrap_or(0);
+
+        let mut result = vec![];
+        for (i, meta) in messages.iter().enumerate() {
+            let raw = OnceCell::new();
+            let candidate = Candidate {
+                seq: i as u32 + 1,
+                meta,
+                raw: &raw,
+                message: OnceCell::new(),
+                text: OnceCell::new(),
+            };
+            if self.matches(criteria, &candidate) {
+                result.push(if uid { meta.uid } else { candidate.seq });
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn matches(&self, key: &SearchKey<'_>, c: &Candidate<'_>) -> bool {
+        let meta = c.meta;
+        match key {
+            SearchKey::And(keys) => {
+                keys.as_ref().iter().all(|key| self.matches(key, c))
+            }
+            SearchKey::Or(a, b) => self.matches(a, c) || self.matches(b, c),
+            SearchKey::Not(key) => !self.matches(key, c),
+            SearchKey::All => true,
+
+            SearchKey::SequenceSet(set) => {
+                in_sequence_set(set, c.seq, self.max_seq)
+            }
+            SearchKey::Uid(set) => in_sequence_set(set, meta.uid, self.max_uid),
+
+            SearchKey::Answered => meta.has_flag("\\Answered"),
+            SearchKey::Deleted => meta.has_flag("\\Deleted"),
+            SearchKey::Draft => meta.has_flag("\\Draft"),
+            SearchKey::Flagged => meta.has_flag("\\Flagged"),
+            SearchKey::Seen => meta.has_flag("\\Seen"),
+            SearchKey::Unanswered => !meta.has_flag("\\Answered"),
+            SearchKey::Undeleted => !meta.has_flag("\\Deleted"),
+            SearchKey::Undraft => !meta.has_flag("\\Draft"),
+            SearchKey::Unflagged => !meta.has_flag("\\Flagged"),
+            SearchKey::Unseen => !meta.has_flag("\\Seen"),
+            SearchKey::Keyword(keyword) => meta.has_flag(keyword.as_ref()),
+            SearchKey::Unkeyword(keyword) => !meta.has_flag(keyword.as_ref()),
+            SearchKey::Recent => self.is_recent(meta),
+            SearchKey::Old => !self.is_recent(meta),
+            SearchKey::New => self.is_recent(meta) && !meta.has_flag("\\Seen"),
+
+            SearchKey::Before(date) => {
+                self.internal_date(meta) < *date.as_ref()
+            }
+            SearchKey::On(date) => self.internal_date(meta) == *date.as_ref(),
+            SearchKey::Since(date) => {
+                self.internal_date(meta) >= *date.as_ref()
+            }
+            SearchKey::SentBefore(date) => {
+                self.sent_date(c).is_some_and(|d| d < *date.as_ref())
+            }
+            SearchKey::SentOn(date) => {
+                self.sent_date(c).is_some_and(|d| d == *date.as_ref())
+            }
+            SearchKey::SentSince(date) => {
+                self.sent_date(c).is_some_and(|d| d >= *date.as_ref())
+            }
+
+            SearchKey::Larger(size) => meta.size > *size,
+            SearchKey::Smaller(size) => meta.size < *size,
+
+            SearchKey::From(value) => self.header_contains(c, "From", value),
+            SearchKey::To(value) => self.header_contains(c, "To", value),
+            SearchKey::Cc(value) => self.header_contains(c, "Cc", value),
+            SearchKey::Bcc(value) => self.header_contains(c, "Bcc", value),
+            SearchKey::Subject(value) => {
+                let needle = self.decode(value);
+                !self.fts_excludes(Field::Subject, &needle, meta.uid)
+                    && self.header_contains(c, "Subject", value)
+            }
+            SearchKey::Header(field, value) => {
+                let field = String::from_utf8_lossy(field.as_ref());
+                self.header_contains(c, &field, value)
+            }
+            SearchKey::Body(value) => {
+                let needle = self.decode(value);
+                !self.fts_excludes(Field::Body, &needle, meta.uid)
+                    && self.with_text(c, |text| contains(&text.body, &needle))
+            }
+            SearchKey::Text(value) => {
+                let needle = self.decode(value);
+                !self.fts_excludes(Field::Text, &needle, meta.uid)
+                    && self.with_text(c, |text| {
+                        contains(&text.headers, &needle)
+                            || contains(&text.body, &needle)
+                    })
+            }
+        }
+    }
+
+    /// Whether the full-text index rules the message out for a text key,
+    /// sparing its scan. A message it doesn't rule out, or doesn't know,
+    /// is scanned.
+    fn fts_excludes(&self, field: Field, needle: &str, uid: u32) -> bool {
+        let fts = match self.fts.get_or_init(|| self.load_fts()) {
+            Some(fts) if fts.is_indexed(uid) => fts,
+            _ => return false,
+        };
+
+        let mut matches = self.fts_matches.borrow_mut();
+        matches
+            .entry((field, needle.to_string()))
+            .or_insert_with(|| fts.matches(field, needle))
+            .as_ref()
+            .is_some_and(|uids| !uids.contains(&uid))
+    }
+
+    fn load_fts(&self) -> Option<Arc<MailboxFts>> {
+        let fts = self.session.fts.as_ref()?;
+        let (owner, mailbox) = self.session.selected_location().ok()?;
+        match fts.load(&owner, &mailbox) {
+            Ok(mailbox_fts) => mailbox_fts,
+            Err(e) => {
+                debug!("full-text index unusable, scanning: {}", e);
+                None
+            }
+        }
+    }
+
+    fn is_recent(&self, meta: &MessageMeta) -> bool {
+        self.session
+            .selected
+            .as_ref()
+            .is_some_and(|s| s.recent.contains(&meta.uid))
+    }
+
+    fn internal_date(&self, meta: &MessageMeta) -> NaiveDate {
+        meta.internal_date.date_naive()
+    }
+
+    fn sent_date(&self, c: &Candidate<'_>) -> Option<NaiveDate> {
+        let mut date = None;
+        self.with_message(c, |msg| {
+            date = msg.sent_date();
+            true
+        });
+        date
+    }
+
+    fn header_contains(
+        &self,
+        c: &Candidate<'_>,
+        field: &str,
+        value: &AString<'_>,
+    ) -> bool {
+        let needle = self.decode(value);
+        self.with_message(c, |msg| {
+            let values = msg.header_values(field);
+            // an empty string matches every message having the field
+            !values.is_empty() && values.iter().any(|v| contains(v, &needle))
+        })
+    }
+
+    /// Run `f` on the parsed message, loading it from the store on first use.
+    fn with_message<F>(&self, c: &Candidate<'_>, f: F) -> bool
+    where
+        F: FnOnce(&Message<'_>) -> bool,
+    {
+        match self.message(c) {
+            Some(msg) => f(msg),
+            None => false,
+        }
+    }
+
+    /// Run `f` on the searchable text of the message, extracted on first
+    /// use.
+    fn with_text<F>(&self, c: &Candidate<'_>, f: F) -> bool
+    where
+        F: FnOnce(&Extracted) -> bool,
+    {
+        let text = c.text.get_or_init(|| self.message(c).map(extract));
+        match text {
+            Some(text) => f(text),
+            None => false,
+        }
+    }
+
+    fn message<'c, 'm>(&self, c: &'c Candidate<'m>) -> Option<&'c Message<'m>> {
+        let raw = c.raw.get_or_init(|| {
+            let (owner, mailbox) = self.session.selected_location().ok()?;
+            match self
+                .session
+                .store
+                .read_message(&owner, &mailbox, c.meta.uid)
+            {
+                Ok(raw) => Some(raw),
+                Err(e) => {
+                    debug!("cannot read message uid {}: {}", c.meta.uid, e);
+                    None
+                }
+            }
+        });
+
+        let msg = c.message.get_or_init(|| {
+            raw.as_deref().and_then(|raw| Message::parse(raw).ok())
+        });
+        msg.as_ref()
+    }
+
+    /// Decode a search string from the requested charset, normalized as
+    /// the full-text index does for case and diacritic insensitive matching.
+    fn decode(&self, value: &AString<'_>) -> String {
+        let (decoded, _, _) = self.encoding.decode(value.as_ref());
+        normalize(&decoded)
+    }
+}
+
+/// Whether `haystack` has `needle`, which is normalized already.
+fn contains(haystack: &str, needle: &str) -> bool {
+    needle.is_empty() || normalize(haystack).contains(needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use chrono::DateTime;
+    use imap_codec::codec::Decode;
+    use imap_codec::command::{Command, CommandBody};
+
+    use super::*;
+    use crate::acl::ALL_RIGHTS;
+    use crate::auth::NoUsers;
+    use crate::events::EventBus;
+    use crate::ext_command::ext_command_decode;
+    use crate::session::SelectedMailbox;
+    use crate::storage::{FsStore, MailStore, INBOX};
+
+    fn args(line: &str) -> Vec<Token> {
+        ext_command_decode(line.as_bytes()).unwrap().args
+    }
+
+    fn partial(range: &str) -> Option<Partial> {
+        Partial::parse(&Token::Atom(range.to_string()))
+    }
+
+    #[test]
+    fn selects_partial_ranges() {
+        let found = [2, 4, 6, 8, 10];
+        let select = |range| partial(range).unwrap().select(&found);
+        assert_eq!(select("1:2"), [2, 4]);
+        assert_eq!(select("4:2"), [4, 6, 8]);
+        assert_eq!(select("4:100"), [8, 10]);
+        assert_eq!(select("6:10"), Vec::<u32>::new());
+        assert_eq!(select("-1:-2"), [8, 10]);
+        assert_eq!(select("-5:-5"), [2]);
+        assert_eq!(select("-3:-100"), [2, 4, 6]);
+        assert_eq!(select("-6:-9"), Vec::<u32>::new());
+        assert_eq!(partial("-1:-3").unwrap().to_string(), "-1:-3");
+
+        for invalid in ["0:3", "1:-3", "-1:3", "1", "a:b", "1:4294967296"] {
+            assert!(partial(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parses_return_options() {
+        let line = args("a SEARCH RETURN (MIN PARTIAL 1:5 SAVE) UNSEEN");
+        let (options, rest) = parse_return(&line).unwrap();
+        let options = options.unwrap();
+        assert!(options.min && options.save && !options.all);
+        assert_eq!(options.partial.unwrap().to_string(), "1:5");
+        assert_eq!(rest.len(), 1);
+
+        let line = args("a SEARCH RETURN () ALL");
+        assert!(parse_return(&line).unwrap().0.unwrap().all);
+        let line = args("a SEARCH ALL");
+        assert!(parse_return(&line).unwrap().0.is_none());
+        for invalid in [
+            "a SEARCH RETURN (ALL PARTIAL 1:5) ALL",
+            "a SEARCH RETURN (BOGUS) ALL",
+            "a SEARCH RETURN (PARTIAL) ALL",
+        ] {
+            assert!(parse_return(&args(invalid)).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn expands_saved_results() {
+        let line = args("a SEARCH OR $ SUBJECT $ (NOT $)");
+        let mut expanded = vec![];
+        for arg in expand_saved(&line, &[3, 4, 7]) {
+            arg.encode(&mut expanded);
+            expanded.push(b' ');
+        }
+        // `$` as the argument of SUBJECT is a string
+        assert_eq!(
+            String::from_utf8(expanded).unwrap(),
+            "OR UID 3:4,7 SUBJECT $ (NOT UID 3:4,7) "
+        );
+        assert_eq!(number_set(&[]), u32::MAX.to_string());
+    }
+
+    const MESSAGES: [(&str, &str, &str); 3] = [
+        (
+            "From: alice@example.org\r\n\
+             Subject: Meeting notes\r\n\
+             Date: Wed, 10 Jan 2024 09:00:00 +0000\r\n\r\n\
+             Agenda: budget\r\n",
+            "\\Seen",
+            "2024-01-10T09:00:00Z",
+        ),
+        (
+            "From: bob@example.org\r\n\
+             Subject: Re: Budget\r\n\
+             Content-Type: text/html\r\n\r\n\
+             <p>Caf&eacute;<br>shared&#32;costs</p>\r\n",
+            "\\Flagged",
+            "2024-02-01T09:00:00Z",
+        ),
+        (
+            "From: carol@example.org\r\n\
+             Subject: Hello\r\n\
+             Date: Tue, 5 Mar 2024 09:00:00 +0000\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\r\n\
+             plain caf\u{e9}\r\n",
+            "",
+            "2024-03-05T09:00:00Z",
+        ),
+    ];
+
+    /// A session of bob, who selected his INBOX holding `MESSAGES`, the
+    /// last one recent.
+    fn selected_session(root: &std::path::Path) -> Session {
+        let store = FsStore::new(root);
+        for (raw, flags, date) in MESSAGES {
+            let mut sink = store.append_message("bob", INBOX, None).unwrap();
+            sink.write_all(raw.as_bytes()).unwrap();
+            let flags = flags.split_whitespace().map(String::from).collect();
+            let date = DateTime::parse_from_rfc3339(date).unwrap();
+            sink.commit(flags, date).unwrap();
+        }
+
+        let conf = format!("mail_dir = {:?}", root.to_str().unwrap());
+        let events = Arc::new(EventBus::new());
+        let mut session = Session::new(
+            Arc::new(toml::from_str(&conf).unwrap()),
+            Arc::new(store),
+            Arc::new(NoUsers),
+            None,
+            events.clone(),
+        );
+        session.user = Some("bob".to_string());
+        session.selected = Some(SelectedMailbox {
+            name: INBOX.to_string(),
+            rights: ALL_RIGHTS.to_string(),
+            uids: vec![1, 2, 3],
+            recent: HashSet::from([3]),
+            read_only: false,
+            events: events.subscribe("bob", INBOX),
+            saved: vec![],
+        });
+        session
+    }
+
+    #[test]
+    fn evaluates_search_keys() {
+        let root = std::env::temp_dir()
+            .join(format!("imaple-search-{}", std::process::id()));
+        let session = selected_session(&root);
+        let search = |keys: &str, uid| {
+            let line = format!("a SEARCH {}\r\n", keys);
+            let criteria = match Command::decode(line.as_bytes()) {
+                Ok((
+                    _,
+                    Command {
+                        body: CommandBody::Search { criteria, .. },
+                        ..
+                    },
+                )) => criteria,
+                _ => panic!("invalid keys {}", keys),
+            };
+            Searcher::new(&session, encoding_rs::UTF_8)
+                .search(&criteria, uid)
+                .unwrap()
+        };
+
+        assert_eq!(search("ALL", false), [1, 2, 3]);
+        assert_eq!(search("2:* UNSEEN", false), [2, 3]);
+        assert_eq!(search("FLAGGED", false), [2]);
+        assert_eq!(search("NEW", false), [3]);
+        assert_eq!(search("OLD SEEN", false), [1]);
+        assert_eq!(search("UID 3:2", true), [2, 3]);
+        assert_eq!(search("LARGER 100 SMALLER 110", false), [1]);
+
+        assert_eq!(search("SUBJECT budget", false), [2]);
+        assert_eq!(search("SUBJECT \"\"", false), [1, 2, 3]);
+        assert_eq!(search("OR FROM bob FROM CAROL", false), [2, 3]);
+        assert_eq!(search("HEADER Date \"\"", false), [1, 3]);
+        assert_eq!(search("NOT HEADER X-Missing \"\"", false), [1, 2, 3]);
+        // HTML is searched by what it displays besides its source
+        assert_eq!(search("BODY \"shared costs\"", false), [2]);
+        assert_eq!(search("BODY eacute", false), [2]);
+        assert_eq!(search("BODY alice", false), Vec::<u32>::new());
+        assert_eq!(search("TEXT alice", false), [1]);
+        // matched as the full-text index does, whatever the case and
+        // diacritics
+        assert_eq!(search("BODY CAFE", false), [2, 3]);
+
+        assert_eq!(search("SINCE 1-Feb-2024", false), [2, 3]);
+        assert_eq!(search("ON 10-Jan-2024", false), [1]);
+        // a message without a Date is sent on no day
+        assert_eq!(search("SENTBEFORE 1-Apr-2024", false), [1, 3]);
+        assert_eq!(search("NOT SENTSINCE 1-Feb-2024", false), [1, 2]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
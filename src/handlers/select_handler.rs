@@ -0,0 +1,235 @@
+use std::collections::HashSet;
+use std::convert::TryFrom;
+
+use imap_codec::sequence::SequenceSet;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::expunge_handler::vanished_since;
+use super::{
+    flags_list, in_sequence_set, permanent_flags, quoted, uid_set_string,
+};
+use crate::ext_command::Token;
+use crate::imap_serv::IMAPServ;
+use crate::result::Result;
+use crate::session::SelectedMailbox;
+use crate::storage::SYSTEM_FLAGS;
+
+/// What a QRESYNC client knew of the mailbox it selects (RFC 7162).
+pub struct Qresync {
+    pub uid_validity: u32,
+    pub modseq: u64,
+
+    /// UIDs the client knows about, all of them if not given.
+    pub known_uids: Option<SequenceSet>,
+}
+
+/// Parameters of a SELECT or EXAMINE imap-codec can't decode.
+pub struct SelectArgs {
+    pub mailbox: String,
+    pub condstore: bool,
+    pub qresync: Option<Qresync>,
+}
+
+/// Parse `mailbox (CONDSTORE | QRESYNC (...))`, `None` if invalid. The
+/// sequence match data of QRESYNC is only an optimization and ignored.
+pub fn parse_args(args: &[Token]) -> Option<SelectArgs> {
+    let (mailbox, params) = match args {
+        [mailbox, Token::List(params)] => (mailbox.as_astring()?, params),
+        _ => return None,
+    };
+
+    let mut condstore = false;
+    let mut qresync = None;
+    let mut params = params.iter();
+    while let Some(param) = params.next() {
+        if param.is_atom("CONDSTORE") {
+            condstore = true;
+        } else if param.is_atom("QRESYNC") {
+            let values = params.next()?.as_list()?;
+            let known_uids = match values.get(2) {
+                Some(Token::List(_)) | None => None,
+                Some(uids) => Some(uids.as_sequence_set()?),
+            };
+            qresync = Some(Qresync {
+                uid_validity: u32::try_from(values.first()?.as_number()?)
+                    .ok()?,
+                modseq: values.get(1)?.as_number()?,
+                known_uids,
+            });
+        } else {
+            return None;
+        }
+    }
+
+    Some(SelectArgs {
+        mailbox,
+        condstore,
+        qresync,
+    })
+}
+
+/// Select mailbox `name`, sending the untagged responses of SELECT and
+/// EXAMINE, and what changed since `qresync` if given. The selection is
+/// read-only if `read_only` is asked or the user can't write to the
+/// mailbox, returns which it ended up being, `None` if the mailbox doesn't
+/// exist.
+pub async fn select<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    name: String,
+    read_only: bool,
+    qresync: Option<&Qresync>,
+) -> Result<Option<bool>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let rev2 = s.session.imap4rev2();
+    if s.session.selected.take().is_some() && (s.session.qresync() || rev2) {
+        s.status("OK [CLOSED] Previous mailbox closed").await;
+    }
+
+    let (owner, mailbox) = s.session.access(&name, "r")?;
+    let store = s.session.store.clone();
+    if !store.has_mailbox(&owner, &mailbox)? {
+        return Ok(None);
+    }
+    // subscribed first, so no change after loading the index is missed
+    let events = s.session.events.subscribe(&owner, &mailbox);
+    let mut index = store.load_index(&owner, &mailbox)?;
+    // without a right to change anything the selection is read-only
+    let rights = s.session.rights_of(&owner, &mailbox)?;
+    let read_only = read_only
+        || !store.is_writable(&owner, &mailbox)?
+        || !rights.contains(['s', 'w', 't', 'e']);
+
+    // \Recent is handed to the first session selecting the mailbox, an
+    // examining one sees it without taking it and IMAP4rev2 has none
+    let recent: HashSet<u32>;
+    if rev2 {
+        recent = HashSet::new();
+    } else if read_only {
+        recent = index
+            .messages
+            .iter()
+            .filter(|m| m.recent)
+            .map(|m| m.uid)
+            .collect();
+    } else {
+        let mut taken = HashSet::new();
+        let mut updated = None;
+        store.update_index(&owner, &mailbox, &mut |index| {
+            taken = index
+                .messages
+                .iter()
+                .filter(|m| m.recent)
+                .map(|m| m.uid)
+                .collect();
+            index.messages.iter_mut().for_each(|m| m.recent = false);
+            updated = Some(index.clone());
+        })?;
+        recent = taken;
+        index = updated.unwrap();
+    }
+
+    let mut flags: Vec<String> =
+        SYSTEM_FLAGS.iter().map(|f| f.to_string()).collect();
+    for meta in index.messages.iter() {
+        for flag in meta.flags.iter() {
+            if !flags.iter().any(|f| f.eq_ignore_ascii_case(flag)) {
+                flags.push(flag.clone());
+            }
+        }
+    }
+    s.status(&format!("FLAGS ({})", flags.join(" "))).await;
+    s.status(&format!("{} EXISTS", index.messages.len())).await;
+    if rev2 {
+        let delimiter = s.session.config.delimiter.to_string();
+        let client_name = s.session.client_name(&name);
+        s.status(&format!(
+            "LIST () {} {}",
+            quoted(&delimiter),
+            quoted(&client_name)
+        ))
+        .await;
+    } else {
+        s.status(&format!("{} RECENT", recent.len())).await;
+    }
+    // IMAP4rev2 leaves finding the first unseen message to SEARCH
+    let unseen = index.messages.iter().position(|m| !m.has_flag("\\Seen"));
+    if let Some(pos) = unseen.filter(|_| !rev2) {
+        let seq = pos + 1;
+        s.status(&format!(
+            "OK [UNSEEN {}] Message {} is first unseen",
+            seq, seq
+        ))
+        .await;
+    }
+    s.status(&format!(
+        "OK [UIDVALIDITY {}] UIDs valid",
+        index.uid_validity
+    ))
+    .await;
+    s.status(&format!(
+        "OK [UIDNEXT {}] Predicted next UID",
+        index.uid_next
+    ))
+    .await;
+    if s.session.uidplus() && !store.has_sticky_uids(&owner, &mailbox)? {
+        s.status("NO [UIDNOTSTICKY] Non-persistent UIDs").await;
+    }
+    if read_only {
+        s.status("OK [PERMANENTFLAGS ()] No permanent flags permitted")
+            .await;
+    } else {
+        s.status(&format!(
+            "OK [PERMANENTFLAGS {}] Flags permitted",
+            permanent_flags(&rights)
+        ))
+        .await;
+    }
+    s.status(&format!(
+        "OK [HIGHESTMODSEQ {}] Highest mod-sequence",
+        index.highest_modseq
+    ))
+    .await;
+
+    if let Some(qresync) =
+        qresync.filter(|q| q.uid_validity == index.uid_validity)
+    {
+        let known = qresync.known_uids.as_ref();
+        let vanished = vanished_since(&index, qresync.modseq, known);
+        if !vanished.is_empty() {
+            let uids = uid_set_string(&vanished);
+            s.status(&format!("VANISHED (EARLIER) {}", uids)).await;
+        }
+
+        let largest = index.uid_next.saturating_sub(1);
+        for (i, meta) in index.messages.iter().enumerate() {
+            if meta.modseq <= qresync.modseq
+                || !known.is_none_or(|k| in_sequence_set(k, meta.uid, largest))
+            {
+                continue;
+            }
+            let flags = flags_list(meta, recent.contains(&meta.uid));
+            s.status(&format!(
+                "{} FETCH (UID {} FLAGS {} MODSEQ ({}))",
+                i + 1,
+                meta.uid,
+                flags,
+                meta.modseq
+            ))
+            .await;
+        }
+    }
+
+    s.session.selected = Some(SelectedMailbox {
+        name,
+        rights,
+        uids: index.messages.iter().map(|m| m.uid).collect(),
+        recent,
+        read_only,
+        events,
+        saved: vec![],
+    });
+    Ok(Some(read_only))
+}
@@ -0,0 +1,491 @@
+use std::cmp::Ordering;
+
+use imap_codec::codec::Decode;
+use imap_codec::command::{Command, CommandBody};
+use mailparse::SingleInfo;
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::search_handler::{
+    search_encoding, search_line, Searcher, SUPPORTED_CHARSETS,
+};
+use crate::ext_command::{ExtCommand, Token};
+use crate::imap_serv::IMAPServ;
+use crate::message::Message;
+use crate::result::Result;
+use crate::session::Session;
+use crate::storage::MessageMeta;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Arrival,
+    Cc,
+    Date,
+    DisplayFrom,
+    DisplayTo,
+    From,
+    Size,
+    Subject,
+    To,
+}
+
+/// A sort key, reversed if `reverse`.
+#[derive(Debug, Clone, Copy)]
+pub struct SortCriterion {
+    pub key: SortKey,
+    pub reverse: bool,
+}
+
+/// Parse the parenthesized sort criteria of SORT (RFC 5256, RFC 5957).
+pub fn parse_criteria(token: &Token) -> Option<Vec<SortCriterion>> {
+    let mut criteria = vec![];
+    let mut reverse = false;
+    for item in token.as_list()? {
+        let name = match item {
+            Token::Atom(name) => name.to_ascii_uppercase(),
+            _ => return None,
+        };
+        let key = match name.as_str() {
+            "REVERSE" if !reverse => {
+                reverse = true;
+                continue;
+            }
+            "ARRIVAL" => SortKey::Arrival,
+            "CC" => SortKey::Cc,
+            "DATE" => SortKey::Date,
+            "DISPLAYFROM" => SortKey::DisplayFrom,
+            "DISPLAYTO" => SortKey::DisplayTo,
+            "FROM" => SortKey::From,
+            "SIZE" => SortKey::Size,
+            "SUBJECT" => SortKey::Subject,
+            "TO" => SortKey::To,
+            _ => return None,
+        };
+        criteria.push(SortCriterion { key, reverse });
+        reverse = false;
+    }
+    // REVERSE modifies the key following it
+    match criteria.is_empty() || reverse {
+        true => None,
+        false => Some(criteria),
+    }
+}
+
+/// What SORT and THREAD order messages by, taken from the index and the
+/// message header.
+pub struct SortMessage {
+    /// Sequence number, or UID for the UID commands.
+    pub number: u32,
+
+    pub arrival: i64,
+    pub size: u32,
+
+    /// Sent date in seconds since the epoch, the internal date if the
+    /// message has no valid `Date:`.
+    pub date: i64,
+
+    /// Base subject (RFC 5256), uppercased to compare as i;ascii-casemap.
+    pub subject: String,
+
+    /// Whether the subject marked the message as a reply or forward.
+    pub reply: bool,
+
+    /// Mailbox of the first address of the field, uppercased.
+    pub from: String,
+    pub to: String,
+    pub cc: String,
+
+    /// Display name of the first address of the field, else its address,
+    /// lowercased (RFC 5957).
+    pub display_from: String,
+    pub display_to: String,
+
+    pub message_id: Option<String>,
+
+    /// Message-ids of the `References:` field, else the first one of
+    /// `In-Reply-To:`.
+    pub references: Vec<String>,
+}
+
+impl SortMessage {
+    fn new(number: u32, meta: &MessageMeta, msg: Option<&Message<'_>>) -> Self {
+        let arrival = meta.internal_date.timestamp();
+        let date = msg.and_then(Message::sent_at).map(|dt| dt.timestamp());
+        let subject = msg.and_then(|msg| msg.header("Subject"));
+        let (subject, reply) = base_subject(&subject.unwrap_or_default());
+        let address = |name| msg.and_then(|msg| msg.first_address(name));
+        let (from, to, cc) = (address("From"), address("To"), address("Cc"));
+
+        let message_id = msg
+            .and_then(|msg| msg.message_ids("Message-ID").into_iter().next());
+        let mut references = msg
+            .map(|msg| msg.message_ids("References"))
+            .unwrap_or_default();
+        if references.is_empty() {
+            references = msg
+                .map(|msg| msg.message_ids("In-Reply-To"))
+                .unwrap_or_default();
+            references.truncate(1);
+        }
+
+        Self {
+            number,
+            arrival,
+            size: meta.size,
+            date: date.unwrap_or(arrival),
+            subject: subject.to_ascii_uppercase(),
+            reply,
+            from: address_mailbox(from.as_ref()),
+            to: address_mailbox(to.as_ref()),
+            cc: address_mailbox(cc.as_ref()),
+            display_from: display_name(from.as_ref()),
+            display_to: display_name(to.as_ref()),
+            message_id,
+            references,
+        }
+    }
+
+    fn compare(&self, other: &Self, key: SortKey) -> Ordering {
+        match key {
+            SortKey::Arrival => self.arrival.cmp(&other.arrival),
+            SortKey::Cc => self.cc.cmp(&other.cc),
+            SortKey::Date => self.date.cmp(&other.date),
+            SortKey::DisplayFrom => self.display_from.cmp(&other.display_from),
+            SortKey::DisplayTo => self.display_to.cmp(&other.display_to),
+            SortKey::From => self.from.cmp(&other.from),
+            SortKey::Size => self.size.cmp(&other.size),
+            SortKey::Subject => self.subject.cmp(&other.subject),
+            SortKey::To => self.to.cmp(&other.to),
+        }
+    }
+}
+
+fn address_mailbox(address: Option<&SingleInfo>) -> String {
+    let addr = address.map_or("", |a| a.addr.as_str());
+    let mailbox = addr.split('@').next().unwrap_or_default();
+    mailbox.to_ascii_uppercase()
+}
+
+fn display_name(address: Option<&SingleInfo>) -> String {
+    let name = match address {
+        Some(SingleInfo {
+            display_name: Some(name),
+            ..
+        }) if !name.trim().is_empty() => name.trim(),
+        Some(address) => address.addr.as_str(),
+        None => "",
+    };
+    name.to_lowercase()
+}
+
+/// Messages matching the search keys of a SORT or THREAD command, `args`
+/// being the charset and the keys. Invalid arguments are answered here
+/// and give `None`.
+pub async fn search<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    ext: &ExtCommand,
+    args: &[Token],
+    uid: bool,
+) -> Result<Option<Vec<u32>>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let (charset, keys) = match args.split_first() {
+        Some((charset, keys)) if !keys.is_empty() => (charset, keys),
+        _ => {
+            let msg = format!("Invalid {} arguments", ext.name);
+            s.bad_completed2(&ext.tag, &msg).await;
+            return Ok(None);
+        }
+    };
+    let mut search_args = vec![Token::Atom("CHARSET".into()), charset.clone()];
+    search_args.extend_from_slice(keys);
+
+    let line = search_line(&ext.tag, &search_args);
+    let (charset, criteria) = match Command::decode(&line) {
+        Ok((
+            _,
+            Command {
+                body:
+                    CommandBody::Search {
+                        charset, criteria, ..
+                    },
+                ..
+            },
+        )) => (charset, criteria),
+        _ => {
+            let msg = format!("Invalid {} arguments", ext.name);
+            s.bad_completed2(&ext.tag, &msg).await;
+            return Ok(None);
+        }
+    };
+
+    let encoding = match search_encoding(charset.as_ref()) {
+        Some(encoding) => encoding,
+        None => {
+            let msg = format!(
+                "[BADCHARSET ({})] Unsupported charset",
+                SUPPORTED_CHARSETS
+            );
+            s.no_completed2(&ext.tag, &msg).await;
+            return Ok(None);
+        }
+    };
+
+    let found = Searcher::new(s.session, encoding).search(&criteria, uid)?;
+    Ok(Some(found))
+}
+
+/// Load what the `found` messages are ordered by, in sequence order.
+pub fn load(
+    session: &Session,
+    found: &[u32],
+    uid: bool,
+) -> Result<Vec<SortMessage>> {
+    let (owner, mailbox) = session.selected_location()?;
+
+    let mut messages = vec![];
+    for (i, meta) in session.selected_messages()?.iter().enumerate() {
+        let number = if uid { meta.uid } else { i as u32 + 1 };
+        if found.binary_search(&number).is_err() {
+            continue;
+        }
+        // unreadable messages sort as if they had no header
+        let raw = match session.store.read_message(&owner, &mailbox, meta.uid) {
+            Ok(raw) => Some(raw),
+            Err(e) => {
+                debug!("cannot read message uid {}: {}", meta.uid, e);
+                None
+            }
+        };
+        let msg = raw.as_deref().and_then(|raw| Message::parse(raw).ok());
+        messages.push(SortMessage::new(number, meta, msg.as_ref()));
+    }
+    Ok(messages)
+}
+
+/// Order `messages` by `criteria`, then by sequence number.
+pub fn sort(
+    mut messages: Vec<SortMessage>,
+    criteria: &[SortCriterion],
+) -> Vec<u32> {
+    messages.sort_by(|a, b| {
+        criteria
+            .iter()
+            .map(|c| match c.reverse {
+                true => b.compare(a, c.key),
+                false => a.compare(b, c.key),
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.number.cmp(&b.number))
+    });
+    messages.iter().map(|m| m.number).collect()
+}
+
+/// Extract the base subject of RFC 5256 from a decoded subject, and whether
+/// the prefixes or suffixes removed marked a reply or forward.
+pub fn base_subject(subject: &str) -> (String, bool) {
+    let mut subject = subject.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mut reply = false;
+    loop {
+        // (2) trailing "(fwd)"s
+        loop {
+            let trimmed = subject.trim_end();
+            let len = trimmed.len();
+            if len >= 5
+                && trimmed.as_bytes()[len - 5..].eq_ignore_ascii_case(b"(fwd)")
+            {
+                subject = trimmed[..len - 5].to_string();
+                reply = true;
+            } else {
+                subject = trimmed.to_string();
+                break;
+            }
+        }
+
+        // (3) and (4), leading "Re:"s and "[blob]"s
+        loop {
+            let mut rest = subject.as_str();
+            while let Some((after, refwd)) = skip_leader(rest) {
+                reply |= refwd;
+                rest = after;
+            }
+            if let Some(after) =
+                skip_blob(rest).filter(|after| !after.is_empty())
+            {
+                rest = after;
+            }
+            if rest.len() == subject.len() {
+                break;
+            }
+            subject = rest.to_string();
+        }
+
+        // (5) "[fwd: subject]"
+        let len = subject.len();
+        if len >= 6
+            && subject.as_bytes()[..5].eq_ignore_ascii_case(b"[fwd:")
+            && subject.ends_with(']')
+        {
+            subject = subject[5..len - 1].to_string();
+            reply = true;
+            continue;
+        }
+        return (subject, reply);
+    }
+}
+
+/// Skip one `subj-leader`, telling whether it was a `subj-refwd` rather
+/// than whitespace.
+fn skip_leader(subject: &str) -> Option<(&str, bool)> {
+    if subject.starts_with(' ') {
+        return Some((subject.trim_start(), false));
+    }
+    let mut rest = subject;
+    loop {
+        if let Some(after) = skip_refwd(rest) {
+            return Some((after, true));
+        }
+        rest = skip_blob(rest)?;
+    }
+}
+
+/// Skip `("re" / ("fw" ["d"])) *WSP [subj-blob] ":"`.
+fn skip_refwd(subject: &str) -> Option<&str> {
+    let prefix = ["fwd", "fw", "re"].iter().find(|prefix| {
+        subject
+            .as_bytes()
+            .get(..prefix.len())
+            .is_some_and(|start| start.eq_ignore_ascii_case(prefix.as_bytes()))
+    })?;
+    let mut rest = subject[prefix.len()..].trim_start();
+    if let Some(after) = skip_blob(rest) {
+        rest = after;
+    }
+    rest.strip_prefix(':')
+}
+
+/// Skip `"[" *BLOBCHAR "]" *WSP`.
+fn skip_blob(subject: &str) -> Option<&str> {
+    let rest = subject.strip_prefix('[')?;
+    let end = rest.find(['[', ']'])?;
+    let after = rest[end..].strip_prefix(']')?;
+    Some(after.trim_start())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::ext_command::ext_command_decode;
+
+    fn criteria(list: &str) -> Option<Vec<SortCriterion>> {
+        let line = format!("a SORT {} UTF-8 ALL", list);
+        parse_criteria(&ext_command_decode(line.as_bytes()).unwrap().args[0])
+    }
+
+    fn keys(list: &str) -> Option<Vec<(SortKey, bool)>> {
+        let criteria = criteria(list)?;
+        Some(criteria.iter().map(|c| (c.key, c.reverse)).collect())
+    }
+
+    fn message(
+        number: u32,
+        arrival: &str,
+        size: u32,
+        header: &str,
+    ) -> SortMessage {
+        let meta = MessageMeta {
+            uid: number,
+            flags: vec![],
+            internal_date: DateTime::parse_from_rfc3339(arrival).unwrap(),
+            size,
+            recent: false,
+            modseq: 1,
+        };
+        let raw = format!("{}\r\n\r\nbody\r\n", header);
+        let msg = Message::parse(raw.as_bytes()).unwrap();
+        SortMessage::new(number, &meta, Some(&msg))
+    }
+
+    #[test]
+    fn parses_sort_criteria() {
+        assert_eq!(
+            keys("(REVERSE DATE subject)").unwrap(),
+            [(SortKey::Date, true), (SortKey::Subject, false)]
+        );
+        assert_eq!(
+            keys("(DISPLAYFROM REVERSE SIZE)").unwrap(),
+            [(SortKey::DisplayFrom, false), (SortKey::Size, true)]
+        );
+        for invalid in [
+            "()",
+            "(REVERSE)",
+            "(REVERSE REVERSE DATE)",
+            "(BOGUS)",
+            "DATE",
+        ] {
+            assert!(keys(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn extracts_base_subjects() {
+        let base = |subject| base_subject(subject);
+        assert_eq!(base("Hello"), ("Hello".to_string(), false));
+        assert_eq!(
+            base("  Plain \t subject "),
+            ("Plain subject".to_string(), false)
+        );
+        assert_eq!(base("Re: Hello"), ("Hello".to_string(), true));
+        assert_eq!(base("Fwd: RE[2]: Hello"), ("Hello".to_string(), true));
+        assert_eq!(base("Re: [list] Re: hi (fwd)"), ("hi".to_string(), true));
+        assert_eq!(base("[fwd: Lunch]"), ("Lunch".to_string(), true));
+        assert_eq!(base("[list] Hello"), ("Hello".to_string(), false));
+        // a blob that would leave nothing is the subject
+        assert_eq!(base("[list]"), ("[list]".to_string(), false));
+        assert_eq!(base("Rex: y"), ("Rex: y".to_string(), false));
+    }
+
+    #[test]
+    fn sorts_by_criteria_then_number() {
+        let messages = || {
+            vec![
+                message(
+                    1,
+                    "2024-01-01T00:00:00Z",
+                    300,
+                    "From: Zed <a@x>\r\nSubject: Re: beta\r\n\
+                     Date: Wed, 3 Jan 2024 00:00:00 +0000",
+                ),
+                // sent on its arrival, without a Date
+                message(
+                    2,
+                    "2024-01-05T00:00:00Z",
+                    100,
+                    "From: b@x\r\nSubject: alpha",
+                ),
+                message(
+                    3,
+                    "2024-01-02T00:00:00Z",
+                    200,
+                    "From: Amy <c@x>\r\nSubject: Beta\r\n\
+                     Date: Tue, 2 Jan 2024 00:00:00 +0000",
+                ),
+            ]
+        };
+        let sorted = |list| sort(messages(), &criteria(list).unwrap());
+
+        assert_eq!(sorted("(SUBJECT)"), [2, 1, 3]);
+        assert_eq!(sorted("(SUBJECT DATE)"), [2, 3, 1]);
+        assert_eq!(sorted("(DATE)"), [3, 1, 2]);
+        assert_eq!(sorted("(ARRIVAL)"), [1, 3, 2]);
+        assert_eq!(sorted("(REVERSE SIZE)"), [1, 3, 2]);
+        assert_eq!(sorted("(FROM)"), [1, 2, 3]);
+        assert_eq!(sorted("(REVERSE FROM)"), [3, 2, 1]);
+        assert_eq!(sorted("(DISPLAYFROM)"), [3, 2, 1]);
+        assert_eq!(sorted("(TO)"), [1, 2, 3]);
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::anyhow;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::quoted;
+use crate::ext_command::Token;
+use crate::imap_serv::IMAPServ;
+use crate::result::Result;
+
+/// Status data items STATUS and LIST-STATUS can report.
+pub const STATUS_ITEMS: [&str; 8] = [
+    "MESSAGES",
+    "RECENT",
+    "UIDNEXT",
+    "UIDVALIDITY",
+    "UNSEEN",
+    "DELETED",
+    "SIZE",
+    "HIGHESTMODSEQ",
+];
+
+/// Parse the `mailbox (items)` arguments of STATUS, `None` if invalid.
+pub fn parse_args(args: &[Token]) -> Option<(String, Vec<String>)> {
+    let (mailbox, items) = match args {
+        [mailbox, Token::List(items)] => (mailbox.as_astring()?, items),
+        _ => return None,
+    };
+    let items = items
+        .iter()
+        .map(|item| {
+            let item = item.as_astring()?.to_uppercase();
+            STATUS_ITEMS.contains(&item.as_str()).then_some(item)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some((mailbox, items))
+}
+
+/// Send `* STATUS` for `mailbox` with the values of `items`, all computed
+/// from its index without opening any message.
+pub async fn send_status<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    mailbox: &str,
+    items: &[String],
+) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let (owner, located) = s.session.locate(mailbox)?;
+    let index = s.session.store.load_index(&owner, &located)?;
+
+    let mut values = vec![];
+    for item in items {
+        let value = match item.as_str() {
+            "MESSAGES" => index.messages.len() as u64,
+            "RECENT" => {
+                // this session took the \Recent of the mailbox it selected,
+                // a read-only selection left it in the index
+                let taken = match s.session.selected.as_ref() {
+                    Some(selected)
+                        if selected.name == mailbox && !selected.read_only =>
+                    {
+                        selected.recent.len()
+                    }
+                    _ => 0,
+                };
+                (index.messages.iter().filter(|m| m.recent).count() + taken)
+                    as u64
+            }
+            "UIDNEXT" => index.uid_next as u64,
+            "UIDVALIDITY" => index.uid_validity as u64,
+            "UNSEEN" => index
+                .messages
+                .iter()
+                .filter(|m| !m.has_flag("\\Seen"))
+                .count() as u64,
+            "DELETED" => index
+                .messages
+                .iter()
+                .filter(|m| m.has_flag("\\Deleted"))
+                .count() as u64,
+            // RFC 8438
+            "SIZE" => index.messages.iter().map(|m| m.size as u64).sum(),
+            "HIGHESTMODSEQ" => index.highest_modseq,
+            _ => return Err(anyhow!("Unknown status item {}", item).into()),
+        };
+        values.push(format!("{} {}", item, value));
+    }
+
+    let name = quoted(&s.session.client_name(mailbox));
+    s.status(&format!("STATUS {} ({})", name, values.join(" ")))
+        .await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::storage::INBOX;
+    use crate::testing::{add_message, mail_dir, run, session};
+
+    #[tokio::test]
+    async fn reports_status_items() {
+        let root = mail_dir("status");
+        let mut s = session(&root);
+        add_message(&s, INBOX, "Subject: a\r\n\r\n", &["\\Seen"]);
+        add_message(&s, INBOX, "Subject: bb\r\n\r\n", &["\\Deleted"]);
+        add_message(&s, INBOX, "Subject: ccc\r\n\r\n", &[]);
+        let index = s.store.load_index("bob", INBOX).unwrap();
+
+        let out = run(&mut s, "a STATUS INBOX (MESSAGES UIDNEXT UNSEEN)").await;
+        assert_eq!(
+            out,
+            "* STATUS \"INBOX\" (MESSAGES 3 UIDNEXT 4 UNSEEN 2)\r\n\
+             a OK STATUS completed\r\n"
+        );
+        let out =
+            run(&mut s, "a STATUS inbox (DELETED SIZE UIDVALIDITY)").await;
+        let expected = format!(
+            "* STATUS \"INBOX\" (DELETED 1 SIZE 45 UIDVALIDITY {})\r\n",
+            index.uid_validity
+        );
+        assert!(out.starts_with(&expected), "{}", out);
+        let out = run(&mut s, "a STATUS INBOX (HIGHESTMODSEQ)").await;
+        let expected = format!("(HIGHESTMODSEQ {})", index.highest_modseq);
+        assert!(out.contains(&expected), "{}", out);
+
+        // the \Recent this session took by selecting the mailbox count too
+        assert!(run(&mut s, "a STATUS INBOX (RECENT)")
+            .await
+            .contains("RECENT 3"));
+        run(&mut s, "a SELECT INBOX").await;
+        assert!(run(&mut s, "a STATUS INBOX (RECENT)")
+            .await
+            .contains("RECENT 3"));
+
+        assert!(run(&mut s, "a STATUS Missing (MESSAGES)")
+            .await
+            .starts_with("a NO "));
+        assert!(run(&mut s, "a STATUS INBOX (BOGUS)")
+            .await
+            .starts_with("a BAD "));
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::HashMap;
+
+use imap_codec::flag::{StoreResponse, StoreType};
+use imap_codec::sequence::SequenceSet;
+
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use super::{flags_list, resolve_sequence_set};
+use crate::acl;
+use crate::events::MailboxChange;
+use crate::ext_command::Token;
+use crate::imap_serv::IMAPServ;
+use crate::result::Result;
+use crate::storage::SYSTEM_FLAGS;
+
+/// Apply a STORE of `flags` to the selected mailbox and report the new flags
+/// unless `.SILENT` was requested. Messages changed after `unchanged_since`
+/// are left alone and returned, as sequence numbers or UIDs like the command
+/// used.
+pub async fn handle_store<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    sequence_set: &SequenceSet,
+    kind: StoreType,
+    response: StoreResponse,
+    flags: &[String],
+    uid: bool,
+    unchanged_since: Option<u64>,
+) -> Result<Vec<u32>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let (owner, mailbox) = s.session.selected_location()?;
+    let selected = s.session.writable_selected()?;
+    let name = selected.name.clone();
+    let rights = selected.rights.clone();
+    let targets = resolve_sequence_set(selected, sequence_set, uid);
+    // flags the user has no right to change silently keep their state
+    let allowed = |flag: &String| rights.contains(acl::flag_right(flag));
+    let flags: Vec<String> =
+        flags.iter().filter(|f| allowed(f)).cloned().collect();
+
+    let mut updated = vec![];
+    let mut modified = vec![];
+    s.session
+        .store
+        .update_index(&owner, &mailbox, &mut |index| {
+            let positions: HashMap<u32, usize> = index
+                .messages
+                .iter()
+                .enumerate()
+                .map(|(i, m)| (m.uid, i))
+                .collect();
+
+            // one mod-sequence for all the messages the command changes
+            let mut modseq = None;
+            for (seq, target_uid) in targets.iter() {
+                let i = match positions.get(target_uid) {
+                    Some(i) => *i,
+                    None => continue,
+                };
+                if unchanged_since.is_some_and(|n| index.messages[i].modseq > n)
+                {
+                    modified.push(if uid { *target_uid } else { *seq });
+                    continue;
+                }
+
+                let before = &index.messages[i].flags;
+                let mut after = before.clone();
+                match kind {
+                    StoreType::Replace => {
+                        after.retain(|f| !allowed(f));
+                        after.extend(flags.iter().cloned());
+                    }
+                    StoreType::Add => after.extend(flags.iter().cloned()),
+                    StoreType::Remove => after.retain(|f| {
+                        !flags.iter().any(|n| n.eq_ignore_ascii_case(f))
+                    }),
+                }
+                normalize_flags(&mut after);
+                debug!("uid {} flags: {:?} -> {:?}", target_uid, before, after);
+
+                let changed = after != *before;
+                if changed && modseq.is_none() {
+                    modseq = Some(index.next_modseq());
+                }
+                let meta = &mut index.messages[i];
+                if let Some(modseq) = modseq.filter(|_| changed) {
+                    meta.flags = after;
+                    meta.modseq = modseq;
+                }
+                updated.push((*seq, meta.clone(), changed));
+            }
+        })?;
+
+    let changed = updated.iter().filter(|(_, _, changed)| *changed);
+    let uids = changed.map(|(_, meta, _)| meta.uid).collect();
+    s.session.publish(&name, MailboxChange::Flags(uids))?;
+
+    // with CONDSTORE the new mod-sequences are sent even when silent
+    let condstore = s.session.condstore();
+    for (seq, meta, changed) in updated {
+        let mut items = vec![];
+        if response != StoreResponse::Silent {
+            let recent = s.session.selected()?.recent.contains(&meta.uid);
+            items.push(format!("FLAGS {}", flags_list(&meta, recent)));
+        } else if !(condstore && changed) {
+            continue;
+        }
+        if uid {
+            items.push(format!("UID {}", meta.uid));
+        }
+        if condstore {
+            items.push(format!("MODSEQ ({})", meta.modseq));
+        }
+        s.status(&format!("{} FETCH ({})", seq, items.join(" ")))
+            .await;
+    }
+
+    Ok(modified)
+}
+
+/// Flags of a STORE or APPEND, `None` if one can't be stored: `\Recent` and
+/// unknown system flags aren't in PERMANENTFLAGS.
+pub(super) fn parse_flags(tokens: &[Token]) -> Option<Vec<String>> {
+    let mut flags = vec![];
+    for flag in tokens {
+        let flag = match flag {
+            Token::Atom(flag) => flag,
+            _ => return None,
+        };
+        if flag.starts_with('\\')
+            && !SYSTEM_FLAGS.iter().any(|f| f.eq_ignore_ascii_case(flag))
+        {
+            return None;
+        }
+        flags.push(flag.clone());
+    }
+    normalize_flags(&mut flags);
+    Some(flags)
+}
+
+/// Arguments of a STORE imap-codec can't decode, one with modifiers.
+pub struct StoreArgs {
+    pub sequence_set: SequenceSet,
+    pub unchanged_since: Option<u64>,
+    pub kind: StoreType,
+    pub response: StoreResponse,
+    pub flags: Vec<String>,
+}
+
+/// Parse `set [(UNCHANGEDSINCE n)] [+|-]FLAGS[.SILENT] flags`, `None` if
+/// invalid.
+pub fn parse_args(args: &[Token]) -> Option<StoreArgs> {
+    let (sequence_set, mut rest) = args.split_first()?;
+    let sequence_set = sequence_set.as_sequence_set()?;
+
+    let mut unchanged_since = None;
+    if let Some(modifiers) = rest.first().and_then(Token::as_list) {
+        match modifiers {
+            [name, value] if name.is_atom("UNCHANGEDSINCE") => {
+                unchanged_since = Some(value.as_number()?);
+            }
+            _ => return None,
+        }
+        rest = &rest[1..];
+    }
+
+    let (item, flags) = rest.split_first()?;
+    let item = item.as_astring()?.to_ascii_uppercase();
+    let (item, response) = match item.strip_suffix(".SILENT") {
+        Some(item) => (item.to_string(), StoreResponse::Silent),
+        None => (item, StoreResponse::Answer),
+    };
+    let kind = match item.as_str() {
+        "FLAGS" => StoreType::Replace,
+        "+FLAGS" => StoreType::Add,
+        "-FLAGS" => StoreType::Remove,
+        _ => return None,
+    };
+    let flags = match flags {
+        [Token::List(flags)] => parse_flags(flags)?,
+        flags => parse_flags(flags)?,
+    };
+
+    Some(StoreArgs {
+        sequence_set,
+        unchanged_since,
+        kind,
+        response,
+        flags,
+    })
+}
+
+/// Spell system flags the canonical way and drop duplicates.
+fn normalize_flags(flags: &mut Vec<String>) {
+    for flag in flags.iter_mut() {
+        if let Some(system) =
+            SYSTEM_FLAGS.iter().find(|f| f.eq_ignore_ascii_case(flag))
+        {
+            *flag = system.to_string();
+        }
+    }
+
+    let mut seen: Vec<String> = vec![];
+    flags.retain(|f| {
+        let lower = f.to_ascii_lowercase();
+        let duplicate = seen.contains(&lower);
+        seen.push(lower);
+        !duplicate
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::storage::INBOX;
+    use crate::testing::{add_message, mail_dir, other_session, run, session};
+
+    #[tokio::test]
+    async fn stores_and_reports_flags() {
+        let root = mail_dir("store");
+        let mut s = session(&root);
+        add_message(&s, INBOX, "Subject: a\r\n\r\n", &["\\Seen"]);
+        add_message(&s, INBOX, "Subject: b\r\n\r\n", &[]);
+        run(&mut s, "a SELECT INBOX").await;
+
+        let out = run(&mut s, "a STORE 1:2 +FLAGS (\\flagged $Work)").await;
+        assert!(
+            out.contains("* 1 FETCH (FLAGS (\\Seen \\Flagged $Work \\Recent))")
+        );
+        assert!(out.contains("* 2 FETCH (FLAGS (\\Flagged $Work \\Recent))"));
+        assert!(out.ends_with("a OK STORE completed\r\n"));
+
+        // system flags are spelled the canonical way, once
+        let out = run(&mut s, "a UID STORE 2 FLAGS (\\SEEN \\seen)").await;
+        assert!(out.contains("* 2 FETCH (FLAGS (\\Seen \\Recent) UID 2)"));
+
+        let out = run(&mut s, "a STORE 1 -FLAGS.SILENT ($work)").await;
+        assert!(!out.contains("FETCH"));
+
+        let index = s.store.load_index("bob", INBOX).unwrap();
+        assert_eq!(index.messages[0].flags, ["\\Seen", "\\Flagged"]);
+        assert_eq!(index.messages[1].flags, ["\\Seen"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stores_unless_changed_since() {
+        let root = mail_dir("store-unchangedsince");
+        let mut s = session(&root);
+        for subject in ["a", "b", "c"] {
+            let raw = format!("Subject: {}\r\n\r\n", subject);
+            add_message(&s, INBOX, &raw, &[]);
+        }
+        run(&mut s, "a SELECT INBOX (CONDSTORE)").await;
+        let since = s.store.load_index("bob", INBOX).unwrap().highest_modseq;
+        run(&mut s, "a STORE 2 +FLAGS.SILENT ($Work)").await;
+
+        let line =
+            format!("a STORE 1:3 (UNCHANGEDSINCE {}) +FLAGS (\\Seen)", since);
+        let out = run(&mut s, &line).await;
+        let modseq = since + 2;
+        assert_eq!(
+            out,
+            format!(
+                "* 1 FETCH (FLAGS (\\Seen \\Recent) MODSEQ ({0}))\r\n\
+                 * 3 FETCH (FLAGS (\\Seen \\Recent) MODSEQ ({0}))\r\n\
+                 a OK [MODIFIED 2] Conditional STORE failed\r\n",
+                modseq
+            )
+        );
+        let index = s.store.load_index("bob", INBOX).unwrap();
+        assert_eq!(index.messages[1].flags, ["$Work"]);
+        assert_eq!(index.highest_modseq, modseq);
+
+        // a silent STORE still sends the new mod-sequences, UIDs as asked
+        let line = format!(
+            "a UID STORE 2:3 (UNCHANGEDSINCE {}) FLAGS.SILENT ()",
+            modseq - 1
+        );
+        let out = run(&mut s, &line).await;
+        assert_eq!(
+            out,
+            format!(
+                "* 2 FETCH (UID 2 MODSEQ ({}))\r\n\
+                 a OK [MODIFIED 3] Conditional UID STORE failed\r\n",
+                modseq + 1
+            )
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn stores_only_the_flags_the_rights_allow() {
+        let root = mail_dir("store-acl");
+        let mut bob = session(&root);
+        add_message(&bob, INBOX, "Subject: a\r\n\r\n", &["$Work"]);
+        run(&mut bob, "a SETACL INBOX alice lrs").await;
+        let mut alice = other_session(&bob);
+        alice.user = Some("alice".to_string());
+        run(&mut alice, "a SELECT \"Other Users/bob/INBOX\"").await;
+
+        let flags = || {
+            let index = bob.store.load_index("bob", INBOX).unwrap();
+            index.messages[0].flags.clone()
+        };
+        run(&mut alice, "a STORE 1 +FLAGS (\\Seen \\Flagged)").await;
+        assert_eq!(flags(), ["$Work", "\\Seen"]);
+        // replacing leaves the flags without the right to change alone
+        let out = run(&mut alice, "a STORE 1 FLAGS (\\Deleted)").await;
+        assert!(out.starts_with("* 1 FETCH (FLAGS ($Work \\Recent))"));
+        assert_eq!(flags(), ["$Work"]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+
+use super::sort_handler::SortMessage;
+
+/// The most references of a message followed, its last ones.
+const MAX_REFERENCES: usize = 50;
+
+/// The most ancestors walked when checking a link doesn't make a loop.
+const MAX_DEPTH: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadAlgorithm {
+    OrderedSubject,
+    References,
+}
+
+impl ThreadAlgorithm {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_uppercase().as_str() {
+            "ORDEREDSUBJECT" => Some(Self::OrderedSubject),
+            "REFERENCES" => Some(Self::References),
+            _ => None,
+        }
+    }
+}
+
+/// A message of a thread, `None` for a missing one others refer to.
+///
+/// Nodes are kept in one arena and refer to each other by index, so that
+/// however deep a thread is it is never walked, nor dropped, recursively.
+#[derive(Default)]
+struct Node {
+    message: Option<usize>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+impl Node {
+    fn new(message: Option<usize>) -> Self {
+        Self {
+            message,
+            ..Self::default()
+        }
+    }
+}
+
+/// The message `node` is dated and named by, its first child's for a
+/// missing message.
+fn first_message(nodes: &[Node], mut node: usize) -> Option<usize> {
+    loop {
+        if let Some(message) = nodes[node].message {
+            return Some(message);
+        }
+        node = *nodes[node].children.first()?;
+    }
+}
+
+/// The nodes of the threads of `roots`, each before its replies.
+fn walk(nodes: &[Node], roots: &[usize]) -> Vec<usize> {
+    let mut order = vec![];
+    let mut stack = roots.to_vec();
+    while let Some(node) = stack.pop() {
+        order.push(node);
+        stack.extend(&nodes[node].children);
+    }
+    order
+}
+
+/// The threads of `messages` as the body of a `* THREAD` response.
+pub fn threads(messages: &[SortMessage], algorithm: ThreadAlgorithm) -> String {
+    let mut nodes = vec![];
+    let roots = match algorithm {
+        ThreadAlgorithm::OrderedSubject => {
+            ordered_subject(&mut nodes, messages)
+        }
+        ThreadAlgorithm::References => references(&mut nodes, messages),
+    };
+    roots
+        .iter()
+        .map(|&root| format!("({})", thread_string(&nodes, root, messages)))
+        .collect()
+}
+
+/// ORDEREDSUBJECT: messages with the same base subject are replies to the
+/// first one sent.
+fn ordered_subject(
+    nodes: &mut Vec<Node>,
+    messages: &[SortMessage],
+) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..messages.len()).collect();
+    order.sort_by(|&a, &b| {
+        let (a, b) = (&messages[a], &messages[b]);
+        (&a.subject, a.date, a.number).cmp(&(&b.subject, b.date, b.number))
+    });
+
+    let mut roots: Vec<usize> = vec![];
+    let mut subject = None;
+    for i in order {
+        nodes.push(Node::new(Some(i)));
+        let node = nodes.len() - 1;
+        match roots.last() {
+            Some(&root) if subject == Some(&messages[i].subject) => {
+                link(nodes, root, node);
+            }
+            _ => {
+                roots.push(node);
+                subject = Some(&messages[i].subject);
+            }
+        }
+    }
+    sort_nodes(nodes, &mut roots, messages);
+    roots
+}
+
+/// REFERENCES: messages are linked by their `References:` and
+/// `In-Reply-To:` fields, then threads are merged by base subject.
+fn references(nodes: &mut Vec<Node>, messages: &[SortMessage]) -> Vec<usize> {
+    let mut ids: HashMap<&str, usize> = HashMap::new();
+
+    for (i, message) in messages.iter().enumerate() {
+        let id = message.message_id.as_deref();
+        let own = match id.and_then(|id| ids.get(id)).copied() {
+            Some(c) if nodes[c].message.is_none() => c,
+            known => {
+                nodes.push(Node::default());
+                let c = nodes.len() - 1;
+                // duplicated message-ids are left unreferenced
+                if let (Some(id), None) = (id, known) {
+                    ids.insert(id, c);
+                }
+                c
+            }
+        };
+        nodes[own].message = Some(i);
+
+        let references = &message.references;
+        let skipped = references.len().saturating_sub(MAX_REFERENCES);
+        let mut previous = None;
+        for reference in references[skipped..].iter() {
+            let c = *ids.entry(reference).or_insert_with(|| {
+                nodes.push(Node::default());
+                nodes.len() - 1
+            });
+            if let Some(p) = previous {
+                if nodes[c].parent.is_none() && !is_ancestor(nodes, c, p) {
+                    link(nodes, p, c);
+                }
+            }
+            previous = Some(c);
+        }
+
+        // the last reference is the parent, whatever was guessed before
+        unlink(nodes, own);
+        if let Some(p) = previous {
+            if !is_ancestor(nodes, own, p) {
+                link(nodes, p, own);
+            }
+        }
+    }
+
+    let roots: Vec<usize> = (0..nodes.len())
+        .filter(|&c| nodes[c].parent.is_none())
+        .collect();
+    let mut roots = prune(nodes, roots);
+    sort_nodes(nodes, &mut roots, messages);
+    let mut roots = merge_subjects(nodes, roots, messages);
+    sort_nodes(nodes, &mut roots, messages);
+    roots
+}
+
+/// Whether `a` is `b` or one of its ancestors, taken to be when `b` is
+/// more than `MAX_DEPTH` deep rather than walking further.
+fn is_ancestor(nodes: &[Node], a: usize, b: usize) -> bool {
+    let mut c = Some(b);
+    for _ in 0..MAX_DEPTH {
+        match c {
+            Some(current) if current == a => return true,
+            Some(current) => c = nodes[current].parent,
+            None => return false,
+        }
+    }
+    true
+}
+
+fn link(nodes: &mut [Node], parent: usize, child: usize) {
+    nodes[child].parent = Some(parent);
+    nodes[parent].children.push(child);
+}
+
+fn unlink(nodes: &mut [Node], child: usize) {
+    if let Some(parent) = nodes[child].parent.take() {
+        nodes[parent].children.retain(|&c| c != child);
+    }
+}
+
+/// Drop missing messages without replies and replace the others by their
+/// replies, unless that would make several threads of one.
+fn prune(nodes: &mut [Node], roots: Vec<usize>) -> Vec<usize> {
+    // replies are pruned before the message they reply to
+    for node in walk(nodes, &roots).into_iter().rev() {
+        let children = std::mem::take(&mut nodes[node].children);
+        let mut pruned = vec![];
+        for child in children {
+            if nodes[child].message.is_none() {
+                pruned.append(&mut nodes[child].children);
+            } else {
+                pruned.push(child);
+            }
+        }
+        nodes[node].children = pruned;
+    }
+
+    let mut pruned = vec![];
+    for root in roots {
+        if nodes[root].message.is_none() && nodes[root].children.len() <= 1 {
+            pruned.append(&mut nodes[root].children);
+        } else {
+            pruned.push(root);
+        }
+    }
+    pruned
+}
+
+/// Gather threads whose first messages have the same base subject.
+fn merge_subjects(
+    nodes: &mut Vec<Node>,
+    roots: Vec<usize>,
+    messages: &[SortMessage],
+) -> Vec<usize> {
+    let subjects: Vec<Option<&SortMessage>> = roots
+        .iter()
+        .map(|&root| first_message(nodes, root).map(|i| &messages[i]))
+        .map(|m| m.filter(|m| !m.subject.is_empty()))
+        .collect();
+
+    // the thread each subject joins: a missing message's, else one which
+    // isn't a reply
+    let mut table: HashMap<&str, usize> = HashMap::new();
+    for (i, message) in subjects.iter().enumerate() {
+        let message = match message {
+            Some(message) => message,
+            None => continue,
+        };
+        let j = *table.entry(&message.subject).or_insert(i);
+        let replace = match (nodes[roots[j]].message, nodes[roots[i]].message) {
+            (Some(_), None) => true,
+            (Some(_), Some(_)) => {
+                subjects[j].is_some_and(|m| m.reply) && !message.reply
+            }
+            _ => false,
+        };
+        if replace {
+            table.insert(&message.subject, i);
+        }
+    }
+
+    let mut slots: Vec<Option<usize>> = roots.into_iter().map(Some).collect();
+    for (i, message) in subjects.iter().enumerate() {
+        let message = match message {
+            Some(message) => message,
+            None => continue,
+        };
+        let j = table[message.subject.as_str()];
+        if j == i {
+            continue;
+        }
+        let current = slots[i].take().unwrap();
+        let target = slots[j].unwrap();
+        let target_reply = subjects[j].is_some_and(|m| m.reply);
+        match (nodes[target].message, nodes[current].message) {
+            (None, None) => {
+                let mut children = std::mem::take(&mut nodes[current].children);
+                nodes[target].children.append(&mut children);
+            }
+            (None, Some(_)) => nodes[target].children.push(current),
+            (Some(_), Some(_)) if !target_reply && message.reply => {
+                nodes[target].children.push(current)
+            }
+            _ => {
+                nodes.push(Node::new(None));
+                let missing = nodes.len() - 1;
+                nodes[missing].children = vec![target, current];
+                slots[j] = Some(missing);
+            }
+        }
+    }
+    slots.into_iter().flatten().collect()
+}
+
+/// Order siblings by sent date, then by sequence number.
+fn sort_nodes(
+    nodes: &mut [Node],
+    roots: &mut [usize],
+    messages: &[SortMessage],
+) {
+    let key = |first: Option<usize>| {
+        first.map(|i| (messages[i].date, messages[i].number))
+    };
+    // a missing message is dated by its first reply, so replies are sorted
+    // before the message they reply to
+    let mut first = vec![None; nodes.len()];
+    for node in walk(nodes, roots).into_iter().rev() {
+        let mut children = std::mem::take(&mut nodes[node].children);
+        children.sort_by_key(|&child| key(first[child]));
+        first[node] = nodes[node]
+            .message
+            .or_else(|| children.first().and_then(|&child| first[child]));
+        nodes[node].children = children;
+    }
+    roots.sort_by_key(|&root| key(first[root]));
+}
+
+/// A thread as in the THREAD response, replies following their message
+/// and parenthesized when there are several.
+fn thread_string(
+    nodes: &[Node],
+    root: usize,
+    messages: &[SortMessage],
+) -> String {
+    enum Part {
+        Node(usize),
+        Text(&'static str),
+    }
+
+    let mut thread = String::new();
+    let mut parts = vec![Part::Node(root)];
+    while let Some(part) = parts.pop() {
+        let node = match part {
+            Part::Node(node) => &nodes[node],
+            Part::Text(text) => {
+                thread.push_str(text);
+                continue;
+            }
+        };
+        if let Some(i) = node.message {
+            thread.push_str(&messages[i].number.to_string());
+        }
+        // parts are taken from the end, so pushed in reverse
+        match node.children.as_slice() {
+            [] => {}
+            [child] if node.message.is_some() => {
+                parts.push(Part::Node(*child));
+                parts.push(Part::Text(" "));
+            }
+            children => {
+                for &child in children.iter().rev() {
+                    parts.push(Part::Text(")"));
+                    parts.push(Part::Node(child));
+                    parts.push(Part::Text("("));
+                }
+                if node.message.is_some() {
+                    parts.push(Part::Text(" "));
+                }
+            }
+        }
+    }
+    thread
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::sort_handler::base_subject;
+    use super::*;
+
+    fn message(
+        number: u32,
+        date: i64,
+        subject: &str,
+        id: &str,
+        references: &[&str],
+    ) -> SortMessage {
+        let (subject, reply) = base_subject(subject);
+        SortMessage {
+            number,
+            arrival: date,
+            size: 0,
+            date,
+            subject: subject.to_ascii_uppercase(),
+            reply,
+            from: String::new(),
+            to: String::new(),
+            cc: String::new(),
+            display_from: String::new(),
+            display_to: String::new(),
+            message_id: Some(id.to_string()),
+            references: references.iter().map(|r| r.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn threads_by_subject() {
+        let messages = [
+            message(1, 10, "Hello", "a", &[]),
+            message(2, 5, "Other", "b", &[]),
+            message(3, 20, "Re: hello", "c", &[]),
+            message(4, 15, "Re: Hello", "d", &[]),
+        ];
+        assert_eq!(
+            threads(&messages, ThreadAlgorithm::OrderedSubject),
+            "(2)(1 (4)(3))"
+        );
+    }
+
+    #[test]
+    fn threads_by_references() {
+        let messages = [
+            message(1, 10, "Hello", "a", &[]),
+            message(2, 20, "Re: Hello", "b", &["a"]),
+            message(3, 30, "Re: Hello", "c", &["a", "b"]),
+            // replies to a message not in the mailbox
+            message(4, 5, "Lunch", "d", &["missing"]),
+            message(5, 25, "Re: Lunch", "e", &["missing"]),
+            // a reply by subject alone joins the thread
+            message(6, 40, "Re: Hello", "f", &[]),
+            message(7, 1, "Alone", "g", &[]),
+        ];
+        assert_eq!(
+            threads(&messages, ThreadAlgorithm::References),
+            "(7)((4)(5))(1 (2 3)(6))"
+        );
+    }
+
+    #[test]
+    fn threads_long_chains() {
+        let ids: Vec<String> = (0..5000).map(|i| i.to_string()).collect();
+        let messages: Vec<SortMessage> = (0..ids.len())
+            .map(|i| {
+                let references: Vec<&str> =
+                    ids[..i].iter().map(String::as_str).collect();
+                let references = &references[i.saturating_sub(3)..];
+                message(i as u32 + 1, i as i64, "Chain", &ids[i], references)
+            })
+            .collect();
+        let threads = threads(&messages, ThreadAlgorithm::References);
+        // chains too deep are cut, then gathered again by subject
+        assert!(threads.starts_with("((1 2 3 4 "));
+        assert!(threads.contains(" 1000)(1001 1002 "));
+        assert!(threads.ends_with(" 4999 5000))"));
+    }
+
+    #[test]
+    fn follows_the_last_references() {
+        let ids: Vec<String> = (0..1000).map(|i| i.to_string()).collect();
+        let references: Vec<&str> = ids.iter().map(String::as_str).collect();
+        let messages = [
+            message(1, 10, "Hello", "0", &[]),
+            message(2, 20, "Lunch", "reply", &references),
+        ];
+        assert_eq!(threads(&messages, ThreadAlgorithm::References), "(1)(2)");
+    }
+
+    #[test]
+    fn parses_algorithms() {
+        assert_eq!(
+            ThreadAlgorithm::parse("references"),
+            Some(ThreadAlgorithm::References)
+        );
+        assert_eq!(
+            ThreadAlgorithm::parse("ORDEREDSUBJECT"),
+            Some(ThreadAlgorithm::OrderedSubject)
+        );
+        assert_eq!(ThreadAlgorithm::parse("REFS"), None);
+    }
+}
@@ -0,0 +1,283 @@
+use std::collections::{HashMap, HashSet};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+
+use super::{expunge_handler, flags_list};
+use crate::events::{MailboxChange, MailboxEvent};
+use crate::imap_serv::IMAPServ;
+use crate::result::Result;
+use crate::storage::MessageMeta;
+
+/// Send the changes other sessions made to the selected mailbox since they
+/// were last reported.
+pub async fn send_pending<IO>(s: &mut IMAPServ<'_, IO>) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        let received = match s.session.selected.as_mut() {
+            Some(selected) => selected.events.try_recv(),
+            None => return Ok(()),
+        };
+        let event = match received {
+            Ok(event) => Ok(event),
+            Err(TryRecvError::Lagged(n)) => Err(RecvError::Lagged(n)),
+            Err(_) => return Ok(()),
+        };
+        apply(s, event).await?;
+    }
+}
+
+/// Send the untagged responses of an event of the selected mailbox and
+/// update the session's numbering. Events the session missed make it
+/// resynchronize with the index.
+pub async fn apply<IO>(
+    s: &mut IMAPServ<'_, IO>,
+    event: std::result::Result<MailboxEvent, RecvError>,
+) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let event = match event {
+        Ok(event) => event,
+        Err(RecvError::Lagged(_)) => return resync(s).await,
+        Err(RecvError::Closed) => return Ok(()),
+    };
+
+    match event.change {
+        // the session's own appends and copies are unknown to it as well
+        MailboxChange::Exists(uids) => add_messages(s, &uids).await,
+        _ if event.origin == s.session.id => Ok(()),
+        MailboxChange::Flags(uids) => send_flags(s, &uids).await,
+        MailboxChange::Expunge(uids) => {
+            expunge_handler::forget_messages(s, &uids, false).await
+        }
+    }
+}
+
+/// Number new messages after the known ones and report them, taking their
+/// `\Recent` unless the mailbox is read-only or the session IMAP4rev2.
+async fn add_messages<IO>(s: &mut IMAPServ<'_, IO>, uids: &[u32]) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let selected = match s.session.selected.as_ref() {
+        Some(selected) => selected,
+        None => return Ok(()),
+    };
+    let known: HashSet<u32> = selected.uids.iter().copied().collect();
+    let mut added: Vec<u32> = uids
+        .iter()
+        .copied()
+        .filter(|u| !known.contains(u))
+        .collect();
+    if added.is_empty() {
+        return Ok(());
+    }
+    added.sort_unstable();
+
+    let rev2 = s.session.imap4rev2();
+    let mut taken = vec![];
+    if !selected.read_only && !rev2 {
+        let (owner, mailbox) = s.session.locate(&selected.name)?;
+        let new: HashSet<u32> = added.iter().copied().collect();
+        s.session
+            .store
+            .update_index(&owner, &mailbox, &mut |index| {
+                for meta in index.messages.iter_mut() {
+                    if meta.recent && new.contains(&meta.uid) {
+                        meta.recent = false;
+                        taken.push(meta.uid);
+                    }
+                }
+            })?;
+    }
+
+    let selected = s.session.selected.as_mut().unwrap();
+    selected.uids.extend(added);
+    selected.recent.extend(taken);
+    let exists = selected.uids.len();
+    let recent = selected.recent.len();
+    s.status(&format!("{} EXISTS", exists)).await;
+    if !rev2 {
+        s.status(&format!("{} RECENT", recent)).await;
+    }
+    Ok(())
+}
+
+/// Send `* n FETCH (FLAGS ...)` for the messages whose flags changed.
+async fn send_flags<IO>(s: &mut IMAPServ<'_, IO>, uids: &[u32]) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let (owner, mailbox) = s.session.selected_location()?;
+    let selected = s.session.selected()?;
+    let index = s.session.store.load_index(&owner, &mailbox)?;
+    let stored: HashMap<u32, &MessageMeta> =
+        index.messages.iter().map(|m| (m.uid, m)).collect();
+    let changed: HashSet<u32> = uids.iter().copied().collect();
+
+    // numbered the way the session knows them, expunges may be pending
+    let mut responses = vec![];
+    for (i, uid) in selected.uids.iter().enumerate() {
+        if let Some(meta) = stored.get(uid).filter(|_| changed.contains(uid)) {
+            let recent = selected.recent.contains(uid);
+            let mut items = format!("FLAGS {}", flags_list(meta, recent));
+            if s.session.qresync() {
+                items.push_str(&format!(" UID {}", uid));
+            }
+            if s.session.condstore() {
+                items.push_str(&format!(" MODSEQ ({})", meta.modseq));
+            }
+            responses.push(format!("{} FETCH ({})", i + 1, items));
+        }
+    }
+    for response in responses {
+        s.status(&response).await;
+    }
+    Ok(())
+}
+
+/// Bring the session up to date with the index after missing events: gone
+/// messages are expunged, new ones added and every message's flags sent.
+async fn resync<IO>(s: &mut IMAPServ<'_, IO>) -> Result<()>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let (owner, mailbox) = s.session.selected_location()?;
+    let selected = s.session.selected()?;
+    let index = s.session.store.load_index(&owner, &mailbox)?;
+    let stored: HashSet<u32> = index.messages.iter().map(|m| m.uid).collect();
+
+    let gone: Vec<u32> = selected
+        .uids
+        .iter()
+        .copied()
+        .filter(|u| !stored.contains(u))
+        .collect();
+    expunge_handler::forget_messages(s, &gone, false).await?;
+
+    let uids: Vec<u32> = index.messages.iter().map(|m| m.uid).collect();
+    add_messages(s, &uids).await?;
+    send_flags(s, &uids).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::time::timeout;
+
+    use crate::events::{MailboxChange, MailboxEvent, REMOTE_ORIGIN};
+    use crate::imap::process_command;
+    use crate::storage::INBOX;
+    use crate::testing::{add_message, mail_dir, other_session, run, session};
+
+    /// Read what the server sends until `end` comes.
+    async fn read_until(client: &mut DuplexStream, end: &str) -> String {
+        let mut read = vec![];
+        while !String::from_utf8_lossy(&read).ends_with(end) {
+            let mut buf = [0; 1024];
+            let n = timeout(Duration::from_secs(5), client.read(&mut buf))
+                .await
+                .unwrap_or_else(|_| {
+                    let read = String::from_utf8_lossy(&read);
+                    panic!("no {:?} after {:?}", end, read)
+                })
+                .unwrap();
+            assert!(n > 0, "closed after {:?}", read);
+            read.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(read).unwrap()
+    }
+
+    #[tokio::test]
+    async fn idle_reports_changes_of_other_sessions() {
+        let root = mail_dir("idle");
+        let mut s = session(&root);
+        add_message(&s, INBOX, "Subject: a\r\n\r\n", &[]);
+        add_message(&s, INBOX, "Subject: b\r\n\r\n", &[]);
+        run(&mut s, "a CREATE Archive").await;
+        add_message(&s, "Archive", "Subject: c\r\n\r\n", &[]);
+        run(&mut s, "a SELECT INBOX").await;
+
+        let mut other = other_session(&s);
+        run(&mut other, "b SELECT Archive").await;
+        let (mut client, mut server) = duplex(4096);
+        let idle = process_command(b"a IDLE\r\n", &mut server, &mut s);
+        let changes = async {
+            assert_eq!(read_until(&mut client, "\r\n").await, "+ idling\r\n");
+
+            run(&mut other, "b COPY 1 INBOX").await;
+            let out = read_until(&mut client, "RECENT\r\n").await;
+            assert_eq!(out, "* 3 EXISTS\r\n* 3 RECENT\r\n");
+
+            run(&mut other, "b SELECT INBOX").await;
+            run(&mut other, "b STORE 1 +FLAGS.SILENT (\\Deleted)").await;
+            let out = read_until(&mut client, "\r\n").await;
+            assert_eq!(out, "* 1 FETCH (FLAGS (\\Deleted \\Recent))\r\n");
+            run(&mut other, "b EXPUNGE").await;
+            assert_eq!(
+                read_until(&mut client, "\r\n").await,
+                "* 1 EXPUNGE\r\n"
+            );
+
+            client.write_all(b"DONE\r\n").await.unwrap();
+            read_until(&mut client, "a OK IDLE completed\r\n").await
+        };
+        let (result, out) = tokio::join!(idle, changes);
+        result.unwrap();
+        assert_eq!(out, "a OK IDLE completed\r\n");
+        assert_eq!(s.selected().unwrap().uids, [2, 3]);
+
+        // anything else than DONE ends the IDLE too
+        let (mut client, mut server) = duplex(4096);
+        client.write_all(b"NOOP\r\n").await.unwrap();
+        process_command(b"a IDLE\r\n", &mut server, &mut s)
+            .await
+            .unwrap();
+        drop(server);
+        let mut out = String::new();
+        client.read_to_string(&mut out).await.unwrap();
+        assert_eq!(out, "+ idling\r\na BAD Expected DONE\r\n");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn noop_and_check_report_changes_of_others() {
+        let root = mail_dir("noop");
+        let mut s = session(&root);
+        add_message(&s, INBOX, "Subject: a\r\n\r\n", &[]);
+        add_message(&s, INBOX, "Subject: b\r\n\r\n", &[]);
+        run(&mut s, "a SELECT INBOX").await;
+        let mut other = other_session(&s);
+        run(&mut other, "b SELECT INBOX").await;
+
+        // the session's own changes were reported when it made them
+        run(&mut other, "b STORE 1 +FLAGS.SILENT (\\Seen)").await;
+        run(&mut s, "a STORE 2 +FLAGS.SILENT ($Work)").await;
+        assert_eq!(
+            run(&mut s, "a NOOP").await,
+            "* 1 FETCH (FLAGS (\\Seen \\Recent))\r\na OK NOOP completed\r\n"
+        );
+        assert_eq!(run(&mut s, "a NOOP").await, "a OK NOOP completed\r\n");
+
+        // as another node would announce its expunge
+        let removed = s.store.remove_messages("bob", INBOX, &mut |_| vec![1]);
+        assert_eq!(removed.unwrap(), [1]);
+        let event = MailboxEvent {
+            origin: REMOTE_ORIGIN,
+            change: MailboxChange::Expunge(vec![1]),
+        };
+        s.events.deliver("bob", INBOX, event);
+        assert_eq!(
+            run(&mut s, "a CHECK").await,
+            "* 1 EXPUNGE\r\na OK CHECK completed\r\n"
+        );
+        assert_eq!(s.selected().unwrap().uids, [2]);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,6 +1,11 @@
+use std::borrow::Cow;
+
+use crate::error::WError;
+use crate::ext_command::{ext_command_decode, ExtCommand, Token};
+use crate::framer::{strip_literal, Literal};
 pub use crate::imap_serv::{CommandPipe, IMAPServ};
 use crate::result::Result;
-use anyhow::anyhow;
+use crate::session::Session;
 use imap_codec::command::CommandBody;
 
 use log::debug;
@@ -13,23 +18,37 @@ use imap_codec::{codec::Decode, command::Command};
 pub async fn process_command<'a, 'b, IO>(
     buf: &'a [u8],
     socket: &'b mut IO,
+    session: &'b mut Session,
 ) -> Result<CommandPipe<'a>>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    let mut imap_sock = IMAPServ::new(socket);
+    let mut imap_sock = IMAPServ::new(socket, session);
 
     // if only CRLF ignore
     if buf.len() == 2 && buf[0] == 13 && buf[1] == 10 {
         return Ok(CommandPipe::Noop);
     }
 
-    let cmd = command_decode(buf)?;
+    let cmd = match command_decode(buf) {
+        Ok(cmd) => cmd,
+        Err(e) => match ext_command_decode(buf) {
+            Some(ext) => return process_ext_command(&mut imap_sock, ext).await,
+            None => {
+                debug!("Invalid command: {}", e);
+                let tag = line_tag(buf);
+                imap_sock.bad_completed2(tag, "Invalid command").await;
+                return Ok(CommandPipe::Noop);
+            }
+        },
+    };
 
     debug!(":< {}", &cmd.body.name());
 
-    match cmd.body.clone() {
+    let result = match cmd.body.clone() {
         CommandBody::Noop => NoopHandler::handle(&mut imap_sock, &cmd).await,
+        CommandBody::Check => CheckHandler::handle(&mut imap_sock, &cmd).await,
+        CommandBody::Idle => IdleHandler::handle(&mut imap_sock, &cmd).await,
         CommandBody::List {
             reference,
             mailbox_wildcard,
@@ -45,6 +64,24 @@ where
         CommandBody::Select { mailbox } => {
             SelectHandler::handle(&mut imap_sock, &cmd, mailbox).await
         }
+        CommandBody::Examine { mailbox } => {
+            ExamineHandler::handle(&mut imap_sock, &cmd, mailbox).await
+        }
+        CommandBody::StartTLS => {
+            StartTlsHandler::handle(&mut imap_sock, &cmd).await
+        }
+        CommandBody::Authenticate {
+            mechanism,
+            initial_response,
+        } => {
+            AuthenticateHandler::handle(
+                &mut imap_sock,
+                &cmd,
+                mechanism,
+                initial_response,
+            )
+            .await
+        }
         CommandBody::Login { username, password } => {
             LoginHandler::handle(&mut imap_sock, &cmd, username, password).await
         }
@@ -62,25 +99,358 @@ where
         CommandBody::Logout => {
             LogoutHandler::handle(&mut imap_sock, &cmd).await
         }
-        CommandBody::Fetch {
+        CommandBody::Fetch { .. } => {
+            // parsed by hand like a FETCH with modifiers, so both are
+            // answered alike
+            if let Some(ext) = ext_command_decode(buf) {
+                return process_ext_command(&mut imap_sock, ext).await;
+            }
+            let tag = cmd.tag.as_ref();
+            imap_sock.bad_completed2(tag, "Invalid FETCH command").await;
+            Ok(CommandPipe::Next(cmd.clone(), None))
+        }
+        CommandBody::Expunge => {
+            ExpungeHandler::handle(&mut imap_sock, &cmd).await
+        }
+        CommandBody::Close => CloseHandler::handle(&mut imap_sock, &cmd).await,
+        CommandBody::Unselect => {
+            UnselectHandler::handle(&mut imap_sock, &cmd).await
+        }
+        CommandBody::Store {
+            sequence_set,
+            kind,
+            response,
+            flags,
+            uid,
+        } => {
+            StoreHandler::handle(
+                &mut imap_sock,
+                &cmd,
+                sequence_set,
+                kind,
+                response,
+                flags,
+                uid,
+            )
+            .await
+        }
+        CommandBody::Copy {
             sequence_set,
-            macro_or_item_names,
+            mailbox,
             uid,
         } => {
-            FetchHandler::handle(
+            CopyHandler::handle(
                 &mut imap_sock,
                 &cmd,
                 sequence_set,
-                macro_or_item_names,
+                mailbox,
                 uid,
             )
             .await
         }
-        _ => Err(anyhow!("Invalid command body for {}", cmd.name()).into()),
+        CommandBody::Move {
+            sequence_set,
+            mailbox,
+            uid,
+        } => {
+            MoveHandler::handle(
+                &mut imap_sock,
+                &cmd,
+                sequence_set,
+                mailbox,
+                uid,
+            )
+            .await
+        }
+        CommandBody::Status {
+            mailbox,
+            item_names,
+        } => {
+            StatusHandler::handle(
+                &mut imap_sock,
+                &cmd,
+                mailbox,
+                item_names.into_owned(),
+            )
+            .await
+        }
+        CommandBody::Create { mailbox } => {
+            CreateHandler::handle(&mut imap_sock, &cmd, mailbox).await
+        }
+        CommandBody::Delete { mailbox } => {
+            DeleteHandler::handle(&mut imap_sock, &cmd, mailbox).await
+        }
+        CommandBody::Rename { from, to } => {
+            RenameHandler::handle(&mut imap_sock, &cmd, from, to).await
+        }
+        CommandBody::Subscribe { mailbox } => {
+            SubscribeHandler::handle(&mut imap_sock, &cmd, mailbox).await
+        }
+        CommandBody::Unsubscribe { mailbox } => {
+            UnsubscribeHandler::handle(&mut imap_sock, &cmd, mailbox).await
+        }
+        CommandBody::Lsub {
+            reference,
+            mailbox_wildcard,
+        } => {
+            LsubHandler::handle(
+                &mut imap_sock,
+                &cmd,
+                reference,
+                mailbox_wildcard,
+            )
+            .await
+        }
+        _ => {
+            let msg = format!("Unsupported command {}", cmd.name());
+            imap_sock.bad_completed2(cmd.tag.as_ref(), &msg).await;
+            Ok(CommandPipe::Next(cmd.clone(), None))
+        }
+    };
+
+    // a failing command is reported to the client, the session goes on
+    match result {
+        Ok(cmd_pipe) => Ok(cmd_pipe),
+        Err(e) => {
+            debug!("{} failed: {}", cmd.name(), e);
+            imap_sock
+                .no_completed(&cmd.tag, &failure(cmd.name(), &e))
+                .await;
+            Ok(CommandPipe::Next(cmd.clone(), None))
+        }
+    }
+}
+
+/// Dispatch a command parsed by hand: one imap-codec doesn't know about, or
+/// FETCH.
+async fn process_ext_command<'a, IO>(
+    imap_sock: &mut IMAPServ<'_, IO>,
+    ext: ExtCommand,
+) -> Result<CommandPipe<'a>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    debug!(":< {}", ext.name);
+
+    let result = match ext.name.as_str() {
+        "UID EXPUNGE" => UidExpungeHandler::handle(imap_sock, &ext).await,
+        "LIST" => ExtendedListHandler::handle(imap_sock, &ext).await,
+        "CREATE" => ExtendedCreateHandler::handle(imap_sock, &ext).await,
+        "STATUS" => ExtendedStatusHandler::handle(imap_sock, &ext).await,
+        "ENABLE" => EnableHandler::handle(imap_sock, &ext).await,
+        "ID" => IdHandler::handle(imap_sock, &ext).await,
+        "COMPRESS" => CompressHandler::handle(imap_sock, &ext).await,
+        "NAMESPACE" => NamespaceHandler::handle(imap_sock, &ext).await,
+        "SELECT" | "EXAMINE" => {
+            ExtendedSelectHandler::handle(imap_sock, &ext).await
+        }
+        "FETCH" | "UID FETCH" => FetchHandler::handle(imap_sock, &ext).await,
+        "STORE" | "UID STORE" => {
+            ExtendedStoreHandler::handle(imap_sock, &ext).await
+        }
+        "SEARCH" | "UID SEARCH" => {
+            ExtendedSearchHandler::handle(imap_sock, &ext).await
+        }
+        "SORT" | "UID SORT" => SortHandler::handle(imap_sock, &ext).await,
+        "THREAD" | "UID THREAD" => ThreadHandler::handle(imap_sock, &ext).await,
+        "GETQUOTA" => GetQuotaHandler::handle(imap_sock, &ext).await,
+        "GETQUOTAROOT" => GetQuotaRootHandler::handle(imap_sock, &ext).await,
+        "SETQUOTA" => SetQuotaHandler::handle(imap_sock, &ext).await,
+        "SETACL" => SetAclHandler::handle(imap_sock, &ext).await,
+        "DELETEACL" => DeleteAclHandler::handle(imap_sock, &ext).await,
+        "GETACL" => GetAclHandler::handle(imap_sock, &ext).await,
+        "LISTRIGHTS" => ListRightsHandler::handle(imap_sock, &ext).await,
+        "MYRIGHTS" => MyRightsHandler::handle(imap_sock, &ext).await,
+        "GETMETADATA" => GetMetadataHandler::handle(imap_sock, &ext).await,
+        "SETMETADATA" => SetMetadataHandler::handle(imap_sock, &ext).await,
+        _ => {
+            let msg = format!("Unknown command {}", ext.name);
+            imap_sock.bad_completed2(&ext.tag, &msg).await;
+            Ok(CommandPipe::Noop)
+        }
+    };
+
+    match result {
+        Ok(cmd_pipe) => Ok(cmd_pipe),
+        Err(e) => {
+            debug!("{} failed: {}", ext.name, e);
+            let msg = failure(&ext.name, &e);
+            imap_sock.no_completed2(&ext.tag, &msg).await;
+            Ok(CommandPipe::Noop)
+        }
+    }
+}
+
+/// Tag of command line `buf` to answer it `BAD`, `*` if it has none that's
+/// valid.
+fn line_tag(buf: &[u8]) -> &str {
+    let tag = buf
+        .split(|c| b" \r\n".contains(c))
+        .next()
+        .unwrap_or_default();
+    let valid = !tag.is_empty()
+        && tag
+            .iter()
+            .all(|c| c.is_ascii_graphic() && !b"(){%*\"\\+".contains(c));
+    match std::str::from_utf8(tag) {
+        Ok(tag) if valid => tag,
+        _ => "*",
+    }
+}
+
+/// Text of the `NO` answering command `name` which failed with `e`, the
+/// reason alone for a refusal.
+fn failure(name: &str, e: &WError) -> String {
+    match e.refusal() {
+        Some(reason) => reason.to_string(),
+        None => format!("{} failed: {}", name, e),
+    }
+}
+
+/// Handle an APPEND whose message `literal` is still unread on `socket`.
+pub async fn process_append<'a, 'b, IO>(
+    line: &'a [u8],
+    literal: &Literal,
+    socket: &'b mut IO,
+    session: &'b mut Session,
+) -> Result<CommandPipe<'a>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut imap_sock = IMAPServ::new(socket, session);
+
+    let ext = match ext_command_decode(strip_literal(line)) {
+        Some(ext) if ext.name == "APPEND" => ext,
+        _ => {
+            let tag = line_tag(line);
+            imap_sock
+                .bad_completed2(tag, "Invalid APPEND command")
+                .await;
+            // a message sent without waiting can't be told from commands
+            return match literal.synchronizing {
+                true => Ok(CommandPipe::Noop),
+                false => Ok(CommandPipe::Quit),
+            };
+        }
+    };
+
+    debug!(":< {}", ext.name);
+
+    let (length, synchronizing) = (literal.length, literal.synchronizing);
+    match AppendHandler::handle(&mut imap_sock, &ext, length, synchronizing)
+        .await
+    {
+        Ok(cmd_pipe) => Ok(cmd_pipe),
+        Err(e) => {
+            debug!("APPEND failed: {}", e);
+            let msg = failure("APPEND", &e);
+            imap_sock.no_completed2(&ext.tag, &msg).await;
+            // a message sent without waiting may be left unread
+            match synchronizing {
+                true => Ok(CommandPipe::Noop),
+                false => Ok(CommandPipe::Quit),
+            }
+        }
     }
 }
 
-pub fn command_decode(buf: &[u8]) -> Result<Command> {
+/// Replace the `$` of RFC 5182 by the messages the last SEARCH saved: the
+/// set of their sequence numbers, or UIDs, for commands taking one, a `UID`
+/// key among search keys. Lines imap-codec decodes hold no `$`.
+pub fn expand_search_result<'a>(
+    buf: &'a [u8],
+    session: &Session,
+) -> Cow<'a, [u8]> {
+    let selected = match session.selected.as_ref() {
+        Some(selected) if buf.contains(&b'$') => selected,
+        _ => return Cow::Borrowed(buf),
+    };
+    if command_decode(buf).is_ok() {
+        return Cow::Borrowed(buf);
+    }
+    let ext = match ext_command_decode(buf) {
+        Some(ext) => ext,
+        None => return Cow::Borrowed(buf),
+    };
+
+    let uid = ext.name.starts_with("UID ");
+    let mut args = ext.args.clone();
+    match ext.name.trim_start_matches("UID ") {
+        "FETCH" | "STORE" | "COPY" | "MOVE" | "EXPUNGE" => match args.first() {
+            Some(set) if set.is_atom("$") => {
+                let numbers: Vec<u32> = match uid {
+                    true => selected.saved.clone(),
+                    false => (1..)
+                        .zip(selected.uids.iter())
+                        .filter(|(_, uid)| selected.saved.contains(uid))
+                        .map(|(seq, _)| seq)
+                        .collect(),
+                };
+                args[0] = Token::Atom(number_set(&numbers));
+            }
+            _ => return Cow::Borrowed(buf),
+        },
+        "SEARCH" | "SORT" | "THREAD" => {
+            args = expand_saved(&args, &selected.saved);
+        }
+        _ => return Cow::Borrowed(buf),
+    }
+    if args == ext.args {
+        return Cow::Borrowed(buf);
+    }
+
+    let mut line = format!("{} {}", ext.tag, ext.name).into_bytes();
+    for arg in args.iter() {
+        line.push(b' ');
+        arg.encode(&mut line);
+    }
+    line.extend_from_slice(b"\r\n");
+    Cow::Owned(line)
+}
+
+pub fn command_decode(buf: &[u8]) -> Result<Command<'_>> {
     let (_remainder, parsed) = Command::decode(buf)?;
     Ok(parsed)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::framer::literal;
+    use crate::storage::INBOX;
+    use crate::testing::{mail_dir, session};
+
+    #[tokio::test]
+    async fn appends_binary_literals() {
+        let root = mail_dir("append-binary");
+        let mut session = session(&root);
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client.write_all(b"a\0b\0c\r\n").await.unwrap();
+
+        let line = b"a APPEND INBOX ~{5+}\r\n";
+        let literal = literal(line).unwrap();
+        process_append(line, &literal, &mut server, &mut session)
+            .await
+            .unwrap();
+        let mut reply = vec![0; 256];
+        let n = client.read(&mut reply).await.unwrap();
+        let reply = String::from_utf8_lossy(&reply[..n]);
+        assert!(reply.starts_with("a OK "), "{}", reply);
+        let stored = session.store.read_message("bob", INBOX, 1).unwrap();
+        assert_eq!(stored, b"a\0b\0c");
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn tags_of_invalid_lines() {
+        assert_eq!(line_tag(b"a1 FROB x\r\n"), "a1");
+        assert_eq!(line_tag(b"a1\r\n"), "a1");
+        assert_eq!(line_tag(b"\r\n"), "*");
+        assert_eq!(line_tag(b"+ x\r\n"), "*");
+        assert_eq!(line_tag(b"(a) x\r\n"), "*");
+    }
+}
@@ -1,16 +1,36 @@
+use crate::events::MailboxEvent;
 use crate::result::Result;
+use crate::session::Session;
 
+use anyhow::anyhow;
 use log::debug;
 
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::error::RecvError;
 
 use imap_codec::{codec::Encode, command::Command, core::Tag, response::Data};
 
+#[allow(clippy::large_enum_variant)]
 pub enum CommandPipe<'a> {
     // next and prev command
     Next(Command<'a>, Option<Command<'a>>),
     Noop,
     Quit,
+
+    /// Switch the connection to TLS, after STARTTLS.
+    StartTls,
+
+    /// Compress the connection, after COMPRESS.
+    Compress,
+}
+
+/// What woke up a session waiting in [`IMAPServ::wait`].
+pub enum Wakeup {
+    /// A byte from the client, `None` once it closed the connection.
+    Input(Option<u8>),
+
+    /// A change to the selected mailbox.
+    Event(std::result::Result<MailboxEvent, RecvError>),
 }
 
 pub struct IMAPServ<'a, IO>
@@ -18,6 +38,7 @@ where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
     socket: &'a mut IO,
+    pub session: &'a mut Session,
     buf: Vec<u8>,
 }
 
@@ -25,9 +46,10 @@ impl<'a, IO> IMAPServ<'a, IO>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    pub fn new(socket: &'a mut IO) -> Self {
+    pub fn new(socket: &'a mut IO, session: &'a mut Session) -> Self {
         Self {
             socket,
+            session,
             buf: Vec::new(),
         }
     }
@@ -39,6 +61,52 @@ where
         Ok(&self.buf)
     }
 
+    pub async fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        self.socket.read_exact(buf).await?;
+        Ok(())
+    }
+
+    /// Read a line of at most `max` bytes, CRLF included. `None` if the
+    /// client closed the connection.
+    pub async fn read_line(&mut self, max: usize) -> Result<Option<Vec<u8>>> {
+        let mut line = vec![];
+        while !line.ends_with(b"\n") {
+            if line.len() == max {
+                return Err(anyhow!("Line too long").into());
+            }
+            let mut byte = [0; 1];
+            if self.socket.read(&mut byte).await? == 0 {
+                return Ok(None);
+            }
+            line.push(byte[0]);
+        }
+        Ok(Some(line))
+    }
+
+    /// Wait for the client to send something or the selected mailbox to
+    /// change, whichever comes first.
+    pub async fn wait(&mut self) -> Result<Wakeup> {
+        let mut byte = [0; 1];
+        let events = self.session.selected.as_mut().map(|m| &mut m.events);
+        let event = async {
+            match events {
+                Some(events) => events.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            n = self.socket.read(&mut byte) => {
+                Ok(Wakeup::Input((n? == 1).then_some(byte[0])))
+            }
+            event = event => Ok(Wakeup::Event(event)),
+        }
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        self.socket.flush().await?;
+        Ok(())
+    }
+
     pub async fn write(&mut self, buf: &[u8]) -> Result<()> {
         self.socket.write_all(buf).await?;
         Ok(())
@@ -65,6 +133,16 @@ where
         self.write_str(&format!("{} OK {}\r\n", tag, msg)).await
     }
 
+    pub async fn no(&mut self, tag: &str, msg: &str) -> Result<()> {
+        debug!(":> {} NO {}", tag, msg);
+        self.write_str(&format!("{} NO {}\r\n", tag, msg)).await
+    }
+
+    pub async fn bad(&mut self, tag: &str, msg: &str) -> Result<()> {
+        debug!(":> {} BAD {}", tag, msg);
+        self.write_str(&format!("{} BAD {}\r\n", tag, msg)).await
+    }
+
     pub async fn ok_completed(&mut self, tag: &Tag<'_>, cmd: &str) {
         self.ok_completed2(tag.as_ref(), cmd).await;
     }
@@ -73,6 +151,18 @@ where
         Self::mon_result(self.ok(tag, &format!("{} completed", cmd)).await);
     }
 
+    pub async fn no_completed(&mut self, tag: &Tag<'_>, msg: &str) {
+        self.no_completed2(tag.as_ref(), msg).await;
+    }
+
+    pub async fn no_completed2(&mut self, tag: &str, msg: &str) {
+        Self::mon_result(self.no(tag, msg).await);
+    }
+
+    pub async fn bad_completed2(&mut self, tag: &str, msg: &str) {
+        Self::mon_result(self.bad(tag, msg).await);
+    }
+
     fn mon_result(result: Result<()>) {
         match result {
             Ok(_) => {}
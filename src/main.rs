@@ -11,7 +11,8 @@
 // from Neuversity.
 #![allow(dead_code)]
 
-use clap::Parser;
+use anyhow::anyhow;
+use clap::{Parser, Subcommand};
 
 use dotenvy::dotenv;
 use tokio_rustls::TlsAcceptor;
@@ -19,24 +20,51 @@ use tokio_rustls::TlsAcceptor;
 use log::debug;
 use result::Result;
 use rustls::ServerConfig;
-use serde::Deserialize;
 
 use std::sync::Arc;
 use std::{env, fs, io::ErrorKind, process::exit};
 
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{
+    AsyncBufRead, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader,
+};
 use tokio::net::TcpListener;
 
+mod acl;
+mod auth;
+mod capability;
 mod cert;
+mod compress;
+mod config;
 mod error;
+mod events;
+mod ext_command;
+mod framer;
+mod fts;
 mod handlers;
 mod imap;
 mod imap_serv;
+mod message;
+mod mime;
 mod result;
+mod session;
+mod storage;
+#[cfg(test)]
+mod testing;
 
-use imap::{process_command, CommandPipe, IMAPServ};
+use imap::{
+    expand_search_result, process_append, process_command, CommandPipe,
+    IMAPServ,
+};
 
+use crate::capability::capabilities;
 use crate::cert::{load_certificates_from_pem, load_private_key_from_file};
+use crate::compress::DeflateStream;
+use crate::config::Config;
+use crate::events::{EventBus, TcpTransport};
+use crate::framer::{read_frame, Frame, MAX_LITERAL_MINUS};
+use crate::fts::FtsIndex;
+use crate::session::Session;
+use crate::storage::{FsStore, MailStore};
 
 #[derive(Parser, Debug)]
 #[command(name = "nu-id-smtp")]
@@ -45,22 +73,23 @@ use crate::cert::{load_certificates_from_pem, load_private_key_from_file};
 struct Args {
     #[arg(short, long, default_value = "default.conf")]
     config: String,
-}
-#[derive(Deserialize, Debug)]
-struct Config {
-    #[serde(default = "default_imap_port")]
-    imap_port: u16,
 
-    #[serde(default = "default_smtp_port")]
-    smtp_port: u16,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn default_imap_port() -> u16 {
-    143
-}
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Rebuild the full-text search index of a user's mailboxes
+    Reindex {
+        user: String,
 
-fn default_smtp_port() -> u16 {
-    25
+        /// Only rebuild this mailbox
+        mailbox: Option<String>,
+    },
+
+    /// Hash a password read from standard input for the password file
+    HashPassword,
 }
 
 // #[async_std::main]
@@ -83,6 +112,28 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
+    if let Some(Command::Reindex { user, mailbox }) = args.command {
+        if let Err(err) = reindex(&config, &user, mailbox) {
+            eprintln!("Error: {}", err);
+            exit(3);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::HashPassword) = args.command {
+        let mut password = String::new();
+        std::io::stdin().read_line(&mut password)?;
+        let password = password.trim_end_matches(['\r', '\n']);
+        match auth::hash_password(password.as_bytes()) {
+            Ok(hash) => println!("{}", hash),
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                exit(3);
+            }
+        }
+        return Ok(());
+    }
+
     if let Err(err) = start_imap_server(config).await {
         eprintln!("Error: {}", err);
         exit(3);
@@ -91,6 +142,30 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+fn reindex(conf: &Config, user: &str, mailbox: Option<String>) -> Result<()> {
+    let fts = match conf.fts_dir.as_ref() {
+        Some(fts_dir) => FtsIndex::new(fts_dir),
+        None => return Err(anyhow!("`fts_dir` is not configured").into()),
+    };
+    let store = FsStore::new(&conf.mail_dir);
+
+    let mailboxes = match mailbox {
+        Some(mailbox) => vec![mailbox],
+        None => store
+            .list_mailboxes(user)?
+            .into_iter()
+            .filter(|entry| entry.selectable)
+            .map(|entry| entry.name)
+            .collect(),
+    };
+    for mailbox in mailboxes {
+        let count = fts.rebuild(&store, user, &mailbox)?;
+        println!("{}: {} messages indexed", mailbox, count);
+    }
+
+    Ok(())
+}
+
 async fn start_imap_server(
     conf: Config,
 ) -> Result<(), Box<dyn std::error::Error>> {
@@ -119,7 +194,31 @@ async fn start_imap_server(
 
     let acceptor = TlsAcceptor::from(Arc::new(config));
 
+    let store: Arc<dyn MailStore> = Arc::new(FsStore::new(&conf.mail_dir));
+    let auth = auth::from_config(&conf);
+    let fts = conf
+        .fts_dir
+        .as_ref()
+        .map(|dir| Arc::new(FtsIndex::new(dir)));
+
     println!("Starting IMAP server at port {}...", conf.imap_port);
+    let conf = Arc::new(conf);
+    let event_secret = || {
+        conf.event_secret
+            .as_deref()
+            .expect("No event_secret for event_listen or event_peers")
+    };
+    let events = match conf.event_peers.is_empty() {
+        true => Arc::new(EventBus::new()),
+        false => {
+            let peers = conf.event_peers.clone();
+            let transport = TcpTransport::new(peers, event_secret());
+            Arc::new(EventBus::with_transport(Box::new(transport)))
+        }
+    };
+    if let Some(addr) = conf.event_listen.as_ref() {
+        TcpTransport::listen(addr, event_secret(), events.clone()).await?;
+    }
 
     loop {
         let (socket, _) = listener.accept().await?;
@@ -127,68 +226,143 @@ async fn start_imap_server(
         debug!("socket: {:?}", socket);
 
         let acceptor = acceptor.clone();
+        let mut session = Session::new(
+            conf.clone(),
+            store.clone(),
+            auth.clone(),
+            fts.clone(),
+            events.clone(),
+        );
 
         tokio::spawn(async move {
-            let mut buf = [0; 1024];
+            let socket = if session.tls {
+                socket
+            } else {
+                let mut socket = BufReader::new(socket);
+                greet(&mut socket, &session).await;
+                if serve(&mut socket, &mut session).await != Ended::StartTls {
+                    return;
+                }
+                // whatever the client sent after STARTTLS is dropped with
+                // the buffer, it can't be trusted
+                session.tls = true;
+                socket.into_inner()
+            };
 
-            let mut socket = match acceptor.accept(socket).await {
+            let socket = match acceptor.accept(socket).await {
                 Ok(socket) => socket,
                 Err(e) => {
                     eprintln!("Failed to accept socket; err = {:?}", e);
                     return;
                 }
             };
+            let mut socket = BufReader::new(socket);
 
-            let _ = socket.write_all(b"* OK IMAP4rev1 server ready\r\n").await;
-
-            loop {
-                let mut n = match socket.read(&mut buf).await {
-                    Ok(n) if n == 0 => return,
-                    Ok(n) => n,
-                    Err(e) => {
-                        eprintln!("Failed to read from socket; err = {:?}", e);
-                        return;
-                    }
-                };
-
-                // apabila buff tidak diakhiri dengan CRLF maka tambahkan CRLF di akhir array
-                if &buf[n - 2..n] != &[13, 10] {
-                    buf[n - 1] = 13;
-                    buf[n] = 10;
-                    n += 1;
-                }
+            // no second greeting after STARTTLS
+            if session.config.implicit_tls {
+                greet(&mut socket, &session).await;
+            }
+            if serve(&mut socket, &mut session).await != Ended::Compress {
+                return;
+            }
 
-                debug!("COMMAND: {:?}", String::from_utf8_lossy(&buf[0..n]));
+            // what's buffered is compressed already
+            let mut socket = BufReader::new(DeflateStream::new(socket));
+            serve(&mut socket, &mut session).await;
+        });
+    }
+}
 
-                let cmd_pipe = match process_command(&buf[0..n], &mut socket)
-                    .await
-                {
-                    Ok(cmd_pipe) => cmd_pipe,
-                    Err(e) => {
-                        eprintln!("Failed to decode command; err = {:?}", e);
-                        return;
-                    }
-                };
+async fn greet<IO>(socket: &mut IO, session: &Session)
+where
+    IO: AsyncWrite + Unpin,
+{
+    let greeting = format!(
+        "* OK [CAPABILITY {}] {}\r\n",
+        capabilities(session),
+        session.config.greeting
+    );
+    let _ = socket.write_all(greeting.as_bytes()).await;
+    let _ = socket.flush().await;
+}
 
-                let _ = process_command_result(&cmd_pipe, &mut socket);
+/// Why [`serve`] stopped processing the commands of a connection.
+#[derive(PartialEq, Eq)]
+enum Ended {
+    Closed,
 
-                match cmd_pipe {
-                    CommandPipe::Quit => return,
-                    _ => {}
-                }
+    /// The connection goes on with TLS.
+    StartTls,
+
+    /// The connection goes on compressed.
+    Compress,
+}
+
+/// Process the commands of a connection until it ends or changes its
+/// encoding, flushing the responses of each.
+async fn serve<IO>(socket: &mut IO, session: &mut Session) -> Ended
+where
+    IO: AsyncBufRead + AsyncWrite + Unpin,
+{
+    loop {
+        let max_non_sync = match session.config.literal_plus {
+            true => u32::MAX,
+            false => MAX_LITERAL_MINUS,
+        };
+        let frame = match read_frame(socket, max_non_sync).await {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Ended::Closed,
+            Err(e) => {
+                eprintln!("Failed to read from socket; err = {:?}", e);
+                return Ended::Closed;
             }
-        });
+        };
+
+        let expanded;
+        let result = match &frame {
+            Frame::Command(buf) => {
+                debug!("COMMAND: {:?}", String::from_utf8_lossy(buf));
+                expanded = expand_search_result(buf, session);
+                process_command(&expanded, socket, session).await
+            }
+            Frame::Append { line, literal } => {
+                debug!("COMMAND: {:?}", String::from_utf8_lossy(line));
+                process_append(line, literal, socket, session).await
+            }
+        };
+        let cmd_pipe = match result {
+            Ok(cmd_pipe) => cmd_pipe,
+            Err(e) => {
+                eprintln!("Failed to decode command; err = {:?}", e);
+                return Ended::Closed;
+            }
+        };
+
+        let _ = process_command_result(&cmd_pipe, socket, session);
+
+        if let Err(e) = socket.flush().await {
+            eprintln!("Failed to write to socket; err = {:?}", e);
+            return Ended::Closed;
+        }
+        match cmd_pipe {
+            CommandPipe::Quit => return Ended::Closed,
+            CommandPipe::StartTls => return Ended::StartTls,
+            CommandPipe::Compress => return Ended::Compress,
+            _ => {}
+        }
     }
 }
 
+#[allow(clippy::single_match)]
 fn process_command_result<'a, IO>(
     cmd: &CommandPipe<'a>,
     socket: &mut IO,
+    session: &mut Session,
 ) -> Result<()>
 where
     IO: AsyncRead + AsyncWrite + Unpin,
 {
-    let _imap_serv = IMAPServ::new(socket);
+    let _imap_serv = IMAPServ::new(socket, session);
     match cmd {
         CommandPipe::Next(_cmd, _prev) => {
             // debug!("Next: {:?}", cmd);
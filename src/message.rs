@@ -0,0 +1,119 @@
+use anyhow::anyhow;
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use mailparse::{
+    addrparse_header, DispositionType, MailAddr, MailHeaderMap, ParsedMail,
+    SingleInfo,
+};
+
+use crate::result::Result;
+
+/// A parsed RFC 5322 message.
+pub struct Message<'a> {
+    raw: &'a [u8],
+    parsed: ParsedMail<'a>,
+}
+
+impl<'a> Message<'a> {
+    pub fn parse(raw: &'a [u8]) -> Result<Self> {
+        let parsed = mailparse::parse_mail(raw)
+            .map_err(|e| anyhow!("Cannot parse message: {}", e))?;
+        Ok(Self { raw, parsed })
+    }
+
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    /// First value of header `name`, with encoded-words decoded.
+    pub fn header(&self, name: &str) -> Option<String> {
+        self.parsed.headers.get_first_value(name)
+    }
+
+    /// All values of header `name`, with encoded-words decoded.
+    pub fn header_values(&self, name: &str) -> Vec<String> {
+        self.parsed.headers.get_all_values(name)
+    }
+
+    /// All header fields as decoded `Name: value` lines.
+    pub fn header_text(&self) -> String {
+        self.parsed
+            .headers
+            .iter()
+            .map(|h| format!("{}: {}\r\n", h.get_key(), h.get_value()))
+            .collect()
+    }
+
+    /// Date of the `Date:` header, in the timezone it was written in.
+    pub fn sent_date(&self) -> Option<NaiveDate> {
+        self.sent_at().map(|dt| dt.date_naive())
+    }
+
+    /// Time of the `Date:` header, in the timezone it was written in if it
+    /// parses as RFC 5322, in UTC if only leniently.
+    pub fn sent_at(&self) -> Option<DateTime<FixedOffset>> {
+        let date = self.header("Date")?;
+        match DateTime::parse_from_rfc2822(date.trim()) {
+            Ok(dt) => Some(dt),
+            Err(_) => mailparse::dateparse(&date)
+                .ok()
+                .and_then(|ts| DateTime::from_timestamp(ts, 0))
+                .map(|dt| dt.fixed_offset()),
+        }
+    }
+
+    /// First address of header `name`, the first member of a group
+    /// included.
+    pub fn first_address(&self, name: &str) -> Option<SingleInfo> {
+        let header = self.parsed.headers.get_first_header(name)?;
+        let addrs = addrparse_header(header).ok()?;
+        addrs.iter().find_map(|addr| match addr {
+            MailAddr::Single(info) => Some(info.clone()),
+            MailAddr::Group(group) => group.addrs.first().cloned(),
+        })
+    }
+
+    /// The `<msg-id>`s of header `name`, without their angle brackets.
+    pub fn message_ids(&self, name: &str) -> Vec<String> {
+        let value = self.header(name).unwrap_or_default();
+        value
+            .split('<')
+            .skip(1)
+            .filter_map(|id| id.split_once('>'))
+            .map(|(id, _)| id.trim().to_string())
+            .filter(|id| !id.is_empty())
+            .collect()
+    }
+
+    /// Decoded `text/*` parts which aren't attachments, as
+    /// `(mime type, text)`.
+    pub fn text_parts(&self) -> Vec<(String, String)> {
+        self.parsed
+            .parts()
+            .filter(|part| part.ctype.mimetype.starts_with("text/"))
+            .filter(|part| {
+                part.get_content_disposition().disposition
+                    != DispositionType::Attachment
+            })
+            .filter_map(|part| {
+                let body = part.get_body().ok()?;
+                Some((part.ctype.mimetype.clone(), body))
+            })
+            .collect()
+    }
+
+    /// Text of every `text/*` part, transfer-decoded and converted to UTF-8
+    /// from the part's charset.
+    pub fn body_text(&self) -> String {
+        let mut text = String::new();
+        for part in self.parsed.parts() {
+            if !part.ctype.mimetype.starts_with("text/") {
+                continue;
+            }
+            if let Ok(body) = part.get_body() {
+                text.push_str(&body);
+                text.push('\n');
+            }
+        }
+        text
+    }
+}
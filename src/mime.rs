@@ -0,0 +1,342 @@
+//! MIME structure of a stored message, as raw bytes, for the body sections
+//! and structure FETCH reports (RFC 3501 section 6.4.5).
+
+use mailparse::body::Body;
+use mailparse::{
+    parse_content_type, parse_headers, MailHeader, MailHeaderMap,
+    ParsedContentType,
+};
+
+/// A message, or a part of one, borrowing the stored bytes.
+pub struct Part<'a> {
+    /// Header fields, with the blank line ending them.
+    pub header: &'a [u8],
+
+    /// Content after the header, transfer encoding left as stored.
+    pub body: &'a [u8],
+
+    pub fields: Vec<MailHeader<'a>>,
+    pub ctype: ParsedContentType,
+
+    /// Parts of a `multipart/*` part.
+    pub parts: Vec<Part<'a>>,
+
+    /// Message a `message/rfc822` part holds.
+    pub message: Option<Box<Part<'a>>>,
+}
+
+impl<'a> Part<'a> {
+    /// Parse a whole message.
+    pub fn parse(raw: &'a [u8]) -> Self {
+        Self::parse_with_default(raw, "text/plain")
+    }
+
+    /// Parse a part whose type is `default` when it has no `Content-Type`,
+    /// `message/rfc822` for those of a `multipart/digest`.
+    fn parse_with_default(raw: &'a [u8], default: &str) -> Self {
+        let split = header_end(raw);
+        let (header, body) = raw.split_at(split);
+        let fields = parse_headers(header)
+            .map(|(fields, _)| fields)
+            .unwrap_or_default();
+        let ctype = match fields.get_first_value("Content-Type") {
+            Some(value) => parse_content_type(&value),
+            None => parse_content_type(default),
+        };
+
+        let mut part = Self {
+            header,
+            body,
+            fields,
+            ctype,
+            parts: vec![],
+            message: None,
+        };
+        if part.ctype.mimetype.starts_with("multipart/") {
+            let default = match part.ctype.mimetype.as_str() {
+                "multipart/digest" => "message/rfc822",
+                _ => "text/plain",
+            };
+            if let Some(boundary) = part.ctype.params.get("boundary") {
+                part.parts = split_multipart(body, boundary.as_bytes())
+                    .into_iter()
+                    .map(|raw| Self::parse_with_default(raw, default))
+                    .collect();
+            }
+        } else if part.ctype.mimetype == "message/rfc822" {
+            part.message = Some(Box::new(Self::parse(body)));
+        }
+        part
+    }
+
+    pub fn is_multipart(&self) -> bool {
+        self.ctype.mimetype.starts_with("multipart/")
+    }
+
+    /// First value of header field `name`, unfolded but otherwise as
+    /// stored, encoded-words included.
+    pub fn field(&self, name: &str) -> Option<String> {
+        let field = self.fields.get_first_header(name)?;
+        Some(unfold(field.get_value_raw()))
+    }
+
+    /// Content with its transfer encoding undone, `None` if the encoding
+    /// is unknown or the content can't be decoded.
+    pub fn decoded_body(&self) -> Option<Vec<u8>> {
+        let encoding = self
+            .field("Content-Transfer-Encoding")
+            .map(|encoding| encoding.to_ascii_lowercase());
+        match encoding.as_deref() {
+            None | Some("7bit") | Some("8bit") | Some("binary") => {
+                Some(self.body.to_vec())
+            }
+            Some("base64") | Some("quoted-printable") => {
+                match Body::new(self.body, &self.ctype, &encoding) {
+                    Body::Base64(body) | Body::QuotedPrintable(body) => {
+                        body.get_decoded().ok()
+                    }
+                    _ => None,
+                }
+            }
+            Some(_) => None,
+        }
+    }
+
+    /// Parts numbered below this one when it's a message: those of its
+    /// body if multipart, otherwise the message itself is part 1.
+    fn numbered_parts(&self) -> Vec<&Part<'a>> {
+        match self.is_multipart() {
+            true => self.parts.iter().collect(),
+            false => vec![self],
+        }
+    }
+
+    /// Part `path` of this message, like `[1, 2]` for `1.2`, `None` if
+    /// there's no such part.
+    pub fn find(&self, path: &[u32]) -> Option<&Part<'a>> {
+        let mut part = self;
+        let mut numbered = self.numbered_parts();
+        for (i, number) in path.iter().enumerate() {
+            part = *numbered.get((*number as usize).checked_sub(1)?)?;
+            if i + 1 == path.len() {
+                break;
+            }
+            numbered = match (part.is_multipart(), part.message.as_deref()) {
+                (true, _) => part.parts.iter().collect(),
+                (false, Some(message)) => message.numbered_parts(),
+                (false, None) => return None,
+            };
+        }
+        Some(part)
+    }
+
+    /// Header fields whose name is among `names`, or isn't if `not`, each
+    /// with its continuation lines, then the blank line ending a header.
+    pub fn header_fields(&self, names: &[String], not: bool) -> Vec<u8> {
+        let mut selected = vec![];
+        for field in raw_fields(self.header) {
+            let name = field.split(|c| *c == b':').next().unwrap_or_default();
+            let name = String::from_utf8_lossy(name);
+            let listed =
+                names.iter().any(|n| n.eq_ignore_ascii_case(name.trim()));
+            if listed != not {
+                selected.extend_from_slice(field);
+            }
+        }
+        selected.extend_from_slice(b"\r\n");
+        selected
+    }
+}
+
+/// Offset where the body of `raw` starts, after the blank line ending its
+/// header, or its end if there's none.
+fn header_end(raw: &[u8]) -> usize {
+    if raw.starts_with(b"\r\n") {
+        return 2;
+    }
+    if raw.starts_with(b"\n") {
+        return 1;
+    }
+    let mut pos = 0;
+    while let Some(end) = raw[pos..].iter().position(|c| *c == b'\n') {
+        let next = pos + end + 1;
+        match &raw[next..] {
+            [b'\r', b'\n', ..] => return next + 2,
+            [b'\n', ..] => return next + 1,
+            _ => pos = next,
+        }
+    }
+    raw.len()
+}
+
+/// Raw header fields of `header`, continuation lines included, without
+/// the blank line ending it.
+fn raw_fields(header: &[u8]) -> Vec<&[u8]> {
+    let mut fields: Vec<(usize, usize)> = vec![];
+    let mut pos = 0;
+    while pos < header.len() {
+        let end = header[pos..]
+            .iter()
+            .position(|c| *c == b'\n')
+            .map_or(header.len(), |end| pos + end + 1);
+        let line = &header[pos..end];
+        if line == b"\r\n" || line == b"\n" {
+            break;
+        }
+        match fields.last_mut() {
+            Some((_, last)) if line[0] == b' ' || line[0] == b'\t' => {
+                *last = end
+            }
+            _ => fields.push((pos, end)),
+        }
+        pos = end;
+    }
+    fields
+        .iter()
+        .map(|(start, end)| &header[*start..*end])
+        .collect()
+}
+
+/// Bodies of the parts of a multipart `body` delimited by `boundary`, the
+/// line break before a delimiter belonging to it.
+fn split_multipart<'a>(body: &'a [u8], boundary: &[u8]) -> Vec<&'a [u8]> {
+    let mut delimiter = b"--".to_vec();
+    delimiter.extend_from_slice(boundary);
+
+    let mut parts = vec![];
+    let mut start = None;
+    let mut pos = 0;
+    while pos < body.len() {
+        let end = body[pos..]
+            .iter()
+            .position(|c| *c == b'\n')
+            .map_or(body.len(), |end| pos + end + 1);
+        let line = &body[pos..end];
+        if line.starts_with(&delimiter) {
+            if let Some(start) = start {
+                parts.push(&body[start..line_break_start(body, start, pos)]);
+            }
+            let close = line[delimiter.len()..].starts_with(b"--");
+            if close {
+                return parts;
+            }
+            start = Some(end);
+        }
+        pos = end;
+    }
+    // an unterminated last part runs to the end
+    if let Some(start) = start {
+        parts.push(&body[start.min(body.len())..]);
+    }
+    parts
+}
+
+/// Start of the line break before `pos`, not before `start`.
+fn line_break_start(body: &[u8], start: usize, pos: usize) -> usize {
+    let mut end = pos;
+    if end > start && body[end - 1] == b'\n' {
+        end -= 1;
+        if end > start && body[end - 1] == b'\r' {
+            end -= 1;
+        }
+    }
+    end
+}
+
+/// Header field value without its folding line breaks.
+fn unfold(value: &[u8]) -> String {
+    let value = String::from_utf8_lossy(value);
+    value
+        .replace("\r\n", "")
+        .replace('\n', "")
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &[u8] = b"From: bob@example.org\r\n\
+        Subject: parts\r\n\
+        Content-Type: multipart/mixed; boundary=\"b1\"\r\n\
+        \r\n\
+        preamble\r\n\
+        --b1\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        first\r\n\
+        --b1\r\n\
+        Content-Type: message/rfc822\r\n\
+        \r\n\
+        Subject: inner\r\n\
+        \r\n\
+        inner body\r\n\
+        --b1--\r\n\
+        epilogue\r\n";
+
+    #[test]
+    fn splits_header_and_body() {
+        let part = Part::parse(b"Subject: a\r\n b\r\n\r\nbody\r\n");
+        assert_eq!(part.header, b"Subject: a\r\n b\r\n\r\n");
+        assert_eq!(part.body, b"body\r\n");
+        assert_eq!(part.field("subject").as_deref(), Some("a b"));
+
+        let part = Part::parse(b"\r\nbody");
+        assert_eq!(part.header, b"\r\n");
+        assert_eq!(part.body, b"body");
+    }
+
+    #[test]
+    fn finds_numbered_parts() {
+        let message = Part::parse(MESSAGE);
+        assert_eq!(message.parts.len(), 2);
+        assert_eq!(message.find(&[1]).unwrap().body, b"first");
+        let inner = message.find(&[2]).unwrap();
+        assert_eq!(inner.body, b"Subject: inner\r\n\r\ninner body");
+        assert_eq!(
+            inner.message.as_ref().unwrap().header,
+            b"Subject: inner\r\n\r\n"
+        );
+        assert_eq!(message.find(&[2, 1]).unwrap().body, b"inner body");
+        assert!(message.find(&[3]).is_none());
+        assert!(message.find(&[1, 1]).is_none());
+
+        // a single part message is its own part 1
+        let single = Part::parse(b"Subject: one\r\n\r\nbody");
+        assert_eq!(single.find(&[1]).unwrap().body, b"body");
+    }
+
+    #[test]
+    fn undoes_transfer_encodings() {
+        let part = |encoding: &str, body: &str| {
+            let raw = format!(
+                "Content-Transfer-Encoding: {}\r\n\r\n{}",
+                encoding, body
+            );
+            Part::parse(raw.as_bytes()).decoded_body()
+        };
+        assert_eq!(part("base64", "AGJpbgA=").unwrap(), b"\0bin\0");
+        assert_eq!(
+            part("Quoted-Printable", "caf=C3=A9").unwrap(),
+            "caf\u{e9}".as_bytes()
+        );
+        assert_eq!(part("8bit", "as is").unwrap(), b"as is");
+        assert!(part("x-uuencode", "begin 644 x").is_none());
+        let plain = Part::parse(b"Subject: none\r\n\r\nbody");
+        assert_eq!(plain.decoded_body().unwrap(), b"body");
+    }
+
+    #[test]
+    fn selects_header_fields() {
+        let message = Part::parse(MESSAGE);
+        let names = ["SUBJECT".to_string()];
+        assert_eq!(
+            message.header_fields(&names, false),
+            b"Subject: parts\r\n\r\n"
+        );
+        let not = message.header_fields(&names, true);
+        assert!(not.starts_with(b"From: bob@example.org\r\nContent-Type"));
+        assert!(!not.windows(7).any(|w| w == b"Subject"));
+    }
+}
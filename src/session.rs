@@ -0,0 +1,362 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use imap_codec::mailbox::Mailbox;
+
+use crate::acl::{self, ALL_RIGHTS};
+use crate::auth::Authenticator;
+use crate::config::Config;
+use crate::error::{Refused, NONEXISTENT, NOPERM};
+use crate::events::{EventBus, MailboxChange, MailboxEvent};
+use crate::fts::FtsIndex;
+use crate::result::Result;
+use crate::storage::{MailStore, MessageMeta, DELIMITER, INBOX, SHARED_OWNER};
+
+use tokio::sync::broadcast;
+
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// State of a single client connection, kept across commands.
+pub struct Session {
+    /// Identifies the session as the origin of mailbox events.
+    pub id: u64,
+    pub config: Arc<Config>,
+    pub store: Arc<dyn MailStore>,
+    pub auth: Arc<dyn Authenticator>,
+    pub fts: Option<Arc<FtsIndex>>,
+    pub events: Arc<EventBus>,
+    pub user: Option<String>,
+
+    /// Whether the connection is encrypted, from the start or after
+    /// STARTTLS.
+    pub tls: bool,
+
+    /// Whether COMPRESS turned on compression.
+    pub compressed: bool,
+    pub selected: Option<SelectedMailbox>,
+
+    /// Extensions the client turned on with ENABLE, or by using them as
+    /// CONDSTORE allows.
+    pub enabled: HashSet<&'static str>,
+}
+
+pub struct SelectedMailbox {
+    /// Name in storage form, as the client named it.
+    pub name: String,
+
+    /// Rights of the user on the mailbox when it was selected (RFC 4314).
+    pub rights: String,
+
+    /// UIDs in message sequence number order.
+    pub uids: Vec<u32>,
+
+    /// Messages which are `\Recent` for this session.
+    pub recent: HashSet<u32>,
+
+    /// Selected by EXAMINE or without write permission: no flag changes,
+    /// the implicit `\Seen` of fetching a body included, and no expunges.
+    pub read_only: bool,
+
+    /// Changes made to the mailbox, the session's own included.
+    pub events: broadcast::Receiver<MailboxEvent>,
+
+    /// UIDs saved by `SEARCH RETURN (SAVE)`, which `$` refers to
+    /// (RFC 5182).
+    pub saved: Vec<u32>,
+}
+
+impl Session {
+    pub fn new(
+        config: Arc<Config>,
+        store: Arc<dyn MailStore>,
+        auth: Arc<dyn Authenticator>,
+        fts: Option<Arc<FtsIndex>>,
+        events: Arc<EventBus>,
+    ) -> Self {
+        Self {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            tls: config.implicit_tls,
+            compressed: false,
+            config,
+            store,
+            auth,
+            fts,
+            events,
+            user: None,
+            selected: None,
+            enabled: HashSet::new(),
+        }
+    }
+
+    /// Turn on CONDSTORE, which every command using mod-sequences does.
+    pub fn enable_condstore(&mut self) {
+        self.enabled.insert("CONDSTORE");
+    }
+
+    /// Whether responses carry mod-sequences (RFC 7162).
+    pub fn condstore(&self) -> bool {
+        self.enabled.contains("CONDSTORE")
+    }
+
+    /// Whether expunges are reported as VANISHED (RFC 7162).
+    pub fn qresync(&self) -> bool {
+        self.enabled.contains("QRESYNC")
+    }
+
+    /// Whether the client switched to IMAP4rev2 (RFC 9051) with ENABLE.
+    pub fn imap4rev2(&self) -> bool {
+        self.enabled.contains("IMAP4rev2")
+    }
+
+    /// Whether APPEND and COPY report UIDs (RFC 4315), which IMAP4rev2
+    /// makes mandatory.
+    pub fn uidplus(&self) -> bool {
+        self.config.uidplus || self.imap4rev2()
+    }
+
+    /// Whether APPEND and COPY to `mailbox` report the new UIDs, which is
+    /// only meaningful if they persist.
+    pub fn reports_uids(&self, mailbox: &str) -> Result<bool> {
+        let (owner, mailbox) = self.locate(mailbox)?;
+        Ok(self.uidplus() && self.store.has_sticky_uids(&owner, &mailbox)?)
+    }
+
+    /// Whether the user may set other users' quotas and has every right on
+    /// the shared mailboxes.
+    pub fn is_admin(&self) -> bool {
+        self.user
+            .as_ref()
+            .is_some_and(|user| self.config.admins.contains(user))
+    }
+
+    pub fn user(&self) -> Result<&str> {
+        self.user
+            .as_deref()
+            .ok_or_else(|| anyhow!("Not authenticated").into())
+    }
+
+    pub fn selected(&self) -> Result<&SelectedMailbox> {
+        self.selected
+            .as_ref()
+            .ok_or_else(|| anyhow!("No mailbox selected").into())
+    }
+
+    /// The selected mailbox, if it may be changed.
+    pub fn writable_selected(&self) -> Result<&SelectedMailbox> {
+        match self.selected()? {
+            selected if selected.read_only => {
+                Err(anyhow!("Mailbox is read-only").into())
+            }
+            selected => Ok(selected),
+        }
+    }
+
+    /// Tell the sessions which have `mailbox` selected about a change.
+    pub fn publish(&self, mailbox: &str, change: MailboxChange) -> Result<()> {
+        let event = MailboxEvent {
+            origin: self.id,
+            change,
+        };
+        let (owner, mailbox) = self.locate(mailbox)?;
+        self.events.publish(&owner, &mailbox, event);
+        Ok(())
+    }
+
+    /// Owner of mailbox `name`, in storage form, and the name it has among
+    /// the owner's mailboxes: `Other Users/alice/Work` is alice's `Work`,
+    /// `Shared/Team` the shared `Team`. A level of a namespace above the
+    /// mailboxes names none.
+    pub fn locate(&self, name: &str) -> Result<(String, String)> {
+        let namespaces = &self.config.namespaces;
+        let prefix = |prefix: &Option<String>| {
+            prefix.as_deref().map(|prefix| self.storage_name(prefix))
+        };
+        let rest = prefix(&namespaces.other_users)
+            .and_then(|prefix| name.strip_prefix(prefix.as_str()));
+        if let Some(rest) = rest {
+            return match rest.split_once(DELIMITER) {
+                // names starting with a dot aren't users'
+                Some((owner, mailbox))
+                    if !owner.is_empty() && !owner.starts_with('.') =>
+                {
+                    Ok((owner.to_string(), self.storage_name(mailbox)))
+                }
+                _ => Err(Refused(NONEXISTENT).into()),
+            };
+        }
+        let rest = prefix(&namespaces.shared)
+            .and_then(|prefix| name.strip_prefix(prefix.as_str()));
+        if let Some(rest) = rest {
+            return Ok((SHARED_OWNER.to_string(), rest.to_string()));
+        }
+        Ok((self.user()?.to_string(), name.to_string()))
+    }
+
+    /// Name in storage form, as the client sees it, of `mailbox` owned by
+    /// `owner`.
+    pub fn qualified_name(&self, owner: &str, mailbox: &str) -> String {
+        let namespaces = &self.config.namespaces;
+        let prefix = if owner == SHARED_OWNER {
+            namespaces.shared.clone().unwrap_or_default()
+        } else if self.user.as_deref() != Some(owner) {
+            let prefix = namespaces.other_users.as_deref().unwrap_or_default();
+            format!("{}{}{}", prefix, owner, self.config.delimiter)
+        } else {
+            String::new()
+        };
+        format!("{}{}", self.storage_name(&prefix), mailbox)
+    }
+
+    /// Rights of the user on `mailbox` of `owner` (RFC 4314): every right
+    /// on their own mailboxes, and on the shared ones for admins, otherwise
+    /// those its ACL grants them.
+    pub fn rights_of(&self, owner: &str, mailbox: &str) -> Result<String> {
+        let user = self.user()?;
+        if owner == user || (owner == SHARED_OWNER && self.is_admin()) {
+            return Ok(ALL_RIGHTS.to_string());
+        }
+        Ok(acl::granted(self.store.acl(owner)?.get(mailbox), user))
+    }
+
+    /// Locate mailbox `name` and make sure the user has each of `needed`
+    /// on it. Without the lookup right it's refused as if it didn't exist,
+    /// which doesn't tell others' mailboxes exist (RFC 4314 section 6).
+    pub fn access(&self, name: &str, needed: &str) -> Result<(String, String)> {
+        let (owner, mailbox) = self.locate(name)?;
+        let rights = self.rights_of(&owner, &mailbox)?;
+        if !needed.chars().all(|r| rights.contains(r)) {
+            return match rights.contains('l') {
+                true => Err(Refused(NOPERM).into()),
+                false => Err(Refused(NONEXISTENT).into()),
+            };
+        }
+        Ok((owner, mailbox))
+    }
+
+    /// Owner and name among the owner's mailboxes of the selected one.
+    pub fn selected_location(&self) -> Result<(String, String)> {
+        self.locate(&self.selected()?.name)
+    }
+
+    /// Metadata of the selected mailbox's messages, in sequence number order.
+    pub fn selected_messages(&self) -> Result<Vec<MessageMeta>> {
+        let selected = self.selected()?;
+        let (owner, mailbox) = self.selected_location()?;
+        let index = self.store.load_index(&owner, &mailbox)?;
+        let mut by_uid: HashMap<u32, MessageMeta> =
+            index.messages.into_iter().map(|m| (m.uid, m)).collect();
+        Ok(selected
+            .uids
+            .iter()
+            .filter_map(|uid| by_uid.remove(uid))
+            .collect())
+    }
+
+    /// Storage name of a mailbox named by the client.
+    pub fn mailbox_name(&self, mailbox: &Mailbox<'_>) -> String {
+        match mailbox {
+            Mailbox::Inbox => INBOX.to_string(),
+            Mailbox::Other(other) => {
+                self.storage_name(&String::from_utf8_lossy(other.as_ref()))
+            }
+        }
+    }
+
+    /// Storage form of a name, or pattern, using the configured hierarchy
+    /// delimiter.
+    pub fn storage_name(&self, name: &str) -> String {
+        if name.eq_ignore_ascii_case(INBOX) {
+            return INBOX.to_string();
+        }
+        name.replace(self.config.delimiter, &DELIMITER.to_string())
+    }
+
+    /// Name of a stored mailbox as shown to the client.
+    pub fn client_name(&self, name: &str) -> String {
+        name.replace(DELIMITER, &self.config.delimiter.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::NoUsers;
+    use crate::storage::FsStore;
+
+    fn session(conf: &str) -> Session {
+        let config: Config = toml::from_str(conf).unwrap();
+        let store = Arc::new(FsStore::new(&config.mail_dir));
+        let mut session = Session::new(
+            Arc::new(config),
+            store,
+            Arc::new(NoUsers),
+            None,
+            Arc::new(EventBus::new()),
+        );
+        session.user = Some("bob".to_string());
+        session
+    }
+
+    fn located(session: &Session, name: &str) -> Option<(String, String)> {
+        session.locate(name).ok()
+    }
+
+    fn owned(owner: &str, mailbox: &str) -> Option<(String, String)> {
+        Some((owner.to_string(), mailbox.to_string()))
+    }
+
+    #[test]
+    fn locates_mailboxes_by_namespace() {
+        let s = session("");
+        assert_eq!(located(&s, "INBOX"), owned("bob", "INBOX"));
+        assert_eq!(located(&s, "Work/2024"), owned("bob", "Work/2024"));
+        assert_eq!(
+            located(&s, "Other Users/alice/Work/2024"),
+            owned("alice", "Work/2024")
+        );
+        assert_eq!(
+            located(&s, "Other Users/alice/inbox"),
+            owned("alice", "INBOX")
+        );
+        assert_eq!(located(&s, "Shared/News"), owned(SHARED_OWNER, "News"));
+
+        // the owner's name is needed, and can't reach the shared mailboxes
+        for name in [
+            "Other Users/alice",
+            "Other Users//Work",
+            "Other Users/.shared/News",
+        ] {
+            assert_eq!(located(&s, name), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn locates_with_the_configured_delimiter() {
+        let s = session(
+            "delimiter = '.'\n\
+             [namespaces]\n\
+             other_users = \"Users.\"\n\
+             shared = \"Public.\"",
+        );
+        // names are in storage form, the prefixes in the client's
+        assert_eq!(
+            located(&s, "Users/alice/Work/2024"),
+            owned("alice", "Work/2024")
+        );
+        assert_eq!(located(&s, "Public/News"), owned(SHARED_OWNER, "News"));
+        assert_eq!(
+            located(&s, "Other Users/alice/Work"),
+            owned("bob", "Other Users/alice/Work")
+        );
+    }
+
+    #[test]
+    fn locating_personal_mailboxes_needs_a_user() {
+        let mut s = session("");
+        s.user = None;
+        assert_eq!(located(&s, "INBOX"), None);
+        assert_eq!(located(&s, "Shared/News"), owned(SHARED_OWNER, "News"));
+    }
+}
@@ -0,0 +1,1151 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::fs::{self, File};
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::{Handle, RuntimeFlavor};
+
+use super::{
+    Acl, MailStore, MailboxEntry, MailboxIndex, MessageMeta, MessageSink,
+    Metadata, QuotaLimits, QuotaUsage, SpecialUse, INBOX, SHARED_OWNER,
+};
+use crate::error::{Refused, OVERQUOTA};
+use crate::result::Result;
+
+const INDEX_FILE: &str = "index.toml";
+const SUBSCRIPTIONS_FILE: &str = ".subscriptions.toml";
+const SPECIAL_USE_FILE: &str = ".special_use.toml";
+const QUOTA_FILE: &str = ".quota.toml";
+const ACL_FILE: &str = ".acl.toml";
+const METADATA_FILE: &str = ".metadata.toml";
+
+/// Filesystem backend, one directory per mailbox:
+///
+/// ```text
+/// <root>/<user>/<mailbox>/index.toml
+/// <root>/<user>/<mailbox>/<uid>.eml
+/// <root>/<user>/.subscriptions.toml
+/// <root>/<user>/.special_use.toml
+/// <root>/<user>/.quota.toml
+/// <root>/<user>/.acl.toml
+/// <root>/<user>/.metadata.toml
+/// ```
+///
+/// A directory without `index.toml` is a `\Noselect` level of the
+/// hierarchy.
+///
+/// Appended messages are written to `append-<n>.tmp` in the mailbox
+/// directory, then renamed once they get their UID.
+pub struct FsStore {
+    root: PathBuf,
+
+    /// Locks of the mailboxes and user files being updated, so concurrent
+    /// sessions update each in turn while the others go on.
+    locks: Locks<Mutex<()>>,
+
+    /// Hierarchy of each user, changed by creating, deleting and renaming
+    /// mailboxes while none is being updated.
+    hierarchies: Locks<RwLock<()>>,
+
+    /// Numbers the temporary files of appends in progress.
+    next_append: AtomicU64,
+
+    /// Last UIDVALIDITY given to a new mailbox.
+    last_uid_validity: AtomicU32,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Subscriptions {
+    #[serde(default)]
+    mailboxes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SpecialUseFile {
+    #[serde(default)]
+    mailboxes: SpecialUse,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct AclFile {
+    #[serde(default)]
+    mailboxes: Acl,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct MetadataFile {
+    #[serde(default)]
+    mailboxes: Metadata,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct QuotaFile {
+    /// Counted from the indexes on first use, then updated along with them.
+    usage: Option<QuotaUsage>,
+
+    limits: Option<QuotaLimits>,
+}
+
+impl FsStore {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            locks: Locks::new(),
+            hierarchies: Locks::new(),
+            next_append: AtomicU64::new(0),
+            last_uid_validity: AtomicU32::new(0),
+        }
+    }
+
+    fn user_path(&self, user: &str) -> Result<PathBuf> {
+        Ok(self.root.join(safe_user(user)?))
+    }
+
+    fn mailbox_path(&self, user: &str, mailbox: &str) -> Result<PathBuf> {
+        let mut path = self.user_path(user)?;
+        for part in mailbox.split('/') {
+            path.push(safe_component(part)?);
+        }
+        Ok(path)
+    }
+
+    /// Run `f` with `mailbox` locked, its hierarchy left as it is meanwhile.
+    fn with_mailbox<R>(
+        &self,
+        user: &str,
+        mailbox: &str,
+        f: impl FnOnce() -> Result<R>,
+    ) -> Result<R> {
+        let path = self.mailbox_path(user, mailbox)?;
+        self.hierarchies.with(self.user_path(user)?, |hierarchy| {
+            let _hierarchy = hierarchy.read().unwrap();
+            self.locks.with(path, |lock| {
+                let _guard = lock.lock().unwrap();
+                f()
+            })
+        })
+    }
+
+    /// Run `f` with the hierarchy of `user` locked, to change it.
+    fn with_hierarchy<R>(
+        &self,
+        user: &str,
+        f: impl FnOnce() -> Result<R>,
+    ) -> Result<R> {
+        self.hierarchies.with(self.user_path(user)?, |hierarchy| {
+            let _guard = hierarchy.write().unwrap();
+            f()
+        })
+    }
+
+    /// Run `f` with file `name` of `user`'s directory locked.
+    fn with_user_file<R>(
+        &self,
+        user: &str,
+        name: &str,
+        f: impl FnOnce() -> Result<R>,
+    ) -> Result<R> {
+        self.locks.with(self.user_path(user)?.join(name), |lock| {
+            let _guard = lock.lock().unwrap();
+            f()
+        })
+    }
+
+    /// Replace file `name` of `user`'s directory by `content`.
+    fn write_user_file(
+        &self,
+        user: &str,
+        name: &str,
+        content: &str,
+    ) -> Result<()> {
+        let path = self.user_path(user)?;
+        fs::create_dir_all(&path)?;
+        let tmp = path.join(format!("{}.tmp", name));
+        fs::write(&tmp, content)?;
+        fs::rename(tmp, path.join(name))?;
+        Ok(())
+    }
+
+    fn load_quota(&self, user: &str) -> Result<QuotaFile> {
+        let path = self.user_path(user)?.join(QUOTA_FILE);
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(toml::from_str(&content)
+                .map_err(|e| anyhow!("Corrupted quota: {}", e))?),
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Ok(QuotaFile::default())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_quota(&self, user: &str, quota: &QuotaFile) -> Result<()> {
+        let content = toml::to_string(quota)
+            .map_err(|e| anyhow!("Cannot serialize quota: {}", e))?;
+        self.write_user_file(user, QUOTA_FILE, &content)
+    }
+
+    /// Count what `user`'s mailboxes hold from their indexes.
+    fn count_usage(&self, user: &str) -> Result<QuotaUsage> {
+        let mut usage = QuotaUsage::default();
+        for entry in self.list_mailboxes(user)? {
+            if !entry.selectable {
+                continue;
+            }
+            let index = self.load_index(user, &entry.name)?;
+            usage.messages += index.messages.len() as u64;
+            usage.storage +=
+                index.messages.iter().map(|m| m.size as u64).sum::<u64>();
+        }
+        Ok(usage)
+    }
+
+    /// Add `storage` bytes and `messages` messages, negative for removed
+    /// ones, to `user`'s usage, with the lock of its quota file held. Usage
+    /// not counted yet is left to `quota_usage`. A failure is only logged,
+    /// the messages are already in or out of their mailbox.
+    fn add_usage_locked(&self, user: &str, storage: i64, messages: i64) {
+        let updated = self.load_quota(user).and_then(|mut quota| {
+            let usage = match quota.usage.as_mut() {
+                Some(usage) => usage,
+                None => return Ok(()),
+            };
+            usage.storage = usage.storage.saturating_add_signed(storage);
+            usage.messages = usage.messages.saturating_add_signed(messages);
+            self.save_quota(user, &quota)
+        });
+        if let Err(e) = updated {
+            eprintln!("Failed to update quota usage; err = {:?}", e);
+        }
+    }
+
+    /// `user`'s usage, counted and saved if it wasn't yet, with the lock of
+    /// its quota file held.
+    fn usage_locked(&self, user: &str) -> Result<QuotaUsage> {
+        let mut quota = self.load_quota(user)?;
+        if let Some(usage) = quota.usage {
+            return Ok(usage);
+        }
+        let usage = self.count_usage(user)?;
+        quota.usage = Some(usage);
+        self.save_quota(user, &quota)?;
+        Ok(usage)
+    }
+
+    /// Count `storage` bytes and `messages` messages added to `user`'s
+    /// mailboxes, refusing them with `OVERQUOTA` if they don't fit `quota`.
+    /// Messages added to other mailboxes meanwhile are counted before or
+    /// after, never both checked against the same usage.
+    fn reserve(
+        &self,
+        user: &str,
+        storage: u64,
+        messages: u64,
+        quota: Option<&QuotaLimits>,
+    ) -> Result<()> {
+        self.with_user_file(user, QUOTA_FILE, || {
+            if let Some(limits) = quota {
+                let usage = self.usage_locked(user)?;
+                if !limits.allow(&usage, storage, messages) {
+                    return Err(Refused(OVERQUOTA).into());
+                }
+            }
+            self.add_usage_locked(user, storage as i64, messages as i64);
+            Ok(())
+        })
+    }
+
+    fn add_usage(&self, user: &str, storage: i64, messages: i64) {
+        let added = self.with_user_file(user, QUOTA_FILE, || {
+            self.add_usage_locked(user, storage, messages);
+            Ok(())
+        });
+        if let Err(e) = added {
+            eprintln!("Failed to update quota usage; err = {:?}", e);
+        }
+    }
+
+    /// A UIDVALIDITY above any given before, so a mailbox created again
+    /// under a deleted one's name never reuses it.
+    fn new_uid_validity(&self) -> u32 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as u32)
+            .unwrap_or(1);
+        let last = self
+            .last_uid_validity
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+                Some(now.max(last + 1))
+            })
+            .unwrap();
+        now.max(last + 1)
+    }
+
+    /// Remove the `\Noselect` levels above `mailbox` left without any
+    /// inferior.
+    fn prune_parents(&self, user: &str, mailbox: &str) -> Result<()> {
+        let mut name = mailbox;
+        while let Some((parent, _)) = name.rsplit_once('/') {
+            let path = self.mailbox_path(user, parent)?;
+            if path.join(INDEX_FILE).exists() || fs::remove_dir(&path).is_err()
+            {
+                break;
+            }
+            name = parent;
+        }
+        Ok(())
+    }
+
+    fn message_path(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<PathBuf> {
+        Ok(self
+            .mailbox_path(user, mailbox)?
+            .join(format!("{}.eml", uid)))
+    }
+}
+
+struct FsMessageSink<'s> {
+    store: &'s FsStore,
+    user: String,
+    mailbox: String,
+    tmp: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    quota: Option<QuotaLimits>,
+    committed: bool,
+}
+
+impl Write for FsMessageSink<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.file.write(buf)?;
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl MessageSink for FsMessageSink<'_> {
+    fn commit(
+        mut self: Box<Self>,
+        flags: Vec<String>,
+        internal_date: DateTime<FixedOffset>,
+    ) -> Result<u32> {
+        self.file.flush()?;
+        self.file.get_ref().sync_all()?;
+        let size = u32::try_from(self.size).map_err(|_| {
+            anyhow!("Message of {} bytes is too big", self.size)
+        })?;
+
+        // the UID is taken and the file renamed under the mailbox lock, so
+        // concurrent appends can't race for the same UID
+        let mut appended: Result<u32> =
+            Err(anyhow!("Mailbox `{}` was not updated", self.mailbox).into());
+        let (store, user, mailbox) = (self.store, &self.user, &self.mailbox);
+        let quota = self.quota.as_ref();
+        store.update_index(user, mailbox, &mut |index| {
+            // counted along with the new UID, before a concurrent commit
+            // can check the usage
+            if let Err(e) = store.reserve(user, size as u64, 1, quota) {
+                appended = Err(e);
+                return;
+            }
+            let uid = index.uid_next;
+            let renamed = store
+                .message_path(user, mailbox, uid)
+                .and_then(|path| Ok(fs::rename(&self.tmp, path)?));
+            appended = match renamed {
+                Ok(()) => {
+                    index.uid_next += 1;
+                    let modseq = index.next_modseq();
+                    index.messages.push(MessageMeta {
+                        uid,
+                        flags: flags.clone(),
+                        internal_date,
+                        size,
+                        recent: true,
+                        modseq,
+                    });
+                    Ok(uid)
+                }
+                Err(e) => {
+                    store.add_usage(user, -(size as i64), -1);
+                    Err(e)
+                }
+            };
+        })?;
+
+        let uid = appended?;
+        self.committed = true;
+        Ok(uid)
+    }
+}
+
+impl Drop for FsMessageSink<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = fs::remove_file(&self.tmp);
+        }
+    }
+}
+
+/// Locks by path, kept only while they are held or awaited.
+struct Locks<T> {
+    held: Mutex<HashMap<PathBuf, Arc<T>>>,
+}
+
+impl<T: Default> Locks<T> {
+    fn new() -> Self {
+        Self {
+            held: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Run `f` with the lock of `path`, for it to take.
+    fn with<R>(&self, path: PathBuf, f: impl FnOnce(&T) -> R) -> R {
+        let lock = {
+            let mut held = self.held.lock().unwrap();
+            held.entry(path.clone()).or_default().clone()
+        };
+        let result = blocking(|| f(&lock));
+
+        // other users of the lock got it from the map, under its lock
+        let mut held = self.held.lock().unwrap();
+        if Arc::strong_count(&lock) == 2 {
+            held.remove(&path);
+        }
+        result
+    }
+}
+
+/// Run `f`, which waits for locks or files, off the async workers when on
+/// a multi-threaded runtime so the other sessions of the worker go on.
+fn blocking<R>(f: impl FnOnce() -> R) -> R {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(f)
+        }
+        _ => f(),
+    }
+}
+
+fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
+
+fn collect_mailboxes(
+    dir: &Path,
+    prefix: &str,
+    entries: &mut Vec<MailboxEntry>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        entries.push(MailboxEntry {
+            name: name.clone(),
+            selectable: entry.path().join(INDEX_FILE).exists(),
+        });
+        collect_mailboxes(&entry.path(), &format!("{}/", name), entries)?;
+    }
+    Ok(())
+}
+
+fn has_subdirectory(dir: &Path) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        if entry?.file_type()?.is_dir() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Make sure user `name` is a single directory below the root, clear of
+/// the shared namespace's which is the only one starting with a dot.
+fn safe_user(name: &str) -> Result<&str> {
+    if name == SHARED_OWNER {
+        return Ok(name);
+    }
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.contains(['/', '\\', '\0'])
+        || Path::new(name).is_absolute()
+    {
+        return Err(anyhow!("Invalid user name `{}`", name).into());
+    }
+    Ok(name)
+}
+
+/// Make sure mailbox level `name` is a single directory that can't be
+/// mistaken for the files kept next to it: indexes, messages, files being
+/// written, and the dotfiles of the user.
+fn safe_component(name: &str) -> Result<&str> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.contains(['\\', '\0'])
+        || name == INDEX_FILE
+        || name.ends_with(".eml")
+        || name.ends_with(".tmp")
+    {
+        return Err(anyhow!("Invalid mailbox name `{}`", name).into());
+    }
+    Ok(name)
+}
+
+impl MailStore for FsStore {
+    fn load_index(&self, user: &str, mailbox: &str) -> Result<MailboxIndex> {
+        let path = self.mailbox_path(user, mailbox)?;
+        match blocking(|| fs::read_to_string(path.join(INDEX_FILE))) {
+            Ok(content) => {
+                let mut index: MailboxIndex = toml::from_str(&content)
+                    .map_err(|e| {
+                        anyhow!("Corrupted index of `{}`: {}", mailbox, e)
+                    })?;
+                index.upgrade();
+                Ok(index)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound && mailbox == INBOX => {
+                // INBOX always exists, create it on first access.
+                let index = MailboxIndex::new(self.new_uid_validity());
+                self.save_index(user, mailbox, &index)?;
+                Ok(index)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                Err(anyhow!("Mailbox `{}` doesn't exist", mailbox).into())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_index(
+        &self,
+        user: &str,
+        mailbox: &str,
+        index: &MailboxIndex,
+    ) -> Result<()> {
+        let path = self.mailbox_path(user, mailbox)?;
+        fs::create_dir_all(&path)?;
+
+        let content = toml::to_string(index)
+            .map_err(|e| anyhow!("Cannot serialize index: {}", e))?;
+
+        // write then rename, so readers never see a partial index
+        let tmp = path.join(format!("{}.tmp", INDEX_FILE));
+        fs::write(&tmp, content)?;
+        fs::rename(tmp, path.join(INDEX_FILE))?;
+        Ok(())
+    }
+
+    fn update_index(
+        &self,
+        user: &str,
+        mailbox: &str,
+        f: &mut dyn FnMut(&mut MailboxIndex),
+    ) -> Result<()> {
+        self.with_mailbox(user, mailbox, || {
+            let mut index = self.load_index(user, mailbox)?;
+            f(&mut index);
+            self.save_index(user, mailbox, &index)
+        })
+    }
+
+    fn has_mailbox(&self, user: &str, mailbox: &str) -> Result<bool> {
+        let path = self.mailbox_path(user, mailbox)?;
+        Ok(mailbox == INBOX || path.join(INDEX_FILE).exists())
+    }
+
+    fn is_writable(&self, user: &str, mailbox: &str) -> Result<bool> {
+        let path = self.mailbox_path(user, mailbox)?;
+        let index = path.join(INDEX_FILE);
+        if !index.exists() {
+            // not stored yet, it's created on first use
+            return Ok(true);
+        }
+        for path in [&path, &index] {
+            if fs::metadata(path)?.permissions().readonly() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    fn has_sticky_uids(&self, _user: &str, _mailbox: &str) -> Result<bool> {
+        // UIDs are assigned and kept in the index
+        Ok(true)
+    }
+
+    fn list_users(&self) -> Result<Vec<String>> {
+        let mut users = vec![];
+        let entries = match fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(users),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if entry.file_type()?.is_dir() && !name.starts_with('.') {
+                users.push(name);
+            }
+        }
+        users.sort();
+        Ok(users)
+    }
+
+    fn list_mailboxes(&self, user: &str) -> Result<Vec<MailboxEntry>> {
+        let mut entries = vec![];
+        let path = self.user_path(user)?;
+        if path.exists() {
+            collect_mailboxes(&path, "", &mut entries)?;
+        }
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    fn create_mailbox(&self, user: &str, mailbox: &str) -> Result<()> {
+        self.with_hierarchy(user, || {
+            if self.has_mailbox(user, mailbox)? {
+                return Err(
+                    anyhow!("Mailbox `{}` already exists", mailbox).into()
+                );
+            }
+            let index = MailboxIndex::new(self.new_uid_validity());
+            self.save_index(user, mailbox, &index)
+        })
+    }
+
+    fn delete_mailbox(&self, user: &str, mailbox: &str) -> Result<()> {
+        self.with_hierarchy(user, || {
+            let path = self.mailbox_path(user, mailbox)?;
+            if !path.is_dir() {
+                return Err(
+                    anyhow!("Mailbox `{}` doesn't exist", mailbox).into()
+                );
+            }
+
+            let messages = match path.join(INDEX_FILE).exists() {
+                true => self.load_index(user, mailbox)?.messages,
+                false => vec![],
+            };
+            let storage: i64 = messages.iter().map(|m| m.size as i64).sum();
+
+            if !has_subdirectory(&path)? {
+                fs::remove_dir_all(&path)?;
+                self.add_usage(user, -storage, -(messages.len() as i64));
+                return self.prune_parents(user, mailbox);
+            }
+            if !path.join(INDEX_FILE).exists() {
+                return Err(
+                    anyhow!("Mailbox `{}` has inferiors", mailbox).into()
+                );
+            }
+
+            // the inferiors stay, only the messages and index go
+            fs::remove_file(path.join(INDEX_FILE))?;
+            self.add_usage(user, -storage, -(messages.len() as i64));
+            for entry in fs::read_dir(&path)? {
+                let entry = entry?;
+                if entry.file_type()?.is_file() {
+                    fs::remove_file(entry.path())?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn rename_mailbox(
+        &self,
+        user: &str,
+        mailbox: &str,
+        new_name: &str,
+    ) -> Result<()> {
+        self.with_hierarchy(user, || {
+            let from = self.mailbox_path(user, mailbox)?;
+            let to = self.mailbox_path(user, new_name)?;
+            if !from.is_dir() {
+                return Err(
+                    anyhow!("Mailbox `{}` doesn't exist", mailbox).into()
+                );
+            }
+            if to.exists() {
+                return Err(
+                    anyhow!("Mailbox `{}` already exists", new_name).into()
+                );
+            }
+            if new_name.starts_with(&format!("{}/", mailbox)) {
+                return Err(
+                    anyhow!("Can't move `{}` into itself", mailbox).into()
+                );
+            }
+
+            fs::create_dir_all(to.parent().unwrap())?;
+            fs::rename(from, to)?;
+            self.prune_parents(user, mailbox)
+        })
+    }
+
+    fn subscriptions(&self, user: &str) -> Result<Vec<String>> {
+        let path = self.user_path(user)?.join(SUBSCRIPTIONS_FILE);
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                let subscriptions: Subscriptions = toml::from_str(&content)
+                    .map_err(|e| anyhow!("Corrupted subscriptions: {}", e))?;
+                Ok(subscriptions.mailboxes)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn update_subscriptions(
+        &self,
+        user: &str,
+        f: &mut dyn FnMut(&mut Vec<String>),
+    ) -> Result<()> {
+        self.with_user_file(user, SUBSCRIPTIONS_FILE, || {
+            let mut subscriptions = Subscriptions {
+                mailboxes: self.subscriptions(user)?,
+            };
+            f(&mut subscriptions.mailboxes);
+
+            let content = toml::to_string(&subscriptions).map_err(|e| {
+                anyhow!("Cannot serialize subscriptions: {}", e)
+            })?;
+            self.write_user_file(user, SUBSCRIPTIONS_FILE, &content)
+        })
+    }
+
+    fn special_use(&self, user: &str) -> Result<SpecialUse> {
+        let path = self.user_path(user)?.join(SPECIAL_USE_FILE);
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                let special_use: SpecialUseFile = toml::from_str(&content)
+                    .map_err(|e| anyhow!("Corrupted special-use: {}", e))?;
+                Ok(special_use.mailboxes)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(SpecialUse::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn update_special_use(
+        &self,
+        user: &str,
+        f: &mut dyn FnMut(&mut SpecialUse),
+    ) -> Result<()> {
+        self.with_user_file(user, SPECIAL_USE_FILE, || {
+            let mut special_use = SpecialUseFile {
+                mailboxes: self.special_use(user)?,
+            };
+            f(&mut special_use.mailboxes);
+
+            let content = toml::to_string(&special_use)
+                .map_err(|e| anyhow!("Cannot serialize special-use: {}", e))?;
+            self.write_user_file(user, SPECIAL_USE_FILE, &content)
+        })
+    }
+
+    fn acl(&self, user: &str) -> Result<Acl> {
+        let path = self.user_path(user)?.join(ACL_FILE);
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                let acl: AclFile = toml::from_str(&content)
+                    .map_err(|e| anyhow!("Corrupted ACL: {}", e))?;
+                Ok(acl.mailboxes)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Acl::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn update_acl(
+        &self,
+        user: &str,
+        f: &mut dyn FnMut(&mut Acl),
+    ) -> Result<()> {
+        self.with_user_file(user, ACL_FILE, || {
+            let mut acl = AclFile {
+                mailboxes: self.acl(user)?,
+            };
+            f(&mut acl.mailboxes);
+
+            let content = toml::to_string(&acl)
+                .map_err(|e| anyhow!("Cannot serialize ACL: {}", e))?;
+            self.write_user_file(user, ACL_FILE, &content)
+        })
+    }
+
+    fn metadata(&self, user: &str) -> Result<Metadata> {
+        let path = self.user_path(user)?.join(METADATA_FILE);
+        match fs::read_to_string(path) {
+            Ok(content) => {
+                let metadata: MetadataFile = toml::from_str(&content)
+                    .map_err(|e| anyhow!("Corrupted metadata: {}", e))?;
+                Ok(metadata.mailboxes)
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Metadata::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn update_metadata(
+        &self,
+        user: &str,
+        f: &mut dyn FnMut(&mut Metadata),
+    ) -> Result<()> {
+        self.with_user_file(user, METADATA_FILE, || {
+            let before = self.metadata(user)?;
+            let mut metadata = MetadataFile {
+                mailboxes: before.clone(),
+            };
+            f(&mut metadata.mailboxes);
+            if metadata.mailboxes == before {
+                return Ok(());
+            }
+
+            let content = toml::to_string(&metadata)
+                .map_err(|e| anyhow!("Cannot serialize metadata: {}", e))?;
+            self.write_user_file(user, METADATA_FILE, &content)
+        })
+    }
+
+    fn quota_usage(&self, user: &str) -> Result<QuotaUsage> {
+        self.with_user_file(user, QUOTA_FILE, || self.usage_locked(user))
+    }
+
+    fn quota_limits(&self, user: &str) -> Result<Option<QuotaLimits>> {
+        Ok(self.load_quota(user)?.limits)
+    }
+
+    fn set_quota_limits(&self, user: &str, limits: &QuotaLimits) -> Result<()> {
+        self.with_user_file(user, QUOTA_FILE, || {
+            let mut quota = self.load_quota(user)?;
+            quota.limits = Some(*limits);
+            self.save_quota(user, &quota)
+        })
+    }
+
+    fn remove_messages(
+        &self,
+        user: &str,
+        mailbox: &str,
+        select: &mut dyn FnMut(&MailboxIndex) -> Vec<u32>,
+    ) -> Result<Vec<u32>> {
+        let (mut storage, mut messages) = (0, 0);
+        let mut uids = vec![];
+        self.update_index(user, mailbox, &mut |index| {
+            let selected: HashSet<u32> = select(index).into_iter().collect();
+            uids = index
+                .messages
+                .iter()
+                .filter(|m| selected.contains(&m.uid))
+                .map(|m| {
+                    storage += m.size as i64;
+                    messages += 1;
+                    m.uid
+                })
+                .collect();
+            index.expunge(&uids)
+        })?;
+        self.add_usage(user, -storage, -messages);
+
+        // files go last, a crash in between leaves orphans, not holes
+        for uid in &uids {
+            match fs::remove_file(self.message_path(user, mailbox, *uid)?) {
+                Err(e) if e.kind() != ErrorKind::NotFound => {
+                    return Err(e.into())
+                }
+                _ => {}
+            }
+        }
+        Ok(uids)
+    }
+
+    fn read_message(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<Vec<u8>> {
+        Ok(fs::read(self.message_path(user, mailbox, uid)?)?)
+    }
+
+    fn append_message<'s>(
+        &'s self,
+        user: &str,
+        mailbox: &str,
+        quota: Option<&QuotaLimits>,
+    ) -> Result<Box<dyn MessageSink + 's>> {
+        self.load_index(user, mailbox)?;
+
+        let n = self.next_append.fetch_add(1, Ordering::Relaxed);
+        let tmp = self
+            .mailbox_path(user, mailbox)?
+            .join(format!("append-{}.tmp", n));
+        let file = BufWriter::new(File::create(&tmp)?);
+
+        Ok(Box::new(FsMessageSink {
+            store: self,
+            user: user.to_string(),
+            mailbox: mailbox.to_string(),
+            tmp,
+            file,
+            size: 0,
+            quota: quota.copied(),
+            committed: false,
+        }))
+    }
+
+    fn copy_messages(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+        dest_user: &str,
+        dest: &str,
+        keep_flag: &dyn Fn(&str) -> bool,
+        quota: Option<&QuotaLimits>,
+    ) -> Result<Vec<(u32, u32)>> {
+        let index = self.load_index(user, mailbox)?;
+        let by_uid: HashMap<u32, &MessageMeta> =
+            index.messages.iter().map(|m| (m.uid, m)).collect();
+        let sources: Vec<&MessageMeta> = uids
+            .iter()
+            .filter_map(|uid| by_uid.get(uid).copied())
+            .collect();
+        let storage = sources.iter().map(|m| m.size as u64).sum();
+
+        // stored messages never change, so copies can share their file
+        let mut copied = vec![];
+        let mut linked: Result<()> = Ok(());
+        self.update_index(dest_user, dest, &mut |dest_index| {
+            let count = sources.len() as u64;
+            if count > 0 {
+                linked = self.reserve(dest_user, storage, count, quota);
+                if linked.is_err() {
+                    return;
+                }
+            }
+            let modseq = dest_index.highest_modseq + 1;
+            let mut added = vec![];
+            for meta in sources.iter() {
+                let uid = dest_index.uid_next + added.len() as u32;
+                linked = self.message_path(user, mailbox, meta.uid).and_then(
+                    |from| {
+                        let to = self.message_path(dest_user, dest, uid)?;
+                        link_or_copy(&from, &to)
+                    },
+                );
+                if linked.is_err() {
+                    break;
+                }
+                let flags = meta.flags.iter().filter(|f| keep_flag(f));
+                added.push(MessageMeta {
+                    uid,
+                    recent: true,
+                    modseq,
+                    flags: flags.cloned().collect(),
+                    ..(*meta).clone()
+                });
+            }
+
+            if linked.is_err() {
+                // all or nothing, drop the files linked so far
+                self.add_usage(dest_user, -(storage as i64), -(count as i64));
+                for meta in added {
+                    if let Ok(path) =
+                        self.message_path(dest_user, dest, meta.uid)
+                    {
+                        let _ = fs::remove_file(path);
+                    }
+                }
+                return;
+            }
+            dest_index.uid_next += added.len() as u32;
+            if !added.is_empty() {
+                dest_index.next_modseq();
+            }
+            copied = sources
+                .iter()
+                .map(|m| m.uid)
+                .zip(added.iter().map(|m| m.uid))
+                .collect();
+            dest_index.messages.extend(added);
+        })?;
+
+        linked?;
+        Ok(copied)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_names_stay_below_the_root() {
+        for name in ["bob", "bob.smith", "bob@example.org", SHARED_OWNER] {
+            assert!(safe_user(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "", ".", "..", "../x", "/tmp/x", "a/b", "a\\b", ".hidden", "a\0",
+        ] {
+            assert!(safe_user(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn mailbox_levels_dont_clash_with_store_files() {
+        for name in ["INBOX", "Sent Items", "a.b", "eml", "tmp"] {
+            assert!(safe_component(name).is_ok(), "{}", name);
+        }
+        for name in [
+            "",
+            ".",
+            "..",
+            ".acl.toml",
+            INDEX_FILE,
+            "12.eml",
+            "append-1.tmp",
+            "a\\b",
+            "a\0",
+        ] {
+            assert!(safe_component(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn paths_reject_escapes() {
+        let store = FsStore::new("/srv/mail");
+        assert_eq!(
+            store.mailbox_path("bob", "a/b").unwrap(),
+            Path::new("/srv/mail/bob/a/b")
+        );
+        assert!(store.user_path("../x").is_err());
+        assert!(store.user_path("/tmp/x").is_err());
+        assert!(store.mailbox_path("bob", "../x").is_err());
+        assert!(store.mailbox_path("bob", "/x").is_err());
+        assert!(store.mailbox_path("bob", "a//b").is_err());
+    }
+
+    #[test]
+    fn removes_messages_picked_from_the_locked_index() {
+        let root = std::env::temp_dir()
+            .join(format!("imaple-store-{}", std::process::id()));
+        let store = FsStore::new(&root);
+        let date =
+            DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        for flags in [vec![], vec!["\\Deleted".to_string()]] {
+            let mut sink = store.append_message("bob", INBOX, None).unwrap();
+            sink.write_all(b"Subject: x\r\n\r\nbody\r\n").unwrap();
+            sink.commit(flags, date).unwrap();
+        }
+
+        let removed = store
+            .remove_messages("bob", INBOX, &mut |index| {
+                let deleted =
+                    index.messages.iter().filter(|m| m.has_flag("\\Deleted"));
+                // UIDs of no message are left out
+                deleted.map(|m| m.uid).chain([7]).collect()
+            })
+            .unwrap();
+        assert_eq!(removed, [2]);
+        let index = store.load_index("bob", INBOX).unwrap();
+        assert_eq!(
+            index.messages.iter().map(|m| m.uid).collect::<Vec<_>>(),
+            [1]
+        );
+        assert!(store.read_message("bob", INBOX, 2).is_err());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn updates_mailboxes_independently() {
+        let root = std::env::temp_dir()
+            .join(format!("imaple-locks-{}", std::process::id()));
+        let store = Arc::new(FsStore::new(&root));
+        store.create_mailbox("bob", "Archive").unwrap();
+
+        // another mailbox is updated while INBOX is locked
+        let (locked_tx, locked) = std::sync::mpsc::channel();
+        let (done, done_rx) = std::sync::mpsc::channel();
+        let holder = store.clone();
+        let holder = std::thread::spawn(move || {
+            holder.update_index("bob", INBOX, &mut |_| {
+                locked_tx.send(()).unwrap();
+                let timeout = std::time::Duration::from_secs(10);
+                assert!(done_rx.recv_timeout(timeout).is_ok());
+            })
+        });
+        locked.recv().unwrap();
+        store
+            .update_index("bob", "Archive", &mut |index| index.uid_next = 5)
+            .unwrap();
+        done.send(()).unwrap();
+        holder.join().unwrap().unwrap();
+        assert_eq!(store.load_index("bob", "Archive").unwrap().uid_next, 5);
+
+        // locks are forgotten once released
+        assert!(store.locks.held.lock().unwrap().is_empty());
+        assert!(store.hierarchies.held.lock().unwrap().is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn commits_reserve_quota_under_the_lock() {
+        let root = std::env::temp_dir()
+            .join(format!("imaple-quota-{}", std::process::id()));
+        let store = FsStore::new(&root);
+        let date =
+            DateTime::parse_from_rfc3339("2024-01-01T00:00:00Z").unwrap();
+        let quota = QuotaLimits {
+            storage: None,
+            messages: Some(1),
+        };
+
+        // both pass a check made before committing, only one fits
+        let mut sinks = vec![];
+        for _ in 0..2 {
+            let mut sink =
+                store.append_message("bob", INBOX, Some(&quota)).unwrap();
+            sink.write_all(b"Subject: x\r\n\r\nbody\r\n").unwrap();
+            sinks.push(sink);
+        }
+        let mut results = sinks.into_iter().map(|s| s.commit(vec![], date));
+        assert!(results.next().unwrap().is_ok());
+        let refused = results.next().unwrap().unwrap_err();
+        assert_eq!(refused.refusal(), Some(OVERQUOTA));
+
+        assert_eq!(store.quota_usage("bob").unwrap().messages, 1);
+        let copied = store.copy_messages(
+            "bob",
+            INBOX,
+            &[1],
+            "bob",
+            INBOX,
+            &|_| true,
+            Some(&quota),
+        );
+        assert!(copied.is_err());
+        assert_eq!(store.quota_usage("bob").unwrap().messages, 1);
+        assert_eq!(store.load_index("bob", INBOX).unwrap().messages.len(), 1);
+        fs::remove_dir_all(root).unwrap();
+    }
+}
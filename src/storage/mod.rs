@@ -0,0 +1,406 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+
+use crate::result::Result;
+
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+mod fs_store;
+
+pub use fs_store::FsStore;
+
+pub const INBOX: &str = "INBOX";
+
+/// Hierarchy delimiter of mailbox names in the storage layer.
+pub const DELIMITER: char = '/';
+
+/// Flags defined by RFC 3501, any other stored flag is a keyword.
+pub const SYSTEM_FLAGS: [&str; 5] =
+    ["\\Answered", "\\Flagged", "\\Deleted", "\\Seen", "\\Draft"];
+
+/// Special-use attributes of RFC 6154 a mailbox may be given.
+pub const SPECIAL_USES: [&str; 7] = [
+    "\\All",
+    "\\Archive",
+    "\\Drafts",
+    "\\Flagged",
+    "\\Junk",
+    "\\Sent",
+    "\\Trash",
+];
+
+/// Special-use attributes of a user's mailboxes, by mailbox name.
+pub type SpecialUse = BTreeMap<String, Vec<String>>;
+
+/// Rights granted on a user's mailboxes (RFC 4314), by mailbox name then
+/// identifier.
+pub type Acl = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Metadata entries (RFC 5464) a user keeps, by mailbox name, the server's
+/// under the empty name, then by entry name.
+pub type Metadata = BTreeMap<String, BTreeMap<String, String>>;
+
+/// Owner of the mailboxes of the shared namespace, which no user has, as
+/// `list_users` leaves it out.
+pub const SHARED_OWNER: &str = ".shared";
+
+/// What a user's mailboxes hold together, counted against their quota
+/// (RFC 9208).
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct QuotaUsage {
+    /// Sum of the message sizes, in bytes.
+    pub storage: u64,
+
+    pub messages: u64,
+}
+
+/// Limits of a user's quota, a resource without one being unlimited.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+pub struct QuotaLimits {
+    /// In units of 1024 bytes, as the STORAGE resource is counted.
+    pub storage: Option<u64>,
+
+    pub messages: Option<u64>,
+}
+
+impl QuotaLimits {
+    /// Whether `storage` bytes and `messages` more messages than `usage`
+    /// stay within the limits.
+    pub fn allow(
+        &self,
+        usage: &QuotaUsage,
+        storage: u64,
+        messages: u64,
+    ) -> bool {
+        let storage = usage.storage.saturating_add(storage);
+        let messages = usage.messages.saturating_add(messages);
+        self.storage
+            .is_none_or(|limit| storage <= limit.saturating_mul(1024))
+            && self.messages.is_none_or(|limit| messages <= limit)
+    }
+}
+
+/// Tombstones kept per mailbox, the oldest are dropped beyond it.
+const MAX_TOMBSTONES: usize = 10_000;
+
+/// Per-message metadata kept in the mailbox index, so most commands can be
+/// answered without opening the message itself.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MessageMeta {
+    pub uid: u32,
+
+    /// System flags (`\Seen`, `\Deleted`, ...) and keywords.
+    #[serde(default)]
+    pub flags: Vec<String>,
+
+    pub internal_date: DateTime<FixedOffset>,
+
+    pub size: u32,
+
+    #[serde(default)]
+    pub recent: bool,
+
+    /// Mod-sequence of the last change to the message (RFC 7162).
+    #[serde(default)]
+    pub modseq: u64,
+}
+
+impl MessageMeta {
+    pub fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f.eq_ignore_ascii_case(flag))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MailboxIndex {
+    pub uid_validity: u32,
+    pub uid_next: u32,
+
+    /// Highest mod-sequence of the mailbox (RFC 7162).
+    #[serde(default)]
+    pub highest_modseq: u64,
+
+    #[serde(default)]
+    pub messages: Vec<MessageMeta>,
+
+    /// Expunged messages, oldest first.
+    #[serde(default)]
+    pub tombstones: Vec<Tombstone>,
+
+    /// Highest mod-sequence of the tombstones dropped so far.
+    #[serde(default)]
+    pub pruned_modseq: u64,
+}
+
+/// What is left of an expunged message, so QRESYNC clients can be told it
+/// vanished.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Tombstone {
+    pub uid: u32,
+
+    /// Mod-sequence of the expunge.
+    pub modseq: u64,
+}
+
+impl MailboxIndex {
+    pub fn new(uid_validity: u32) -> Self {
+        Self {
+            uid_validity,
+            uid_next: 1,
+            highest_modseq: 1,
+            messages: vec![],
+            tombstones: vec![],
+            pruned_modseq: 0,
+        }
+    }
+
+    /// Give mod-sequences to an index written before they were kept.
+    pub fn upgrade(&mut self) {
+        if self.highest_modseq == 0 {
+            self.highest_modseq = 1;
+            self.messages.iter_mut().for_each(|m| m.modseq = 1);
+        }
+    }
+
+    /// Take a new mod-sequence for a change to the mailbox.
+    pub fn next_modseq(&mut self) -> u64 {
+        self.highest_modseq += 1;
+        self.highest_modseq
+    }
+
+    /// Remove the messages of `uids`, leaving tombstones.
+    pub fn expunge(&mut self, uids: &[u32]) {
+        let uids: HashSet<u32> = uids.iter().copied().collect();
+        if !self.messages.iter().any(|m| uids.contains(&m.uid)) {
+            return;
+        }
+        let modseq = self.next_modseq();
+        let tombstones = &mut self.tombstones;
+        self.messages.retain(|m| {
+            if !uids.contains(&m.uid) {
+                return true;
+            }
+            tombstones.push(Tombstone { uid: m.uid, modseq });
+            false
+        });
+
+        if self.tombstones.len() > MAX_TOMBSTONES {
+            let excess = self.tombstones.len() - MAX_TOMBSTONES;
+            let pruned = self.tombstones.drain(..excess);
+            self.pruned_modseq = pruned.map(|t| t.modseq).max().unwrap_or(0);
+        }
+    }
+
+    /// UIDs expunged after `modseq`, `None` if tombstones that old were
+    /// dropped.
+    pub fn expunged_since(&self, modseq: u64) -> Option<Vec<u32>> {
+        if modseq < self.pruned_modseq {
+            return None;
+        }
+        let mut uids: Vec<u32> = self
+            .tombstones
+            .iter()
+            .filter(|t| t.modseq > modseq)
+            .map(|t| t.uid)
+            .collect();
+        uids.sort_unstable();
+        Some(uids)
+    }
+}
+
+/// A mailbox as found by `MailStore::list_mailboxes`.
+#[derive(Clone, Debug)]
+pub struct MailboxEntry {
+    /// `DELIMITER` separated name.
+    pub name: String,
+
+    /// `false` for a level of the hierarchy holding no messages, which is
+    /// listed as `\Noselect`.
+    pub selectable: bool,
+}
+
+/// A message being added to a mailbox, written in pieces so it never has to
+/// be held in memory. Dropping it uncommitted discards what was written.
+pub trait MessageSink: Write + Send {
+    /// Make the written message part of the mailbox, returning its UID.
+    fn commit(
+        self: Box<Self>,
+        flags: Vec<String>,
+        internal_date: DateTime<FixedOffset>,
+    ) -> Result<u32>;
+}
+
+/// Storage backend holding the users' mailboxes.
+pub trait MailStore: Send + Sync {
+    /// Load the index of `mailbox`, failing if the mailbox doesn't exist.
+    fn load_index(&self, user: &str, mailbox: &str) -> Result<MailboxIndex>;
+
+    fn save_index(
+        &self,
+        user: &str,
+        mailbox: &str,
+        index: &MailboxIndex,
+    ) -> Result<()>;
+
+    /// Load, modify and save the index of `mailbox` without any other
+    /// update interleaving.
+    fn update_index(
+        &self,
+        user: &str,
+        mailbox: &str,
+        f: &mut dyn FnMut(&mut MailboxIndex),
+    ) -> Result<()>;
+
+    fn has_mailbox(&self, user: &str, mailbox: &str) -> Result<bool>;
+
+    /// Whether `user` may change the messages and flags of `mailbox`, which
+    /// is selected read-only otherwise.
+    fn is_writable(&self, user: &str, mailbox: &str) -> Result<bool>;
+
+    /// Whether the UIDs of `mailbox` persist with its UID validity, so
+    /// clients may keep them across sessions (RFC 4315).
+    fn has_sticky_uids(&self, user: &str, mailbox: &str) -> Result<bool>;
+
+    /// Every user owning mailboxes, sorted by name.
+    fn list_users(&self) -> Result<Vec<String>>;
+
+    /// Every mailbox owned by `user`, sorted by name.
+    fn list_mailboxes(&self, user: &str) -> Result<Vec<MailboxEntry>>;
+
+    /// Create `mailbox`, and any missing level above it as a `\Noselect`
+    /// one. Fails if the mailbox exists already.
+    fn create_mailbox(&self, user: &str, mailbox: &str) -> Result<()>;
+
+    /// Delete `mailbox` with its messages. A mailbox with inferiors only
+    /// loses its messages and stays as a `\Noselect` level.
+    fn delete_mailbox(&self, user: &str, mailbox: &str) -> Result<()>;
+
+    /// Rename `mailbox`, along with its inferiors, to `new_name`.
+    fn rename_mailbox(
+        &self,
+        user: &str,
+        mailbox: &str,
+        new_name: &str,
+    ) -> Result<()>;
+
+    /// Mailbox names `user` subscribed to, existing or not.
+    fn subscriptions(&self, user: &str) -> Result<Vec<String>>;
+
+    /// Load, modify and save the subscriptions of `user`.
+    fn update_subscriptions(
+        &self,
+        user: &str,
+        f: &mut dyn FnMut(&mut Vec<String>),
+    ) -> Result<()>;
+
+    /// Special-use attributes of `user`'s mailboxes.
+    fn special_use(&self, user: &str) -> Result<SpecialUse>;
+
+    /// Load, modify and save the special-use attributes of `user`'s
+    /// mailboxes.
+    fn update_special_use(
+        &self,
+        user: &str,
+        f: &mut dyn FnMut(&mut SpecialUse),
+    ) -> Result<()>;
+
+    /// What `user`'s mailboxes hold, kept up to date as messages are added
+    /// and removed rather than counted on every call.
+    fn quota_usage(&self, user: &str) -> Result<QuotaUsage>;
+
+    /// Limits set for `user`, `None` if the configured ones apply.
+    fn quota_limits(&self, user: &str) -> Result<Option<QuotaLimits>>;
+
+    fn set_quota_limits(&self, user: &str, limits: &QuotaLimits) -> Result<()>;
+
+    /// Access control lists of `user`'s mailboxes.
+    fn acl(&self, user: &str) -> Result<Acl>;
+
+    /// Load, modify and save the access control lists of `user`'s
+    /// mailboxes.
+    fn update_acl(&self, user: &str, f: &mut dyn FnMut(&mut Acl))
+        -> Result<()>;
+
+    /// Metadata entries `user` keeps.
+    fn metadata(&self, user: &str) -> Result<Metadata>;
+
+    /// Load, modify and save the metadata entries `user` keeps, not saved
+    /// if `f` leaves them unchanged.
+    fn update_metadata(
+        &self,
+        user: &str,
+        f: &mut dyn FnMut(&mut Metadata),
+    ) -> Result<()>;
+
+    /// Remove the messages of `mailbox` that `select` picks from its index,
+    /// under the same lock as `update_index`, both from the index and the
+    /// store. Returns the UIDs removed, those of existing messages.
+    fn remove_messages(
+        &self,
+        user: &str,
+        mailbox: &str,
+        select: &mut dyn FnMut(&MailboxIndex) -> Vec<u32>,
+    ) -> Result<Vec<u32>>;
+
+    /// Read the raw RFC 5322 message stored under `uid`.
+    fn read_message(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uid: u32,
+    ) -> Result<Vec<u8>>;
+
+    /// Start adding a message to `mailbox`, which must exist. With `quota`,
+    /// committing it fails with `OVERQUOTA` unless it fits the limits, the
+    /// usage being checked and counted under the lock of the index update
+    /// so concurrent additions can't both pass.
+    fn append_message<'s>(
+        &'s self,
+        user: &str,
+        mailbox: &str,
+        quota: Option<&QuotaLimits>,
+    ) -> Result<Box<dyn MessageSink + 's>>;
+
+    /// Copy messages of `mailbox` to `dest` of `dest_user` with their flags
+    /// `keep_flag` keeps and internal date, returning `(uid, new uid)` pairs
+    /// in the order of `uids`. Missing UIDs are skipped. `quota` limits the
+    /// copies as for `append_message`. Either every message is copied or,
+    /// on failure, none is.
+    #[allow(clippy::too_many_arguments)]
+    fn copy_messages(
+        &self,
+        user: &str,
+        mailbox: &str,
+        uids: &[u32],
+        dest_user: &str,
+        dest: &str,
+        keep_flag: &dyn Fn(&str) -> bool,
+        quota: Option<&QuotaLimits>,
+    ) -> Result<Vec<(u32, u32)>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quota_checks_dont_overflow() {
+        let usage = QuotaUsage {
+            storage: u64::MAX - 10,
+            messages: 3,
+        };
+        let huge = QuotaLimits {
+            storage: Some(u64::MAX / 2),
+            messages: Some(10),
+        };
+        assert!(huge.allow(&usage, 20, 7));
+        assert!(!huge.allow(&usage, 0, u64::MAX));
+
+        let small = QuotaLimits {
+            storage: Some(1),
+            messages: None,
+        };
+        assert!(!small.allow(&usage, u64::MAX, 0));
+    }
+}
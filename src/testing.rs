@@ -0,0 +1,82 @@
+//! Helpers for the tests driving commands through a whole session.
+
+use std::fs;
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::DateTime;
+
+use crate::auth::NoUsers;
+use crate::config::Config;
+use crate::events::EventBus;
+use crate::imap::process_command;
+use crate::session::Session;
+use crate::storage::FsStore;
+
+/// An empty directory for the mail of the test `name`.
+pub fn mail_dir(name: &str) -> PathBuf {
+    let root = std::env::temp_dir().join(format!(
+        "imaple-{}-{}",
+        name,
+        std::process::id()
+    ));
+    let _ = fs::remove_dir_all(&root);
+    root
+}
+
+/// A session of bob, logged in, his mail kept below `root`.
+pub fn session(root: &Path) -> Session {
+    configured_session(root, "")
+}
+
+/// A session of bob, logged in, on a server configured with `conf`.
+pub fn configured_session(root: &Path, conf: &str) -> Session {
+    let conf = format!("mail_dir = {:?}\n{}", root, conf);
+    let config: Config = toml::from_str(&conf).unwrap();
+    let store = Arc::new(FsStore::new(&config.mail_dir));
+    let events = Arc::new(EventBus::new());
+    let mut session =
+        Session::new(Arc::new(config), store, Arc::new(NoUsers), None, events);
+    session.user = Some("bob".to_string());
+    session
+}
+
+/// Another session of the user of `session`, on the same server.
+pub fn other_session(session: &Session) -> Session {
+    let mut other = Session::new(
+        session.config.clone(),
+        session.store.clone(),
+        session.auth.clone(),
+        session.fts.clone(),
+        session.events.clone(),
+    );
+    other.user = session.user.clone();
+    other
+}
+
+/// Store `raw` in `mailbox` of the session's user with `flags`, returning
+/// its UID.
+pub fn add_message(
+    session: &Session,
+    mailbox: &str,
+    raw: &str,
+    flags: &[&str],
+) -> u32 {
+    let user = session.user().unwrap();
+    let mut sink = session.store.append_message(user, mailbox, None).unwrap();
+    sink.write_all(raw.as_bytes()).unwrap();
+    let flags = flags.iter().map(|f| f.to_string()).collect();
+    let date = DateTime::parse_from_rfc3339("2024-01-10T09:00:00Z").unwrap();
+    sink.commit(flags, date).unwrap()
+}
+
+/// Process the command `line` and return what the server answered.
+pub async fn run(session: &mut Session, line: &str) -> String {
+    let mut out = Cursor::new(vec![]);
+    let command = format!("{}\r\n", line);
+    process_command(command.as_bytes(), &mut out, session)
+        .await
+        .unwrap();
+    String::from_utf8(out.into_inner()).unwrap()
+}